@@ -1,31 +1,67 @@
-use std::{cmp::min, marker::PhantomData};
+use std::{cmp::min, marker::PhantomData, ops::Range};
 
-use ndarray::{s, Array3, ArrayView3, ArrayViewMut3, Dim, Ix3, SliceArg};
-use ndarray_ndimage::PadMode;
+use ndarray::{s, Array2, Array3, ArrayView2, ArrayView3, ArrayViewMut3, Dim, Ix3, SliceArg};
 use thiserror::Error;
 
-use crate::ChunkSize;
+use crate::{tensor_element::TensorElement, ChunkSize};
 
 pub struct Finalized;
 
+/// The chunksize of a new `ImageChunkGeneratorBuilder`, the default values are from nind-denoise
+///
+/// `ImageProcessor` uses the chunksize of the model and derives its padding and overlap from
+/// it instead.
+pub const DEFAULT_CHUNKSIZE: ChunkSize = ChunkSize {
+    width: 440,
+    height: 440,
+};
+/// The overlap of a new `ImageChunkGeneratorBuilder`
+pub const DEFAULT_OVERLAP: usize = 6;
+/// The chunk padding of a new `ImageChunkGeneratorBuilder`
+pub const DEFAULT_CHUNK_PADDING: usize = 60;
+
+/// How the image is continued beyond its borders to give the border chunks context
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PadMode {
+    /// Mirror the image at its borders, this is the default
+    Reflect,
+    /// Continue with the opposite edge, as if the image was tiled infinitely
+    ///
+    /// This keeps seamless textures seamless.
+    Wrap,
+    /// Repeat the outermost pixels of the image
+    Edge,
+    /// Fill the padding with a value in the value range of the padded data, e.g. the model input
+    /// range for the chunks of an `ImageProcessor`
+    Constant(f32),
+    /// Fill the padding with the mean value of each channel
+    ///
+    /// Some models expect the context outside of the image to be the dataset mean.
+    Mean,
+}
+
 pub type ImageTensor = Array3<f32>;
 
-pub struct ImageChunkGenerator<M> {
-    image_data: ImageTensor,
+pub struct ImageChunkGenerator<M, T = f32> {
+    image_data: Array3<T>,
     chunksize: ChunkSize,
     overlap: usize,
     chunk_padding: usize,
     input_image_resolution: (usize, usize),
     input_image_padding: (usize, usize),
+    pad_mode: PadMode,
     _marker: PhantomData<M>,
 }
 
-pub type ImageChunkGeneratorBuilder = ImageChunkGenerator<()>;
-pub type FinalizedImageChunkGenerator = ImageChunkGenerator<Finalized>;
+pub type ImageChunkGeneratorBuilder<T = f32> = ImageChunkGenerator<(), T>;
+pub type FinalizedImageChunkGenerator<T = f32> = ImageChunkGenerator<Finalized, T>;
 
-pub struct ImageChunkIterator<'a> {
-    data: &'a FinalizedImageChunkGenerator,
-    current_coords: (usize, usize),
+pub struct ImageChunkIterator<'a, T = f32> {
+    data: &'a FinalizedImageChunkGenerator<T>,
+    x_origins: Vec<usize>,
+    y_origins: Vec<usize>,
+    index: usize,
 }
 
 pub struct Coords {
@@ -33,77 +69,482 @@ pub struct Coords {
     pub y: usize,
 }
 
-pub struct ImageChunk<'a> {
-    pub chunk: ArrayView3<'a, f32>,
+pub struct ImageChunk<'a, T = f32> {
+    pub chunk: ArrayView3<'a, T>,
     pub global_coordinate_offset: Coords,
-    pub gen: &'a FinalizedImageChunkGenerator,
+    pub gen: &'a FinalizedImageChunkGenerator<T>,
 }
 
-impl<'a> Iterator for ImageChunkIterator<'a> {
-    type Item = ImageChunk<'a>;
+/// Describes the tiling grid of an image
+///
+/// The grid only depends on the image size and the chunk settings. Two images of similar size
+/// share their chunk origins, as long as they need the same number of chunks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkGeometryReport {
+    pub image_size: (usize, usize),
+    pub chunksize: ChunkSize,
+    pub chunk_padding: usize,
+    pub overlap: usize,
+    pub step_size: ChunkSize,
+    /// The x coordinates at which the usable area of each chunk column starts
+    pub x_origins: Vec<usize>,
+    /// The y coordinates at which the usable area of each chunk row starts
+    pub y_origins: Vec<usize>,
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let useful_chunksize = self
-            .data
+impl ChunkGeometryReport {
+    /// Compute the tiling grid for an image of `image_size` (width, height) pixels
+    ///
+    /// This fails for the same chunk settings as `ImageChunkGeneratorBuilder::finalize`.
+    pub fn new(
+        image_size: (usize, usize),
+        chunksize: ChunkSize,
+        chunk_padding: usize,
+        overlap: usize,
+    ) -> Result<Self, ImageChunkGeneratorError> {
+        check_chunk_settings(chunksize, chunk_padding, overlap)?;
+        Ok(Self::new_unchecked(
+            image_size,
+            chunksize,
+            chunk_padding,
+            overlap,
+        ))
+    }
+
+    fn new_unchecked(
+        image_size: (usize, usize),
+        chunksize: ChunkSize,
+        chunk_padding: usize,
+        overlap: usize,
+    ) -> Self {
+        let step_size = chunksize
+            .remaining_area_after_padding(chunk_padding)
+            .stepsize_with_overlap(overlap);
+        ChunkGeometryReport {
+            image_size,
+            chunksize,
+            chunk_padding,
+            overlap,
+            step_size,
+            x_origins: chunk_origins(image_size.0, step_size.width),
+            y_origins: chunk_origins(image_size.1, step_size.height),
+        }
+    }
+
+    /// The number of chunks in the grid
+    pub fn chunk_count(&self) -> usize {
+        self.x_origins.len() * self.y_origins.len()
+    }
+
+    /// The grid column and row of the chunk with the given index in iteration order
+    pub fn grid_position(&self, index: usize) -> (usize, usize) {
+        let columns = self.x_origins.len().max(1);
+        (index % columns, index / columns)
+    }
+
+    /// The region of the image (x range, y range) that a chunk contributes to the output
+    pub fn usable_region(&self, index: usize) -> (Range<usize>, Range<usize>) {
+        let (column, row) = self.grid_position(index);
+        let (x, y) = (self.x_origins[column], self.y_origins[row]);
+        let usable = self
             .chunksize
-            .remaining_area_after_padding(self.data.chunk_padding);
-        let step_size = useful_chunksize.stepsize_with_overlap(self.data.overlap);
-
-        if self.current_coords.1 < self.data.input_image_resolution.1 {
-            let mut x = self.current_coords.0 + self.data.input_image_padding.0;
-            let mut y = self.current_coords.1 + self.data.input_image_padding.1;
-
-            x -= self.data.chunk_padding;
-            y -= self.data.chunk_padding;
-
-            let chunk = self.data.image_data.slice(s![
-                ..,
-                y..y + self.data.chunksize.height,
-                x..x + self.data.chunksize.width,
-            ]);
-            let global_coordinate_offset = Coords {
-                x: self.current_coords.0,
-                y: self.current_coords.1,
-            };
-
-            self.current_coords.0 += step_size.width;
-            if self.current_coords.0 >= self.data.input_image_resolution.0 {
-                self.current_coords.0 = 0;
-                self.current_coords.1 += step_size.height;
+            .remaining_area_after_padding(self.chunk_padding);
+        (
+            x..min(x + usable.width, self.image_size.0),
+            y..min(y + usable.height, self.image_size.1),
+        )
+    }
+
+    /// The region of the image (x range, y range) that is passed to the model for a chunk
+    ///
+    /// Parts of the chunk that lie in the padding around the image are not included.
+    pub fn input_region(&self, index: usize) -> (Range<usize>, Range<usize>) {
+        let (x, y) = self.usable_region(index);
+        let padding = self.chunk_padding;
+        (
+            x.start.saturating_sub(padding)..min(x.end + padding, self.image_size.0),
+            y.start.saturating_sub(padding)..min(y.end + padding, self.image_size.1),
+        )
+    }
+
+    /// The region of the image (x range, y range) that a chunk is built from, including its padding
+    ///
+    /// Unlike `input_region`, this includes the pixels that `PadMode::Reflect` mirrors into the
+    /// padding around the image. It is enough for the other pad modes but `PadMode::Wrap`.
+    pub fn source_region(&self, index: usize) -> (Range<usize>, Range<usize>) {
+        let (column, row) = self.grid_position(index);
+        (
+            mirrored_range(
+                self.x_origins[column],
+                self.chunk_padding,
+                self.chunksize.width,
+                self.image_size.0,
+            ),
+            mirrored_range(
+                self.y_origins[row],
+                self.chunk_padding,
+                self.chunksize.height,
+                self.image_size.1,
+            ),
+        )
+    }
+
+    /// Build a chunk from the CxHxW image data of its `source_region`
+    ///
+    /// The chunk is padded like the chunks of an `ImageChunkGenerator` with the same pad mode.
+    /// `PadMode::Mean` and `PadMode::Wrap` need the whole image and are not supported.
+    pub(crate) fn chunk_from_source_region<T: TensorElement>(
+        &self,
+        index: usize,
+        source: ArrayView3<T>,
+        pad_mode: PadMode,
+    ) -> Array3<T> {
+        assert!(
+            !matches!(pad_mode, PadMode::Wrap | PadMode::Mean),
+            "{:?} padding needs the whole image",
+            pad_mode
+        );
+        let (x_range, y_range) = self.source_region(index);
+        let (column, row) = self.grid_position(index);
+        let x_start = self.x_origins[column] as isize - self.chunk_padding as isize;
+        let y_start = self.y_origins[row] as isize - self.chunk_padding as isize;
+        let (width, height) = self.image_size;
+        let shape = (
+            source.shape()[0],
+            self.chunksize.height,
+            self.chunksize.width,
+        );
+        Array3::from_shape_fn(shape, |(c, y, x)| {
+            let source_y = padded_source_index(y_start + y as isize, height, pad_mode);
+            let source_x = padded_source_index(x_start + x as isize, width, pad_mode);
+            match (source_y, source_x, pad_mode) {
+                (Some(y), Some(x), _) => source[(c, y - y_range.start, x - x_range.start)],
+                (_, _, PadMode::Constant(value)) => T::from_f32(value),
+                _ => unreachable!("Only constant padding has pixels without a source"),
             }
+        })
+    }
 
-            Some(ImageChunk {
-                chunk,
-                global_coordinate_offset,
-                gen: &self.data,
-            })
-        } else {
-            None
+    /// Scale the overlap regions of a chunk by 0.5, so that two overlapping chunks are averaged
+    ///
+    /// `scale` is the factor by which the chunk is larger than the input chunk, e.g. 2 for the
+    /// output of a 2x super resolution model.
+    pub fn scale_overlap(
+        &self,
+        global_coords: &Coords,
+        chunk: &mut ArrayViewMut3<'_, f32>,
+        scale: usize,
+    ) {
+        self.weight_overlap(global_coords, chunk, scale, |_| 0.5);
+    }
+
+    /// Scale the overlap regions of a chunk with a linear ramp
+    ///
+    /// The weights of two overlapping chunks add up to one, so a constant field stays constant
+    /// while each chunk fades out towards its border.
+    pub fn feather_overlap(
+        &self,
+        global_coords: &Coords,
+        chunk: &mut ArrayViewMut3<'_, f32>,
+        scale: usize,
+    ) {
+        self.weight_overlap(global_coords, chunk, scale, |t| t);
+    }
+
+    /// Scale the overlap regions of a chunk with a raised cosine ramp
+    ///
+    /// Like `feather_overlap`, but the slope of the weights is zero at both ends of the overlap.
+    pub fn cosine_overlap(
+        &self,
+        global_coords: &Coords,
+        chunk: &mut ArrayViewMut3<'_, f32>,
+        scale: usize,
+    ) {
+        self.weight_overlap(global_coords, chunk, scale, |t| {
+            0.5 - 0.5 * (std::f32::consts::PI * t).cos()
+        });
+    }
+
+    /// Scale the overlap regions of a chunk that are shared with neighbouring chunks
+    ///
+    /// `leading_weight` returns the weight of a row or column of the leading overlap strip from
+    /// its relative position within the strip, the center of the i-th of n rows is at
+    /// (i + 0.5) / n. The trailing strip gets the complementary weight, so that it adds up to one
+    /// with the leading strip of the next chunk.
+    ///
+    /// The weights of both axes are multiplied, so at a point shared by four chunks each of them
+    /// gets a product like 0.5 * 0.5. Since the chunks form a grid, the weights still add up to
+    /// one there, as long as a strip is weighted exactly when the neighbouring chunk exists. The
+    /// trailing strip is weighted when a next chunk starts within the image, even if this chunk
+    /// is clipped at the image border, and it starts one step size after the chunk origin.
+    ///
+    /// The strips and the step size are multiplied by `scale`, the chunk coordinates are those of
+    /// the input image.
+    fn weight_overlap(
+        &self,
+        global_coords: &Coords,
+        chunk: &mut ArrayViewMut3<'_, f32>,
+        scale: usize,
+        leading_weight: impl Fn(f32) -> f32,
+    ) {
+        let step_size = self.step_size;
+        let overlap = self.overlap * scale;
+        let weight = |i: usize| leading_weight((i as f32 + 0.5) / overlap as f32);
+        // The last chunk of a row or column can be clipped to less than the overlap
+        if global_coords.x > 0 {
+            for i in 0..min(overlap, chunk.shape()[2]) {
+                *(&mut chunk.slice_mut(s![.., .., i])) *= weight(i);
+            }
+        }
+        if global_coords.y > 0 {
+            for i in 0..min(overlap, chunk.shape()[1]) {
+                *(&mut chunk.slice_mut(s![.., i, ..])) *= weight(i);
+            }
         }
+        if global_coords.x + step_size.width < self.image_size.0 {
+            let start = step_size.width * scale;
+            let end = min(start + overlap, chunk.shape()[2]);
+            for (i, column) in (start..end).enumerate() {
+                *(&mut chunk.slice_mut(s![.., .., column])) *= 1.0 - weight(i);
+            }
+        }
+        if global_coords.y + step_size.height < self.image_size.1 {
+            let start = step_size.height * scale;
+            let end = min(start + overlap, chunk.shape()[1]);
+            for (i, row) in (start..end).enumerate() {
+                *(&mut chunk.slice_mut(s![.., row, ..])) *= 1.0 - weight(i);
+            }
+        }
+    }
+}
+
+/// The start coordinates of all chunks along one axis
+///
+/// Chunks start at every multiple of the step size that lies inside the image, so the grid is
+/// anchored at the top left corner and never depends on the parity of the image size.
+fn chunk_origins(image_size: usize, step_size: usize) -> Vec<usize> {
+    (0..image_size).step_by(step_size).collect()
+}
+
+impl<'a, T> Iterator for ImageChunkIterator<'a, T> {
+    type Item = ImageChunk<'a, T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let x_index = self.index % self.x_origins.len().max(1);
+        let y_index = self.index / self.x_origins.len().max(1);
+        let (x, y) = (*self.x_origins.get(x_index)?, *self.y_origins.get(y_index)?);
+        self.index += 1;
+
+        let padded_x = x + self.data.input_image_padding.0 - self.data.chunk_padding;
+        let padded_y = y + self.data.input_image_padding.1 - self.data.chunk_padding;
+        let chunk = self.data.image_data.slice(s![
+            ..,
+            padded_y..padded_y + self.data.chunksize.height,
+            padded_x..padded_x + self.data.chunksize.width,
+        ]);
+
+        Some(ImageChunk {
+            chunk,
+            global_coordinate_offset: Coords { x, y },
+            gen: self.data,
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (self.x_origins.len() * self.y_origins.len()).saturating_sub(self.index);
+        (remaining, Some(remaining))
     }
 }
 
+impl<'a, T> ExactSizeIterator for ImageChunkIterator<'a, T> {}
+
+/// Check that the padding leaves a usable area in each chunk and the overlap fits into it
+pub fn check_chunk_settings(
+    chunksize: ChunkSize,
+    chunk_padding: usize,
+    overlap: usize,
+) -> Result<(), ImageChunkGeneratorError> {
+    if 2 * chunk_padding >= std::cmp::min(chunksize.width, chunksize.height) {
+        return Err(ImageChunkGeneratorError::InvalidPaddingValue(
+            chunk_padding,
+            chunksize,
+        ));
+    }
+
+    let usable_output_chunksize = chunksize.remaining_area_after_padding(chunk_padding);
+    if 2 * overlap
+        > std::cmp::min(
+            usable_output_chunksize.width,
+            usable_output_chunksize.height,
+        )
+    {
+        return Err(ImageChunkGeneratorError::InvalidOverlapValue(
+            overlap,
+            usable_output_chunksize,
+        ));
+    }
+    Ok(())
+}
+
 #[derive(Debug, Clone, Error)]
 pub enum ImageChunkGeneratorError {
     #[error("Padding {0} exceeds chunksize {1:?}")]
     InvalidPaddingValue(usize, ChunkSize),
     #[error("Overlap {0} exceeds usable chunk area {1:?}")]
     InvalidOverlapValue(usize, ChunkSize),
+    #[error("The image of {0}x{1} pixels is empty")]
+    EmptyImage(usize, usize),
+}
+
+/// Pad CxHxW image data by `leading` pixels before and `trailing` (width, height) pixels after
+/// the image, the way chunks are padded
+///
+/// The padding is filled according to `pad_mode`.
+pub(crate) fn pad_image_data<T: TensorElement>(
+    image_data: &Array3<T>,
+    leading: usize,
+    trailing: (usize, usize),
+    pad_mode: PadMode,
+) -> Array3<T> {
+    let (height, width) = (image_data.shape()[1], image_data.shape()[2]);
+    let padded_shape = (
+        image_data.shape()[0],
+        leading + height + trailing.1,
+        leading + width + trailing.0,
+    );
+    // The index into the image for an index into the padded data along one axis
+    let source_index = |index: usize| index as isize - leading as isize;
+
+    match pad_mode {
+        // Repeated reflections would fill the padding with copies of a small image, so it is
+        // mirrored once and the edge of the mirrored copy is continued beyond that
+        PadMode::Reflect
+            if leading.max(trailing.0) >= width || leading.max(trailing.1) >= height =>
+        {
+            Array3::from_shape_fn(padded_shape, |(c, y, x)| {
+                image_data[(
+                    c,
+                    reflect_once(source_index(y), height),
+                    reflect_once(source_index(x), width),
+                )]
+            })
+        }
+        PadMode::Reflect => ndarray_ndimage::pad(
+            image_data,
+            &[[0, 0], [leading, trailing.1], [leading, trailing.0]],
+            ndarray_ndimage::PadMode::Reflect,
+        ),
+        // Index the image modulo its size, which also fills the corners with the opposite
+        // corner and works for padding that is larger than the image
+        PadMode::Wrap => Array3::from_shape_fn(padded_shape, |(c, y, x)| {
+            image_data[(
+                c,
+                (y + height - leading % height) % height,
+                (x + width - leading % width) % width,
+            )]
+        }),
+        PadMode::Edge => Array3::from_shape_fn(padded_shape, |(c, y, x)| {
+            image_data[(
+                c,
+                source_index(y).clamp(0, height as isize - 1) as usize,
+                source_index(x).clamp(0, width as isize - 1) as usize,
+            )]
+        }),
+        PadMode::Constant(value) => {
+            pad_with_channel_values(image_data, leading, padded_shape, |_| T::from_f32(value))
+        }
+        PadMode::Mean => pad_with_channel_values(image_data, leading, padded_shape, |channel| {
+            // Sum up in f64, f16 could overflow for large images
+            let sum: f64 = channel.iter().map(|v| v.to_f32() as f64).sum();
+            T::from_f32((sum / channel.len().max(1) as f64) as f32)
+        }),
+    }
+}
+
+/// Pad CxHxW image data with a single value for each channel, which is computed from the channel
+fn pad_with_channel_values<T: TensorElement>(
+    image_data: &Array3<T>,
+    leading: usize,
+    padded_shape: (usize, usize, usize),
+    channel_value: impl Fn(ArrayView2<T>) -> T,
+) -> Array3<T> {
+    let (height, width) = (image_data.shape()[1], image_data.shape()[2]);
+    let mut padded = Array3::zeros(padded_shape);
+    for (mut padded_channel, channel) in padded.outer_iter_mut().zip(image_data.outer_iter()) {
+        padded_channel.fill(channel_value(channel));
+    }
+    padded
+        .slice_mut(s![.., leading..leading + height, leading..leading + width])
+        .assign(image_data);
+    padded
+}
+
+/// Mirror an index outside of `0..size` at the nearest border without repeating the edge, and
+/// clamp indices that are beyond the mirrored copy
+fn reflect_once(index: isize, size: usize) -> usize {
+    let last = size as isize - 1;
+    let reflected = if index < 0 {
+        -index
+    } else if index > last {
+        2 * last - index
+    } else {
+        index
+    };
+    reflected.clamp(0, last) as usize
+}
+
+/// The index into the image for an index along one axis of the padded image, or `None` for
+/// constant and mean padding
+fn padded_source_index(index: isize, size: usize, pad_mode: PadMode) -> Option<usize> {
+    match pad_mode {
+        PadMode::Reflect => Some(reflect_once(index, size)),
+        PadMode::Wrap => Some(index.rem_euclid(size as isize) as usize),
+        PadMode::Edge => Some(index.clamp(0, size as isize - 1) as usize),
+        PadMode::Constant(_) | PadMode::Mean => (0..size as isize)
+            .contains(&index)
+            .then_some(index as usize),
+    }
 }
 
-impl ImageChunkGeneratorBuilder {
-    pub fn new_from_array(image: ImageTensor) -> Self {
+/// The range of image indices along one axis that a chunk starting `padding` pixels before
+/// `origin` reads with `PadMode::Reflect`
+fn mirrored_range(origin: usize, padding: usize, chunk_size: usize, size: usize) -> Range<usize> {
+    let start = origin as isize - padding as isize;
+    let (first, last) = (start..start + chunk_size as isize)
+        .map(|index| reflect_once(index, size))
+        .fold((usize::MAX, 0), |(first, last), index| {
+            (first.min(index), last.max(index))
+        });
+    first..last + 1
+}
+
+impl<M, T> ImageChunkGenerator<M, T> {
+    pub fn chunksize(&self) -> ChunkSize {
+        self.chunksize
+    }
+
+    pub fn overlap(&self) -> usize {
+        self.overlap
+    }
+
+    pub fn chunk_padding(&self) -> usize {
+        self.chunk_padding
+    }
+}
+
+impl<T: TensorElement> ImageChunkGeneratorBuilder<T> {
+    /// Start building a generator with `DEFAULT_CHUNKSIZE`, `DEFAULT_OVERLAP` and
+    /// `DEFAULT_CHUNK_PADDING`
+    pub fn new_from_array(image: Array3<T>) -> Self {
         Self {
             image_data: image,
-            chunksize: ChunkSize {
-                width: 440,
-                height: 440,
-            }, // Default values from nind-denoise
-            overlap: 6,
-            chunk_padding: 60,
+            chunksize: DEFAULT_CHUNKSIZE,
+            overlap: DEFAULT_OVERLAP,
+            chunk_padding: DEFAULT_CHUNK_PADDING,
             input_image_resolution: (0, 0), // We will calculate the actual size of these when
             // finalizing
             input_image_padding: (0, 0),
+            pad_mode: PadMode::Reflect,
             _marker: PhantomData,
         }
     }
@@ -135,44 +576,66 @@ impl ImageChunkGeneratorBuilder {
         self
     }
 
-    fn pad_image(&mut self) {
-        let needed_padding = self.chunksize;
-        self.image_data = ndarray_ndimage::pad(
-            &self.image_data,
-            &[
-                [0, 0],
-                [needed_padding.height, needed_padding.height],
-                [needed_padding.width, needed_padding.width],
-            ],
-            PadMode::Reflect,
-        );
-        self.input_image_padding = (needed_padding.width, needed_padding.height);
+    /// How the image is continued beyond its borders
+    pub fn set_pad_mode(&mut self, pad_mode: PadMode) {
+        self.pad_mode = pad_mode;
     }
 
-    pub fn finalize(mut self) -> Result<FinalizedImageChunkGenerator, ImageChunkGeneratorError> {
-        if 2 * self.chunk_padding >= std::cmp::min(self.chunksize.width, self.chunksize.height) {
-            return Err(ImageChunkGeneratorError::InvalidPaddingValue(
-                self.chunk_padding,
-                self.chunksize,
-            ));
-        }
+    pub fn with_pad_mode(mut self, pad_mode: PadMode) -> Self {
+        self.set_pad_mode(pad_mode);
+        self
+    }
 
-        let usable_output_chunksize = self
+    /// Calculate the padding needed after the image data along one axis
+    ///
+    /// This is exactly the amount needed to complete the last chunk along that axis. The image
+    /// must not be empty, see `finalize`.
+    fn trailing_padding(&self, image_size: usize, chunk_size: usize, step_size: usize) -> usize {
+        let last_chunk_start = chunk_origins(image_size, step_size)
+            .last()
+            .copied()
+            .expect("The image is not empty");
+        (last_chunk_start + chunk_size)
+            .checked_sub(self.chunk_padding + image_size)
+            .expect("The last chunk reaches the image border")
+    }
+
+    fn pad_image(&mut self) {
+        let step_size = self
             .chunksize
-            .remaining_area_after_padding(self.chunk_padding);
-        if 2 * self.overlap
-            > std::cmp::min(
-                usable_output_chunksize.width,
-                usable_output_chunksize.height,
-            )
-        {
-            return Err(ImageChunkGeneratorError::InvalidOverlapValue(
-                self.overlap,
-                usable_output_chunksize,
-            ));
-        }
+            .remaining_area_after_padding(self.chunk_padding)
+            .stepsize_with_overlap(self.overlap);
+        let (width, height) = self.input_image_resolution;
+        // The first chunk only needs its padding as context before the image data, the last
+        // chunk needs to be completed by the trailing padding.
+        let leading_padding = self.chunk_padding;
+        let trailing_padding = (
+            self.trailing_padding(width, self.chunksize.width, step_size.width),
+            self.trailing_padding(height, self.chunksize.height, step_size.height),
+        );
+        self.image_data = pad_image_data(
+            &self.image_data,
+            leading_padding,
+            trailing_padding,
+            self.pad_mode,
+        );
+        self.input_image_padding = (leading_padding, leading_padding);
+    }
 
+    /// Validate the chunk settings and pad the image, empty images are rejected
+    pub fn finalize(mut self) -> Result<FinalizedImageChunkGenerator<T>, ImageChunkGeneratorError> {
         self.input_image_resolution = (self.image_data.shape()[2], self.image_data.shape()[1]);
+        let (width, height) = self.input_image_resolution;
+        if width == 0 || height == 0 {
+            return Err(ImageChunkGeneratorError::EmptyImage(width, height));
+        }
+        // Validate the chunk settings before padding the image
+        ChunkGeometryReport::new(
+            self.input_image_resolution,
+            self.chunksize,
+            self.chunk_padding,
+            self.overlap,
+        )?;
         self.pad_image();
 
         Ok(FinalizedImageChunkGenerator {
@@ -182,12 +645,13 @@ impl ImageChunkGeneratorBuilder {
             chunk_padding: self.chunk_padding,
             input_image_resolution: self.input_image_resolution,
             input_image_padding: self.input_image_padding,
+            pad_mode: self.pad_mode,
             _marker: PhantomData,
         })
     }
 }
 
-impl FinalizedImageChunkGenerator {
+impl<T> FinalizedImageChunkGenerator<T> {
     /// Returns the useful area of image data in each chunk.
     /// The result is a pair of the inclusive range start and the exclusive range end
     pub fn useful_chunk_area(&self) -> (Coords, Coords) {
@@ -203,37 +667,109 @@ impl FinalizedImageChunkGenerator {
         )
     }
 
-    pub fn iter(&self) -> ImageChunkIterator {
+    /// A chunk of the padded image data whose usable area starts at the image coordinates (x, y)
+    /// when it is used with the given padding
+    ///
+    /// Near the image borders, the chunk is shifted to stay inside the padded image data, so it
+    /// has less context on that side. Returns the chunk and the position of (x, y) in it.
+    pub fn padded_chunk_at(&self, x: usize, y: usize, padding: usize) -> (ArrayView3<T>, Coords) {
+        let (padded_height, padded_width) =
+            (self.image_data.shape()[1], self.image_data.shape()[2]);
+        let padded_x = x + self.input_image_padding.0;
+        let padded_y = y + self.input_image_padding.1;
+        let start_x = padded_x
+            .saturating_sub(padding)
+            .min(padded_width - self.chunksize.width);
+        let start_y = padded_y
+            .saturating_sub(padding)
+            .min(padded_height - self.chunksize.height);
+        let chunk = self.image_data.slice(s![
+            ..,
+            start_y..start_y + self.chunksize.height,
+            start_x..start_x + self.chunksize.width,
+        ]);
+        (
+            chunk,
+            Coords {
+                x: padded_x - start_x,
+                y: padded_y - start_y,
+            },
+        )
+    }
+
+    /// Describe the tiling grid used for this image
+    pub fn geometry_report(&self) -> ChunkGeometryReport {
+        ChunkGeometryReport::new_unchecked(
+            self.input_image_resolution,
+            self.chunksize,
+            self.chunk_padding,
+            self.overlap,
+        )
+    }
+
+    /// Iterate over all chunks, row by row
+    pub fn iter(&self) -> ImageChunkIterator<T> {
+        let report = self.geometry_report();
         ImageChunkIterator {
             data: self,
-            current_coords: (0, 0),
+            x_origins: report.x_origins,
+            y_origins: report.y_origins,
+            index: 0,
         }
     }
 
-    pub fn scale_overlap(&self, global_coords: &Coords, chunk: &mut ArrayViewMut3<'_, f32>) {
-        if global_coords.x > 0 {
-            *(&mut chunk.slice_mut(s![.., .., 0..self.overlap])) *= 0.5;
-        }
-        if global_coords.y > 0 {
-            *(&mut chunk.slice_mut(s![.., 0..self.overlap, ..])) *= 0.5;
-        }
-        if global_coords.x + self.chunksize.width - 2 * self.chunk_padding
-            < self.input_image_resolution.0
-        {
-            let start = chunk.shape()[2] - self.overlap;
-            *(&mut chunk.slice_mut(s![.., .., start..start + self.overlap])) *= 0.5;
-        }
-        if global_coords.y + self.chunksize.height - 2 * self.chunk_padding
-            < self.input_image_resolution.1
-        {
-            let start = chunk.shape()[1] - self.overlap;
-            *(&mut chunk.slice_mut(s![.., start..start + self.overlap, ..])) *= 0.5;
-        }
+    /// The number of chunks that `iter` yields, including the partial chunks at the edges
+    pub fn chunk_count(&self) -> usize {
+        self.geometry_report().chunk_count()
+    }
+
+    /// Scale the overlap regions of a chunk by 0.5, see `ChunkGeometryReport::scale_overlap`
+    pub fn scale_overlap(
+        &self,
+        global_coords: &Coords,
+        chunk: &mut ArrayViewMut3<'_, f32>,
+        scale: usize,
+    ) {
+        self.geometry_report()
+            .scale_overlap(global_coords, chunk, scale);
+    }
+
+    /// Scale the overlap regions of a chunk with a linear ramp, see
+    /// `ChunkGeometryReport::feather_overlap`
+    pub fn feather_overlap(
+        &self,
+        global_coords: &Coords,
+        chunk: &mut ArrayViewMut3<'_, f32>,
+        scale: usize,
+    ) {
+        self.geometry_report()
+            .feather_overlap(global_coords, chunk, scale);
+    }
+
+    /// Scale the overlap regions of a chunk with a raised cosine ramp, see
+    /// `ChunkGeometryReport::cosine_overlap`
+    pub fn cosine_overlap(
+        &self,
+        global_coords: &Coords,
+        chunk: &mut ArrayViewMut3<'_, f32>,
+        scale: usize,
+    ) {
+        self.geometry_report()
+            .cosine_overlap(global_coords, chunk, scale);
     }
 }
 
-impl<'a> ImageChunk<'a> {
+impl<'a, T> ImageChunk<'a, T> {
     pub fn get_usable_range(&self) -> impl SliceArg<Ix3, OutDim = Dim<[usize; 3]>> {
+        self.get_scaled_usable_range(1)
+    }
+
+    /// The usable range of a chunk that is `scale` times larger than the input chunk, e.g. the
+    /// output of a super resolution model
+    pub fn get_scaled_usable_range(
+        &self,
+        scale: usize,
+    ) -> impl SliceArg<Ix3, OutDim = Dim<[usize; 3]>> {
         let width = min(
             self.gen.chunksize.width - 2 * self.gen.chunk_padding,
             self.gen.input_image_resolution.0 - self.global_coordinate_offset.x,
@@ -242,11 +778,353 @@ impl<'a> ImageChunk<'a> {
             self.gen.chunksize.height - 2 * self.gen.chunk_padding,
             self.gen.input_image_resolution.1 - self.global_coordinate_offset.y,
         );
+        let padding = self.gen.chunk_padding * scale;
 
         s![
             ..,
-            self.gen.chunk_padding..self.gen.chunk_padding + height,
-            self.gen.chunk_padding..self.gen.chunk_padding + width
+            padding..padding + height * scale,
+            padding..padding + width * scale
         ]
     }
+
+    /// A HxW mask of the chunk that is 1 for pixels of the image and 0 for the padding around it
+    ///
+    /// Models that should not learn from the padded content can receive this as an additional
+    /// input.
+    pub fn validity_mask(&self) -> Array2<f32> {
+        let (width, height) = self.gen.input_image_resolution;
+        let padding = self.gen.chunk_padding;
+        let offset = &self.global_coordinate_offset;
+        Array2::from_shape_fn(
+            (self.gen.chunksize.height, self.gen.chunksize.width),
+            |(y, x)| {
+                // The chunk starts `padding` pixels before its usable area
+                let image_x = (offset.x + x).checked_sub(padding);
+                let image_y = (offset.y + y).checked_sub(padding);
+                match (image_x, image_y) {
+                    (Some(image_x), Some(image_y)) if image_x < width && image_y < height => 1.0,
+                    _ => 0.0,
+                }
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn generator(width: usize, height: usize) -> FinalizedImageChunkGenerator {
+        ImageChunkGeneratorBuilder::new_from_array(ImageTensor::zeros((3, height, width)))
+            .with_chunksize(ChunkSize {
+                width: 64,
+                height: 64,
+            })
+            .with_chunk_padding(8)
+            .with_overlap(4)
+            .finalize()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_last_chunk_aligns_with_padded_image() {
+        // The step size is 44, none of these resolutions are a multiple of it
+        for (width, height) in [(100, 70), (45, 131), (300, 200)] {
+            let gen = generator(width, height);
+            let padded_height = gen.image_data.shape()[1];
+            let padded_width = gen.image_data.shape()[2];
+
+            for chunk in gen.iter() {
+                assert_eq!(chunk.chunk.shape(), &[3, 64, 64]);
+            }
+
+            let last = gen.iter().last().unwrap();
+            let offset = last.global_coordinate_offset;
+            assert_eq!(offset.x + 64, padded_width);
+            assert_eq!(offset.y + 64, padded_height);
+            // The usable area of the last chunk reaches the image border
+            assert!(offset.x + 48 >= width);
+            assert!(offset.y + 48 >= height);
+        }
+    }
+
+    #[test]
+    fn test_builder_defaults() {
+        let builder = ImageChunkGeneratorBuilder::new_from_array(Array3::<f32>::zeros((3, 8, 8)));
+        assert_eq!(builder.chunksize(), DEFAULT_CHUNKSIZE);
+        assert_eq!(builder.overlap(), DEFAULT_OVERLAP);
+        assert_eq!(builder.chunk_padding(), DEFAULT_CHUNK_PADDING);
+        // The defaults are a valid chunk geometry
+        assert!(ChunkGeometryReport::new(
+            (1000, 1000),
+            DEFAULT_CHUNKSIZE,
+            DEFAULT_CHUNK_PADDING,
+            DEFAULT_OVERLAP
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_empty_image() {
+        for (width, height) in [(0, 0), (0, 70), (100, 0)] {
+            let result =
+                ImageChunkGeneratorBuilder::new_from_array(ImageTensor::zeros((3, height, width)))
+                    .finalize();
+            assert!(matches!(
+                result,
+                Err(ImageChunkGeneratorError::EmptyImage(w, h)) if (w, h) == (width, height)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_padding_is_asymmetric() {
+        let gen = generator(100, 70);
+
+        assert_eq!(gen.input_image_padding, (8, 8));
+        // Chunks start at x = 0, 44, 88 and y = 0, 44
+        assert_eq!(gen.image_data.shape(), &[3, 44 + 64, 88 + 64]);
+    }
+
+    #[test]
+    fn test_chunk_origins_are_pinned() {
+        // The step size is 64 - 2 * 8 - 4 = 44 for both axes
+        for (width, height, x_origins, y_origins) in [
+            (100, 70, vec![0, 44, 88], vec![0, 44]),
+            (101, 71, vec![0, 44, 88], vec![0, 44]),
+            (88, 44, vec![0, 44], vec![0]),
+            (89, 45, vec![0, 44, 88], vec![0, 44]),
+        ] {
+            let gen = generator(width, height);
+            let report = gen.geometry_report();
+            assert_eq!(
+                report.step_size,
+                ChunkSize {
+                    width: 44,
+                    height: 44
+                }
+            );
+            assert_eq!(report.x_origins, x_origins);
+            assert_eq!(report.y_origins, y_origins);
+
+            let chunk_origins: Vec<_> = gen
+                .iter()
+                .map(|chunk| {
+                    (
+                        chunk.global_coordinate_offset.x,
+                        chunk.global_coordinate_offset.y,
+                    )
+                })
+                .collect();
+            let expected: Vec<_> = y_origins
+                .iter()
+                .flat_map(|&y| x_origins.iter().map(move |&x| (x, y)))
+                .collect();
+            assert_eq!(chunk_origins, expected);
+        }
+    }
+
+    #[test]
+    fn test_small_image_is_a_single_chunk() {
+        let image = ImageTensor::from_shape_fn((3, 32, 32), |(c, y, x)| {
+            (c * 1024 + y * 32 + x) as f32 / 3072.0
+        });
+        let gen = ImageChunkGeneratorBuilder::new_from_array(image.clone())
+            .with_chunksize(ChunkSize {
+                width: 440,
+                height: 440,
+            })
+            .finalize()
+            .unwrap();
+
+        assert_eq!(gen.chunk_count(), 1);
+        let chunks: Vec<_> = gen.iter().collect();
+        assert_eq!(chunks.len(), 1);
+        let chunk = &chunks[0];
+        assert_eq!(chunk.chunk.shape(), &[3, 440, 440]);
+        assert_eq!(chunk.chunk.slice(chunk.get_usable_range()), image);
+
+        // The image is mirrored once at each border, beyond that the edge of the mirror repeats
+        let padding = DEFAULT_CHUNK_PADDING;
+        assert_eq!(
+            chunk.chunk[(0, padding + 32, padding + 5)],
+            image[(0, 30, 5)]
+        );
+        assert_eq!(
+            chunk.chunk[(0, padding + 200, padding + 5)],
+            image[(0, 0, 5)]
+        );
+        assert_eq!(chunk.chunk[(1, 0, 0)], image[(1, 31, 31)]);
+    }
+
+    #[test]
+    fn test_chunk_count() {
+        // The step size is 44, none of these resolutions are a multiple of it
+        for (width, height, count) in [(100, 70, 6), (45, 131, 6), (1, 1, 1), (133, 89, 12)] {
+            let gen = generator(width, height);
+            assert_eq!(gen.chunk_count(), count);
+            assert_eq!(gen.iter().count(), count);
+
+            let mut chunks = gen.iter();
+            assert_eq!(chunks.len(), count);
+            chunks.next();
+            assert_eq!(chunks.len(), count - 1);
+            assert_eq!(chunks.by_ref().count(), count - 1);
+            assert_eq!(chunks.len(), 0);
+        }
+
+        // Without padding and overlap the step is the whole chunk
+        let gen = ImageChunkGeneratorBuilder::new_from_array(ImageTensor::zeros((3, 65, 129)))
+            .with_chunksize(ChunkSize {
+                width: 64,
+                height: 64,
+            })
+            .with_chunk_padding(0)
+            .with_overlap(0)
+            .finalize()
+            .unwrap();
+        assert_eq!(gen.chunk_count(), 3 * 2);
+        assert_eq!(gen.iter().len(), gen.iter().count());
+    }
+
+    #[test]
+    fn test_geometry_is_independent_of_parity() {
+        let even = generator(200, 150).geometry_report();
+        let odd = generator(201, 151).geometry_report();
+
+        assert_ne!(even, odd);
+        assert_eq!(even.x_origins, odd.x_origins);
+        assert_eq!(even.y_origins, odd.y_origins);
+    }
+
+    #[test]
+    fn test_mean_padding() {
+        let image = ImageTensor::from_shape_fn((3, 70, 100), |(c, y, x)| {
+            (c * 1000 + y * 100 + x) as f32 / 10000.0
+        });
+        let means: Vec<f32> = image
+            .outer_iter()
+            .map(|channel| channel.mean().unwrap())
+            .collect();
+
+        let gen = ImageChunkGeneratorBuilder::new_from_array(image.clone())
+            .with_chunksize(ChunkSize {
+                width: 64,
+                height: 64,
+            })
+            .with_chunk_padding(8)
+            .with_overlap(4)
+            .with_pad_mode(PadMode::Mean)
+            .finalize()
+            .unwrap();
+
+        for c in 0..3 {
+            let channel = gen.image_data.index_axis(ndarray::Axis(0), c);
+            for value in [
+                channel[(0, 0)],
+                channel[(7, 50)],
+                channel[(40, 7)],
+                channel[(8 + 70, 50)],
+                channel[(40, 8 + 100)],
+            ] {
+                assert!((value - means[c]).abs() < 1e-5);
+            }
+        }
+        assert_eq!(gen.image_data.slice(s![.., 8..8 + 70, 8..8 + 100]), image);
+    }
+
+    #[test]
+    fn test_wrap_padding() {
+        let image = ImageTensor::from_shape_fn((1, 5, 7), |(_, y, x)| (y * 10 + x) as f32);
+
+        let padded = pad_image_data(&image, 3, (9, 2), PadMode::Wrap);
+        assert_eq!(padded.shape(), &[1, 3 + 5 + 2, 3 + 7 + 9]);
+        assert_eq!(padded.slice(s![.., 3..8, 3..10]), image);
+        // Left of the image is its right edge, above it its bottom edge
+        assert_eq!(padded[(0, 3, 2)], image[(0, 0, 6)]);
+        assert_eq!(padded[(0, 2, 3)], image[(0, 4, 0)]);
+        // The corners continue with the opposite corner
+        assert_eq!(padded[(0, 2, 2)], image[(0, 4, 6)]);
+        assert_eq!(padded[(0, 8, 10)], image[(0, 0, 0)]);
+        // Padding larger than the image wraps around more than once
+        assert_eq!(padded[(0, 3, 17)], image[(0, 0, 0)]);
+    }
+
+    #[test]
+    fn test_edge_and_constant_padding() {
+        let image = ImageTensor::from_elem((2, 5, 7), 0.75);
+        let pad = |pad_mode| pad_image_data(&image, 3, (4, 2), pad_mode);
+
+        let reflected = pad(PadMode::Reflect);
+        let constant = pad(PadMode::Constant(0.0));
+        for padded in [&reflected, &constant] {
+            assert_eq!(padded.shape(), &[2, 3 + 5 + 2, 3 + 7 + 4]);
+            assert_eq!(padded.slice(s![.., 3..8, 3..10]), image);
+        }
+        // A constant image continues with its value, unless the padding is a constant
+        assert!(reflected.iter().all(|&v| v == 0.75));
+        let border = constant.slice(s![.., 0..3, ..]);
+        assert!(border.iter().all(|&v| v == 0.0));
+        assert_eq!(constant[(1, 9, 13)], 0.0);
+        assert_eq!(constant[(1, 4, 2)], 0.0);
+
+        let image = ImageTensor::from_shape_fn((1, 5, 7), |(_, y, x)| (y * 10 + x) as f32);
+        let edge = pad_image_data(&image, 3, (4, 2), PadMode::Edge);
+        assert_eq!(edge.slice(s![.., 3..8, 3..10]), image);
+        assert_eq!(edge[(0, 4, 0)], image[(0, 1, 0)]);
+        assert_eq!(edge[(0, 0, 0)], image[(0, 0, 0)]);
+        assert_eq!(edge[(0, 9, 13)], image[(0, 4, 6)]);
+    }
+
+    #[test]
+    fn test_validity_mask() {
+        let (width, height) = (100, 70);
+        let gen = generator(width, height);
+        let all = |mask: ndarray::ArrayView2<f32>, value: f32| mask.iter().all(|&v| v == value);
+
+        let first = gen.iter().next().unwrap();
+        let mask = first.validity_mask();
+        assert_eq!(mask.shape(), &[64, 64]);
+        assert!(all(mask.slice(s![..8, ..]), 0.0));
+        assert!(all(mask.slice(s![.., ..8]), 0.0));
+        assert!(all(mask.slice(s![8.., 8..]), 1.0));
+
+        let last = gen.iter().last().unwrap();
+        let mask = last.validity_mask();
+        let real_width = width - (last.global_coordinate_offset.x - 8);
+        let real_height = height - (last.global_coordinate_offset.y - 8);
+        assert!(real_width < 64 && real_height < 64);
+        assert!(all(mask.slice(s![..real_height, ..real_width]), 1.0));
+        assert!(all(mask.slice(s![real_height.., ..]), 0.0));
+        assert!(all(mask.slice(s![.., real_width..]), 0.0));
+    }
+
+    #[test]
+    fn test_chunk_from_source_region() {
+        // Small images are mirrored once and clamped, larger ones only mirrored
+        for (width, height) in [(100, 70), (20, 13), (45, 131)] {
+            let image = ImageTensor::from_shape_fn((2, height, width), |(c, y, x)| {
+                (c * 10000 + y * 100 + x) as f32
+            });
+            for pad_mode in [PadMode::Reflect, PadMode::Edge, PadMode::Constant(-1.0)] {
+                let gen = ImageChunkGeneratorBuilder::new_from_array(image.clone())
+                    .with_chunksize(ChunkSize {
+                        width: 64,
+                        height: 64,
+                    })
+                    .with_chunk_padding(8)
+                    .with_overlap(4)
+                    .with_pad_mode(pad_mode)
+                    .finalize()
+                    .unwrap();
+                let report = gen.geometry_report();
+                for (index, chunk) in gen.iter().enumerate() {
+                    let (x, y) = report.source_region(index);
+                    let source = image.slice(s![.., y, x]);
+                    let rebuilt = report.chunk_from_source_region(index, source, pad_mode);
+                    assert_eq!(rebuilt, chunk.chunk, "chunk {} with {:?}", index, pad_mode);
+                }
+            }
+        }
+    }
 }
@@ -1,12 +1,69 @@
-use crate::{model_value_range::ModelValueRange, ChunkSize};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::time::{Duration, Instant};
 
-use super::image_chunk_iterator::ImageChunkGeneratorBuilder;
-use super::model_runner::ModelRunner;
-use image::{ImageBuffer, Rgb};
-use ndarray::Array3;
+use crate::{model_value_range::ModelValueRange, tensor_element::TensorElement, ChunkSize};
+
+use super::image_chunk_iterator::{
+    check_chunk_settings, pad_image_data, ChunkGeometryReport, Coords,
+    FinalizedImageChunkGenerator, ImageChunkGeneratorBuilder, ImageChunkGeneratorError, PadMode,
+};
+use super::image_tensor::{
+    image_f32_to_tensor, image_rgba_to_tensor, image_to_tensor, tensor_into_image, tensor_to_image,
+    tensor_to_image_f32, tensor_to_image_rgba, tensor_to_image_u8_dithered, TensorConversionError,
+};
+use super::model_runner::{
+    AuxiliaryInput, BackendInfo, DownscaleFilter, ModelRunner, ModelRunnerError,
+    ProcessingConcurrency,
+};
+use super::post_process::{apply_chain, PostProcess};
+use image::{ImageBuffer, Rgb, Rgba};
+use ndarray::{s, Array2, Array3, ArrayView3, ArrayViewMut3, Axis, CowArray, Ix3};
 use thiserror::Error;
 use wonnx::utils::{DataTypeError, Shape};
 
+/// The maximum number of passes `ImageProcessor` will run on an image
+pub const MAX_PASSES: usize = 10;
+/// Pass counts above this value will cause a warning
+const HIGH_PASS_COUNT: usize = 3;
+/// Automatic chunksize reduction will not go below this size
+const MIN_AUTO_CHUNKSIZE: usize = 32;
+/// A rough factor for the memory used by intermediate results of the model, relative to the
+/// size of the input and output chunk
+const CHUNK_ACTIVATION_FACTOR: usize = 16;
+
+/// Estimate the peak memory in bytes needed to process an image of `width` x `height` pixels
+///
+/// This is an upper bound for the buffers held by `ImageProcessor` and a rough guess for the
+/// memory needed by the model itself.
+pub fn estimate_peak_memory(
+    width: usize,
+    height: usize,
+    chunksize: ChunkSize,
+    model_scale: usize,
+) -> usize {
+    let pixel_size = 3 * std::mem::size_of::<f32>();
+    // The normalized input and its copy in the model value range
+    let input = 2 * width * height * pixel_size;
+    // The padded copy in the chunk generator, padding never exceeds one chunk per axis
+    let padded = (width + chunksize.width) * (height + chunksize.height) * pixel_size;
+    let output = width * height * pixel_size;
+    let chunk_pixels = chunksize.width * chunksize.height;
+    let chunk = (chunk_pixels + chunk_pixels * model_scale * model_scale)
+        * pixel_size
+        * CHUNK_ACTIVATION_FACTOR;
+
+    input + padded + output + chunk
+}
+
+/// Reject images without pixels, which have no chunks
+fn check_not_empty(width: usize, height: usize) -> Result<(), ImageChunkGeneratorError> {
+    if width == 0 || height == 0 {
+        return Err(ImageChunkGeneratorError::EmptyImage(width, height));
+    }
+    Ok(())
+}
+
 #[derive(Debug, Error)]
 pub enum ImageProcessingError {
     #[error("Session could not be created")]
@@ -20,7 +77,74 @@ pub enum ImageProcessingError {
     #[error("The models input is unsupported (a [1,3,h,w] shaped input is required and h must be equal to w)")]
     InvalidInputShape(Shape),
     #[error("The chunk generator failed")]
-    ChunkGeneratorError(#[from] super::image_chunk_iterator::ImageChunkGeneratorError),
+    ChunkGeneratorError(#[from] ImageChunkGeneratorError),
+    #[error("The model could not process a chunk")]
+    ModelRunnerError(#[from] ModelRunnerError),
+    #[error("The image could not be converted")]
+    TensorConversionError(#[from] TensorConversionError),
+    #[error(
+        "Processing needs an estimated {required} bytes, exceeding the budget of {budget} bytes"
+    )]
+    MemoryBudgetExceeded { required: usize, budget: usize },
+    #[error("The image has {image} channels, but the model expects {model} channels")]
+    ChannelCountMismatch { image: usize, model: usize },
+    #[error("The reference images do not match the image dimensions {0:?}")]
+    IncrementalReferenceMismatch((u32, u32)),
+    #[error("The output buffer has shape {actual:?}, but the output has shape {expected:?}")]
+    OutputBufferMismatch {
+        expected: Vec<usize>,
+        actual: Vec<usize>,
+    },
+    #[error("The model does not accept chunks of {chunksize:?}: {reason}")]
+    ChunkValidationFailed {
+        chunksize: ChunkSize,
+        reason: String,
+    },
+    #[error("The model output contains {0} NaN or infinite values")]
+    NonFiniteOutput(usize),
+    #[error("The model has no auxiliary input {0}")]
+    UnknownAuxiliaryInput(String),
+    #[error("No data was set for the auxiliary input {0}")]
+    MissingAuxiliaryInput(String),
+    #[error("The data of the auxiliary input {name} has shape {actual:?}, but the image requires shape {expected:?}")]
+    AuxiliaryInputMismatch {
+        name: String,
+        expected: Vec<usize>,
+        actual: Vec<usize>,
+    },
+    #[error("The channel adjustment has {gains} gains and {offsets} offsets, but the output has {channels} channels")]
+    ChannelAdjustmentMismatch {
+        gains: usize,
+        offsets: usize,
+        channels: usize,
+    },
+    #[error("The mask has shape {actual:?}, but the image has shape {expected:?}")]
+    MaskMismatch {
+        expected: Vec<usize>,
+        actual: Vec<usize>,
+    },
+    #[error("The prepared image does not match the chunk or input settings, prepare it again")]
+    PreparedImageOutdated,
+    #[error("Keeping the model scale is not supported with {0}")]
+    UpscalingNotSupported(&'static str),
+    #[error("At least one pass is needed to process an image")]
+    NoPasses,
+    #[error("Streaming an image is not supported with {0}")]
+    StreamingNotSupported(&'static str),
+    #[error("The chunk geometry does not match the current settings, get it again")]
+    StreamingGeometryOutdated,
+    #[error("Chunk {index} could not be read: {reason}")]
+    ChunkReadFailed { index: usize, reason: String },
+    #[error("The inference scale {0} is not in (0,1]")]
+    InvalidInferScale(f32),
+}
+
+/// The data of an auxiliary model input, see `ImageProcessor::set_auxiliary_input`
+enum AuxiliaryData {
+    /// HxWxC data with the size of the processed images
+    Map(Array3<f32>),
+    /// A value that is used for every pixel and channel
+    Constant(f32),
 }
 
 pub struct ImageProcessor {
@@ -29,11 +153,316 @@ pub struct ImageProcessor {
     model_input_range: ModelValueRange,
     model_output_range: ModelValueRange,
     chunksize: ChunkSize,
-    chunk_padding: usize,
-    chunk_overlap: usize,
+    process_mode: ProcessMode,
+    blend_mode: BlendMode,
+    chunk_error_policy: ChunkErrorPolicy,
+    non_finite_policy: NonFinitePolicy,
+    chunk_hook: Option<ChunkHook>,
+    progress_callback: Option<ProgressCallback>,
+    auto_chunksize: bool,
+    /// The chunksize and process mode before `reduce_chunksize`, restored for the next image
+    unreduced_chunking: Option<(ChunkSize, ProcessMode)>,
+    memory_budget: Option<usize>,
+    min_tiles: Option<usize>,
+    max_tile_pixels: Option<usize>,
+    preserve_border: usize,
+    output_range_check: OutputRangeCheck,
+    adaptive_padding: Option<AdaptivePadding>,
+    /// Set once the output range has been checked, it is only checked for the first image
+    output_range_checked: bool,
+    skip_uniform: Option<f32>,
+    pad_mode: PadMode,
+    post_processes: Vec<PostProcess>,
+    mask: Option<Array2<f32>>,
+    infer_scale: f32,
+    /// The data of the auxiliary model inputs by input name
+    auxiliary_data: HashMap<String, AuxiliaryData>,
+    /// The model output of `process_image_into`, kept to avoid allocations for same-size images
+    output_scratchpad: Array3<f32>,
+    /// The inference time of each chunk of the last processed image
+    chunk_timings: Vec<Option<Duration>>,
+    #[cfg(feature = "half")]
+    half_precision: bool,
+    accumulator_precision: AccumulatorPrecision,
+}
+
+/// The point in the processing loop at which a chunk hook is called
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkStage {
+    /// The hook receives the model input of a chunk before inference, including the channels of
+    /// auxiliary inputs
+    PreInference,
+    /// The hook receives the model output of a chunk after inference
+    PostInference,
+}
+
+type ChunkHook = Box<dyn FnMut(ChunkStage, &mut Array3<f32>)>;
+
+/// The progress of processing an image, passed to the progress callback after each chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgressEvent {
+    /// The number of finished chunks, starting at 1
+    pub current_chunk: usize,
+    /// The number of chunks of the image, only selected chunks count for incremental processing
+    pub total_chunks: usize,
+}
+
+type ProgressCallback = Box<dyn FnMut(ProgressEvent)>;
+
+/// Defines how an image is split into chunks for processing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ProcessMode {
+    /// Process chunks with `padding` pixels of context on each side and blend the `overlap`
+    /// region between neighbouring chunks
+    Tiled { padding: usize, overlap: usize },
+    /// Process chunks edge-to-edge without any padding or blending
+    ///
+    /// This is faster than `Tiled`, but only suitable for shift-invariant models that produce
+    /// seamless tiles. Other models will show visible seams at the chunk borders.
+    Simple,
+}
+
+/// The settings an `ImageProcessor` uses to process images
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProcessorSettings {
+    pub color_model: ImageColorModel,
+    pub input_range: ModelValueRange,
+    pub output_range: ModelValueRange,
+    pub chunksize: ChunkSize,
+    pub process_mode: ProcessMode,
+    pub blend_mode: BlendMode,
+    pub backend: String,
+    pub post_processes: Vec<PostProcess>,
+    pub pad_mode: PadMode,
+    pub preserve_border: usize,
+    pub adaptive_padding: Option<AdaptivePadding>,
+    /// Whether the output was blended with the input by a mask, the mask itself is not stored
+    pub mask: bool,
+    pub infer_scale: f32,
+    pub accumulator_precision: AccumulatorPrecision,
+    pub skip_uniform: Option<f32>,
+    pub upscale: bool,
+    pub downscale_filter: DownscaleFilter,
+}
+
+/// Defines how the overlap regions of neighbouring chunks are combined in `ProcessMode::Tiled`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BlendMode {
+    /// Average overlapping chunks with equal weights
+    Average,
+    /// Fade each chunk out towards its border with a linear ramp, best for super resolution
+    Feather,
+    /// Fade each chunk out with a raised cosine, which has no kinks at the ends of the overlap
+    Cosine,
+    /// Keep the value with the larger magnitude in the model value range
+    Max,
+}
+
+/// Defines what happens when the model fails to process a chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkErrorPolicy {
+    /// Abort processing the image with the error
+    Strict,
+    /// Log the error and use the input of the failed chunk as its output
+    KeepGoing,
+}
+
+/// Defines what happens to NaN and infinite values in the model output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonFinitePolicy {
+    /// Abort processing the image with an error
+    Error,
+    /// Replace the values with 0
+    Zero,
+    /// Replace infinite values with the nearest bound of the output range and NaN with 0
+    Clamp,
+}
+
+/// The precision of the buffer in which the chunk outputs are summed up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AccumulatorPrecision {
+    /// Half the memory of `F32`, for images that barely fit into memory
+    #[cfg(feature = "half")]
+    F16,
+    F32,
+    /// Twice the memory of `F32` without rounding errors when many chunks overlap
+    F64,
+}
+
+/// A linear correction of each output channel, `gain * value + offset`
+///
+/// This is applied to the normalized output before it is quantized, e.g. to remove a color cast
+/// of a model. Values are given in RGB order.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChannelAdjustment {
+    pub gain: Vec<f32>,
+    pub offset: Vec<f32>,
+}
+
+impl ChannelAdjustment {
+    /// The adjustment that does not change any value
+    pub fn identity(channels: usize) -> Self {
+        Self {
+            gain: vec![1.0; channels],
+            offset: vec![0.0; channels],
+        }
+    }
+
+    /// Apply the adjustment to HxWxC data
+    pub fn apply(&self, data: &mut Array3<f32>) -> Result<(), ImageProcessingError> {
+        let channels = data.shape()[2];
+        if self.gain.len() != channels || self.offset.len() != channels {
+            return Err(ImageProcessingError::ChannelAdjustmentMismatch {
+                gains: self.gain.len(),
+                offsets: self.offset.len(),
+                channels,
+            });
+        }
+        for ((mut channel, gain), offset) in data
+            .axis_iter_mut(Axis(2))
+            .zip(&self.gain)
+            .zip(&self.offset)
+        {
+            channel.mapv_inplace(|v| gain * v + offset);
+        }
+        Ok(())
+    }
+}
+
+/// Stretches the output linearly so that the given percentiles of its values become 0 and 1
+///
+/// The percentiles are computed over all channels of the whole image, so the colors and the
+/// levels of neighbouring chunks stay consistent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AutoLevel {
+    /// The percentile that becomes 0, e.g. 0.5
+    pub low_percentile: f32,
+    /// The percentile that becomes 1, e.g. 99.5
+    pub high_percentile: f32,
+}
+
+impl Default for AutoLevel {
+    fn default() -> Self {
+        Self {
+            low_percentile: 0.5,
+            high_percentile: 99.5,
+        }
+    }
+}
+
+impl AutoLevel {
+    /// Stretch the data, it is left unchanged if the percentiles have the same value
+    pub fn apply(&self, data: &mut Array3<f32>) {
+        if data.is_empty() {
+            return;
+        }
+        let mut values: Vec<f32> = data.iter().copied().collect();
+        let mut percentile = |percent: f32| {
+            let index = (percent.clamp(0.0, 100.0) / 100.0 * (values.len() - 1) as f32).round();
+            *values
+                .select_nth_unstable_by(index as usize, f32::total_cmp)
+                .1
+        };
+        let (low, high) = (
+            percentile(self.low_percentile),
+            percentile(self.high_percentile),
+        );
+        if high - low <= f32::EPSILON {
+            log::warn!("The output has no contrast to stretch, skipping the auto level");
+            return;
+        }
+        log::info!("Stretching the output range [{}, {}] to [0, 1]", low, high);
+        data.mapv_inplace(|v| (v - low) / (high - low));
+    }
+}
+
+/// Settings for chunks that need more or less padding, see `ImageProcessor::set_adaptive_padding`
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AdaptivePadding {
+    /// The padding used for flat chunks
+    pub min_padding: usize,
+    /// The padding used for high-contrast chunks
+    pub max_padding: usize,
+    /// The mean gradient magnitude, relative to the input value range, below which a chunk is
+    /// considered flat
+    pub flat_threshold: f32,
+    /// The mean gradient magnitude, relative to the input value range, above which a chunk is
+    /// considered high-contrast
+    pub contrast_threshold: f32,
+}
+
+/// Defines if the model output is compared to the declared output range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputRangeCheck {
+    Disabled,
+    /// Log a warning if the output of the first image with enough contrast does not fit the
+    /// declared range
+    Warn,
+    /// Like `Warn`, but also use a range that fits the measured output from then on
+    Adopt,
+}
+
+/// The previous version of an image, see `ImageProcessor::process_image_incremental`
+#[derive(Debug, Clone, Copy)]
+pub struct IncrementalReference<'a> {
+    pub input: &'a ImageBuffer<Rgb<u16>, Vec<u16>>,
+    /// The output for `input`, processed with the same model and settings
+    pub output: &'a ImageBuffer<Rgb<u16>, Vec<u16>>,
+    /// The largest difference of normalized input values that still counts as unchanged
+    pub tolerance: f32,
+}
+
+/// An image that is converted and padded once to be processed repeatedly
+///
+/// See `ImageProcessor::prepare_image`. Settings that change the chunk grid or the model input,
+/// like the chunksize, the padding, the input range or the inference scale, make it outdated.
+/// The auxiliary inputs are taken from the processor when the image is prepared.
+pub struct PreparedImage {
+    /// The image data in HxWxC order with values in [0,1]
+    data: Array3<f32>,
+    /// The image data at the inference scale, if it is below 1
+    scaled: Option<Array3<f32>>,
+    input: PreparedInput,
+    settings: PreparationSettings,
+}
+
+/// The model input of a `PreparedImage`, in the form its process mode consumes it
+enum PreparedInput {
+    /// The padded model input of an image that is processed in overlapping chunks
+    Tiled(FinalizedImageChunkGenerator<f32>),
+    /// The CxHxW model input of an image that is processed in `ProcessMode::Simple` or fits
+    /// into a single chunk, these paths pad the image themselves
+    Untiled(Array3<f32>),
+}
+
+impl PreparedImage {
+    /// The (width, height) of the image
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.data.shape()[1], self.data.shape()[0])
+    }
+}
+
+/// The settings that the padded data of a `PreparedImage` depends on
+#[derive(Debug, Clone, PartialEq)]
+struct PreparationSettings {
+    chunksize: ChunkSize,
+    process_mode: ProcessMode,
+    adaptive_padding: Option<AdaptivePadding>,
+    pad_mode: PadMode,
+    input_range: ModelValueRange,
+    color_model: ImageColorModel,
+    infer_scale: f32,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ImageColorModel {
     RGB,
     BGR,
@@ -50,8 +479,22 @@ impl ImageProcessor {
 
         let min_dim = std::cmp::min(chunksize.width, chunksize.height);
 
-        let default_padding = min_dim / 7; // TODO: This is an experimental value and will probably to
-                                           // work for many models
+        // Keep at least half of each chunk usable, even for models with a large receptive field
+        let max_padding = min_dim / 4;
+        let default_padding = match runner.recommended_padding() {
+            Some(padding) if padding > max_padding => {
+                log::warn!(
+                    "The model needs a padding of {} pixels, limiting it to {} for chunksize {:?}",
+                    padding,
+                    max_padding,
+                    chunksize
+                );
+                max_padding
+            }
+            Some(padding) => padding,
+            // Without an estimate, fall back to an experimental value that works for many models
+            None => min_dim / 7,
+        };
         let default_overlap = default_padding / 10;
 
         Ok(ImageProcessor {
@@ -60,85 +503,4428 @@ impl ImageProcessor {
             model_input_range,
             model_output_range,
             chunksize,
-            chunk_padding: default_padding,
-            chunk_overlap: default_overlap,
+            process_mode: ProcessMode::Tiled {
+                padding: default_padding,
+                overlap: default_overlap,
+            },
+            blend_mode: BlendMode::Average,
+            chunk_error_policy: ChunkErrorPolicy::Strict,
+            non_finite_policy: NonFinitePolicy::Error,
+            chunk_hook: None,
+            progress_callback: None,
+            auto_chunksize: false,
+            unreduced_chunking: None,
+            memory_budget: None,
+            min_tiles: None,
+            max_tile_pixels: None,
+            preserve_border: 0,
+            output_range_check: OutputRangeCheck::Disabled,
+            adaptive_padding: None,
+            output_range_checked: false,
+            skip_uniform: None,
+            pad_mode: PadMode::Reflect,
+            post_processes: Vec::new(),
+            mask: None,
+            infer_scale: 1.0,
+            auxiliary_data: HashMap::new(),
+            output_scratchpad: Array3::zeros((0, 0, 3)),
+            chunk_timings: Vec::new(),
+            #[cfg(feature = "half")]
+            half_precision: false,
+            accumulator_precision: AccumulatorPrecision::F32,
         })
     }
 
-    /// Change the color channel order of an image in RGB to BGR (or vice versa)
+    /// The name of the backend that runs the model
+    pub fn backend_name(&self) -> &'static str {
+        self.runner.backend_name()
+    }
+
+    /// The backend that runs the model, see `ModelRunner::backend_info`
+    pub fn backend_info(&self) -> BackendInfo {
+        self.runner.backend_info()
+    }
+
+    /// The number of channels the model processes, e.g. 3 for RGB or 4 for RGBA models
+    pub fn channels(&self) -> usize {
+        self.runner.get_channels()
+    }
+
+    /// Run a blank chunk of the current chunksize through the model
     ///
-    /// The data channel order must be in HxWxC order (i.e. height x width x 3)
+    /// Models with a dynamic input shape may not support every chunksize. This finds out before
+    /// any image is loaded, instead of failing on the first chunk of the first image.
+    pub async fn validate(&mut self) -> Result<(), ImageProcessingError> {
+        let channels = self.channels()
+            + self
+                .auxiliary_inputs()
+                .iter()
+                .map(|input| input.channels)
+                .sum::<usize>();
+        let chunk = Array3::zeros((channels, self.chunksize.height, self.chunksize.width));
+        let scale = self.runner.get_model_scale();
+        let expected = [
+            self.channels(),
+            self.chunksize.height * scale,
+            self.chunksize.width * scale,
+        ];
+
+        let output = self
+            .runner
+            .process_chunk(chunk.view())
+            .await
+            .map_err(|err| ImageProcessingError::ChunkValidationFailed {
+                chunksize: self.chunksize,
+                reason: err.to_string(),
+            })?;
+        if output.shape() != expected {
+            return Err(ImageProcessingError::ChunkValidationFailed {
+                chunksize: self.chunksize,
+                reason: format!(
+                    "the output has shape {:?} instead of {:?}",
+                    output.shape(),
+                    expected
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    /// Run the model on a copy of the image that is scaled by this factor
     ///
-    /// This is very inefficient, but to make it more eficient would probably take unsafe code.
-    /// Maybe we could look into adding a "permute_axis" function to ndarray.
-    fn rgb_to_bgr<T>(data: &mut Array3<T>) {
-        log::debug!(
-            "Swapping the first and third index of the third axis in data shape {:?}",
-            data.shape()
-        );
-        for y in 0..data.shape()[0] {
-            for x in 0..data.shape()[1] {
-                data.swap((y, x, 0), (y, x, 2))
-            }
+    /// Only the change the model makes is scaled back up and added to the full resolution image.
+    /// This keeps fine detail while a slow model runs on fewer pixels, which works well for
+    /// models that remove low frequency noise. The scale must be in (0,1], 1.0 disables this.
+    /// Auxiliary maps are scaled like the image, the mask is applied at the full resolution.
+    /// It does not apply to `process_image_incremental` and `process_image_with_coverage`.
+    pub fn set_infer_scale(&mut self, infer_scale: f32) -> Result<(), ImageProcessingError> {
+        if !(infer_scale > 0.0 && infer_scale <= 1.0) {
+            return Err(ImageProcessingError::InvalidInferScale(infer_scale));
         }
+        self.infer_scale = infer_scale;
+        Ok(())
     }
 
-    pub async fn process_image(
+    /// Keep the enlarged output of a super resolution model, see `ModelRunner::set_keep_scale`
+    ///
+    /// The output is then `output_scale` times larger than the input in both dimensions.
+    /// Otherwise the output of the model is scaled back down to the input size. This can not be
+    /// combined with a mask, an inference scale, a preserved border, adaptive padding,
+    /// `process_image_incremental` and `process_image_with_coverage`.
+    pub fn set_upscale(&mut self, upscale: bool) {
+        self.runner.set_keep_scale(upscale);
+    }
+
+    pub fn with_upscale(mut self, upscale: bool) -> Self {
+        self.set_upscale(upscale);
+        self
+    }
+
+    /// The factor by which the output is larger than the input, see `set_upscale`
+    pub fn output_scale(&self) -> usize {
+        self.runner.get_scale()
+    }
+
+    /// The HxWxC shape of the output for input data of the given shape
+    fn output_shape(&self, input_shape: &[usize]) -> [usize; 3] {
+        let scale = self.output_scale();
+        [
+            input_shape[0] * scale,
+            input_shape[1] * scale,
+            input_shape[2],
+        ]
+    }
+
+    /// Fail if a setting that assumes the output size of the input is combined with upscaling
+    fn check_upscaling_support(&self) -> Result<(), ImageProcessingError> {
+        if self.output_scale() == 1 {
+            return Ok(());
+        }
+        let unsupported = if self.mask.is_some() {
+            "a mask"
+        } else if self.infer_scale < 1.0 {
+            "an inference scale"
+        } else if self.preserve_border > 0 {
+            "a preserved border"
+        } else if self.adaptive_padding.is_some() {
+            "adaptive padding"
+        } else {
+            return Ok(());
+        };
+        Err(ImageProcessingError::UpscalingNotSupported(unsupported))
+    }
+
+    /// Scale HxWxC data to the given size with bilinear interpolation
+    fn resize_tensor(data: &Array3<f32>, height: usize, width: usize) -> Array3<f32> {
+        let (source_height, source_width) = (data.shape()[0], data.shape()[1]);
+        // The source position and weight of the next pixel for a target position, pixel
+        // centers are aligned
+        let sample = |target: usize, target_size: usize, source_size: usize| {
+            let position = ((target as f32 + 0.5) * source_size as f32 / target_size as f32 - 0.5)
+                .clamp(0.0, (source_size - 1) as f32);
+            let low = position.floor() as usize;
+            (low, (low + 1).min(source_size - 1), position - low as f32)
+        };
+        Array3::from_shape_fn((height, width, data.shape()[2]), |(y, x, c)| {
+            let (y0, y1, wy) = sample(y, height, source_height);
+            let (x0, x1, wx) = sample(x, width, source_width);
+            let top = data[(y0, x0, c)] * (1.0 - wx) + data[(y0, x1, c)] * wx;
+            let bottom = data[(y1, x0, c)] * (1.0 - wx) + data[(y1, x1, c)] * wx;
+            top * (1.0 - wy) + bottom * wy
+        })
+    }
+
+    /// The inputs of the model besides the image, see `set_auxiliary_input`
+    pub fn auxiliary_inputs(&self) -> &[AuxiliaryInput] {
+        self.runner.auxiliary_inputs()
+    }
+
+    /// Set the data of an auxiliary model input, e.g. a mask, in HxWxC order
+    ///
+    /// The data must have the size of the processed images. Unlike the image, it is passed to
+    /// the model as it is, without converting it to the color model and value range of the model.
+    pub fn set_auxiliary_input(
         &mut self,
-        image: ImageBuffer<Rgb<u16>, Vec<u16>>,
-    ) -> Result<ImageBuffer<Rgb<u16>, Vec<u16>>, ImageProcessingError> {
-        let width = image.width() as usize;
-        let height = image.height() as usize;
+        name: &str,
+        data: Array3<f32>,
+    ) -> Result<(), ImageProcessingError> {
+        let input = self
+            .auxiliary_inputs()
+            .iter()
+            .find(|input| input.name == name)
+            .ok_or_else(|| ImageProcessingError::UnknownAuxiliaryInput(name.to_owned()))?;
+        if data.shape()[2] != input.channels {
+            return Err(ImageProcessingError::ChannelCountMismatch {
+                image: data.shape()[2],
+                model: input.channels,
+            });
+        }
+        self.auxiliary_data
+            .insert(name.to_owned(), AuxiliaryData::Map(data));
+        Ok(())
+    }
 
-        let mut image_data = Array3::from_shape_vec((height, width, 3), image.into_raw())
-            .unwrap()
-            .mapv(|v| self.model_input_range.pixel_value_to_model(v));
-        if self.model_color_model == ImageColorModel::BGR {
-            Self::rgb_to_bgr(&mut image_data);
+    /// Use the same value for every pixel of an auxiliary model input
+    ///
+    /// This is useful for inputs like the noise level of non-blind denoisers, which is often the
+    /// same for the whole image. The value is passed to the model as it is.
+    pub fn set_auxiliary_constant(
+        &mut self,
+        name: &str,
+        value: f32,
+    ) -> Result<(), ImageProcessingError> {
+        if !self
+            .auxiliary_inputs()
+            .iter()
+            .any(|input| input.name == name)
+        {
+            return Err(ImageProcessingError::UnknownAuxiliaryInput(name.to_owned()));
         }
-        image_data = image_data.permuted_axes([2, 0, 1]); // The image data comes in HxWxC format, we need CxHxW
+        self.auxiliary_data
+            .insert(name.to_owned(), AuxiliaryData::Constant(value));
+        Ok(())
+    }
 
-        let generator = ImageChunkGeneratorBuilder::new_from_array(image_data)
-            .with_chunksize(self.chunksize)
-            .with_chunk_padding(self.chunk_padding)
-            .with_overlap(self.chunk_overlap)
-            .finalize()?;
-
-        // Caution: We create the output buffer in the image layout directly, that way we won't
-        // have to worry about permutation when creating the resulting image
-        let mut output_image: Array3<f32> = Array3::zeros((height, width, 3));
-
-        for (i, chunk) in generator.iter().enumerate() {
-            log::info!("Processing chunk {}", i);
-
-            let mut result_tensor = self.runner.process_chunk(chunk.chunk).await.unwrap();
-
-            let mut usable_output_chunk = result_tensor.slice_mut(chunk.get_usable_range());
-            generator.scale_overlap(&chunk.global_coordinate_offset, &mut usable_output_chunk);
-            let mut output_range = output_image.slice_mut(ndarray::s![
-                chunk.global_coordinate_offset.y
-                    ..chunk.global_coordinate_offset.y + usable_output_chunk.shape()[1],
-                chunk.global_coordinate_offset.x
-                    ..chunk.global_coordinate_offset.x + usable_output_chunk.shape()[2],
-                ..,
-            ]);
-            // Since the network returns data in CxHxW order, we need to permute to HxWxC order
-            output_range += &usable_output_chunk.permuted_axes([1, 2, 0]);
+    /// Append the auxiliary input data to HxWxC image data in the model input range
+    fn append_auxiliary_inputs<T: TensorElement>(
+        &self,
+        image_data: Array3<T>,
+    ) -> Result<Array3<T>, ImageProcessingError> {
+        if self.auxiliary_inputs().is_empty() {
+            return Ok(image_data);
+        }
+        let (height, width) = (image_data.shape()[0], image_data.shape()[1]);
+        let mut parts = vec![image_data];
+        for input in self.auxiliary_inputs() {
+            let data = self
+                .auxiliary_data
+                .get(&input.name)
+                .ok_or_else(|| ImageProcessingError::MissingAuxiliaryInput(input.name.clone()))?;
+            let expected = [height, width, input.channels];
+            match data {
+                AuxiliaryData::Map(data) if data.shape() == &expected[..] => {
+                    parts.push(data.mapv(T::from_f32))
+                }
+                // Maps have the size of the processed images, which are scaled to the inference
+                // resolution before they get here
+                AuxiliaryData::Map(data)
+                    if self.infer_scale < 1.0
+                        && data.shape()[2] == input.channels
+                        && self.inference_dimensions(data.shape()[0], data.shape()[1])
+                            == (height, width) =>
+                {
+                    parts.push(Self::resize_tensor(data, height, width).mapv(T::from_f32))
+                }
+                AuxiliaryData::Map(data) => {
+                    return Err(ImageProcessingError::AuxiliaryInputMismatch {
+                        name: input.name.clone(),
+                        expected: expected.to_vec(),
+                        actual: data.shape().to_vec(),
+                    });
+                }
+                AuxiliaryData::Constant(value) => {
+                    parts.push(Array3::from_elem(expected, T::from_f32(*value)))
+                }
+            }
         }
+        let views: Vec<_> = parts.iter().map(|part| part.view()).collect();
+        Ok(ndarray::concatenate(Axis(2), &views).map_err(TensorConversionError::from)?)
+    }
 
-        log::debug!("Output Mean: {}", output_image.mean().unwrap());
-        self.model_output_range
-            .normalize_model_value(&mut output_image);
+    /// The image channels of a CxHxW chunk, without the auxiliary input channels
+    fn image_channels<'a>(&self, chunk: ArrayView3<'a, f32>) -> ArrayView3<'a, f32> {
+        chunk.slice_move(s![..self.channels(), .., ..])
+    }
 
-        let mut raw_output_image_data = output_image.mapv(|v| (v * u16::MAX as f32) as u16);
-        if self.model_color_model == ImageColorModel::BGR {
-            Self::rgb_to_bgr(&mut raw_output_image_data);
+    /// The current processing settings
+    ///
+    /// The chunksize and padding may change while processing, e.g. with `set_auto_chunksize`.
+    pub fn settings(&self) -> ProcessorSettings {
+        ProcessorSettings {
+            color_model: self.model_color_model,
+            input_range: self.model_input_range.clone(),
+            output_range: self.model_output_range.clone(),
+            chunksize: self.chunksize,
+            process_mode: self.process_mode,
+            blend_mode: self.blend_mode,
+            backend: self.backend_name().to_owned(),
+            post_processes: self.post_processes.clone(),
+            pad_mode: self.pad_mode,
+            preserve_border: self.preserve_border,
+            adaptive_padding: self.adaptive_padding,
+            mask: self.mask.is_some(),
+            infer_scale: self.infer_scale,
+            accumulator_precision: self.effective_accumulator_precision(),
+            skip_uniform: self.skip_uniform,
+            upscale: self.output_scale() > 1,
+            downscale_filter: self.runner.get_downscale_filter(),
         }
-        Ok(ImageBuffer::from_raw(
-            width as u32,
-            height as u32,
-            raw_output_image_data.into_raw_vec(),
-        )
-        .unwrap())
+    }
+
+    /// The accumulator precision in use, half precision processing always accumulates in `F16`
+    fn effective_accumulator_precision(&self) -> AccumulatorPrecision {
+        #[cfg(feature = "half")]
+        if self.half_precision {
+            return AccumulatorPrecision::F16;
+        }
+        self.accumulator_precision
+    }
+
+    pub fn set_process_mode(&mut self, process_mode: ProcessMode) {
+        self.process_mode = process_mode;
+        if let Some((_, unreduced_mode)) = &mut self.unreduced_chunking {
+            *unreduced_mode = process_mode;
+        }
+    }
+
+    pub fn with_process_mode(mut self, process_mode: ProcessMode) -> Self {
+        self.set_process_mode(process_mode);
+        self
+    }
+
+    /// Override the padding of each chunk, which is derived from the model by default
+    ///
+    /// This switches `ProcessMode::Simple` to `ProcessMode::Tiled`. The padding and overlap are
+    /// checked against the chunksize before an image is processed, see `check_chunk_settings`.
+    pub fn set_padding(&mut self, padding: usize) {
+        let (_, overlap) = self.chunk_padding_and_overlap();
+        self.set_process_mode(ProcessMode::Tiled { padding, overlap });
+    }
+
+    pub fn with_padding(mut self, padding: usize) -> Self {
+        self.set_padding(padding);
+        self
+    }
+
+    /// Override the blended overlap of neighbouring chunks, like `set_padding`
+    pub fn set_overlap(&mut self, overlap: usize) {
+        let padding = match self.process_mode {
+            ProcessMode::Tiled { padding, .. } => padding,
+            ProcessMode::Simple => 0,
+        };
+        self.set_process_mode(ProcessMode::Tiled { padding, overlap });
+    }
+
+    pub fn with_overlap(mut self, overlap: usize) -> Self {
+        self.set_overlap(overlap);
+        self
+    }
+
+    /// Check that the padding and overlap of the process mode fit into the chunksize
+    pub fn check_chunk_settings(&self) -> Result<(), ImageChunkGeneratorError> {
+        let (padding, overlap) = self.chunk_padding_and_overlap();
+        check_chunk_settings(self.chunksize, padding, overlap)
+    }
+
+    pub fn set_blend_mode(&mut self, blend_mode: BlendMode) {
+        self.blend_mode = blend_mode;
+    }
+
+    pub fn with_blend_mode(mut self, blend_mode: BlendMode) -> Self {
+        self.set_blend_mode(blend_mode);
+        self
+    }
+
+    /// Choose whether a failing chunk aborts the image or is passed through unprocessed
+    ///
+    /// With `ChunkErrorPolicy::KeepGoing`, failed chunks never reach the automatic chunksize
+    /// reduction of `set_auto_chunksize`.
+    pub fn set_chunk_error_policy(&mut self, chunk_error_policy: ChunkErrorPolicy) {
+        self.chunk_error_policy = chunk_error_policy;
+    }
+
+    pub fn with_chunk_error_policy(mut self, chunk_error_policy: ChunkErrorPolicy) -> Self {
+        self.set_chunk_error_policy(chunk_error_policy);
+        self
+    }
+
+    /// Let up to `depth` chunks be in flight on the GPU at the same time
+    ///
+    /// See `ModelRunner::set_pipeline_depth`. The chunk hook sees the inputs of all chunks that
+    /// are submitted together before it sees their outputs.
+    pub async fn set_pipeline_depth(&mut self, depth: usize) -> Result<(), ImageProcessingError> {
+        Ok(self.runner.set_pipeline_depth(depth).await?)
+    }
+
+    /// Run chunks on all CPU cores with the tract backend, see `ModelRunner::set_concurrency`
+    ///
+    /// Like with `set_pipeline_depth`, the chunk hook sees the inputs of all chunks that run
+    /// together before it sees their outputs.
+    pub fn set_concurrency(&mut self, concurrency: ProcessingConcurrency) {
+        self.runner.set_concurrency(concurrency);
+    }
+
+    pub fn with_concurrency(mut self, concurrency: ProcessingConcurrency) -> Self {
+        self.set_concurrency(concurrency);
+        self
+    }
+
+    /// Choose how NaN and infinite values in the model output are handled
+    ///
+    /// The values are replaced before the output is converted to an image, where they would
+    /// become arbitrary pixel values. The default is `NonFinitePolicy::Error`.
+    pub fn set_non_finite_policy(&mut self, non_finite_policy: NonFinitePolicy) {
+        self.non_finite_policy = non_finite_policy;
+    }
+
+    pub fn with_non_finite_policy(mut self, non_finite_policy: NonFinitePolicy) -> Self {
+        self.set_non_finite_policy(non_finite_policy);
+        self
+    }
+
+    /// Apply the `NonFinitePolicy` to accumulated model output in the model output range
+    fn replace_non_finite(&self, output: &mut Array3<f32>) -> Result<(), ImageProcessingError> {
+        let count = output.iter().filter(|v| !v.is_finite()).count();
+        if count == 0 {
+            return Ok(());
+        }
+        let clamp = match self.non_finite_policy {
+            NonFinitePolicy::Error => return Err(ImageProcessingError::NonFiniteOutput(count)),
+            NonFinitePolicy::Zero => false,
+            NonFinitePolicy::Clamp => true,
+        };
+        // The output is in RGB order, like the per channel ranges
+        for (channel, mut values) in output.axis_iter_mut(Axis(2)).enumerate() {
+            let range = self.model_output_range.channel(channel);
+            let lower = range.normalized_value_to_model(0.0);
+            let upper = range.normalized_value_to_model(1.0);
+            values.mapv_inplace(|v| match v {
+                v if v.is_finite() => v,
+                v if clamp && v == f32::INFINITY => upper,
+                _ => lower,
+            });
+        }
+        log::warn!(
+            "Replaced {} NaN or infinite values in the model output",
+            count
+        );
+        Ok(())
+    }
+
+    /// How the image is continued beyond its borders, e.g. `PadMode::Wrap` for seamless textures
+    pub fn set_pad_mode(&mut self, pad_mode: PadMode) {
+        self.pad_mode = pad_mode;
+    }
+
+    pub fn with_pad_mode(mut self, pad_mode: PadMode) -> Self {
+        self.set_pad_mode(pad_mode);
+        self
+    }
+
+    /// Apply these steps in order to the output of each image, see `PostProcess`
+    ///
+    /// With multiple passes, the chain is only applied once after the last pass.
+    /// `process_image_incremental` only applies the steps that work on each pixel on its own,
+    /// since the reference output already went through the others.
+    pub fn set_post_processes(&mut self, post_processes: Vec<PostProcess>) {
+        self.post_processes = post_processes;
+    }
+
+    pub fn with_post_processes(mut self, post_processes: Vec<PostProcess>) -> Self {
+        self.set_post_processes(post_processes);
+        self
+    }
+
+    /// Only change the image where the HxW mask is set
+    ///
+    /// Each output pixel is blended with the input pixel by its mask value, 1 uses the processed
+    /// value and 0 keeps the input, so feathered mask edges give smooth transitions. The mask is
+    /// applied after all post-processing and must have the size of the processed images. It does
+    /// not apply to `process_image_with_coverage`.
+    pub fn set_mask(&mut self, mask: Option<Array2<f32>>) {
+        self.mask = mask;
+    }
+
+    pub fn with_mask(mut self, mask: Option<Array2<f32>>) -> Self {
+        self.set_mask(mask);
+        self
+    }
+
+    /// Skip inference for chunks where each channel varies by at most `tolerance`
+    ///
+    /// The tolerance is relative to the [0,1] value range of the image. Skipped chunks are copied
+    /// to the output unchanged and the chunk hook is not called for them. This speeds up images
+    /// with large flat regions, but is only correct for models that keep flat regions unchanged.
+    pub fn set_skip_uniform(&mut self, tolerance: Option<f32>) {
+        self.skip_uniform = tolerance;
+    }
+
+    pub fn with_skip_uniform(mut self, tolerance: Option<f32>) -> Self {
+        self.set_skip_uniform(tolerance);
+        self
+    }
+
+    /// Retry with smaller chunks if inference fails, e.g. because the GPU is out of memory
+    ///
+    /// The chunksize is halved until processing succeeds, each image starts with the configured
+    /// chunksize again. This only works for models with a dynamic input shape.
+    pub fn set_auto_chunksize(&mut self, auto_chunksize: bool) {
+        self.auto_chunksize = auto_chunksize;
+    }
+
+    pub fn with_auto_chunksize(mut self, auto_chunksize: bool) -> Self {
+        self.set_auto_chunksize(auto_chunksize);
+        self
+    }
+
+    /// Hold the image data in half precision while processing
+    ///
+    /// This halves the memory needed for the input and output buffers at the cost of precision,
+    /// the model itself still runs in full precision.
+    #[cfg(feature = "half")]
+    pub fn set_half_precision(&mut self, half_precision: bool) {
+        self.half_precision = half_precision;
+    }
+
+    #[cfg(feature = "half")]
+    pub fn with_half_precision(mut self, half_precision: bool) -> Self {
+        self.set_half_precision(half_precision);
+        self
+    }
+
+    /// Set the precision of the buffer in which the chunk outputs are summed up, the default is
+    /// `AccumulatorPrecision::F32`
+    ///
+    /// This has no effect with `set_half_precision`, which always accumulates in half precision.
+    pub fn set_accumulator_precision(&mut self, accumulator_precision: AccumulatorPrecision) {
+        self.accumulator_precision = accumulator_precision;
+    }
+
+    pub fn with_accumulator_precision(
+        mut self,
+        accumulator_precision: AccumulatorPrecision,
+    ) -> Self {
+        self.set_accumulator_precision(accumulator_precision);
+        self
+    }
+
+    /// Limit the estimated peak memory usage to `memory_budget` bytes
+    ///
+    /// The chunksize is reduced until `estimate_peak_memory` fits into the budget. Models with a
+    /// fixed input shape are always processed one chunk at a time, for them processing fails if
+    /// the budget is too small.
+    pub fn set_memory_budget(&mut self, memory_budget: Option<usize>) {
+        self.memory_budget = memory_budget;
+    }
+
+    pub fn with_memory_budget(mut self, memory_budget: Option<usize>) -> Self {
+        self.set_memory_budget(memory_budget);
+        self
+    }
+
+    /// Use more padding for chunks with high-contrast content, which are prone to artifacts, and
+    /// less for flat chunks
+    ///
+    /// Chunks are tiled with `min_padding` if it is smaller than the padding of the process mode,
+    /// which is enough for flat chunks. The usable area of other chunks is processed in smaller
+    /// sub-chunks with the padding of the process mode, or with `max_padding` if the mean
+    /// gradient magnitude of their input is above the contrast threshold. This has no effect in
+    /// `ProcessMode::Simple`.
+    pub fn set_adaptive_padding(&mut self, adaptive_padding: Option<AdaptivePadding>) {
+        self.adaptive_padding = adaptive_padding;
+    }
+
+    pub fn with_adaptive_padding(mut self, adaptive_padding: Option<AdaptivePadding>) -> Self {
+        self.set_adaptive_padding(adaptive_padding);
+        self
+    }
+
+    /// The mean absolute difference of neighbouring values in a CxHxW chunk
+    fn gradient_magnitude(chunk: &ArrayView3<f32>) -> f32 {
+        let dx = &chunk.slice(s![.., .., 1..]) - &chunk.slice(s![.., .., ..-1]);
+        let dy = &chunk.slice(s![.., 1.., ..]) - &chunk.slice(s![.., ..-1, ..]);
+        let mean_abs = |d: Array3<f32>| d.mapv(f32::abs).mean().unwrap_or(0.0);
+        (mean_abs(dx) + mean_abs(dy)) / 2.0
+    }
+
+    /// The padding to use for a chunk in the model input range if it differs from the padding of
+    /// the chunk grid
+    fn adaptive_chunk_padding(&self, chunk: &ArrayView3<f32>) -> Option<usize> {
+        let adaptive_padding = self.adaptive_padding?;
+        let padding = match self.process_mode {
+            ProcessMode::Tiled { padding, .. } => padding,
+            ProcessMode::Simple => return None,
+        };
+        let (grid_padding, _) = self.chunk_padding_and_overlap();
+        let channels = chunk.shape()[0];
+        let contrast = chunk
+            .axis_iter(Axis(0))
+            .enumerate()
+            .map(|(channel, values)| {
+                let range = self
+                    .model_input_range
+                    .channel(self.image_channel(channel, channels));
+                let (lower, upper) = range.bounds();
+                Self::gradient_magnitude(&values.insert_axis(Axis(0))) / (upper - lower)
+            })
+            .sum::<f32>()
+            / channels as f32;
+        let chunk_padding = if contrast > adaptive_padding.contrast_threshold {
+            adaptive_padding.max_padding.max(padding)
+        } else if contrast < adaptive_padding.flat_threshold {
+            grid_padding
+        } else {
+            padding
+        };
+        (chunk_padding != grid_padding).then_some(chunk_padding)
+    }
+
+    /// Compare the output of the first processed image to the declared output range
+    ///
+    /// A wrong output range results in nearly black or white images, this helps to find it. The
+    /// output of a dark or low-contrast image also fits a smaller range, so images are checked
+    /// until the output of one is decisive, see `ModelValueRange::adapted_to`.
+    pub fn set_output_range_check(&mut self, output_range_check: OutputRangeCheck) {
+        self.output_range_check = output_range_check;
+        self.output_range_checked = false;
+    }
+
+    pub fn with_output_range_check(mut self, output_range_check: OutputRangeCheck) -> Self {
+        self.set_output_range_check(output_range_check);
+        self
+    }
+
+    /// Check the output in the model value range, see `set_output_range_check`
+    fn check_output_range(&mut self, output: &Array3<f32>) {
+        if self.output_range_check == OutputRangeCheck::Disabled || self.output_range_checked {
+            return;
+        }
+        // The minimum and maximum of each channel, the output is in RGB order
+        let extremes: Vec<_> = output
+            .axis_iter(Axis(2))
+            .map(|channel| {
+                channel
+                    .iter()
+                    .filter(|v| v.is_finite())
+                    .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &v| {
+                        (min.min(v), max.max(v))
+                    })
+            })
+            .collect();
+        if extremes.iter().any(|(min, max)| min > max) {
+            // No finite output to measure, check the next image
+            return;
+        }
+        if self.model_output_range.is_plausible(&extremes) {
+            self.output_range_checked = true;
+            return;
+        }
+
+        let fitting = match self.model_output_range.adapted_to(&extremes) {
+            Some(fitting) => fitting,
+            None => {
+                log::debug!(
+                    "The model output channels in {:?} have too little contrast to check the output range",
+                    extremes
+                );
+                return;
+            }
+        };
+        self.output_range_checked = true;
+        log::warn!(
+            "The model output channels are in {:?}, which does not fit the output range {:?}, maybe the model uses {:?}",
+            extremes,
+            self.model_output_range,
+            fitting
+        );
+        if self.output_range_check == OutputRangeCheck::Adopt {
+            log::warn!("Using the output range {:?} from now on", fitting);
+            self.model_output_range = fitting;
+        }
+    }
+
+    /// Copy the outermost `width` rows and columns of the input to the output unchanged
+    ///
+    /// The image border is processed with reflected padding as context, which can cause artifacts.
+    /// This guarantees that the frame edge is not altered, e.g. for pixel-exact applications.
+    pub fn set_preserve_border(&mut self, width: usize) {
+        self.preserve_border = width;
+    }
+
+    pub fn with_preserve_border(mut self, width: usize) -> Self {
+        self.set_preserve_border(width);
+        self
+    }
+
+    /// The (y, x) regions of the border that is preserved for an image of the given size
+    fn border_regions(&self, height: usize, width: usize) -> Vec<(Range<usize>, Range<usize>)> {
+        let border = self.preserve_border.min(height).min(width);
+        if border == 0 {
+            return Vec::new();
+        }
+        vec![
+            (0..border, 0..width),
+            (height - border..height, 0..width),
+            (0..height, 0..border),
+            (0..height, width - border..width),
+        ]
+    }
+
+    /// Split each image into at least `min_tiles` chunks
+    ///
+    /// This avoids processing images that are only slightly larger than the chunksize as a
+    /// single, mostly padded chunk. The chunksize is halved until the image is split into enough
+    /// chunks, for models with a fixed input shape only a warning is logged.
+    pub fn set_min_tiles(&mut self, min_tiles: Option<usize>) {
+        self.min_tiles = min_tiles;
+    }
+
+    pub fn with_min_tiles(mut self, min_tiles: Option<usize>) -> Self {
+        self.set_min_tiles(min_tiles);
+        self
+    }
+
+    /// Limit the number of pixels in each chunk
+    ///
+    /// Like with `set_min_tiles`, the chunksize is halved until the chunks are small enough.
+    pub fn set_max_tile_pixels(&mut self, max_tile_pixels: Option<usize>) {
+        self.max_tile_pixels = max_tile_pixels;
+    }
+
+    pub fn with_max_tile_pixels(mut self, max_tile_pixels: Option<usize>) -> Self {
+        self.set_max_tile_pixels(max_tile_pixels);
+        self
+    }
+
+    /// Choose the chunksize for an image of the given size
+    ///
+    /// The reductions for the previous image are undone, then the tile limits and the memory
+    /// budget are applied.
+    fn fit_chunksize(&mut self, width: usize, height: usize) -> Result<(), ImageProcessingError> {
+        self.restore_chunksize()?;
+        self.apply_tile_limits(width, height)?;
+        self.apply_memory_budget(width, height)
+    }
+
+    /// Undo the reductions of `reduce_chunksize`
+    fn restore_chunksize(&mut self) -> Result<(), ImageProcessingError> {
+        if let Some((chunksize, process_mode)) = self.unreduced_chunking.take() {
+            self.runner.set_chunksize(chunksize)?;
+            self.chunksize = chunksize;
+            self.process_mode = process_mode;
+        }
+        Ok(())
+    }
+
+    /// Reduce the chunksize until the chunks of an image of the given size are within the tile
+    /// limits
+    fn apply_tile_limits(
+        &mut self,
+        width: usize,
+        height: usize,
+    ) -> Result<(), ImageProcessingError> {
+        loop {
+            let (padding, overlap) = self.chunk_padding_and_overlap();
+            let tiles =
+                ChunkGeometryReport::new((width, height), self.chunksize, padding, overlap)?
+                    .chunk_count();
+            let too_few_tiles = matches!(self.min_tiles, Some(min_tiles) if tiles < min_tiles);
+            let too_large_tiles = matches!(self.max_tile_pixels, Some(max_tile_pixels)
+                if self.chunksize.width * self.chunksize.height > max_tile_pixels);
+            if !too_few_tiles && !too_large_tiles {
+                return Ok(());
+            }
+            match self.reduce_chunksize() {
+                Ok(true) => log::info!(
+                    "Reduced chunksize to {:?} to fit the tile limits",
+                    self.chunksize
+                ),
+                Ok(false)
+                | Err(ImageProcessingError::ModelRunnerError(ModelRunnerError::FixedInputShape(
+                    _,
+                ))) => {
+                    log::warn!(
+                        "The image is split into {} chunks of {:?}, which violates the tile limits",
+                        tiles,
+                        self.chunksize
+                    );
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Reduce the chunksize until processing an image of the given size fits the memory budget
+    fn apply_memory_budget(
+        &mut self,
+        width: usize,
+        height: usize,
+    ) -> Result<(), ImageProcessingError> {
+        let budget = match self.memory_budget {
+            Some(budget) => budget,
+            None => return Ok(()),
+        };
+
+        loop {
+            let required =
+                estimate_peak_memory(width, height, self.chunksize, self.runner.get_model_scale());
+            if required <= budget {
+                return Ok(());
+            }
+            match self.reduce_chunksize() {
+                Ok(true) => log::info!(
+                    "Reduced chunksize to {:?} to fit the memory budget",
+                    self.chunksize
+                ),
+                Ok(false)
+                | Err(ImageProcessingError::ModelRunnerError(ModelRunnerError::FixedInputShape(
+                    _,
+                ))) => return Err(ImageProcessingError::MemoryBudgetExceeded { required, budget }),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Halve the chunksize, e.g. after a failed inference
+    ///
+    /// Padding and overlap are halved as well to keep their proportions. The reduction only
+    /// applies to the current image, see `restore_chunksize`.
+    /// Returns `false` if the chunksize can not be reduced any further.
+    fn reduce_chunksize(&mut self) -> Result<bool, ImageProcessingError> {
+        let chunksize = ChunkSize {
+            width: self.chunksize.width / 2,
+            height: self.chunksize.height / 2,
+        };
+        if std::cmp::min(chunksize.width, chunksize.height) < MIN_AUTO_CHUNKSIZE {
+            return Ok(false);
+        }
+
+        self.runner.set_chunksize(chunksize)?;
+        self.unreduced_chunking
+            .get_or_insert((self.chunksize, self.process_mode));
+        self.chunksize = chunksize;
+        if let ProcessMode::Tiled { padding, overlap } = &mut self.process_mode {
+            *padding /= 2;
+            *overlap /= 2;
+        }
+
+        Ok(true)
+    }
+
+    /// Install a hook that is called for each chunk before and after inference
+    ///
+    /// The hook receives the chunk data in CxHxW order and in the value range of the model.
+    pub fn set_chunk_hook(&mut self, hook: impl FnMut(ChunkStage, &mut Array3<f32>) + 'static) {
+        self.chunk_hook = Some(Box::new(hook));
+    }
+
+    /// Install a callback that is called after each chunk is added to the output
+    ///
+    /// With several passes, the chunks of each pass are counted from the start.
+    pub fn set_progress_callback(&mut self, callback: impl FnMut(ProgressEvent) + 'static) {
+        self.progress_callback = Some(Box::new(callback));
+    }
+
+    fn report_progress(&mut self, current_chunk: usize, total_chunks: usize) {
+        if let Some(callback) = &mut self.progress_callback {
+            callback(ProgressEvent {
+                current_chunk,
+                total_chunks,
+            });
+        }
+    }
+
+    /// The RGB channel of a CxHxW model channel, for the per channel value ranges
+    fn image_channel(&self, model_channel: usize, channels: usize) -> usize {
+        match model_channel {
+            0 | 2 if self.model_color_model == ImageColorModel::BGR && channels >= 3 => {
+                2 - model_channel
+            }
+            _ => model_channel,
+        }
+    }
+
+    /// Change the color channel order of an image in RGB to BGR (or vice versa)
+    ///
+    /// The data channel order must be in HxWxC order (i.e. height x width x 3)
+    ///
+    /// This is very inefficient, but to make it more eficient would probably take unsafe code.
+    /// Maybe we could look into adding a "permute_axis" function to ndarray.
+    fn rgb_to_bgr<T>(data: &mut Array3<T>) {
+        // There is nothing to swap for single channel data
+        if data.shape()[2] < 3 {
+            return;
+        }
+        log::debug!(
+            "Swapping the first and third index of the third axis in data shape {:?}",
+            data.shape()
+        );
+        for y in 0..data.shape()[0] {
+            for x in 0..data.shape()[1] {
+                data.swap((y, x, 0), (y, x, 2))
+            }
+        }
+    }
+
+    pub async fn process_image(
+        &mut self,
+        image: ImageBuffer<Rgb<u16>, Vec<u16>>,
+    ) -> Result<ImageBuffer<Rgb<u16>, Vec<u16>>, ImageProcessingError> {
+        let output_image = self.process_tensor(image_to_tensor(image)?).await?;
+        Ok(tensor_to_image(output_image)?)
+    }
+
+    /// Process an RGBA image with a model that has four channels
+    ///
+    /// The alpha channel is passed to the model like the color channels.
+    pub async fn process_image_rgba(
+        &mut self,
+        image: ImageBuffer<Rgba<u16>, Vec<u16>>,
+    ) -> Result<ImageBuffer<Rgba<u16>, Vec<u16>>, ImageProcessingError> {
+        let output_image = self.process_tensor(image_rgba_to_tensor(image)?).await?;
+        Ok(tensor_to_image_rgba(output_image)?)
+    }
+
+    /// Process an RGBA image multiple times, see `process_image_rgba` and `process_image_passes`
+    pub async fn process_image_rgba_passes(
+        &mut self,
+        image: ImageBuffer<Rgba<u16>, Vec<u16>>,
+        passes: usize,
+    ) -> Result<ImageBuffer<Rgba<u16>, Vec<u16>>, ImageProcessingError> {
+        let output_image = self
+            .process_tensor_passes(image_rgba_to_tensor(image)?, passes)
+            .await?;
+        Ok(tensor_to_image_rgba(output_image)?)
+    }
+
+    /// Process an image into an existing output image
+    ///
+    /// The output image must have the dimensions of the input image times `output_scale`. This
+    /// reuses the output image and an internal buffer instead of allocating new ones, which helps
+    /// when processing many images of the same size, e.g. video frames.
+    pub async fn process_image_into(
+        &mut self,
+        image: ImageBuffer<Rgb<u16>, Vec<u16>>,
+        output: &mut ImageBuffer<Rgb<u16>, Vec<u16>>,
+    ) -> Result<(), ImageProcessingError> {
+        let (width, height) = image.dimensions();
+        let expected = self.output_shape(&[height as usize, width as usize, 3]);
+        if [output.height() as usize, output.width() as usize, 3] != expected {
+            return Err(ImageProcessingError::OutputBufferMismatch {
+                expected: expected.to_vec(),
+                actual: vec![output.height() as usize, output.width() as usize, 3],
+            });
+        }
+
+        let image_data = image_to_tensor(image)?;
+        // Take the buffer so that it can be borrowed while processing
+        let mut output_data = std::mem::take(&mut self.output_scratchpad);
+        if output_data.shape() != expected {
+            output_data = Array3::zeros(expected);
+        }
+        self.process_tensor_into(image_data, &mut output_data)
+            .await?;
+        tensor_into_image(&output_data, output)?;
+        self.output_scratchpad = output_data;
+        Ok(())
+    }
+
+    /// Convert and pad an image once, so that it can be processed with `process_prepared`
+    /// several times, e.g. while tuning the post-processing
+    ///
+    /// The tile limits and the memory budget are applied here, the automatic chunksize reduction
+    /// is not used for prepared images since it would change the chunk grid. The image data is
+    /// held as f32, even with half precision enabled.
+    pub fn prepare_image(
+        &mut self,
+        image: ImageBuffer<Rgb<u16>, Vec<u16>>,
+    ) -> Result<PreparedImage, ImageProcessingError> {
+        self.prepare_tensor(image_to_tensor(image)?)
+    }
+
+    /// Prepare image data in HxWxC order with values in the [0,1] range, see `prepare_image`
+    pub fn prepare_tensor(
+        &mut self,
+        image_data: Array3<f32>,
+    ) -> Result<PreparedImage, ImageProcessingError> {
+        if image_data.shape()[2] != self.channels() {
+            return Err(ImageProcessingError::ChannelCountMismatch {
+                image: image_data.shape()[2],
+                model: self.channels(),
+            });
+        }
+        let (height, width) = (image_data.shape()[0], image_data.shape()[1]);
+        check_not_empty(width, height)?;
+        let (scaled_height, scaled_width) = self.inference_dimensions(height, width);
+        let scaled = (self.infer_scale < 1.0)
+            .then(|| Self::resize_tensor(&image_data, scaled_height, scaled_width));
+        self.fit_chunksize(scaled_width, scaled_height)?;
+
+        let model_input =
+            self.model_input_data::<f32>(scaled.as_ref().unwrap_or(&image_data).clone())?;
+        let input = if self.process_mode == ProcessMode::Simple
+            || self.fits_single_chunk(scaled_width, scaled_height)?
+        {
+            PreparedInput::Untiled(model_input)
+        } else {
+            PreparedInput::Tiled(self.chunk_generator(model_input)?)
+        };
+        Ok(PreparedImage {
+            input,
+            data: image_data,
+            scaled,
+            settings: self.preparation_settings(),
+        })
+    }
+
+    /// Check if a prepared image can still be processed with the current settings
+    pub fn is_prepared_image_current(&self, prepared: &PreparedImage) -> bool {
+        prepared.settings == self.preparation_settings()
+    }
+
+    fn preparation_settings(&self) -> PreparationSettings {
+        PreparationSettings {
+            chunksize: self.chunksize,
+            process_mode: self.process_mode,
+            adaptive_padding: self.adaptive_padding,
+            pad_mode: self.pad_mode,
+            input_range: self.model_input_range.clone(),
+            color_model: self.model_color_model,
+            infer_scale: self.infer_scale.min(1.0),
+        }
+    }
+
+    /// Process a prepared image, see `prepare_image`
+    ///
+    /// Only the chunks are run through the model, the image is not converted or padded again.
+    pub async fn process_prepared(
+        &mut self,
+        prepared: &PreparedImage,
+    ) -> Result<ImageBuffer<Rgb<u16>, Vec<u16>>, ImageProcessingError> {
+        Ok(tensor_to_image(
+            self.process_prepared_tensor(prepared).await?,
+        )?)
+    }
+
+    /// Process a prepared image like `process_tensor`, see `process_prepared`
+    pub async fn process_prepared_tensor(
+        &mut self,
+        prepared: &PreparedImage,
+    ) -> Result<Array3<f32>, ImageProcessingError> {
+        if !self.is_prepared_image_current(prepared) {
+            return Err(ImageProcessingError::PreparedImageOutdated);
+        }
+        self.check_upscaling_support()?;
+        let masked_input = self.masked_input(&prepared.data)?;
+        let inferred = prepared.scaled.as_ref().unwrap_or(&prepared.data);
+        let border = self.preserved_border(inferred);
+
+        let mut output = Array3::zeros(self.output_shape(inferred.shape()));
+        match self.accumulator_precision {
+            #[cfg(feature = "half")]
+            AccumulatorPrecision::F16 => {
+                self.process_prepared_accumulated::<half::f16>(prepared, &mut output)
+                    .await?
+            }
+            AccumulatorPrecision::F32 => {
+                self.process_prepared_input(&prepared.input, &mut output)
+                    .await?
+            }
+            AccumulatorPrecision::F64 => {
+                self.process_prepared_accumulated::<f64>(prepared, &mut output)
+                    .await?
+            }
+        }
+        self.finish_output(&mut output)?;
+        Self::restore_border(&mut output, border);
+        if let Some(scaled) = &prepared.scaled {
+            let residual = output - scaled;
+            let (height, width) = (prepared.data.shape()[0], prepared.data.shape()[1]);
+            output = &prepared.data + &Self::resize_tensor(&residual, height, width);
+        }
+        self.post_process(&mut output)?;
+        self.apply_mask(masked_input, &mut output);
+        Ok(output)
+    }
+
+    /// Process the chunks of a prepared image, summing them up in a buffer of type `A`
+    async fn process_prepared_accumulated<A: TensorElement>(
+        &mut self,
+        prepared: &PreparedImage,
+        output: &mut Array3<f32>,
+    ) -> Result<(), ImageProcessingError> {
+        let mut accumulator = Array3::<A>::zeros(output.raw_dim());
+        self.process_prepared_input(&prepared.input, &mut accumulator)
+            .await?;
+        output.zip_mut_with(&accumulator, |o, a| *o = a.to_f32());
+        Ok(())
+    }
+
+    /// Process the model input of a prepared image like `process_chunks_as`
+    async fn process_prepared_input<A: TensorElement>(
+        &mut self,
+        input: &PreparedInput,
+        output: &mut Array3<A>,
+    ) -> Result<(), ImageProcessingError> {
+        match input {
+            PreparedInput::Tiled(generator) => {
+                self.process_generator_tiles(generator, output, None, None)
+                    .await
+            }
+            PreparedInput::Untiled(data) if self.process_mode == ProcessMode::Simple => {
+                self.process_simple_chunks(data.clone(), output, None, None)
+                    .await
+            }
+            PreparedInput::Untiled(data) => {
+                self.process_single_chunk(data.clone(), output, None).await
+            }
+        }
+    }
+
+    /// Only process the chunks of an image that changed compared to a previous version
+    ///
+    /// Chunks whose input, including their padding, differs from the reference input are
+    /// processed together with their neighbours, since those share blended overlap regions.
+    /// The output of all other chunks is copied from the reference output. Automatic chunksize
+    /// reduction is not used here, since it would change the chunk grid.
+    pub async fn process_image_incremental(
+        &mut self,
+        image: ImageBuffer<Rgb<u16>, Vec<u16>>,
+        reference: IncrementalReference<'_>,
+    ) -> Result<ImageBuffer<Rgb<u16>, Vec<u16>>, ImageProcessingError> {
+        if self.output_scale() > 1 {
+            return Err(ImageProcessingError::UpscalingNotSupported(
+                "incremental processing",
+            ));
+        }
+        let dimensions = image.dimensions();
+        if reference.input.dimensions() != dimensions || reference.output.dimensions() != dimensions
+        {
+            return Err(ImageProcessingError::IncrementalReferenceMismatch(
+                dimensions,
+            ));
+        }
+        let (width, height) = (dimensions.0 as usize, dimensions.1 as usize);
+        self.fit_chunksize(width, height)?;
+
+        let (chunk_padding, chunk_overlap) = self.chunk_padding_and_overlap();
+        let geometry = ChunkGeometryReport::new(
+            (width, height),
+            self.chunksize,
+            chunk_padding,
+            chunk_overlap,
+        )?;
+        let image_data = image_to_tensor(image)?;
+        let masked_input = self.masked_input(&image_data)?;
+        let reference_input = image_to_tensor(reference.input.clone())?;
+        let changed: Vec<bool> = (0..geometry.chunk_count())
+            .map(|index| {
+                let (x, y) = geometry.input_region(index);
+                image_data
+                    .slice(s![y.clone(), x.clone(), ..])
+                    .iter()
+                    .zip(reference_input.slice(s![y, x, ..]))
+                    .any(|(a, b)| (a - b).abs() > reference.tolerance)
+            })
+            .collect();
+        let selection: Vec<bool> = (0..geometry.chunk_count())
+            .map(|index| {
+                let (column, row) = geometry.grid_position(index);
+                changed.iter().enumerate().any(|(other, &changed)| {
+                    let (other_column, other_row) = geometry.grid_position(other);
+                    changed && column.abs_diff(other_column) <= 1 && row.abs_diff(other_row) <= 1
+                })
+            })
+            .collect();
+        log::info!(
+            "Reprocessing {} of {} chunks",
+            selection.iter().filter(|&&selected| selected).count(),
+            selection.len()
+        );
+
+        let mut output = Array3::zeros(image_data.raw_dim());
+        self.process_chunks_into(image_data, &mut output, None, Some(selection.as_slice()))
+            .await?;
+        for step in self
+            .post_processes
+            .iter()
+            .filter(|step| step.is_pixelwise())
+        {
+            step.apply(&mut output)?;
+        }
+        self.apply_mask(masked_input, &mut output);
+        let reference_output = image_to_tensor(reference.output.clone())?;
+        for index in (0..selection.len()).filter(|&index| !selection[index]) {
+            let (x, y) = geometry.usable_region(index);
+            output
+                .slice_mut(s![y.clone(), x.clone(), ..])
+                .assign(&reference_output.slice(s![y, x, ..]));
+        }
+        Ok(tensor_to_image(output)?)
+    }
+
+    /// Process an image multiple times, feeding the result of each pass into the next one
+    ///
+    /// All passes are done in memory, the result is only quantized after the last pass.
+    /// `passes` must be at least 1 and is limited to `MAX_PASSES`.
+    pub async fn process_image_passes(
+        &mut self,
+        image: ImageBuffer<Rgb<u16>, Vec<u16>>,
+        passes: usize,
+    ) -> Result<ImageBuffer<Rgb<u16>, Vec<u16>>, ImageProcessingError> {
+        let output_image = self
+            .process_tensor_passes(image_to_tensor(image)?, passes)
+            .await?;
+        Ok(tensor_to_image(output_image)?)
+    }
+
+    /// Process an image and additionally return a dithered 8 bit preview of the output
+    ///
+    /// Both images are derived from the same model output, so this avoids running the model twice.
+    pub async fn process_image_with_preview(
+        &mut self,
+        image: ImageBuffer<Rgb<u16>, Vec<u16>>,
+    ) -> Result<
+        (
+            ImageBuffer<Rgb<u16>, Vec<u16>>,
+            ImageBuffer<Rgb<u8>, Vec<u8>>,
+        ),
+        ImageProcessingError,
+    > {
+        self.process_image_passes_with_preview(image, 1).await
+    }
+
+    /// Process an image multiple times, see `process_image_passes` and
+    /// `process_image_with_preview`
+    pub async fn process_image_passes_with_preview(
+        &mut self,
+        image: ImageBuffer<Rgb<u16>, Vec<u16>>,
+        passes: usize,
+    ) -> Result<
+        (
+            ImageBuffer<Rgb<u16>, Vec<u16>>,
+            ImageBuffer<Rgb<u8>, Vec<u8>>,
+        ),
+        ImageProcessingError,
+    > {
+        let output_image = self
+            .process_tensor_passes(image_to_tensor(image)?, passes)
+            .await?;
+        let preview = tensor_to_image_u8_dithered(&output_image)?;
+        Ok((tensor_to_image(output_image)?, preview))
+    }
+
+    /// Process floating point RGB image data without any quantization
+    ///
+    /// Values are expected to be in the [0,1] range, but values outside of that range are kept.
+    pub async fn process_image_f32(
+        &mut self,
+        image: ImageBuffer<Rgb<f32>, Vec<f32>>,
+    ) -> Result<ImageBuffer<Rgb<f32>, Vec<f32>>, ImageProcessingError> {
+        self.process_image_f32_passes(image, 1).await
+    }
+
+    /// Process floating point RGB image data multiple times, see `process_image_passes`
+    pub async fn process_image_f32_passes(
+        &mut self,
+        image: ImageBuffer<Rgb<f32>, Vec<f32>>,
+        passes: usize,
+    ) -> Result<ImageBuffer<Rgb<f32>, Vec<f32>>, ImageProcessingError> {
+        let output_image = self
+            .process_tensor_passes(image_f32_to_tensor(image)?, passes)
+            .await?;
+        Ok(tensor_to_image_f32(output_image)?)
+    }
+
+    /// Process image data multiple times, see `process_image_passes` and `process_tensor`
+    pub async fn process_tensor_passes(
+        &mut self,
+        mut tensor: Array3<f32>,
+        passes: usize,
+    ) -> Result<Array3<f32>, ImageProcessingError> {
+        if passes == 0 {
+            return Err(ImageProcessingError::NoPasses);
+        }
+        let passes = if passes > MAX_PASSES {
+            log::warn!(
+                "{} passes requested, limiting to {} passes",
+                passes,
+                MAX_PASSES
+            );
+            MAX_PASSES
+        } else {
+            passes
+        };
+        if passes > HIGH_PASS_COUNT {
+            log::warn!(
+                "Running {} passes, this will take long and remove a lot of detail",
+                passes
+            );
+        }
+
+        let masked_input = self.masked_input(&tensor)?;
+        for pass in 0..passes {
+            log::info!("Running pass {}/{}", pass + 1, passes);
+            let mut output = Array3::zeros(self.output_shape(tensor.shape()));
+            self.process_tensor_pass_into(tensor, &mut output).await?;
+            tensor = output;
+        }
+        self.post_process(&mut tensor)?;
+        self.apply_mask(masked_input, &mut tensor);
+        Ok(tensor)
+    }
+
+    /// Process image data in HxWxC order with values in the [0,1] range
+    ///
+    /// The channel count must match the model, e.g. RGB for a model with three channels.
+    ///
+    /// The result uses the same layout and value range, but values are not clamped to [0,1]. Its
+    /// dimensions are those of the input times `output_scale`.
+    pub async fn process_tensor(
+        &mut self,
+        image_data: Array3<f32>,
+    ) -> Result<Array3<f32>, ImageProcessingError> {
+        let mut output = Array3::zeros(self.output_shape(image_data.shape()));
+        self.process_tensor_into(image_data, &mut output).await?;
+        Ok(output)
+    }
+
+    /// Process image data into an existing output buffer, see `process_tensor`
+    ///
+    /// The output buffer must have the shape of the output, its content is overwritten.
+    pub async fn process_tensor_into(
+        &mut self,
+        image_data: Array3<f32>,
+        output: &mut Array3<f32>,
+    ) -> Result<(), ImageProcessingError> {
+        let masked_input = self.masked_input(&image_data)?;
+        self.process_tensor_pass_into(image_data, output).await?;
+        self.post_process(output)?;
+        self.apply_mask(masked_input, output);
+        Ok(())
+    }
+
+    /// A copy of the input that the output is blended with, if a mask is set
+    fn masked_input(
+        &self,
+        image_data: &Array3<f32>,
+    ) -> Result<Option<Array3<f32>>, ImageProcessingError> {
+        match &self.mask {
+            Some(mask) if mask.shape() != &image_data.shape()[..2] => {
+                Err(ImageProcessingError::MaskMismatch {
+                    expected: image_data.shape()[..2].to_vec(),
+                    actual: mask.shape().to_vec(),
+                })
+            }
+            Some(_) => Ok(Some(image_data.clone())),
+            None => Ok(None),
+        }
+    }
+
+    /// Blend the output with the input by the mask, see `set_mask`
+    fn apply_mask(&self, input: Option<Array3<f32>>, output: &mut Array3<f32>) {
+        if let (Some(mask), Some(input)) = (&self.mask, input) {
+            for ((y, x, c), value) in output.indexed_iter_mut() {
+                let weight = mask[(y, x)];
+                *value = input[(y, x, c)] + weight * (*value - input[(y, x, c)]);
+            }
+        }
+    }
+
+    /// Apply the post-process chain to the output
+    fn post_process(&self, output: &mut Array3<f32>) -> Result<(), ImageProcessingError> {
+        apply_chain(&self.post_processes, output)
+    }
+
+    /// Process image data without the post-processing, see `process_tensor_into`
+    async fn process_tensor_pass_into(
+        &mut self,
+        image_data: Array3<f32>,
+        output: &mut Array3<f32>,
+    ) -> Result<(), ImageProcessingError> {
+        let expected = self.output_shape(image_data.shape());
+        if output.shape() != expected {
+            return Err(ImageProcessingError::OutputBufferMismatch {
+                expected: expected.to_vec(),
+                actual: output.shape().to_vec(),
+            });
+        }
+        check_not_empty(image_data.shape()[1], image_data.shape()[0])?;
+        if self.infer_scale >= 1.0 {
+            return self.process_tensor_at_scale(image_data, output).await;
+        }
+
+        let (height, width) = (image_data.shape()[0], image_data.shape()[1]);
+        let (scaled_height, scaled_width) = self.inference_dimensions(height, width);
+        log::info!(
+            "Running the model at {}x{} instead of {}x{}",
+            scaled_width,
+            scaled_height,
+            width,
+            height
+        );
+        let scaled_input = Self::resize_tensor(&image_data, scaled_height, scaled_width);
+        let mut scaled_output = Array3::zeros(scaled_input.raw_dim());
+        self.process_tensor_at_scale(scaled_input.clone(), &mut scaled_output)
+            .await?;
+        let residual = scaled_output - scaled_input;
+        output.assign(&image_data);
+        *output += &Self::resize_tensor(&residual, height, width);
+        Ok(())
+    }
+
+    /// The (height, width) at which the model runs for an image of the given size
+    fn inference_dimensions(&self, height: usize, width: usize) -> (usize, usize) {
+        if self.infer_scale >= 1.0 {
+            return (height, width);
+        }
+        (
+            ((height as f32 * self.infer_scale).round() as usize).max(1),
+            ((width as f32 * self.infer_scale).round() as usize).max(1),
+        )
+    }
+
+    /// Process image data at its own resolution, see `process_tensor_into`
+    async fn process_tensor_at_scale(
+        &mut self,
+        image_data: Array3<f32>,
+        output: &mut Array3<f32>,
+    ) -> Result<(), ImageProcessingError> {
+        self.fit_chunksize(image_data.shape()[1], image_data.shape()[0])?;
+        if !self.auto_chunksize {
+            return self
+                .process_chunks_into(image_data, output, None, None)
+                .await;
+        }
+
+        loop {
+            match self
+                .process_chunks_into(image_data.clone(), output, None, None)
+                .await
+            {
+                Err(ImageProcessingError::ModelRunnerError(ModelRunnerError::InferenceFailed(
+                    reason,
+                ))) => {
+                    if !self.reduce_chunksize()? {
+                        return Err(ModelRunnerError::InferenceFailed(reason).into());
+                    }
+                    log::warn!(
+                        "Inference failed, retrying with chunksize {:?}",
+                        self.chunksize
+                    );
+                }
+                result => {
+                    log::info!("Processed image with chunksize {:?}", self.chunksize);
+                    return result;
+                }
+            }
+        }
+    }
+
+    /// Process an image and additionally return how much each output pixel is covered by chunks
+    ///
+    /// After blending, the coverage should be 1.0 for every pixel. Other values point at a
+    /// problem in the chunk geometry.
+    pub async fn process_image_with_coverage(
+        &mut self,
+        image: ImageBuffer<Rgb<u16>, Vec<u16>>,
+    ) -> Result<(ImageBuffer<Rgb<u16>, Vec<u16>>, Array2<f32>), ImageProcessingError> {
+        if self.output_scale() > 1 {
+            return Err(ImageProcessingError::UpscalingNotSupported("the coverage"));
+        }
+        let mut coverage = Array2::zeros((image.height() as usize, image.width() as usize));
+        let image_data = image_to_tensor(image)?;
+        let mut output_image = Array3::zeros(image_data.raw_dim());
+        self.process_chunks_into(image_data, &mut output_image, Some(&mut coverage), None)
+            .await?;
+        Ok((tensor_to_image(output_image)?, coverage))
+    }
+
+    /// Check if all values of each channel of a chunk in the model input range are within the
+    /// skip tolerance
+    fn is_uniform(&self, chunk: &ArrayView3<f32>) -> bool {
+        let tolerance = match self.skip_uniform {
+            Some(tolerance) => tolerance,
+            None => return false,
+        };
+        let channels = chunk.shape()[0];
+        chunk.outer_iter().enumerate().all(|(index, channel)| {
+            let range = self
+                .model_input_range
+                .channel(self.image_channel(index, channels));
+            let tolerance = tolerance
+                * (range.normalized_value_to_model(1.0) - range.normalized_value_to_model(0.0));
+            let (min, max) = channel
+                .iter()
+                .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &v| {
+                    (min.min(v), max.max(v))
+                });
+            max - min <= tolerance
+        })
+    }
+
+    /// Convert a chunk from the model input range to the model output range
+    ///
+    /// With `set_upscale`, the chunk is enlarged to the output size by repeating its pixels.
+    fn model_input_to_output(&self, mut chunk: Array3<f32>) -> Array3<f32> {
+        let channels = chunk.shape()[0];
+        for (channel, mut values) in chunk.outer_iter_mut().enumerate() {
+            let image_channel = self.image_channel(channel, channels);
+            self.model_input_range
+                .channel(image_channel)
+                .normalize_model_value(&mut values);
+            let output_range = self.model_output_range.channel(image_channel);
+            values.mapv_inplace(|v| output_range.normalized_value_to_model(v));
+        }
+        let scale = self.output_scale();
+        if scale == 1 {
+            return chunk;
+        }
+        let (channels, height, width) = chunk.dim();
+        Array3::from_shape_fn((channels, height * scale, width * scale), |(c, y, x)| {
+            chunk[(c, y / scale, x / scale)]
+        })
+    }
+
+    /// Log the mean of the model output if debug logging is enabled
+    ///
+    /// The mean needs a full pass over the output, so it is only computed if it will be logged.
+    /// Returns the mean if it was computed.
+    fn log_output_mean(output: &Array3<f32>) -> Option<f32> {
+        if !log::log_enabled!(log::Level::Debug) {
+            return None;
+        }
+        let mean = Self::mean_or_nan(output);
+        log::debug!("Output Mean: {}", mean);
+        Some(mean)
+    }
+
+    /// The mean of all values, or NaN for empty data
+    fn mean_or_nan(data: &Array3<f32>) -> f32 {
+        data.mean().unwrap_or(f32::NAN)
+    }
+
+    /// Apply the blend weights of the process mode and blend mode to a chunk of model output
+    fn weight_chunk(
+        &self,
+        geometry: &ChunkGeometryReport,
+        global_coords: &Coords,
+        chunk: &mut ArrayViewMut3<f32>,
+    ) {
+        if let ProcessMode::Tiled { .. } = self.process_mode {
+            let scale = self.output_scale();
+            match self.blend_mode {
+                BlendMode::Average => geometry.scale_overlap(global_coords, chunk, scale),
+                BlendMode::Feather => geometry.feather_overlap(global_coords, chunk, scale),
+                BlendMode::Cosine => geometry.cosine_overlap(global_coords, chunk, scale),
+                BlendMode::Max => {}
+            }
+        }
+    }
+
+    /// The chunk grid for an image of `width` x `height` pixels with the current settings
+    ///
+    /// The chunksize may be reduced while processing, e.g. by `set_min_tiles`, so after
+    /// processing an image this is the grid that was used for it.
+    pub fn chunk_geometry(
+        &self,
+        width: usize,
+        height: usize,
+    ) -> Result<ChunkGeometryReport, ImageProcessingError> {
+        let (padding, overlap) = self.chunk_padding_and_overlap();
+        Ok(ChunkGeometryReport::new(
+            (width, height),
+            self.chunksize,
+            padding,
+            overlap,
+        )?)
+    }
+
+    /// The chunk grid for streaming an image of `width` x `height` pixels, see
+    /// `process_chunk_regions`
+    ///
+    /// The chunksize is fitted to the image like for `process_tensor`. Settings that need the
+    /// whole image at once are rejected.
+    pub fn streaming_geometry(
+        &mut self,
+        width: usize,
+        height: usize,
+    ) -> Result<ChunkGeometryReport, ImageProcessingError> {
+        check_not_empty(width, height)?;
+        self.check_streaming_support()?;
+        self.check_upscaling_support()?;
+        self.fit_chunksize(width, height)?;
+        self.chunk_geometry(width, height)
+    }
+
+    /// Fail if a setting needs the whole image, which is not available when streaming
+    fn check_streaming_support(&self) -> Result<(), ImageProcessingError> {
+        let unsupported = if self.process_mode == ProcessMode::Simple {
+            "the simple process mode"
+        } else if self.pad_mode == PadMode::Mean {
+            "mean padding"
+        } else if self.pad_mode == PadMode::Wrap {
+            "wrap padding"
+        } else if self.mask.is_some() {
+            "a mask"
+        } else if self.infer_scale < 1.0 {
+            "an inference scale"
+        } else if self.preserve_border > 0 {
+            "a preserved border"
+        } else if self.adaptive_padding.is_some() {
+            "adaptive padding"
+        } else if self
+            .auxiliary_data
+            .values()
+            .any(|data| matches!(data, AuxiliaryData::Map(_)))
+        {
+            "auxiliary input maps"
+        } else {
+            return Ok(());
+        };
+        Err(ImageProcessingError::StreamingNotSupported(unsupported))
+    }
+
+    /// Process an image chunk by chunk from regions of a streaming source
+    ///
+    /// `regions` yields the HxWxC data of `geometry.source_region(index)` for each chunk in
+    /// iteration order, with values in the [0,1] range. Besides the output, only the chunks in
+    /// flight are held in memory. The geometry must come from `streaming_geometry`, the result
+    /// is like that of `process_tensor`. Failed chunks do not reduce the chunksize with
+    /// `set_auto_chunksize`.
+    pub async fn process_chunk_regions<E: std::fmt::Display>(
+        &mut self,
+        geometry: &ChunkGeometryReport,
+        regions: impl IntoIterator<Item = Result<Array3<f32>, E>>,
+    ) -> Result<Array3<f32>, ImageProcessingError> {
+        let (width, height) = geometry.image_size;
+        if *geometry != self.chunk_geometry(width, height)? {
+            return Err(ImageProcessingError::StreamingGeometryOutdated);
+        }
+        self.check_streaming_support()?;
+        self.check_upscaling_support()?;
+
+        let mut output = Array3::zeros(self.output_shape(&[height, width, self.channels()]));
+        self.chunk_timings = vec![None; geometry.chunk_count()];
+        let mut finished_chunks = 0;
+        let scale = self.output_scale();
+        let depth = self.runner.concurrent_chunks();
+        let mut regions = regions.into_iter().take(geometry.chunk_count()).enumerate();
+        loop {
+            // Collect chunks until `depth` of them need inference, like `process_generator_tiles`
+            let mut window = Vec::new();
+            let mut pending = Vec::new();
+            while pending.len() < depth {
+                let (i, region) = match regions.next() {
+                    Some(region) => region,
+                    None => break,
+                };
+                log::info!("Processing chunk {}", i);
+
+                let region = region.map_err(|err| ImageProcessingError::ChunkReadFailed {
+                    index: i,
+                    reason: err.to_string(),
+                })?;
+                let input = self.streaming_chunk(geometry, i, region)?;
+                let image_input = self.image_channels(input.view());
+                let result_tensor = if self.is_uniform(&image_input) {
+                    log::debug!("Chunk {} is uniform, skipping inference", i);
+                    Some(self.model_input_to_output(image_input.to_owned()))
+                } else {
+                    pending.push((i, CowArray::from(input)));
+                    None
+                };
+                window.push((i, result_tensor));
+            }
+            if window.is_empty() {
+                break;
+            }
+
+            let mut results = self.run_chunks(pending).await?.into_iter();
+            for (i, result_tensor) in window {
+                let mut result_tensor = match result_tensor {
+                    Some(result_tensor) => result_tensor,
+                    None => results.next().expect("Every pending chunk has a result"),
+                };
+                let (x, y) = geometry.usable_region(i);
+                let padding = geometry.chunk_padding * scale;
+                let mut usable_output_chunk = result_tensor.slice_mut(s![
+                    ..,
+                    padding..padding + y.len() * scale,
+                    padding..padding + x.len() * scale,
+                ]);
+                let origin = Coords {
+                    x: x.start,
+                    y: y.start,
+                };
+                self.weight_chunk(geometry, &origin, &mut usable_output_chunk);
+                let output_range = output.slice_mut(s![
+                    y.start * scale..y.end * scale,
+                    x.start * scale..x.end * scale,
+                    ..
+                ]);
+                let usable_output_chunk = usable_output_chunk.view().permuted_axes([1, 2, 0]);
+                if self.blend_mode == BlendMode::Max {
+                    f32::accumulate_max(output_range, usable_output_chunk);
+                } else {
+                    f32::accumulate(output_range, usable_output_chunk);
+                }
+                finished_chunks += 1;
+                self.report_progress(finished_chunks, geometry.chunk_count());
+            }
+        }
+        if finished_chunks < geometry.chunk_count() {
+            return Err(ImageProcessingError::ChunkReadFailed {
+                index: finished_chunks,
+                reason: "the source has no more regions".to_owned(),
+            });
+        }
+        self.finish_output(&mut output)?;
+        self.post_process(&mut output)?;
+        Ok(output)
+    }
+
+    /// Convert the HxWxC source region of a chunk to the padded chunk in the model input range
+    fn streaming_chunk(
+        &self,
+        geometry: &ChunkGeometryReport,
+        index: usize,
+        region: Array3<f32>,
+    ) -> Result<Array3<f32>, ImageProcessingError> {
+        let (x, y) = geometry.source_region(index);
+        let expected = [y.len(), x.len(), self.channels()];
+        if region.shape() != expected {
+            return Err(ImageProcessingError::ChunkReadFailed {
+                index,
+                reason: format!(
+                    "the region has shape {:?} instead of {:?}",
+                    region.shape(),
+                    expected
+                ),
+            });
+        }
+        let source = self.model_input_data::<f32>(region)?;
+        Ok(geometry.chunk_from_source_region(index, source.view(), self.pad_mode))
+    }
+
+    /// The inference time of each chunk of the last processed image, in iteration order
+    ///
+    /// Chunks that were not run through the model, e.g. uniform chunks with `set_skip_uniform`,
+    /// have no timing. Chunks that are run together with a pipeline depth above one share the
+    /// time evenly. With several passes, the timings are those of the last pass.
+    pub fn chunk_timings(&self) -> &[Option<Duration>] {
+        &self.chunk_timings
+    }
+
+    /// The padding and overlap of the chunk grid for the current process mode
+    fn chunk_padding_and_overlap(&self) -> (usize, usize) {
+        match (self.process_mode, self.adaptive_padding) {
+            // Flat chunks are processed as they are, see `adaptive_chunk_padding`
+            (ProcessMode::Tiled { padding, overlap }, Some(adaptive_padding)) => {
+                (padding.min(adaptive_padding.min_padding), overlap)
+            }
+            (ProcessMode::Tiled { padding, overlap }, None) => (padding, overlap),
+            (ProcessMode::Simple, _) => (0, 0),
+        }
+    }
+
+    /// Process all chunks of the image data and write the result to `output`
+    ///
+    /// If a selection is given, only chunks for which it is `true` are processed.
+    async fn process_chunks_into(
+        &mut self,
+        image_data: Array3<f32>,
+        output: &mut Array3<f32>,
+        coverage: Option<&mut Array2<f32>>,
+        selection: Option<&[bool]>,
+    ) -> Result<(), ImageProcessingError> {
+        if image_data.shape()[2] != self.channels() {
+            return Err(ImageProcessingError::ChannelCountMismatch {
+                image: image_data.shape()[2],
+                model: self.channels(),
+            });
+        }
+        check_not_empty(image_data.shape()[1], image_data.shape()[0])?;
+        self.check_upscaling_support()?;
+        self.check_chunk_settings()?;
+        let border = self.preserved_border(&image_data);
+        #[cfg(feature = "half")]
+        if self.half_precision {
+            self.process_chunks_accumulated::<half::f16, half::f16>(
+                image_data, output, coverage, selection,
+            )
+            .await?;
+            self.finish_output(output)?;
+            Self::restore_border(output, border);
+            return Ok(());
+        }
+        match self.accumulator_precision {
+            #[cfg(feature = "half")]
+            AccumulatorPrecision::F16 => {
+                self.process_chunks_accumulated::<f32, half::f16>(
+                    image_data, output, coverage, selection,
+                )
+                .await?
+            }
+            AccumulatorPrecision::F32 => {
+                output.fill(0.0);
+                self.process_chunks_as::<f32, f32>(image_data, output, coverage, selection)
+                    .await?
+            }
+            AccumulatorPrecision::F64 => {
+                self.process_chunks_accumulated::<f32, f64>(image_data, output, coverage, selection)
+                    .await?
+            }
+        }
+        self.finish_output(output)?;
+        Self::restore_border(output, border);
+        Ok(())
+    }
+
+    /// Copy the border of the input that is preserved, see `set_preserve_border`
+    fn preserved_border(
+        &self,
+        image_data: &Array3<f32>,
+    ) -> Vec<(Range<usize>, Range<usize>, Array3<f32>)> {
+        self.border_regions(image_data.shape()[0], image_data.shape()[1])
+            .into_iter()
+            .map(|(y, x)| {
+                let data = image_data.slice(s![y.clone(), x.clone(), ..]).to_owned();
+                (y, x, data)
+            })
+            .collect()
+    }
+
+    /// Copy the preserved border of the input, see `set_preserve_border`
+    fn restore_border(
+        output: &mut Array3<f32>,
+        border: Vec<(Range<usize>, Range<usize>, Array3<f32>)>,
+    ) {
+        for (y, x, data) in border {
+            output.slice_mut(s![y, x, ..]).assign(&data);
+        }
+    }
+
+    /// Convert accumulated model output to the normalized RGB range
+    fn finish_output(&mut self, output: &mut Array3<f32>) -> Result<(), ImageProcessingError> {
+        // Per channel ranges are in RGB order
+        if self.model_color_model == ImageColorModel::BGR {
+            Self::rgb_to_bgr(output);
+        }
+        self.replace_non_finite(output)?;
+        Self::log_output_mean(output);
+        self.check_output_range(output);
+        self.model_output_range
+            .normalize_model_values(output, Axis(2));
+        Ok(())
+    }
+
+    /// Like `process_chunks_as`, but sum up the chunks in a separate buffer of type `A` and copy
+    /// the result to `output`
+    async fn process_chunks_accumulated<T: TensorElement, A: TensorElement>(
+        &mut self,
+        image_data: Array3<f32>,
+        output: &mut Array3<f32>,
+        coverage: Option<&mut Array2<f32>>,
+        selection: Option<&[bool]>,
+    ) -> Result<(), ImageProcessingError> {
+        let mut accumulator = Array3::<A>::zeros(output.raw_dim());
+        self.process_chunks_as::<T, A>(image_data, &mut accumulator, coverage, selection)
+            .await?;
+        output.zip_mut_with(&accumulator, |o, a| *o = a.to_f32());
+        Ok(())
+    }
+
+    /// Process all chunks while holding the image data as `T`
+    ///
+    /// The model output is accumulated into `output_image`, which must be zeroed.
+    async fn process_chunks_as<T: TensorElement, A: TensorElement>(
+        &mut self,
+        image_data: Array3<f32>,
+        output_image: &mut Array3<A>,
+        coverage: Option<&mut Array2<f32>>,
+        selection: Option<&[bool]>,
+    ) -> Result<(), ImageProcessingError> {
+        let image_data = self.model_input_data::<T>(image_data)?;
+        if self.process_mode == ProcessMode::Simple {
+            return self
+                .process_simple_chunks(image_data, output_image, coverage, selection)
+                .await;
+        }
+        let (height, width) = (image_data.shape()[1], image_data.shape()[2]);
+        if selection.is_none() && self.fits_single_chunk(width, height)? {
+            self.process_single_chunk(image_data, output_image, coverage)
+                .await
+        } else {
+            self.process_tiles(image_data, output_image, coverage, selection)
+                .await
+        }
+    }
+
+    /// Whether tiled image data of this size is processed as a single chunk without blending
+    fn fits_single_chunk(&self, width: usize, height: usize) -> Result<bool, ImageProcessingError> {
+        let (chunk_padding, chunk_overlap) = self.chunk_padding_and_overlap();
+        let chunk_count = ChunkGeometryReport::new(
+            (width, height),
+            self.chunksize,
+            chunk_padding,
+            chunk_overlap,
+        )?
+        .chunk_count();
+        Ok(chunk_count == 1 && self.adaptive_padding.is_none())
+    }
+
+    /// Convert HxWxC image data to CxHxW model input data, including the auxiliary inputs
+    fn model_input_data<T: TensorElement>(
+        &self,
+        image_data: Array3<f32>,
+    ) -> Result<Array3<T>, ImageProcessingError> {
+        let mut image_data = image_data;
+        self.model_input_range
+            .normalized_values_to_model(&mut image_data, Axis(2));
+        let mut image_data = image_data.mapv(T::from_f32);
+        if self.model_color_model == ImageColorModel::BGR {
+            Self::rgb_to_bgr(&mut image_data);
+        }
+        // The color model and value range only apply to the image, not to auxiliary inputs
+        image_data = self.append_auxiliary_inputs(image_data)?;
+        // The image data comes in HxWxC format, we need CxHxW
+        Ok(image_data.permuted_axes([2, 0, 1]))
+    }
+
+    /// Process CxHxW image data that fits into the usable area of a single chunk
+    ///
+    /// The image is padded like by the chunk generator, but there are no chunks to iterate over
+    /// and to blend. `output_image` must be zeroed.
+    async fn process_single_chunk<T: TensorElement, A: TensorElement>(
+        &mut self,
+        image_data: Array3<T>,
+        output_image: &mut Array3<A>,
+        coverage: Option<&mut Array2<f32>>,
+    ) -> Result<(), ImageProcessingError> {
+        let (height, width) = (image_data.shape()[1], image_data.shape()[2]);
+        let (padding, overlap) = self.chunk_padding_and_overlap();
+        let geometry = ChunkGeometryReport::new((width, height), self.chunksize, padding, overlap)?;
+        debug_assert_eq!(geometry.chunk_count(), 1);
+        self.chunk_timings = vec![None; geometry.chunk_count()];
+        // The padding after the image fills the rest of the chunk
+        let usable = self.chunksize.remaining_area_after_padding(padding);
+        let trailing_padding = (
+            padding + usable.width - width,
+            padding + usable.height - height,
+        );
+        let chunk = pad_image_data(&image_data, padding, trailing_padding, self.pad_mode);
+        log::info!("The image fits into a single chunk, processing it without tiling");
+
+        let input = T::array_to_f32(chunk);
+        let image_input = self.image_channels(input.view());
+        let result_tensor = if self.is_uniform(&image_input) {
+            log::debug!("Chunk 0 is uniform, skipping inference");
+            self.model_input_to_output(image_input.to_owned())
+        } else {
+            self.run_chunk(input.into(), 0).await?
+        };
+
+        let scale = self.output_scale();
+        let usable_output = result_tensor
+            .slice(s![
+                ..,
+                padding * scale..(padding + height) * scale,
+                padding * scale..(padding + width) * scale
+            ])
+            .permuted_axes([1, 2, 0]);
+        A::accumulate(output_image.view_mut(), usable_output);
+        if let Some(coverage) = coverage {
+            *coverage += 1.0;
+        }
+        self.report_progress(1, 1);
+        Ok(())
+    }
+
+    /// Process CxHxW image data in the model input range in overlapping chunks
+    async fn process_tiles<T: TensorElement, A: TensorElement>(
+        &mut self,
+        image_data: Array3<T>,
+        output_image: &mut Array3<A>,
+        coverage: Option<&mut Array2<f32>>,
+        selection: Option<&[bool]>,
+    ) -> Result<(), ImageProcessingError> {
+        let generator = self.chunk_generator(image_data)?;
+        self.process_generator_tiles(&generator, output_image, coverage, selection)
+            .await
+    }
+
+    /// Process CxHxW image data in the model input range edge-to-edge, see `ProcessMode::Simple`
+    ///
+    /// The chunks are cut from the image data directly, without padding and without cropping
+    /// their output. The last chunk of each row and column is shifted back to end at the image
+    /// border, only its part that is not covered by the previous chunk is used. Only an image that
+    /// is smaller than a chunk is padded to the chunksize. `output_image` must be zeroed.
+    async fn process_simple_chunks<T: TensorElement, A: TensorElement>(
+        &mut self,
+        image_data: Array3<T>,
+        output_image: &mut Array3<A>,
+        mut coverage: Option<&mut Array2<f32>>,
+        selection: Option<&[bool]>,
+    ) -> Result<(), ImageProcessingError> {
+        let (height, width) = (image_data.shape()[1], image_data.shape()[2]);
+        let chunksize = self.chunksize;
+        let image_data = if width < chunksize.width || height < chunksize.height {
+            let trailing_padding = (
+                chunksize.width.saturating_sub(width),
+                chunksize.height.saturating_sub(height),
+            );
+            pad_image_data(&image_data, 0, trailing_padding, self.pad_mode)
+        } else {
+            image_data
+        };
+        let (data_height, data_width) = (image_data.shape()[1], image_data.shape()[2]);
+        let geometry = ChunkGeometryReport::new((width, height), chunksize, 0, 0)?;
+
+        self.chunk_timings = vec![None; geometry.chunk_count()];
+        let total_chunks = match selection {
+            Some(selection) => selection.iter().filter(|&&selected| selected).count(),
+            None => geometry.chunk_count(),
+        };
+        let mut finished_chunks = 0;
+        let scale = self.output_scale();
+        let depth = self.runner.concurrent_chunks();
+        let mut indices = (0..geometry.chunk_count())
+            .filter(|&i| !matches!(selection, Some(selection) if !selection[i]));
+        loop {
+            // Collect chunks until `depth` of them need inference, like `process_generator_tiles`
+            let mut window = Vec::new();
+            let mut pending = Vec::new();
+            while pending.len() < depth {
+                let i = match indices.next() {
+                    Some(i) => i,
+                    None => break,
+                };
+                log::info!("Processing chunk {}", i);
+
+                let (x, y) = geometry.usable_region(i);
+                let start = (
+                    x.start.min(data_width - chunksize.width),
+                    y.start.min(data_height - chunksize.height),
+                );
+                let input = T::view_to_f32(image_data.slice(s![
+                    ..,
+                    start.1..start.1 + chunksize.height,
+                    start.0..start.0 + chunksize.width,
+                ]));
+                let image_input = self.image_channels(input.view());
+                let result_tensor = if self.is_uniform(&image_input) {
+                    log::debug!("Chunk {} is uniform, skipping inference", i);
+                    Some(self.model_input_to_output(image_input.to_owned()))
+                } else {
+                    pending.push((i, input));
+                    None
+                };
+                window.push(((x, y), start, result_tensor));
+            }
+            if window.is_empty() {
+                break;
+            }
+
+            let mut results = self.run_chunks(pending).await?.into_iter();
+            for ((x, y), start, result_tensor) in window {
+                let result_tensor = match result_tensor {
+                    Some(result_tensor) => result_tensor,
+                    None => results.next().expect("Every pending chunk has a result"),
+                };
+                let output_chunk = result_tensor
+                    .slice(s![
+                        ..,
+                        (y.start - start.1) * scale..(y.end - start.1) * scale,
+                        (x.start - start.0) * scale..(x.end - start.0) * scale,
+                    ])
+                    .permuted_axes([1, 2, 0]);
+                A::accumulate(
+                    output_image.slice_mut(s![
+                        y.start * scale..y.end * scale,
+                        x.start * scale..x.end * scale,
+                        ..
+                    ]),
+                    output_chunk,
+                );
+                if let Some(coverage) = coverage.as_deref_mut() {
+                    coverage.slice_mut(s![y, x]).fill(1.0);
+                }
+                finished_chunks += 1;
+                self.report_progress(finished_chunks, total_chunks);
+            }
+        }
+        Ok(())
+    }
+
+    /// Pad CxHxW image data for the chunks of the current settings
+    fn chunk_generator<T: TensorElement>(
+        &self,
+        image_data: Array3<T>,
+    ) -> Result<FinalizedImageChunkGenerator<T>, ImageProcessingError> {
+        let (chunk_padding, chunk_overlap) = self.chunk_padding_and_overlap();
+        Ok(ImageChunkGeneratorBuilder::<T>::new_from_array(image_data)
+            .with_chunksize(self.chunksize)
+            .with_chunk_padding(chunk_padding)
+            .with_overlap(chunk_overlap)
+            .with_pad_mode(self.pad_mode)
+            .finalize()?)
+    }
+
+    /// Process the chunks of an already padded image, see `process_tiles`
+    async fn process_generator_tiles<T: TensorElement, A: TensorElement>(
+        &mut self,
+        generator: &FinalizedImageChunkGenerator<T>,
+        output_image: &mut Array3<A>,
+        mut coverage: Option<&mut Array2<f32>>,
+        selection: Option<&[bool]>,
+    ) -> Result<(), ImageProcessingError> {
+        let geometry = generator.geometry_report();
+        let (width, height) = geometry.image_size;
+        if let (Some(adaptive_padding), ProcessMode::Tiled { padding, .. }) =
+            (self.adaptive_padding, self.process_mode)
+        {
+            // Sub-chunks do not overlap, but their padding has to fit the chunksize
+            for padding in [padding, adaptive_padding.max_padding] {
+                ChunkGeometryReport::new((width, height), self.chunksize, padding, 0)?;
+            }
+        }
+
+        self.chunk_timings = vec![None; geometry.chunk_count()];
+        let total_chunks = match selection {
+            Some(selection) => selection.iter().filter(|&&selected| selected).count(),
+            None => geometry.chunk_count(),
+        };
+        let mut finished_chunks = 0;
+        // The end of the region covered by chunks so far, used to check the output dimensions
+        let mut covered_end = (0, 0);
+        let scale = self.output_scale();
+        let depth = self.runner.concurrent_chunks();
+        let mut chunks = generator
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !matches!(selection, Some(selection) if !selection[*i]));
+        loop {
+            // Collect chunks until `depth` of them need inference, so they are run together
+            let mut window = Vec::new();
+            let mut pending = Vec::new();
+            while pending.len() < depth {
+                let (i, chunk) = match chunks.next() {
+                    Some(chunk) => chunk,
+                    None => break,
+                };
+                log::info!("Processing chunk {}", i);
+
+                let input = T::view_to_f32(chunk.chunk);
+                let image_input = self.image_channels(input.view());
+                let result_tensor = if self.is_uniform(&image_input) {
+                    log::debug!("Chunk {} is uniform, skipping inference", i);
+                    Some(self.model_input_to_output(image_input.to_owned()))
+                } else if let Some(padding) = self.adaptive_chunk_padding(&image_input) {
+                    log::debug!("Chunk {} has high contrast, using padding {}", i, padding);
+                    let offset = &chunk.global_coordinate_offset;
+                    Some(
+                        self.run_chunk_with_padding(generator, offset, padding, i)
+                            .await?,
+                    )
+                } else {
+                    pending.push((i, input));
+                    None
+                };
+                window.push((i, chunk, result_tensor));
+            }
+            if window.is_empty() {
+                break;
+            }
+
+            let mut results = self.run_chunks(pending).await?.into_iter();
+            for (i, chunk, result_tensor) in window {
+                let mut result_tensor = match result_tensor {
+                    Some(result_tensor) => result_tensor,
+                    None => results.next().expect("Every pending chunk has a result"),
+                };
+
+                let [channels, chunk_height, chunk_width] =
+                    self.chunksize_shape(result_tensor.shape()[0]);
+                debug_assert_eq!(
+                    result_tensor.shape(),
+                    [channels, chunk_height * scale, chunk_width * scale],
+                    "The model output of chunk {} was not scaled to the chunksize",
+                    i
+                );
+
+                // Without padding, the usable range only clips chunks that exceed the image borders
+                let mut usable_output_chunk =
+                    result_tensor.slice_mut(chunk.get_scaled_usable_range(scale));
+                self.weight_chunk(
+                    &geometry,
+                    &chunk.global_coordinate_offset,
+                    &mut usable_output_chunk,
+                );
+                // The placement of the chunk in the output, which is `scale` times larger
+                let (x, y) = (
+                    chunk.global_coordinate_offset.x * scale,
+                    chunk.global_coordinate_offset.y * scale,
+                );
+                let mut output_range = output_image.slice_mut(ndarray::s![
+                    y..y + usable_output_chunk.shape()[1],
+                    x..x + usable_output_chunk.shape()[2],
+                    ..,
+                ]);
+                covered_end = (
+                    covered_end.0.max(x + output_range.shape()[1]),
+                    covered_end.1.max(y + output_range.shape()[0]),
+                );
+                // Since the network returns data in CxHxW order, we need to permute to HxWxC order
+                let usable_output_chunk = usable_output_chunk.view().permuted_axes([1, 2, 0]);
+                if self.blend_mode == BlendMode::Max {
+                    A::accumulate_max(output_range.view_mut(), usable_output_chunk);
+                } else {
+                    A::accumulate(output_range.view_mut(), usable_output_chunk);
+                }
+
+                if let Some(coverage) = coverage.as_deref_mut() {
+                    // Apply the same weights as for the chunk data to a single channel of ones
+                    let mut weights =
+                        Array3::ones((1, output_range.shape()[0], output_range.shape()[1]));
+                    self.weight_chunk(
+                        &geometry,
+                        &chunk.global_coordinate_offset,
+                        &mut weights.view_mut(),
+                    );
+                    let mut coverage_range = coverage.slice_mut(ndarray::s![
+                        chunk.global_coordinate_offset.y
+                            ..chunk.global_coordinate_offset.y + output_range.shape()[0],
+                        chunk.global_coordinate_offset.x
+                            ..chunk.global_coordinate_offset.x + output_range.shape()[1],
+                    ]);
+                    if self.blend_mode == BlendMode::Max {
+                        coverage_range
+                            .zip_mut_with(&weights.index_axis(Axis(0), 0), |c, &w| *c = c.max(w));
+                    } else {
+                        coverage_range += &weights.index_axis(Axis(0), 0);
+                    }
+                }
+                finished_chunks += 1;
+                self.report_progress(finished_chunks, total_chunks);
+            }
+        }
+        // The chunks must reach the image borders, but not extend beyond them
+        debug_assert!(
+            selection.is_some() || covered_end == (width * scale, height * scale),
+            "The chunks cover {:?}, but the output has the dimensions {:?}",
+            covered_end,
+            (width * scale, height * scale)
+        );
+
+        Ok(())
+    }
+
+    /// Run the model on a chunk in the model input range, with the chunk hooks and the chunk
+    /// error policy
+    async fn run_chunk(
+        &mut self,
+        input: CowArray<'_, f32, Ix3>,
+        index: usize,
+    ) -> Result<Array3<f32>, ImageProcessingError> {
+        let mut results = self.run_chunks(vec![(index, input)]).await?;
+        Ok(results.remove(0))
+    }
+
+    /// Run the model on several indexed chunks like `run_chunk`, see `set_pipeline_depth`
+    async fn run_chunks(
+        &mut self,
+        inputs: Vec<(usize, CowArray<'_, f32, Ix3>)>,
+    ) -> Result<Vec<Array3<f32>>, ImageProcessingError> {
+        let inputs: Vec<(usize, CowArray<f32, Ix3>)> = match &mut self.chunk_hook {
+            Some(hook) => inputs
+                .into_iter()
+                .map(|(index, input)| {
+                    let mut hooked_input = input.into_owned();
+                    hook(ChunkStage::PreInference, &mut hooked_input);
+                    (index, hooked_input.into())
+                })
+                .collect(),
+            None => inputs,
+        };
+        let views: Vec<_> = inputs.iter().map(|(_, input)| input.view()).collect();
+        let start = Instant::now();
+        let results = self.runner.process_chunks(&views).await;
+        let elapsed = start.elapsed() / views.len().max(1) as u32;
+        for (index, _) in &inputs {
+            // Sub-chunks of adaptive padding add up to the time of their chunk
+            if let Some(timing) = self.chunk_timings.get_mut(*index) {
+                *timing = Some(timing.unwrap_or_default() + elapsed);
+            }
+        }
+
+        let mut outputs = Vec::with_capacity(results.len());
+        for ((index, input), result) in inputs.iter().zip(results) {
+            outputs.push(match result {
+                Ok(mut result_tensor) => {
+                    if let Some(hook) = &mut self.chunk_hook {
+                        hook(ChunkStage::PostInference, &mut result_tensor);
+                    }
+                    result_tensor
+                }
+                Err(err) if self.chunk_error_policy == ChunkErrorPolicy::KeepGoing => {
+                    log::warn!("Chunk {} failed, passing its input through: {}", index, err);
+                    self.model_input_to_output(self.image_channels(input.view()).to_owned())
+                }
+                Err(err) => return Err(err.into()),
+            });
+        }
+        Ok(outputs)
+    }
+
+    /// Process the usable area of the chunk at `offset` in sub-chunks with a larger padding
+    ///
+    /// The result has the shape of a chunk, but only its usable area is filled.
+    async fn run_chunk_with_padding<T: TensorElement>(
+        &mut self,
+        generator: &FinalizedImageChunkGenerator<T>,
+        offset: &Coords,
+        padding: usize,
+        index: usize,
+    ) -> Result<Array3<f32>, ImageProcessingError> {
+        let (chunk_padding, _) = self.chunk_padding_and_overlap();
+        let (image_width, image_height) = generator.geometry_report().image_size;
+        let usable = self.chunksize.remaining_area_after_padding(chunk_padding);
+        let usable_width = usable.width.min(image_width - offset.x);
+        let usable_height = usable.height.min(image_height - offset.y);
+        let step = self.chunksize.remaining_area_after_padding(padding);
+
+        let mut result = Array3::zeros(self.chunksize_shape(self.channels()));
+        for y in (0..usable_height).step_by(step.height) {
+            for x in (0..usable_width).step_by(step.width) {
+                let (sub_chunk, position) =
+                    generator.padded_chunk_at(offset.x + x, offset.y + y, padding);
+                let sub_result = self.run_chunk(T::view_to_f32(sub_chunk), index).await?;
+                let width = step.width.min(usable_width - x);
+                let height = step.height.min(usable_height - y);
+                result
+                    .slice_mut(s![
+                        ..,
+                        chunk_padding + y..chunk_padding + y + height,
+                        chunk_padding + x..chunk_padding + x + width,
+                    ])
+                    .assign(&sub_result.slice(s![
+                        ..,
+                        position.y..position.y + height,
+                        position.x..position.x + width,
+                    ]));
+            }
+        }
+        Ok(result)
+    }
+
+    /// The CxHxW shape of a chunk with the given channel count
+    fn chunksize_shape(&self, channels: usize) -> [usize; 3] {
+        [channels, self.chunksize.height, self.chunksize.width]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model_runner::test::{blur_model_bytes, dynamic_identity_model_bytes};
+    use crate::model_runner::{
+        BackendPreference, DownscaleFilter, OutputSelector, DEFAULT_CHANNEL_COUNTS,
+    };
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    fn identity_runner(chunksize: ChunkSize) -> ModelRunner {
+        ModelRunner::from_stub(chunksize, 1, |input, _| Ok(input.to_owned()))
+    }
+
+    /// A tract runner for an identity model with a dynamic input shape
+    fn dynamic_identity_runner(chunksize: ChunkSize) -> ModelRunner {
+        pollster::block_on(ModelRunner::new_with_dynamic_chunksize(
+            &mut std::io::Cursor::new(dynamic_identity_model_bytes()),
+            BackendPreference::Cpu,
+            DEFAULT_CHANNEL_COUNTS,
+            &OutputSelector::Auto,
+            false,
+            Some(chunksize),
+        ))
+        .unwrap()
+    }
+
+    fn gradient_image(width: u32, height: u32) -> ImageBuffer<Rgb<u16>, Vec<u16>> {
+        ImageBuffer::from_fn(width, height, |x, y| {
+            Rgb([(x * 300) as u16, (y * 300) as u16, ((x + y) * 150) as u16])
+        })
+    }
+
+    /// A processor for an RGB model with the value range [0, 1]
+    fn test_processor(runner: ModelRunner) -> ImageProcessor {
+        pollster::block_on(ImageProcessor::new(
+            runner,
+            ImageColorModel::RGB,
+            ModelValueRange::asymmetric(1.0),
+            ModelValueRange::asymmetric(1.0),
+        ))
+        .unwrap()
+    }
+
+    fn assert_images_close(
+        a: &ImageBuffer<Rgb<u16>, Vec<u16>>,
+        b: &ImageBuffer<Rgb<u16>, Vec<u16>>,
+    ) {
+        assert_eq!(a.dimensions(), b.dimensions());
+        for (pa, pb) in a.pixels().zip(b.pixels()) {
+            for c in 0..3 {
+                // The float round trip may truncate a value to the next lower integer
+                assert!((pa[c] as i32 - pb[c] as i32).abs() <= 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_simple_mode_reconstructs_input() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let mut processor =
+            test_processor(identity_runner(chunksize)).with_process_mode(ProcessMode::Simple);
+
+        let input = gradient_image(100, 70);
+        let output = pollster::block_on(processor.process_image(input.clone())).unwrap();
+
+        assert_images_close(&input, &output);
+    }
+
+    #[test]
+    fn test_simple_mode_does_not_pad() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let mut processor = test_processor(identity_runner(chunksize))
+            .with_process_mode(ProcessMode::Simple)
+            .with_pad_mode(PadMode::Constant(-1.0));
+        let padded_chunks = Rc::new(Cell::new(0));
+        let hook_padded_chunks = padded_chunks.clone();
+        processor.set_chunk_hook(move |stage, chunk| {
+            if stage == ChunkStage::PreInference && chunk.iter().any(|&v| v < 0.0) {
+                hook_padded_chunks.set(hook_padded_chunks.get() + 1);
+            }
+        });
+
+        // The last chunk of each row and column is shifted back into the image
+        let input = gradient_image(100, 70);
+        let output = pollster::block_on(processor.process_image(input.clone())).unwrap();
+        assert_images_close(&input, &output);
+        assert_eq!(padded_chunks.get(), 0);
+
+        // An image smaller than a chunk can only be processed with padding
+        let input = gradient_image(20, 40);
+        let output = pollster::block_on(processor.process_image(input.clone())).unwrap();
+        assert_images_close(&input, &output);
+        assert_eq!(padded_chunks.get(), 2);
+    }
+
+    #[test]
+    fn test_post_inference_hook() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let mut processor = test_processor(identity_runner(chunksize));
+        processor.set_chunk_hook(|stage, chunk| {
+            if stage == ChunkStage::PostInference {
+                chunk.fill(0.0);
+            }
+        });
+
+        let output = pollster::block_on(processor.process_image(gradient_image(100, 70))).unwrap();
+
+        assert!(output.pixels().all(|p| p == &Rgb([0, 0, 0])));
+    }
+
+    #[test]
+    fn test_progress_callback() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let mut processor = test_processor(identity_runner(chunksize));
+        let events = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let callback_events = events.clone();
+        processor.set_progress_callback(move |event| callback_events.borrow_mut().push(event));
+
+        let chunk_count = processor.chunk_geometry(100, 70).unwrap().chunk_count();
+        pollster::block_on(processor.process_image(gradient_image(100, 70))).unwrap();
+        let expected: Vec<_> = (1..=chunk_count)
+            .map(|current_chunk| ProgressEvent {
+                current_chunk,
+                total_chunks: chunk_count,
+            })
+            .collect();
+        assert!(chunk_count > 1);
+        assert_eq!(*events.borrow(), expected);
+
+        // An image that fits into a single chunk is one step
+        events.borrow_mut().clear();
+        pollster::block_on(processor.process_image(gradient_image(10, 10))).unwrap();
+        assert_eq!(
+            *events.borrow(),
+            vec![ProgressEvent {
+                current_chunk: 1,
+                total_chunks: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn test_process_image_passes() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let runner =
+            ModelRunner::from_stub(chunksize, 1, |input, _| Ok(input.mapv(|v| v * 0.5 + 0.1)));
+        let mut processor = test_processor(runner);
+        let input = gradient_image(100, 70);
+
+        let single_pass = pollster::block_on(processor.process_image(input.clone())).unwrap();
+        let one_pass =
+            pollster::block_on(processor.process_image_passes(input.clone(), 1)).unwrap();
+        assert_eq!(one_pass, single_pass);
+
+        let tensor = image_to_tensor(input.clone()).unwrap();
+        let tensor = pollster::block_on(processor.process_tensor(tensor)).unwrap();
+        let tensor = pollster::block_on(processor.process_tensor(tensor)).unwrap();
+        let two_passes =
+            pollster::block_on(processor.process_image_passes(input.clone(), 2)).unwrap();
+        assert_eq!(two_passes, tensor_to_image(tensor).unwrap());
+
+        assert!(matches!(
+            pollster::block_on(processor.process_image_passes(input, 0)),
+            Err(ImageProcessingError::NoPasses)
+        ));
+    }
+
+    #[test]
+    fn test_float_round_trip() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let mut processor = test_processor(identity_runner(chunksize));
+        // None of these values can be represented exactly in 16 bits
+        let input = ImageBuffer::from_fn(100, 70, |x, y| {
+            Rgb([
+                x as f32 * 0.00123,
+                y as f32 * 0.00456,
+                0.1 + (x + y) as f32 * 1e-5,
+            ])
+        });
+
+        let output = pollster::block_on(processor.process_image_f32(input.clone())).unwrap();
+
+        assert_eq!(output.dimensions(), input.dimensions());
+        for (a, b) in input.pixels().zip(output.pixels()) {
+            for c in 0..3 {
+                assert!((a[c] - b[c]).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[cfg(feature = "half")]
+    #[test]
+    fn test_half_precision_matches_full_precision() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let runner =
+            || ModelRunner::from_stub(chunksize, 1, |input, _| Ok(input.mapv(|v| v * 0.9 + 0.05)));
+        let processor = |runner| {
+            pollster::block_on(ImageProcessor::new(
+                runner,
+                ImageColorModel::RGB,
+                ModelValueRange::symmetric(1.0),
+                ModelValueRange::symmetric(1.0),
+            ))
+            .unwrap()
+        };
+        let input = gradient_image(100, 70);
+
+        let full = pollster::block_on(processor(runner()).process_image(input.clone())).unwrap();
+        let half = pollster::block_on(
+            processor(runner())
+                .with_half_precision(true)
+                .process_image(input),
+        )
+        .unwrap();
+
+        // f16 has an 11 bit significand, values in [-1,1] are stored with an error below 2^-11.
+        // Input and output rounding add up to less than 1/1024 of the full value range.
+        let tolerance = u16::MAX as i32 / 1024;
+        for (a, b) in full.pixels().zip(half.pixels()) {
+            for c in 0..3 {
+                assert!((a[c] as i32 - b[c] as i32).abs() <= tolerance);
+            }
+        }
+    }
+
+    #[test]
+    fn test_accumulator_precision() {
+        // Many small contributions, like a pixel that is covered by many overlapping chunks
+        let contribution = Array3::from_elem((1, 1, 1), 0.1f32);
+        let mut single = Array3::<f32>::zeros((1, 1, 1));
+        let mut double = Array3::<f64>::zeros((1, 1, 1));
+        for _ in 0..100_000 {
+            f32::accumulate(single.view_mut(), contribution.view());
+            f64::accumulate(double.view_mut(), contribution.view());
+        }
+        let exact = 100_000.0 * 0.1f32 as f64;
+        let single_error = (single[(0, 0, 0)] as f64 - exact).abs();
+        let double_error = (double[(0, 0, 0)] - exact).abs();
+        assert!(single_error > 0.1);
+        assert!(double_error < 1e-6);
+
+        let chunksize = ChunkSize {
+            width: 16,
+            height: 16,
+        };
+        let processor = |precision| {
+            test_processor(ModelRunner::from_stub(chunksize, 1, |input, _| {
+                Ok(input.mapv(|v| v * 0.9 + 0.05))
+            }))
+            .with_process_mode(ProcessMode::Tiled {
+                padding: 2,
+                overlap: 4,
+            })
+            .with_blend_mode(BlendMode::Feather)
+            .with_accumulator_precision(precision)
+        };
+        let input = gradient_image(100, 70);
+
+        let single =
+            pollster::block_on(processor(AccumulatorPrecision::F32).process_image(input.clone()))
+                .unwrap();
+        let double =
+            pollster::block_on(processor(AccumulatorPrecision::F64).process_image(input)).unwrap();
+        assert_images_close(&single, &double);
+    }
+
+    #[test]
+    fn test_single_chunk_fast_path() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        // The output depends on the position in the chunk, so any shift of the image would show
+        let runner = ModelRunner::from_stub(chunksize, 1, |input, _| {
+            Ok(Array3::from_shape_fn(input.raw_dim(), |(c, y, x)| {
+                input[(c, y, x)] * 0.5 + (x + 2 * y) as f32 / 200.0
+            }))
+        });
+        let mut processor = test_processor(runner).with_process_mode(ProcessMode::Tiled {
+            padding: 4,
+            overlap: 2,
+        });
+        // The timings of a previous image with several chunks are replaced
+        pollster::block_on(processor.process_image(gradient_image(60, 60))).unwrap();
+        assert!(processor.chunk_timings().len() > 1);
+        let calls = Rc::new(Cell::new(0));
+        let hook_calls = calls.clone();
+        processor.set_chunk_hook(move |stage, _| {
+            if stage == ChunkStage::PreInference {
+                hook_calls.set(hook_calls.get() + 1);
+            }
+        });
+        let input = gradient_image(20, 12);
+
+        pollster::block_on(processor.process_image(input.clone())).unwrap();
+        assert_eq!(calls.get(), 1);
+        assert_eq!(processor.chunk_timings().len(), 1);
+        assert!(processor.chunk_timings()[0].is_some());
+
+        let data = image_to_tensor(input).unwrap().permuted_axes([2, 0, 1]);
+        let mut single = Array3::<f32>::zeros((12, 20, 3));
+        pollster::block_on(processor.process_single_chunk::<f32, f32>(
+            data.clone(),
+            &mut single,
+            None,
+        ))
+        .unwrap();
+        let mut tiled = Array3::<f32>::zeros((12, 20, 3));
+        pollster::block_on(processor.process_tiles::<f32, f32>(data, &mut tiled, None, None))
+            .unwrap();
+        assert_eq!(single, tiled);
+    }
+
+    fn oom_runner(chunksize: ChunkSize) -> ModelRunner {
+        ModelRunner::from_stub(chunksize, 1, |input, _| {
+            if input.shape()[2] > 32 {
+                Err(ModelRunnerError::InferenceFailed(
+                    "out of memory".to_owned(),
+                ))
+            } else {
+                Ok(input.to_owned())
+            }
+        })
+    }
+
+    #[test]
+    fn test_auto_chunksize() {
+        let chunksize = ChunkSize {
+            width: 64,
+            height: 64,
+        };
+        let mut processor = test_processor(oom_runner(chunksize).with_dynamic_input_shape())
+            .with_auto_chunksize(true);
+        let input = gradient_image(100, 70);
+
+        let output = pollster::block_on(processor.process_image(input.clone())).unwrap();
+
+        assert_images_close(&input, &output);
+        assert_eq!(
+            processor.chunksize,
+            ChunkSize {
+                width: 32,
+                height: 32
+            }
+        );
+    }
+
+    #[test]
+    fn test_auto_chunksize_per_image() {
+        let chunksize = ChunkSize {
+            width: 64,
+            height: 64,
+        };
+        let out_of_memory = Rc::new(Cell::new(true));
+        let model_out_of_memory = out_of_memory.clone();
+        let runner = ModelRunner::from_stub(chunksize, 1, move |input, _| {
+            if model_out_of_memory.get() && input.shape()[2] > 32 {
+                Err(ModelRunnerError::InferenceFailed(
+                    "out of memory".to_owned(),
+                ))
+            } else {
+                Ok(input.to_owned())
+            }
+        });
+        let mut processor =
+            test_processor(runner.with_dynamic_input_shape()).with_auto_chunksize(true);
+        let (padding, overlap) = processor.chunk_padding_and_overlap();
+
+        pollster::block_on(processor.process_image(gradient_image(100, 70))).unwrap();
+        assert_eq!(processor.chunksize.width, 32);
+
+        // The next image starts with the configured chunksize again
+        out_of_memory.set(false);
+        let input = gradient_image(100, 70);
+        let output = pollster::block_on(processor.process_image(input.clone())).unwrap();
+        assert_images_close(&input, &output);
+        assert_eq!(processor.chunksize, chunksize);
+        assert_eq!(processor.chunk_padding_and_overlap(), (padding, overlap));
+    }
+
+    #[test]
+    fn test_auto_chunksize_fixed_shape() {
+        let chunksize = ChunkSize {
+            width: 64,
+            height: 64,
+        };
+        let mut processor = test_processor(oom_runner(chunksize)).with_auto_chunksize(true);
+
+        let result = pollster::block_on(processor.process_image(gradient_image(100, 70)));
+
+        assert!(matches!(
+            result,
+            Err(ImageProcessingError::ModelRunnerError(
+                ModelRunnerError::FixedInputShape(_)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let chunksize = ChunkSize {
+            width: 256,
+            height: 256,
+        };
+        let small_chunksize = ChunkSize {
+            width: 64,
+            height: 64,
+        };
+        for (width, height) in [(100, 70), (640, 480), (1000, 800)] {
+            let mut processor =
+                test_processor(identity_runner(chunksize).with_dynamic_input_shape());
+            let budget = estimate_peak_memory(width as usize, height as usize, small_chunksize, 1);
+            processor.set_memory_budget(Some(budget));
+
+            let input = gradient_image(width, height);
+            let output = pollster::block_on(processor.process_image(input.clone())).unwrap();
+
+            assert_images_close(&input, &output);
+            assert!(
+                estimate_peak_memory(width as usize, height as usize, processor.chunksize, 1)
+                    <= budget
+            );
+            assert!(processor.chunksize.width >= small_chunksize.width);
+        }
+    }
+
+    #[test]
+    fn test_memory_budget_dynamic_model() {
+        let chunksize = ChunkSize {
+            width: 256,
+            height: 256,
+        };
+        let mut processor = test_processor(dynamic_identity_runner(chunksize));
+        let budget = estimate_peak_memory(
+            640,
+            480,
+            ChunkSize {
+                width: 128,
+                height: 128,
+            },
+            1,
+        );
+        processor.set_memory_budget(Some(budget));
+
+        let input = gradient_image(640, 480);
+        let output = pollster::block_on(processor.process_image(input.clone())).unwrap();
+
+        assert_images_close(&input, &output);
+        assert!(processor.chunksize.width < chunksize.width);
+        assert_eq!(processor.runner.get_chunksize(), processor.chunksize);
+    }
+
+    #[test]
+    fn test_memory_budget_fixed_shape() {
+        let chunksize = ChunkSize {
+            width: 64,
+            height: 64,
+        };
+        let mut processor =
+            test_processor(identity_runner(chunksize)).with_memory_budget(Some(1024));
+
+        let result = pollster::block_on(processor.process_image(gradient_image(100, 70)));
+
+        assert!(matches!(
+            result,
+            Err(ImageProcessingError::MemoryBudgetExceeded { budget: 1024, .. })
+        ));
+    }
+
+    #[test]
+    fn test_min_tiles() {
+        let chunksize = ChunkSize {
+            width: 256,
+            height: 256,
+        };
+        let mut processor = test_processor(identity_runner(chunksize).with_dynamic_input_shape())
+            .with_min_tiles(Some(16));
+        let input = gradient_image(260, 260);
+
+        let output = pollster::block_on(processor.process_image(input.clone())).unwrap();
+
+        assert_images_close(&input, &output);
+        let (padding, overlap) = processor.chunk_padding_and_overlap();
+        let tiles = ChunkGeometryReport::new((260, 260), processor.chunksize, padding, overlap)
+            .unwrap()
+            .chunk_count();
+        assert!(tiles >= 16, "{} tiles", tiles);
+        assert!(processor.chunksize.width < 256);
+    }
+
+    #[test]
+    fn test_max_tile_pixels_dynamic_model() {
+        let chunksize = ChunkSize {
+            width: 256,
+            height: 256,
+        };
+        let mut processor = test_processor(dynamic_identity_runner(chunksize))
+            .with_max_tile_pixels(Some(128 * 128));
+        let input = gradient_image(300, 200);
+
+        let output = pollster::block_on(processor.process_image(input.clone())).unwrap();
+
+        assert_images_close(&input, &output);
+        let small_chunksize = ChunkSize {
+            width: 128,
+            height: 128,
+        };
+        assert_eq!(processor.chunksize, small_chunksize);
+        assert_eq!(processor.runner.get_chunksize(), small_chunksize);
+    }
+
+    #[test]
+    fn test_tile_limits_fixed_shape() {
+        let chunksize = ChunkSize {
+            width: 64,
+            height: 64,
+        };
+        let mut processor = test_processor(identity_runner(chunksize))
+            .with_min_tiles(Some(100))
+            .with_max_tile_pixels(Some(1000));
+        let input = gradient_image(100, 70);
+
+        let output = pollster::block_on(processor.process_image(input.clone())).unwrap();
+
+        assert_images_close(&input, &output);
+        assert_eq!(processor.chunksize, chunksize);
+    }
+
+    #[test]
+    fn test_process_image_with_preview() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let mut calls = 0;
+        let runner = ModelRunner::from_stub(chunksize, 1, move |input, _| {
+            calls += 1;
+            assert!(calls <= 9, "the model is only run once per chunk");
+            Ok(input.to_owned())
+        });
+        let mut processor = test_processor(runner).with_process_mode(ProcessMode::Simple);
+        let input = gradient_image(90, 70);
+
+        let (output, preview) =
+            pollster::block_on(processor.process_image_with_preview(input.clone())).unwrap();
+
+        assert_images_close(&input, &output);
+        assert_eq!(preview.dimensions(), output.dimensions());
+        for (output, preview) in output.pixels().zip(preview.pixels()) {
+            for c in 0..3 {
+                let expected = output[c] as f32 / 257.0;
+                assert!((preview[c] as f32 - expected).abs() <= 1.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_output_range_check() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        for (check, expected_range) in [
+            (OutputRangeCheck::Warn, ModelValueRange::asymmetric(1.0)),
+            (OutputRangeCheck::Adopt, ModelValueRange::asymmetric(255.0)),
+        ] {
+            let runner =
+                ModelRunner::from_stub(chunksize, 1, |input, _| Ok(input.mapv(|v| v * 255.0)));
+            let mut processor = test_processor(runner).with_output_range_check(check);
+            let input = gradient_image(90, 70);
+
+            for _ in 0..2 {
+                let output = pollster::block_on(processor.process_image(input.clone())).unwrap();
+                if check == OutputRangeCheck::Adopt {
+                    assert_images_close(&input, &output);
+                } else {
+                    assert!(output.pixels().all(|p| p[0] == u16::MAX || p[0] == 0));
+                }
+            }
+            assert_eq!(processor.settings().output_range, expected_range);
+        }
+
+        // A dark image does not decide the range, the next image does
+        let runner = ModelRunner::from_stub(chunksize, 1, |input, _| Ok(input.mapv(|v| v * 255.0)));
+        let mut processor = pollster::block_on(ImageProcessor::new(
+            runner,
+            ImageColorModel::RGB,
+            ModelValueRange::asymmetric(1.0),
+            ModelValueRange::asymmetric(255.0),
+        ))
+        .unwrap()
+        .with_output_range_check(OutputRangeCheck::Adopt);
+        let dark = ImageBuffer::from_fn(90, 70, |x, y| Rgb([128 + ((x + y) % 2) as u16 * 40; 3]));
+        pollster::block_on(processor.process_image(dark)).unwrap();
+        assert!(!processor.output_range_checked);
+        pollster::block_on(processor.process_image(gradient_image(90, 70))).unwrap();
+        assert!(processor.output_range_checked);
+        assert_eq!(
+            processor.settings().output_range,
+            ModelValueRange::asymmetric(255.0)
+        );
+
+        // Only the range of the channel that does not fit is replaced
+        let runner = ModelRunner::from_stub(chunksize, 1, |input, _| {
+            Ok(Array3::from_shape_fn(input.raw_dim(), |(c, y, x)| {
+                input[(c, y, x)] * if c == 2 { 255.0 } else { 1.0 }
+            }))
+        });
+        let mut processor = pollster::block_on(ImageProcessor::new(
+            runner,
+            ImageColorModel::RGB,
+            ModelValueRange::asymmetric(1.0),
+            ModelValueRange::per_channel([
+                ModelValueRange::symmetric(1.0),
+                ModelValueRange::asymmetric(1.0),
+                ModelValueRange::asymmetric(1.0),
+            ]),
+        ))
+        .unwrap()
+        .with_output_range_check(OutputRangeCheck::Adopt);
+        pollster::block_on(processor.process_image(gradient_image(90, 70))).unwrap();
+        assert_eq!(
+            processor.settings().output_range,
+            ModelValueRange::per_channel([
+                ModelValueRange::symmetric(1.0),
+                ModelValueRange::asymmetric(1.0),
+                ModelValueRange::asymmetric(255.0),
+            ])
+        );
+    }
+
+    /// An NCHW model that upscales by repeating each pixel `scale` times in both directions
+    fn upscaling_runner(chunksize: ChunkSize, scale: usize) -> ModelRunner {
+        ModelRunner::from_stub(chunksize, scale, move |input, output_shape| {
+            Ok(Array3::from_shape_fn(
+                (output_shape[0], output_shape[1], output_shape[2]),
+                |(c, y, x)| input[(c, y / scale, x / scale)],
+            ))
+        })
+    }
+
+    #[test]
+    fn test_per_channel_value_ranges() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let range = ModelValueRange::per_channel([
+            ModelValueRange::asymmetric(255.0),
+            ModelValueRange::symmetric(1.0),
+            ModelValueRange::asymmetric(1.0),
+        ]);
+        // The left half is uniform and skipped, which converts the ranges without the model
+        let input = ImageBuffer::from_fn(100, 70, |x, y| {
+            if x < 50 {
+                Rgb([40000, 20000, 60000])
+            } else {
+                Rgb([(x * 600) as u16, (y * 900) as u16, ((x + y) * 300) as u16])
+            }
+        });
+        for color_model in [ImageColorModel::RGB, ImageColorModel::BGR] {
+            let maxima = Rc::new(Cell::new([f32::NEG_INFINITY; 3]));
+            let runner_maxima = maxima.clone();
+            let runner = ModelRunner::from_stub(chunksize, 1, move |input, _| {
+                let mut maxima = runner_maxima.get();
+                for (max, channel) in maxima.iter_mut().zip(input.outer_iter()) {
+                    *max = channel.fold(*max, |max, &v| max.max(v));
+                }
+                runner_maxima.set(maxima);
+                Ok(input.to_owned())
+            });
+            let mut processor = pollster::block_on(ImageProcessor::new(
+                runner,
+                color_model,
+                range.clone(),
+                range.clone(),
+            ))
+            .unwrap()
+            .with_skip_uniform(Some(1e-4));
+            let output = pollster::block_on(processor.process_image(input.clone())).unwrap();
+
+            assert_images_close(&input, &output);
+            // The ranges are in RGB order, so red is in the third channel of BGR models
+            let [first, _, third] = maxima.get();
+            let (red, blue) = match color_model {
+                ImageColorModel::BGR => (third, first),
+                ImageColorModel::RGB => (first, third),
+            };
+            assert!(red > 1.0 && red <= 255.0, "red maximum {}", red);
+            assert!(blue <= 1.0, "blue maximum {}", blue);
+        }
+    }
+
+    #[test]
+    fn test_output_dimensions_match_input() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        for scale in [1, 2, 3] {
+            // The box average exactly undoes the repeated pixels, so the output matches the input
+            let runner = upscaling_runner(chunksize, scale)
+                .with_downscale_filter(DownscaleFilter::BoxAverage);
+            let mut processor = test_processor(runner);
+            let (padding, overlap) = processor.chunk_padding_and_overlap();
+            let step = ChunkGeometryReport::new((1, 1), chunksize, padding, overlap)
+                .unwrap()
+                .step_size
+                .width;
+
+            // Reflect padding needs at least as many pixels as it adds, so no tiny images here
+            let mut sizes = vec![(17, 19), (31, 32), (32, 33), (97, 89), (101, 23)];
+            for multiple in [1, 2, 5] {
+                for offset in [-1, 0, 1] {
+                    let size = (step * multiple) as i64 + offset;
+                    sizes.push((size as u32, 20 + multiple as u32));
+                    sizes.push((53, size as u32));
+                }
+            }
+            for (width, height) in sizes {
+                let input = gradient_image(width, height);
+
+                let output = pollster::block_on(processor.process_image(input.clone())).unwrap();
+
+                assert_eq!(
+                    output.dimensions(),
+                    (width, height),
+                    "scale {} changed the dimensions",
+                    scale
+                );
+                assert_images_close(&input, &output);
+            }
+        }
+    }
+
+    #[test]
+    fn test_upscale_output() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        for blend_mode in [BlendMode::Average, BlendMode::Feather, BlendMode::Cosine] {
+            let mut processor = test_processor(upscaling_runner(chunksize, 2))
+                .with_upscale(true)
+                .with_blend_mode(blend_mode);
+            assert_eq!(processor.output_scale(), 2);
+
+            // A single chunk and several chunks that are clipped at the borders
+            for (width, height) in [(17, 19), (97, 89)] {
+                let input = gradient_image(width, height);
+
+                let output = pollster::block_on(processor.process_image(input.clone())).unwrap();
+
+                assert_eq!(output.dimensions(), (2 * width, 2 * height));
+                let expected = ImageBuffer::from_fn(2 * width, 2 * height, |x, y| {
+                    *input.get_pixel(x / 2, y / 2)
+                });
+                assert_images_close(&expected, &output);
+            }
+        }
+
+        let mut processor = test_processor(upscaling_runner(chunksize, 2))
+            .with_upscale(true)
+            .with_preserve_border(2);
+        assert!(matches!(
+            pollster::block_on(processor.process_image(gradient_image(40, 40))),
+            Err(ImageProcessingError::UpscalingNotSupported(_))
+        ));
+    }
+
+    /// A model that blurs each chunk with a 3x3 box filter, clamped at the chunk borders
+    fn blur_runner(chunksize: ChunkSize, calls: Rc<Cell<usize>>) -> ModelRunner {
+        ModelRunner::from_stub(chunksize, 1, move |input, _| {
+            calls.set(calls.get() + 1);
+            let (height, width) = (input.shape()[1], input.shape()[2]);
+            Ok(Array3::from_shape_fn(input.raw_dim(), |(c, y, x)| {
+                let mut sum = 0.0;
+                for ny in y.saturating_sub(1)..=(y + 1).min(height - 1) {
+                    for nx in x.saturating_sub(1)..=(x + 1).min(width - 1) {
+                        sum += input[(c, ny, nx)];
+                    }
+                }
+                let count = (y.min(1) + 1 + (height - 1 - y).min(1))
+                    * (x.min(1) + 1 + (width - 1 - x).min(1));
+                sum / count as f32
+            }))
+        })
+    }
+
+    #[test]
+    fn test_infer_scale() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let processor = |infer_scale: Option<f32>| {
+            let mut processor = test_processor(blur_runner(chunksize, Rc::new(Cell::new(0))));
+            if let Some(infer_scale) = infer_scale {
+                processor.set_infer_scale(infer_scale).unwrap();
+            }
+            processor
+        };
+        // A smooth gradient with a fine checkerboard on top
+        let input = Array3::from_shape_fn((64, 96, 3), |(y, x, c)| {
+            0.2 + (x + y + c) as f32 / 400.0 + ((x + y) % 2) as f32 * 0.2
+        });
+        let detail = |data: &Array3<f32>| {
+            let dx = &data.slice(s![.., 1.., ..]) - &data.slice(s![.., ..-1, ..]);
+            dx.mapv(f32::abs).mean().unwrap()
+        };
+
+        let normal = pollster::block_on(processor(None).process_tensor(input.clone())).unwrap();
+        let full_scale =
+            pollster::block_on(processor(Some(1.0)).process_tensor(input.clone())).unwrap();
+        assert_eq!(full_scale, normal);
+        // The blur removes the checkerboard at full resolution
+        assert!(detail(&normal) < 0.5 * detail(&input));
+
+        // The downscaled copy has no checkerboard left to blur, so the detail is kept
+        let half_scale =
+            pollster::block_on(processor(Some(0.5)).process_tensor(input.clone())).unwrap();
+        assert_eq!(half_scale.shape(), input.shape());
+        assert!((detail(&half_scale) - detail(&input)).abs() < 0.05 * detail(&input));
+        // The smooth gradient is changed little by the blur
+        assert!((half_scale.mean().unwrap() - input.mean().unwrap()).abs() < 0.01);
+
+        // The mask is applied at the full resolution
+        let mut masked = processor(Some(0.5));
+        masked.set_mask(Some(Array2::from_shape_fn((64, 96), |(_, x)| {
+            (x >= 48) as u8 as f32
+        })));
+        let masked_output = pollster::block_on(masked.process_tensor(input.clone())).unwrap();
+        assert_eq!(
+            masked_output.slice(s![.., ..48, ..]),
+            input.slice(s![.., ..48, ..])
+        );
+        assert!(masked_output
+            .slice(s![.., 48.., ..])
+            .iter()
+            .zip(half_scale.slice(s![.., 48.., ..]))
+            .all(|(a, b)| (a - b).abs() < 1e-6));
+
+        let mut invalid = processor(None);
+        for infer_scale in [0.0, -0.5, 1.5, f32::NAN] {
+            assert!(matches!(
+                invalid.set_infer_scale(infer_scale),
+                Err(ImageProcessingError::InvalidInferScale(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_channel_adjustment() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let mut processor = test_processor(identity_runner(chunksize));
+        let input = Array3::from_shape_fn((40, 50, 3), |(y, x, c)| (x + y + c * 10) as f32 / 200.0);
+        // Blending the overlap of the chunks may change the last bits
+        let assert_close = |a: ArrayView3<f32>, b: ArrayView3<f32>| {
+            assert_eq!(a.shape(), b.shape());
+            assert!(a.iter().zip(b.iter()).all(|(a, b)| (a - b).abs() < 1e-5));
+        };
+
+        let adjust = |adjustment| vec![PostProcess::ChannelAdjustment(adjustment)];
+        processor.set_post_processes(adjust(ChannelAdjustment::identity(3)));
+        let output = pollster::block_on(processor.process_tensor(input.clone())).unwrap();
+        assert_close(output.view(), input.view());
+
+        processor.set_post_processes(adjust(ChannelAdjustment {
+            gain: vec![1.0, 1.0, 2.0],
+            offset: vec![0.0; 3],
+        }));
+        let mut expected = input.clone();
+        expected.slice_mut(s![.., .., 2]).mapv_inplace(|v| v * 2.0);
+        let output = pollster::block_on(processor.process_tensor(input.clone())).unwrap();
+        assert_close(output.view(), expected.view());
+        // Values above 1 are kept until the output is quantized
+        assert!(output[(39, 49, 2)] > 1.0);
+
+        // The adjustment is only applied once for multiple passes
+        let output = pollster::block_on(processor.process_tensor_passes(input.clone(), 3)).unwrap();
+        assert_close(output.view(), expected.view());
+
+        processor.set_post_processes(adjust(ChannelAdjustment::identity(4)));
+        assert!(matches!(
+            pollster::block_on(processor.process_tensor(input)),
+            Err(ImageProcessingError::ChannelAdjustmentMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_mask() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let inverting_runner =
+            ModelRunner::from_stub(chunksize, 1, |input, _| Ok(input.mapv(|v| 1.0 - v)));
+        // Process the left half, keep the right half and blend the column in between
+        let mask = Array2::from_shape_fn((48, 65), |(_, x)| match x {
+            0..=31 => 1.0,
+            32 => 0.5,
+            _ => 0.0,
+        });
+        let mut processor = test_processor(inverting_runner).with_mask(Some(mask));
+        let input = Array3::from_shape_fn((48, 65, 3), |(y, x, c)| {
+            (y * 65 + x + c) as f32 / (48 * 65 + 3) as f32
+        });
+
+        let output = pollster::block_on(processor.process_tensor(input.clone())).unwrap();
+        for ((y, x, c), &value) in output.indexed_iter() {
+            let expected = match x {
+                0..=31 => 1.0 - input[(y, x, c)],
+                32 => 0.5,
+                _ => input[(y, x, c)],
+            };
+            if x > 32 {
+                assert_eq!(value, expected);
+            } else {
+                assert!((value - expected).abs() < 1e-5);
+            }
+        }
+
+        let wrong_size = Array3::zeros((48, 64, 3));
+        assert!(matches!(
+            pollster::block_on(processor.process_tensor(wrong_size)),
+            Err(ImageProcessingError::MaskMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_auto_level() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let mut processor = test_processor(identity_runner(chunksize))
+            .with_post_processes(vec![PostProcess::AutoLevel(AutoLevel::default())]);
+        let range = |data: &Array3<f32>| {
+            data.iter().fold((f32::MAX, f32::MIN), |(min, max), &v| {
+                (min.min(v), max.max(v))
+            })
+        };
+
+        // Evenly distributed values from 0 to 1
+        let full_range = Array3::from_shape_fn((40, 50, 3), |(y, x, c)| {
+            ((y * 50 + x) * 3 + c) as f32 / (40 * 50 * 3 - 1) as f32
+        });
+        let output = pollster::block_on(processor.process_tensor(full_range.clone())).unwrap();
+        assert!(output
+            .iter()
+            .zip(full_range.iter())
+            .all(|(a, b)| (a - b).abs() < 0.01));
+
+        let low_contrast = full_range.mapv(|v| 0.4 + v * 0.2);
+        let output = pollster::block_on(processor.process_tensor(low_contrast)).unwrap();
+        let (min, max) = range(&output);
+        assert!(min < 0.0 && min > -0.01);
+        assert!(max > 1.0 && max < 1.01);
+        // The stretch is the same for all chunks
+        assert!(output
+            .iter()
+            .zip(full_range.iter())
+            .all(|(a, b)| (a - b).abs() < 0.01));
+    }
+
+    #[test]
+    fn test_wrap_padding_keeps_textures_seamless() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let (height, width) = (40, 72);
+        let input = Array3::from_shape_fn((height, width, 3), |(y, x, c)| {
+            ((x * 5 + y * 11 + c * 3) % 17) as f32 / 17.0
+        });
+        // The blur of the texture repeated infinitely
+        let seamless = Array3::from_shape_fn(input.raw_dim(), |(y, x, c)| {
+            let mut sum = 0.0;
+            for dy in [height - 1, 0, 1] {
+                for dx in [width - 1, 0, 1] {
+                    sum += input[((y + dy) % height, (x + dx) % width, c)];
+                }
+            }
+            sum / 9.0
+        });
+        let process = |pad_mode| {
+            let mut processor = test_processor(blur_runner(chunksize, Rc::new(Cell::new(0))))
+                .with_process_mode(ProcessMode::Tiled {
+                    padding: 4,
+                    overlap: 2,
+                })
+                .with_pad_mode(pad_mode);
+            pollster::block_on(processor.process_tensor(input.clone())).unwrap()
+        };
+        let max_difference = |output: &Array3<f32>| {
+            (output - &seamless)
+                .iter()
+                .fold(0.0f32, |max, v| max.max(v.abs()))
+        };
+
+        assert!(max_difference(&process(PadMode::Wrap)) < 1e-4);
+        // Reflecting the texture at its borders creates a seam when it is tiled
+        assert!(max_difference(&process(PadMode::Reflect)) > 0.01);
+    }
+
+    /// An encoded image that counts how often it is decoded
+    struct CountingSource {
+        encoded: Vec<u8>,
+        decodes: Cell<usize>,
+    }
+
+    impl CountingSource {
+        fn new(image: ImageBuffer<Rgb<u16>, Vec<u16>>) -> Self {
+            let mut encoded = Vec::new();
+            image::DynamicImage::ImageRgb16(image)
+                .write_to(
+                    &mut std::io::Cursor::new(&mut encoded),
+                    image::ImageOutputFormat::Png,
+                )
+                .unwrap();
+            Self {
+                encoded,
+                decodes: Cell::new(0),
+            }
+        }
+
+        fn decode(&self) -> ImageBuffer<Rgb<u16>, Vec<u16>> {
+            self.decodes.set(self.decodes.get() + 1);
+            image::load_from_memory(&self.encoded).unwrap().into_rgb16()
+        }
+    }
+
+    #[test]
+    fn test_prepared_image() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let strength = |strength| {
+            vec![PostProcess::ChannelAdjustment(ChannelAdjustment {
+                gain: vec![strength; 3],
+                offset: vec![0.0; 3],
+            })]
+        };
+        let tiled = ProcessMode::Tiled {
+            padding: 4,
+            overlap: 2,
+        };
+        // Tiled, a single chunk and edge-to-edge chunks
+        for (process_mode, width, height) in [
+            (tiled, 70, 50),
+            (tiled, 20, 12),
+            (ProcessMode::Simple, 70, 50),
+        ] {
+            let calls = Rc::new(Cell::new(0));
+            let mut processor = test_processor(blur_runner(chunksize, calls.clone()))
+                .with_process_mode(process_mode);
+            let source = CountingSource::new(gradient_image(width, height));
+
+            let mut expected = Vec::new();
+            let calls_before = calls.get();
+            for gain in [1.0, 0.5] {
+                processor.set_post_processes(strength(gain));
+                expected
+                    .push(pollster::block_on(processor.process_image(source.decode())).unwrap());
+            }
+            let reference_calls = calls.get() - calls_before;
+            assert_eq!(source.decodes.get(), 2);
+
+            let prepared = processor.prepare_image(source.decode()).unwrap();
+            assert_eq!(prepared.dimensions(), (width as usize, height as usize));
+            let mut outputs = Vec::new();
+            let calls_before = calls.get();
+            for gain in [1.0, 0.5] {
+                processor.set_post_processes(strength(gain));
+                outputs.push(pollster::block_on(processor.process_prepared(&prepared)).unwrap());
+            }
+            // The prepared image is decoded once and chunked like by `process_image`
+            assert_eq!(source.decodes.get(), 3);
+            assert_eq!(calls.get() - calls_before, reference_calls);
+            assert_eq!(outputs, expected);
+            assert_ne!(outputs[0], outputs[1]);
+        }
+
+        let mut processor = test_processor(blur_runner(chunksize, Rc::default()))
+            .with_process_mode(ProcessMode::Simple);
+        let prepared = processor.prepare_image(gradient_image(70, 50)).unwrap();
+
+        // Geometry changes invalidate the prepared image
+        processor.set_process_mode(ProcessMode::Tiled {
+            padding: 0,
+            overlap: 0,
+        });
+        assert!(!processor.is_prepared_image_current(&prepared));
+        processor.set_process_mode(ProcessMode::Simple);
+        assert!(processor.is_prepared_image_current(&prepared));
+        processor.set_pad_mode(PadMode::Wrap);
+        assert!(!processor.is_prepared_image_current(&prepared));
+        assert!(matches!(
+            pollster::block_on(processor.process_prepared(&prepared)),
+            Err(ImageProcessingError::PreparedImageOutdated)
+        ));
+    }
+
+    #[test]
+    fn test_pipeline_depth_matches_sequential() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        // The left half is flat, so windows mix skipped chunks and chunks that need inference
+        let input = Array3::from_shape_fn((70, 90, 3), |(y, x, c)| {
+            if x < 45 {
+                0.5
+            } else {
+                ((x * 7 + y * 3 + c) % 11) as f32 / 10.0
+            }
+        });
+        let process = |depth| {
+            let calls = Rc::new(Cell::new(0));
+            let mut processor = test_processor(blur_runner(chunksize, calls.clone()))
+                .with_process_mode(ProcessMode::Tiled {
+                    padding: 4,
+                    overlap: 2,
+                })
+                .with_skip_uniform(Some(0.0));
+            pollster::block_on(processor.set_pipeline_depth(depth)).unwrap();
+            let output = pollster::block_on(processor.process_tensor(input.clone())).unwrap();
+            (output, calls.get())
+        };
+
+        let (sequential, sequential_calls) = process(1);
+        let (pipelined, pipelined_calls) = process(3);
+        assert_eq!(pipelined, sequential);
+        assert_eq!(pipelined_calls, sequential_calls);
+        assert!(sequential_calls > 0);
+    }
+
+    #[test]
+    fn test_parallel_chunks_match_sequential() {
+        let input = Array3::from_shape_fn((100, 130, 3), |(y, x, c)| {
+            ((x * 7 + y * 3 + c) % 11) as f32 / 10.0
+        });
+        let process = |concurrency| {
+            let runner = pollster::block_on(ModelRunner::from_bytes(&blur_model_bytes(), true))
+                .unwrap()
+                .with_concurrency(concurrency);
+            let mut processor = test_processor(runner).with_process_mode(ProcessMode::Tiled {
+                padding: 4,
+                overlap: 2,
+            });
+            let output = pollster::block_on(processor.process_tensor(input.clone())).unwrap();
+            let timed = processor.chunk_timings().iter().flatten().count();
+            (output, timed)
+        };
+
+        let (sequential, sequential_chunks) = process(ProcessingConcurrency::Sequential);
+        let (parallel, parallel_chunks) = process(ProcessingConcurrency::Parallel);
+        assert!(sequential_chunks > 1);
+        assert_eq!(parallel_chunks, sequential_chunks);
+        assert_eq!(
+            parallel.iter().map(|v| v.to_bits()).collect::<Vec<_>>(),
+            sequential.iter().map(|v| v.to_bits()).collect::<Vec<_>>()
+        );
+        // The blur changes the image, so the chunks were run through the model
+        assert_ne!(sequential, input);
+    }
+
+    #[test]
+    fn test_process_chunk_regions() {
+        let input = Array3::from_shape_fn((100, 130, 3), |(y, x, c)| {
+            ((x * 7 + y * 3 + c) % 11) as f32 / 10.0
+        });
+        for pad_mode in [PadMode::Reflect, PadMode::Edge, PadMode::Constant(0.5)] {
+            let runner =
+                pollster::block_on(ModelRunner::from_bytes(&blur_model_bytes(), true)).unwrap();
+            let mut processor = test_processor(runner)
+                .with_process_mode(ProcessMode::Tiled {
+                    padding: 4,
+                    overlap: 2,
+                })
+                .with_pad_mode(pad_mode);
+            let expected = pollster::block_on(processor.process_tensor(input.clone())).unwrap();
+
+            let geometry = processor.streaming_geometry(130, 100).unwrap();
+            assert!(geometry.chunk_count() > 1);
+            let chunksize = geometry.chunksize;
+            let regions = (0..geometry.chunk_count()).map(|index| {
+                // Only the data of a single chunk is read at a time
+                let (x, y) = geometry.source_region(index);
+                assert!(x.len() <= chunksize.width && y.len() <= chunksize.height);
+                Ok::<_, String>(input.slice(s![y, x, ..]).to_owned())
+            });
+            let streamed =
+                pollster::block_on(processor.process_chunk_regions(&geometry, regions)).unwrap();
+            assert_eq!(streamed, expected, "{:?}", pad_mode);
+        }
+    }
+
+    #[test]
+    fn test_process_chunk_regions_errors() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let mut processor =
+            test_processor(identity_runner(chunksize)).with_process_mode(ProcessMode::Tiled {
+                padding: 4,
+                overlap: 2,
+            });
+        let geometry = processor.streaming_geometry(80, 60).unwrap();
+        let read = |index| {
+            let (x, y) = geometry.source_region(index);
+            Array3::<f32>::zeros((y.len(), x.len(), 3))
+        };
+
+        let failing = (0..geometry.chunk_count()).map(|index| {
+            if index < 2 {
+                Ok(read(index))
+            } else {
+                Err("disk error")
+            }
+        });
+        assert!(matches!(
+            pollster::block_on(processor.process_chunk_regions(&geometry, failing)),
+            Err(ImageProcessingError::ChunkReadFailed { index: 2, .. })
+        ));
+        let short = (0..2).map(|index| Ok::<_, String>(read(index)));
+        assert!(matches!(
+            pollster::block_on(processor.process_chunk_regions(&geometry, short)),
+            Err(ImageProcessingError::ChunkReadFailed { index: 2, .. })
+        ));
+
+        processor.set_pad_mode(PadMode::Wrap);
+        assert!(matches!(
+            processor.streaming_geometry(80, 60),
+            Err(ImageProcessingError::StreamingNotSupported(_))
+        ));
+        processor.set_pad_mode(PadMode::Reflect);
+        processor.set_process_mode(ProcessMode::Tiled {
+            padding: 2,
+            overlap: 2,
+        });
+        let regions = (0..geometry.chunk_count()).map(|index| Ok::<_, String>(read(index)));
+        assert!(matches!(
+            pollster::block_on(processor.process_chunk_regions(&geometry, regions)),
+            Err(ImageProcessingError::StreamingGeometryOutdated)
+        ));
+    }
+
+    #[test]
+    fn test_adaptive_padding() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let processor = |adaptive_padding, calls| {
+            test_processor(blur_runner(chunksize, calls))
+                .with_process_mode(ProcessMode::Tiled {
+                    padding: 2,
+                    overlap: 0,
+                })
+                .with_adaptive_padding(adaptive_padding)
+        };
+        let adaptive_padding = Some(AdaptivePadding {
+            min_padding: 0,
+            max_padding: 8,
+            flat_threshold: 0.01,
+            contrast_threshold: 0.05,
+        });
+        // A checkerboard in the top left corner, the rest of the image is flat
+        let input = Array3::from_shape_fn((60, 60, 3), |(y, x, _)| {
+            if x < 20 && y < 20 {
+                ((x + y) % 2) as f32
+            } else {
+                0.4
+            }
+        });
+
+        let flat = processor(None, Rc::default());
+        let checker = Array3::from_shape_fn((3, 32, 32), |(_, y, x)| ((x + y) % 2) as f32);
+        assert_eq!(flat.adaptive_chunk_padding(&checker.view()), None);
+        let calls = Rc::new(Cell::new(0));
+        let mut adaptive = processor(adaptive_padding, calls.clone());
+        assert_eq!(adaptive.chunk_padding_and_overlap(), (0, 0));
+        assert_eq!(adaptive.adaptive_chunk_padding(&checker.view()), Some(8));
+        let faint_checker = checker.mapv(|v| 0.4 + v * 0.03);
+        assert_eq!(
+            adaptive.adaptive_chunk_padding(&faint_checker.view()),
+            Some(2)
+        );
+        // Flat chunks keep the minimum padding of the chunk grid
+        assert_eq!(
+            adaptive.adaptive_chunk_padding(&Array3::from_elem((3, 32, 32), 0.4).view()),
+            None
+        );
+
+        // The contrast is relative to the range of each channel
+        let per_channel = pollster::block_on(ImageProcessor::new(
+            identity_runner(chunksize),
+            ImageColorModel::RGB,
+            ModelValueRange::per_channel([
+                ModelValueRange::asymmetric(255.0),
+                ModelValueRange::asymmetric(1.0),
+                ModelValueRange::asymmetric(1.0),
+            ]),
+            ModelValueRange::asymmetric(1.0),
+        ))
+        .unwrap()
+        .with_process_mode(ProcessMode::Tiled {
+            padding: 2,
+            overlap: 0,
+        })
+        .with_adaptive_padding(adaptive_padding);
+        let green_blue_checker = Array3::from_shape_fn((3, 32, 32), |(c, y, x)| {
+            if c == 0 {
+                100.0
+            } else {
+                ((x + y) % 2) as f32
+            }
+        });
+        assert_eq!(
+            per_channel.adaptive_chunk_padding(&green_blue_checker.view()),
+            Some(8)
+        );
+
+        // 2x2 chunks with a step of 32, the first one is split into 2x2 sub-chunks with a step of 16
+        let output = pollster::block_on(adaptive.process_tensor(input.clone())).unwrap();
+        assert_eq!(calls.get(), 3 + 4);
+
+        let mut reference = processor(None, Rc::default());
+        let expected = pollster::block_on(reference.process_tensor(input)).unwrap();
+        for (value, expected) in output.iter().zip(expected.iter()) {
+            assert!((value - expected).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_preserve_border() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let mut processor = test_processor(ModelRunner::from_stub(chunksize, 1, |input, _| {
+            Ok(input.mapv(|v| v * 0.5))
+        }))
+        .with_preserve_border(3);
+        let input =
+            Array3::from_shape_fn((50, 70, 3), |(y, x, c)| 0.2 + (x + y + c) as f32 / 500.0);
+
+        let output = pollster::block_on(processor.process_tensor(input.clone())).unwrap();
+
+        for ((y, x, c), &value) in output.indexed_iter() {
+            if y < 3 || y >= 47 || x < 3 || x >= 67 {
+                assert_eq!(value, input[(y, x, c)]);
+            } else {
+                assert!((value - input[(y, x, c)] * 0.5).abs() < 1e-5);
+            }
+        }
+    }
+
+    /// Process an image covered by exactly two chunks next to each other
+    ///
+    /// The first chunk returns `0.2` everywhere, the second one returns `0.6`. The overlap region
+    /// of the chunks are the columns 44 to 47.
+    fn blend_two_chunks(blend_mode: BlendMode) -> Array3<f32> {
+        let chunksize = ChunkSize {
+            width: 64,
+            height: 64,
+        };
+        let mut values = [0.2, 0.6].into_iter();
+        let runner = ModelRunner::from_stub(chunksize, 1, move |input, _| {
+            Ok(Array3::from_elem(input.raw_dim(), values.next().unwrap()))
+        });
+        let mut processor = test_processor(runner)
+            .with_process_mode(ProcessMode::Tiled {
+                padding: 8,
+                overlap: 4,
+            })
+            .with_blend_mode(blend_mode);
+
+        pollster::block_on(processor.process_tensor(Array3::zeros((40, 88, 3)))).unwrap()
+    }
+
+    #[test]
+    fn test_blend_modes() {
+        let feather_weights = [0.125, 0.375, 0.625, 0.875];
+        for blend_mode in [BlendMode::Average, BlendMode::Feather, BlendMode::Max] {
+            let output = blend_two_chunks(blend_mode);
+            for x in 0..88 {
+                let expected = if x < 44 {
+                    0.2
+                } else if x >= 48 {
+                    0.6
+                } else {
+                    match blend_mode {
+                        BlendMode::Average => 0.4,
+                        BlendMode::Feather => {
+                            let weight = feather_weights[x - 44];
+                            0.2 * (1.0 - weight) + 0.6 * weight
+                        }
+                        BlendMode::Max => 0.6,
+                    }
+                };
+                assert!((output[(20, x, 0)] - expected).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_ramps_keep_constant_field() {
+        let chunksize = ChunkSize {
+            width: 64,
+            height: 64,
+        };
+        for blend_mode in [BlendMode::Feather, BlendMode::Cosine] {
+            let mut processor = test_processor(identity_runner(chunksize))
+                .with_process_mode(ProcessMode::Tiled {
+                    padding: 8,
+                    overlap: 6,
+                })
+                .with_blend_mode(blend_mode);
+
+            // The weights also add up to one where four chunks meet
+            let output =
+                pollster::block_on(processor.process_tensor(Array3::from_elem((150, 200, 3), 0.3)))
+                    .unwrap();
+
+            assert!(
+                output.iter().all(|&v| (v - 0.3).abs() < 1e-6),
+                "{:?} does not keep a constant field",
+                blend_mode
+            );
+        }
+    }
+
+    #[test]
+    fn test_skip_uniform_chunks() {
+        let chunksize = ChunkSize {
+            width: 64,
+            height: 64,
+        };
+        // The left half of the image is flat, the right half has a gradient
+        let input = ImageBuffer::from_fn(300, 100, |x, y| {
+            if x < 150 {
+                Rgb([20000, 30000, 40000])
+            } else {
+                Rgb([(x * 200) as u16, (y * 300) as u16, 1000])
+            }
+        });
+        let process = |skip_uniform| {
+            let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+            let runner_calls = calls.clone();
+            let runner = ModelRunner::from_stub(chunksize, 1, move |input, _| {
+                runner_calls.set(runner_calls.get() + 1);
+                Ok(input.to_owned())
+            });
+            let mut processor = test_processor(runner).with_skip_uniform(skip_uniform);
+            let output = pollster::block_on(processor.process_image(input.clone())).unwrap();
+            // Only chunks that were run through the model are timed
+            let timed = processor.chunk_timings().iter().flatten().count();
+            assert_eq!(timed, calls.get());
+            (output, calls.get())
+        };
+
+        let (full_output, full_calls) = process(None);
+        let (skipped_output, skipped_calls) = process(Some(1e-4));
+
+        assert!(skipped_calls < full_calls);
+        assert_eq!(skipped_output, full_output);
+    }
+
+    #[test]
+    fn test_output_mean_is_only_computed_for_debug_logging() {
+        // No logger is installed in tests, so debug logging is disabled
+        assert!(!log::log_enabled!(log::Level::Debug));
+        assert_eq!(
+            ImageProcessor::log_output_mean(&Array3::ones((4, 4, 3))),
+            None
+        );
+    }
+
+    #[test]
+    fn test_empty_image() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        for process_mode in [
+            ProcessMode::Simple,
+            ProcessMode::Tiled {
+                padding: 4,
+                overlap: 2,
+            },
+        ] {
+            let mut processor =
+                test_processor(identity_runner(chunksize)).with_process_mode(process_mode);
+            for (width, height) in [(0, 12), (20, 0)] {
+                let is_empty = |result: Result<(), ImageProcessingError>| {
+                    matches!(
+                        result,
+                        Err(ImageProcessingError::ChunkGeneratorError(
+                            ImageChunkGeneratorError::EmptyImage(w, h)
+                        )) if (w, h) == (width, height)
+                    )
+                };
+                let empty = Array3::<f32>::zeros((height, width, 3));
+                let mut output = empty.clone();
+                assert!(is_empty(pollster::block_on(processor.process_chunks_into(
+                    empty.clone(),
+                    &mut output,
+                    None,
+                    None
+                ))));
+                assert!(is_empty(
+                    pollster::block_on(processor.process_tensor(empty.clone())).map(|_| ())
+                ));
+                assert!(is_empty(processor.prepare_tensor(empty).map(|_| ())));
+            }
+        }
+    }
+
+    #[test]
+    fn test_mean_of_empty_data() {
+        assert!(ImageProcessor::mean_or_nan(&Array3::zeros((0, 0, 3))).is_nan());
+        assert_eq!(ImageProcessor::mean_or_nan(&Array3::ones((2, 2, 3))), 1.0);
+    }
+
+    fn coverage(
+        width: u32,
+        height: u32,
+        chunksize: ChunkSize,
+        process_mode: ProcessMode,
+    ) -> Array2<f32> {
+        let mut processor =
+            test_processor(identity_runner(chunksize)).with_process_mode(process_mode);
+
+        let (_, coverage) = pollster::block_on(
+            processor.process_image_with_coverage(gradient_image(width, height)),
+        )
+        .unwrap();
+        coverage
+    }
+
+    #[test]
+    fn test_coverage_is_uniform() {
+        let chunksize = ChunkSize {
+            width: 64,
+            height: 64,
+        };
+        for process_mode in [
+            ProcessMode::Tiled {
+                padding: 8,
+                overlap: 4,
+            },
+            ProcessMode::Simple,
+        ] {
+            let coverage = coverage(100, 70, chunksize, process_mode);
+            assert!(coverage.iter().all(|&c| (c - 1.0).abs() < 1e-6));
+        }
+    }
+
+    #[test]
+    fn test_coverage_at_border_overlap() {
+        let chunksize = ChunkSize {
+            width: 64,
+            height: 64,
+        };
+        let process_mode = ProcessMode::Tiled {
+            padding: 8,
+            overlap: 4,
+        };
+        // With a step size of 44, the second chunk ends exactly at the right image border while
+        // a third chunk still starts in its overlap region
+        let coverage = coverage(92, 70, chunksize, process_mode);
+        assert!((coverage[(10, 89)] - 1.0).abs() < 1e-6);
+        assert!((coverage[(10, 80)] - 1.0).abs() < 1e-6);
+
+        // Four chunks meet at (88, 44) and (88, 88), where the last column and row are clipped
+        // by the image border, the last ones to less than the overlap
+        for (width, height) in [(92, 92), (90, 90), (91, 134)] {
+            let coverage = coverage(width, height, chunksize, process_mode);
+            assert!(
+                coverage.iter().all(|&c| (c - 1.0).abs() < 1e-6),
+                "The coverage of a {}x{} image is not uniform",
+                width,
+                height
+            );
+        }
+    }
+
+    #[test]
+    fn test_coverage_shows_gaps() {
+        let chunksize = ChunkSize {
+            width: 64,
+            height: 64,
+        };
+        let mut processor =
+            test_processor(identity_runner(chunksize)).with_process_mode(ProcessMode::Tiled {
+                padding: 8,
+                overlap: 4,
+            });
+        let image_data = image_to_tensor(gradient_image(100, 70)).unwrap();
+        let mut output = Array3::zeros(image_data.raw_dim());
+        let mut coverage = Array2::zeros((70, 100));
+        // Leaving out the top left of the 3x2 chunks breaks the geometry
+        let selection = [false, true, true, true, true, true];
+        pollster::block_on(processor.process_chunks_into(
+            image_data,
+            &mut output,
+            Some(&mut coverage),
+            Some(&selection),
+        ))
+        .unwrap();
+
+        assert_eq!(coverage[(10, 10)], 0.0);
+        // Only the second chunk covers the overlap with the first one
+        assert!(coverage[(10, 45)] > 0.0 && coverage[(10, 45)] < 1.0);
+        assert!((coverage[(60, 95)] - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_process_image_into_matches_process_image() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let mut processor = pollster::block_on(ImageProcessor::new(
+            ModelRunner::from_stub(chunksize, 1, |input, _| Ok(input.mapv(|v| v * 0.5))),
+            ImageColorModel::BGR,
+            ModelValueRange::asymmetric(1.0),
+            ModelValueRange::asymmetric(1.0),
+        ))
+        .unwrap();
+        let first = gradient_image(90, 70);
+        let second = ImageBuffer::from_fn(90, 70, |x, y| {
+            Rgb([(y * 500) as u16, 1000, (x * 700) as u16])
+        });
+
+        let mut output = ImageBuffer::new(90, 70);
+        pollster::block_on(processor.process_image_into(first.clone(), &mut output)).unwrap();
+        assert_eq!(
+            output,
+            pollster::block_on(processor.process_image(first)).unwrap()
+        );
+        // The reused buffers must not leak results of the previous image
+        pollster::block_on(processor.process_image_into(second.clone(), &mut output)).unwrap();
+        assert_eq!(
+            output,
+            pollster::block_on(processor.process_image(second.clone())).unwrap()
+        );
+
+        let mut wrong_size = ImageBuffer::new(70, 90);
+        assert!(matches!(
+            pollster::block_on(processor.process_image_into(second, &mut wrong_size)),
+            Err(ImageProcessingError::OutputBufferMismatch { expected, actual })
+                if expected == vec![70, 90, 3] && actual == vec![90, 70, 3]
+        ));
+    }
+
+    #[test]
+    fn test_default_padding_uses_recommended_padding() {
+        let chunksize = ChunkSize {
+            width: 64,
+            height: 64,
+        };
+        let heuristic = test_processor(identity_runner(chunksize));
+        let recommended = test_processor(identity_runner(chunksize).with_recommended_padding(12));
+        let limited = test_processor(identity_runner(chunksize).with_recommended_padding(40));
+
+        assert_eq!(
+            heuristic.process_mode,
+            ProcessMode::Tiled {
+                padding: 9,
+                overlap: 0
+            }
+        );
+        assert_eq!(
+            recommended.process_mode,
+            ProcessMode::Tiled {
+                padding: 12,
+                overlap: 1
+            }
+        );
+        assert_eq!(
+            limited.process_mode,
+            ProcessMode::Tiled {
+                padding: 16,
+                overlap: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_padding_and_overlap_setters() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let new_processor = || test_processor(identity_runner(chunksize));
+
+        let mut processor = new_processor().with_padding(6).with_overlap(3);
+        assert_eq!(
+            processor.process_mode,
+            ProcessMode::Tiled {
+                padding: 6,
+                overlap: 3
+            }
+        );
+        let input = gradient_image(70, 50);
+        let output = pollster::block_on(processor.process_image(input.clone())).unwrap();
+        assert_images_close(&input, &output);
+
+        // The simple mode has no padding, so setting the overlap keeps a padding of zero
+        let simple = new_processor()
+            .with_process_mode(ProcessMode::Simple)
+            .with_overlap(2);
+        assert_eq!(
+            simple.process_mode,
+            ProcessMode::Tiled {
+                padding: 0,
+                overlap: 2
+            }
+        );
+
+        let mut too_much_padding = new_processor().with_padding(16);
+        assert!(matches!(
+            too_much_padding.check_chunk_settings(),
+            Err(ImageChunkGeneratorError::InvalidPaddingValue(16, _))
+        ));
+        assert!(matches!(
+            pollster::block_on(too_much_padding.process_image(input.clone())),
+            Err(ImageProcessingError::ChunkGeneratorError(
+                ImageChunkGeneratorError::InvalidPaddingValue(16, _)
+            ))
+        ));
+        let too_much_overlap = new_processor().with_padding(8).with_overlap(9);
+        assert!(matches!(
+            too_much_overlap.check_chunk_settings(),
+            Err(ImageChunkGeneratorError::InvalidOverlapValue(9, _))
+        ));
+    }
+
+    #[test]
+    fn test_incremental_processing() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let mut processor = test_processor(ModelRunner::from_stub(chunksize, 1, |input, _| {
+            Ok(input.mapv(|v| v * 0.8 + 0.1))
+        }))
+        .with_process_mode(ProcessMode::Tiled {
+            padding: 4,
+            overlap: 2,
+        });
+        let previous_input = gradient_image(200, 120);
+        let previous_output =
+            pollster::block_on(processor.process_image(previous_input.clone())).unwrap();
+        // With a step size of 22, only the input of the chunk in column 4 and row 2 contains
+        // this pixel
+        let mut input = previous_input.clone();
+        input.put_pixel(100, 60, Rgb([u16::MAX, 0, u16::MAX]));
+
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        let hook_calls = calls.clone();
+        processor.set_chunk_hook(move |stage, _| {
+            if stage == ChunkStage::PreInference {
+                hook_calls.set(hook_calls.get() + 1);
+            }
+        });
+        let output = pollster::block_on(processor.process_image_incremental(
+            input.clone(),
+            IncrementalReference {
+                input: &previous_input,
+                output: &previous_output,
+                tolerance: 0.0,
+            },
+        ))
+        .unwrap();
+
+        // The changed chunk and its eight neighbours
+        assert_eq!(calls.get(), 9);
+        calls.set(0);
+        assert_eq!(
+            output,
+            pollster::block_on(processor.process_image(input)).unwrap()
+        );
+        assert_eq!(calls.get(), 60);
+    }
+
+    #[test]
+    fn test_rgba_model() {
+        let model_bytes = crate::test_image::identity_model_bytes_with_channels(4);
+        let runner = pollster::block_on(ModelRunner::new_with_channel_counts(
+            &mut std::io::Cursor::new(&model_bytes),
+            true,
+            &[3, 4],
+        ))
+        .unwrap();
+        let mut processor = pollster::block_on(ImageProcessor::new(
+            runner,
+            ImageColorModel::BGR,
+            ModelValueRange::asymmetric(1.0),
+            ModelValueRange::asymmetric(1.0),
+        ))
+        .unwrap();
+        assert_eq!(processor.channels(), 4);
+        let input = ImageBuffer::from_fn(50, 40, |x, y| {
+            Rgba([
+                (x * 1000) as u16,
+                (y * 1000) as u16,
+                500,
+                (x * y * 20) as u16,
+            ])
+        });
+
+        let output = pollster::block_on(processor.process_image_rgba(input.clone())).unwrap();
+
+        for (a, b) in input.pixels().zip(output.pixels()) {
+            for c in 0..4 {
+                assert!((a[c] as i32 - b[c] as i32).abs() <= 1);
+            }
+        }
+        let output =
+            pollster::block_on(processor.process_image_rgba_passes(input.clone(), 2)).unwrap();
+        for (a, b) in input.pixels().zip(output.pixels()) {
+            for c in 0..4 {
+                assert!((a[c] as i32 - b[c] as i32).abs() <= 2);
+            }
+        }
+        assert!(matches!(
+            pollster::block_on(processor.process_image(gradient_image(50, 40))),
+            Err(ImageProcessingError::ChannelCountMismatch { image: 3, model: 4 })
+        ));
+    }
+
+    #[test]
+    fn test_auxiliary_input_is_passed_through() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let runner = ModelRunner::from_stub(chunksize, 1, |input, _| {
+            assert_eq!(input.shape()[0], 4);
+            // The image is in BGR order and the [0,255] range, the mask is unchanged
+            assert!(input.slice(s![0, .., ..]).iter().all(|&v| v == 0.0));
+            assert!(input.slice(s![2, .., ..]).iter().all(|&v| v == 255.0));
+            assert!(input
+                .slice(s![3, .., ..])
+                .iter()
+                .all(|&v| (v - 0.3).abs() < 1e-6));
+            Ok(input.slice(s![..3, .., ..]).to_owned())
+        })
+        .with_auxiliary_input("mask", 1);
+        let mut processor = pollster::block_on(ImageProcessor::new(
+            runner,
+            ImageColorModel::BGR,
+            ModelValueRange::asymmetric(255.0),
+            ModelValueRange::asymmetric(255.0),
+        ))
+        .unwrap()
+        .with_process_mode(ProcessMode::Simple);
+        let input = ImageBuffer::from_pixel(64, 32, Rgb([u16::MAX, 0, 0]));
+
+        assert!(matches!(
+            pollster::block_on(processor.process_image(input.clone())),
+            Err(ImageProcessingError::MissingAuxiliaryInput(name)) if name == "mask"
+        ));
+        assert!(matches!(
+            processor.set_auxiliary_input("sigma", Array3::zeros((32, 64, 1))),
+            Err(ImageProcessingError::UnknownAuxiliaryInput(_))
+        ));
+        processor
+            .set_auxiliary_input("mask", Array3::from_elem((16, 64, 1), 0.3))
+            .unwrap();
+        assert!(matches!(
+            pollster::block_on(processor.process_image(input.clone())),
+            Err(ImageProcessingError::AuxiliaryInputMismatch { .. })
+        ));
+
+        processor
+            .set_auxiliary_input("mask", Array3::from_elem((32, 64, 1), 0.3))
+            .unwrap();
+        let output = pollster::block_on(processor.process_image(input.clone())).unwrap();
+        assert_images_close(&input, &output);
+
+        // The map is scaled to the inference resolution with the image
+        processor.set_infer_scale(0.5).unwrap();
+        let output = pollster::block_on(processor.process_image(input.clone())).unwrap();
+        assert_images_close(&input, &output);
+    }
+
+    #[test]
+    fn test_auxiliary_constant() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        // A non-blind denoiser stub that darkens the image by the given noise level
+        let runner = ModelRunner::from_stub(chunksize, 1, |input, _| {
+            let sigma = input.slice(s![3..4, .., ..]);
+            Ok(&input.slice(s![..3, .., ..]) - &sigma)
+        })
+        .with_auxiliary_input("sigma", 1);
+        let mut processor = test_processor(runner);
+        let input = ImageBuffer::from_pixel(48, 40, Rgb([u16::MAX / 2; 3]));
+
+        assert!(matches!(
+            processor.set_auxiliary_constant("noise", 0.1),
+            Err(ImageProcessingError::UnknownAuxiliaryInput(_))
+        ));
+        processor.set_auxiliary_constant("sigma", 0.0).unwrap();
+        let unchanged = pollster::block_on(processor.process_image(input.clone())).unwrap();
+        assert_images_close(&input, &unchanged);
+
+        processor.set_auxiliary_constant("sigma", 0.25).unwrap();
+        let denoised = pollster::block_on(processor.process_image(input.clone())).unwrap();
+        let expected = ImageBuffer::from_pixel(48, 40, Rgb([u16::MAX / 4; 3]));
+        assert_images_close(&expected, &denoised);
+    }
+
+    #[test]
+    fn test_non_finite_policy() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let processor = |policy| {
+            let runner = ModelRunner::from_stub(chunksize, 1, |input, _| {
+                let mut output = input.to_owned();
+                output[(0, 1, 1)] = f32::NAN;
+                output[(1, 2, 2)] = f32::INFINITY;
+                output[(2, 3, 3)] = f32::NEG_INFINITY;
+                Ok(output)
+            });
+            pollster::block_on(ImageProcessor::new(
+                runner,
+                ImageColorModel::RGB,
+                ModelValueRange::asymmetric(255.0),
+                ModelValueRange::asymmetric(255.0),
+            ))
+            .unwrap()
+            .with_process_mode(ProcessMode::Simple)
+            .with_non_finite_policy(policy)
+        };
+        let input = Array3::from_elem((32, 32, 3), 0.5);
+
+        assert!(matches!(
+            pollster::block_on(processor(NonFinitePolicy::Error).process_tensor(input.clone())),
+            Err(ImageProcessingError::NonFiniteOutput(3))
+        ));
+
+        let zero =
+            pollster::block_on(processor(NonFinitePolicy::Zero).process_tensor(input.clone()))
+                .unwrap();
+        assert_eq!(
+            [zero[(1, 1, 0)], zero[(2, 2, 1)], zero[(3, 3, 2)]],
+            [0.0, 0.0, 0.0]
+        );
+        assert_eq!(zero[(0, 0, 0)], 0.5);
+
+        let clamp =
+            pollster::block_on(processor(NonFinitePolicy::Clamp).process_tensor(input.clone()))
+                .unwrap();
+        assert_eq!(
+            [clamp[(1, 1, 0)], clamp[(2, 2, 1)], clamp[(3, 3, 2)]],
+            [0.0, 1.0, 0.0]
+        );
+        assert!(clamp.iter().all(|v| v.is_finite()));
+    }
+
+    #[test]
+    fn test_chunk_error_policy() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let failing_processor = |policy| {
+            let mut calls = 0;
+            let runner = ModelRunner::from_stub(chunksize, 1, move |input, _| {
+                calls += 1;
+                if calls == 2 {
+                    Err(ModelRunnerError::InferenceFailed(
+                        "out of memory".to_owned(),
+                    ))
+                } else {
+                    Ok(input.mapv(|v| v * 0.5))
+                }
+            });
+            test_processor(runner)
+                .with_process_mode(ProcessMode::Simple)
+                .with_chunk_error_policy(policy)
+        };
+        let input = gradient_image(64, 32);
+
+        let mut strict = failing_processor(ChunkErrorPolicy::Strict);
+        assert!(matches!(
+            pollster::block_on(strict.process_image(input.clone())),
+            Err(ImageProcessingError::ModelRunnerError(
+                ModelRunnerError::InferenceFailed(_)
+            ))
+        ));
+
+        let mut keep_going = failing_processor(ChunkErrorPolicy::KeepGoing);
+        let output = pollster::block_on(keep_going.process_image(input.clone())).unwrap();
+        // The first chunk is processed, the second one is passed through
+        assert_images_close(
+            &image::imageops::crop_imm(&output, 32, 0, 32, 32).to_image(),
+            &image::imageops::crop_imm(&input, 32, 0, 32, 32).to_image(),
+        );
+        let (processed, original) = (output.get_pixel(10, 10)[0], input.get_pixel(10, 10)[0]);
+        assert!((processed as i32 - original as i32 / 2).abs() <= 1);
+    }
+
+    #[test]
+    fn test_validate_rejects_unsupported_chunksize() {
+        // A model that only accepts sizes that are a multiple of 16, like many U-Nets
+        let processor = |size: usize| {
+            let chunksize = ChunkSize {
+                width: size,
+                height: size,
+            };
+            let runner = ModelRunner::from_stub(chunksize, 1, |input, _| {
+                if input.shape()[1] % 16 == 0 && input.shape()[2] % 16 == 0 {
+                    Ok(input.to_owned())
+                } else {
+                    Err(ModelRunnerError::InferenceFailed(
+                        "incompatible shape".to_owned(),
+                    ))
+                }
+            });
+            test_processor(runner)
+        };
+
+        assert!(pollster::block_on(processor(32).validate()).is_ok());
+        assert!(matches!(
+            pollster::block_on(processor(40).validate()),
+            Err(ImageProcessingError::ChunkValidationFailed {
+                chunksize: ChunkSize {
+                    width: 40,
+                    height: 40
+                },
+                ..
+            })
+        ));
     }
 }
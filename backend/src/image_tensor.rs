@@ -0,0 +1,208 @@
+use image::{ImageBuffer, Pixel, Rgb, Rgba};
+use ndarray::{Array3, ShapeError};
+use thiserror::Error;
+
+/// A 4x4 Bayer matrix for ordered dithering
+pub(crate) const BAYER_4X4: [[u8; 4]; 4] =
+    [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+#[derive(Debug, Error)]
+pub enum TensorConversionError {
+    #[error("The image data does not match the image dimensions")]
+    InvalidImageData(#[from] ShapeError),
+    #[error("The tensor shape {0:?} is not a HxWxC image shape with the expected channel count")]
+    InvalidTensorShape(Vec<usize>),
+}
+
+/// Convert 16 bit RGB image data to a HxWxC tensor with values in the [0,1] range
+pub fn image_to_tensor(
+    image: ImageBuffer<Rgb<u16>, Vec<u16>>,
+) -> Result<Array3<f32>, TensorConversionError> {
+    Ok(image_to_tensor_raw(image)?.mapv(|v| v as f32 / u16::MAX as f32))
+}
+
+/// Convert 16 bit RGBA image data to a HxWxC tensor with values in the [0,1] range
+pub fn image_rgba_to_tensor(
+    image: ImageBuffer<Rgba<u16>, Vec<u16>>,
+) -> Result<Array3<f32>, TensorConversionError> {
+    Ok(image_to_tensor_raw(image)?.mapv(|v| v as f32 / u16::MAX as f32))
+}
+
+/// Convert a HxWx4 tensor with values in the [0,1] range to 16 bit RGBA image data
+///
+/// Values outside of the [0,1] range are clamped.
+pub fn tensor_to_image_rgba(
+    tensor: Array3<f32>,
+) -> Result<ImageBuffer<Rgba<u16>, Vec<u16>>, TensorConversionError> {
+    check_tensor_shape(&tensor, 4)?;
+    tensor_to_image_raw(tensor.mapv(|v| (v * u16::MAX as f32) as u16))
+}
+
+/// Convert a HxWxC tensor with values in the [0,1] range to 16 bit RGB image data
+///
+/// Values outside of the [0,1] range are clamped.
+pub fn tensor_to_image(
+    tensor: Array3<f32>,
+) -> Result<ImageBuffer<Rgb<u16>, Vec<u16>>, TensorConversionError> {
+    check_tensor_shape(&tensor, 3)?;
+    tensor_to_image_raw(tensor.mapv(|v| (v * u16::MAX as f32) as u16))
+}
+
+/// Write a HxWxC tensor with values in the [0,1] range into existing 16 bit RGB image data
+///
+/// Values outside of the [0,1] range are clamped like in `tensor_to_image`.
+pub fn tensor_into_image(
+    tensor: &Array3<f32>,
+    image: &mut ImageBuffer<Rgb<u16>, Vec<u16>>,
+) -> Result<(), TensorConversionError> {
+    let (width, height) = check_tensor_shape(tensor, 3)?;
+    if image.dimensions() != (width, height) {
+        return Err(TensorConversionError::InvalidTensorShape(
+            tensor.shape().to_vec(),
+        ));
+    }
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        for c in 0..3 {
+            pixel[c] = (tensor[(y as usize, x as usize, c)] * u16::MAX as f32) as u16;
+        }
+    }
+    Ok(())
+}
+
+/// Convert a HxWxC tensor with values in the [0,1] range to 8 bit RGB image data
+///
+/// Values are rounded with an ordered dither, which avoids banding in smooth gradients. Values
+/// outside of the [0,1] range are clamped.
+pub fn tensor_to_image_u8_dithered(
+    tensor: &Array3<f32>,
+) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>, TensorConversionError> {
+    let (width, height) = check_tensor_shape(tensor, 3)?;
+    Ok(ImageBuffer::from_fn(width, height, |x, y| {
+        // Shift the rounding threshold by less than half a step, the shifts average to zero
+        let threshold = BAYER_4X4[y as usize % 4][x as usize % 4] as f32;
+        let offset = (threshold + 0.5) / 16.0 - 0.5;
+        let mut pixel = Rgb([0; 3]);
+        for c in 0..3 {
+            let value = tensor[(y as usize, x as usize, c)] * u8::MAX as f32 + offset;
+            pixel[c] = value.round().clamp(0.0, u8::MAX as f32) as u8;
+        }
+        pixel
+    }))
+}
+
+/// Convert floating point RGB image data to a HxWxC tensor without changing the values
+pub fn image_f32_to_tensor(
+    image: ImageBuffer<Rgb<f32>, Vec<f32>>,
+) -> Result<Array3<f32>, TensorConversionError> {
+    image_to_tensor_raw(image)
+}
+
+/// Convert a HxWxC tensor to floating point RGB image data without changing the values
+pub fn tensor_to_image_f32(
+    tensor: Array3<f32>,
+) -> Result<ImageBuffer<Rgb<f32>, Vec<f32>>, TensorConversionError> {
+    tensor_to_image_raw(tensor)
+}
+
+fn image_to_tensor_raw<P>(
+    image: ImageBuffer<P, Vec<P::Subpixel>>,
+) -> Result<Array3<P::Subpixel>, TensorConversionError>
+where
+    P: Pixel,
+{
+    let width = image.width() as usize;
+    let height = image.height() as usize;
+
+    Ok(Array3::from_shape_vec(
+        (height, width, P::CHANNEL_COUNT as usize),
+        image.into_raw(),
+    )?)
+}
+
+fn check_tensor_shape<T>(
+    tensor: &Array3<T>,
+    channels: usize,
+) -> Result<(u32, u32), TensorConversionError> {
+    let shape = tensor.shape();
+    match (u32::try_from(shape[1]), u32::try_from(shape[0])) {
+        (Ok(width), Ok(height)) if shape[2] == channels => Ok((width, height)),
+        _ => Err(TensorConversionError::InvalidTensorShape(shape.to_vec())),
+    }
+}
+
+fn tensor_to_image_raw<P>(
+    tensor: Array3<P::Subpixel>,
+) -> Result<ImageBuffer<P, Vec<P::Subpixel>>, TensorConversionError>
+where
+    P: Pixel,
+{
+    let (width, height) = check_tensor_shape(&tensor, P::CHANNEL_COUNT as usize)?;
+    // The raw data is only in pixel order for tensors in standard layout
+    let tensor = if tensor.is_standard_layout() {
+        tensor
+    } else {
+        tensor.as_standard_layout().into_owned()
+    };
+    ImageBuffer::from_raw(width, height, tensor.into_raw_vec()).ok_or_else(|| {
+        TensorConversionError::InvalidTensorShape(vec![
+            height as usize,
+            width as usize,
+            P::CHANNEL_COUNT as usize,
+        ])
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let image = ImageBuffer::from_fn(37, 23, |x, y| {
+            Rgb([(x * 1000) as u16, (y * 2000) as u16, (x * y * 50) as u16])
+        });
+
+        let tensor = image_to_tensor(image.clone()).unwrap();
+        assert_eq!(tensor.shape(), &[23, 37, 3]);
+        assert_eq!(tensor_to_image(tensor).unwrap(), image);
+    }
+
+    #[test]
+    fn test_round_trip_permuted_tensor() {
+        let image = ImageBuffer::from_fn(5, 3, |x, y| Rgb([x as f32, y as f32, 0.5]));
+
+        let tensor = image_f32_to_tensor(image.clone()).unwrap();
+        let mut permuted = Array3::<f32>::zeros((5, 3, 3)).permuted_axes([1, 0, 2]);
+        permuted.assign(&tensor);
+        assert!(!permuted.is_standard_layout());
+
+        assert_eq!(tensor_to_image_f32(permuted).unwrap(), image);
+    }
+
+    #[test]
+    fn test_dithered_u8_keeps_mean() {
+        let tensor = Array3::from_elem((8, 8, 3), 127.75 / 255.0);
+
+        let image = tensor_to_image_u8_dithered(&tensor).unwrap();
+
+        assert!(image.pixels().all(|p| p[0] == 127 || p[0] == 128));
+        let mean = image.pixels().map(|p| p[0] as f32).sum::<f32>() / 64.0;
+        assert!((mean - 127.75).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_shape_mismatch() {
+        assert!(matches!(
+            tensor_to_image(Array3::zeros((4, 4, 2))),
+            Err(TensorConversionError::InvalidTensorShape(shape)) if shape == vec![4, 4, 2]
+        ));
+
+        let mut raw = vec![0u16; 4 * 4 * 3];
+        raw.extend([1, 2, 3]);
+        let image = ImageBuffer::from_raw(4, 4, raw).unwrap();
+        assert!(matches!(
+            image_to_tensor(image),
+            Err(TensorConversionError::InvalidImageData(_))
+        ));
+    }
+}
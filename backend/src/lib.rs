@@ -1,7 +1,16 @@
 pub mod image_chunk_iterator;
 pub mod image_processor;
+pub mod image_tensor;
 pub mod model_runner;
 pub mod model_value_range;
+pub mod post_process;
+pub mod tensor_element;
+pub mod test_image;
 
 mod chunksize;
 pub use chunksize::ChunkSize;
+
+/// The version of NeuraTable
+pub fn version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
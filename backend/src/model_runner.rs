@@ -1,16 +1,89 @@
 use std::collections::HashMap;
+use std::io::{Cursor, Read, Seek};
+use std::sync::Arc;
 
 use protobuf::Message;
+use rayon::prelude::*;
 use thiserror::Error;
 use tract_onnx::prelude::*;
 use wonnx::{
-    onnx::GraphProto,
-    utils::{DataTypeError, OutputTensor, Shape},
+    onnx::{GraphProto, NodeProto, TensorShapeProto_Dimension, ValueInfoProto},
+    utils::{DataTypeError, InputTensor, OutputTensor, Shape},
     Session,
 };
 
 use crate::ChunkSize;
 
+/// The channel counts that `ModelRunner::new` accepts for the model input
+pub const DEFAULT_CHANNEL_COUNTS: &[usize] = &[3];
+
+/// The environment variable that selects the backend, see `BackendPreference`
+pub const BACKEND_ENV_VAR: &str = "NEURATABLE_BACKEND";
+
+/// The first bytes of a gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// The first bytes of a zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Selects the backend that runs a model
+///
+/// The backend is chosen by the first of these that is set:
+/// 1. An explicit choice, e.g. a command line flag like `--force-cpu`
+/// 2. The `NEURATABLE_BACKEND` environment variable (`auto`, `gpu` or `cpu`)
+/// 3. `Auto`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendPreference {
+    /// Use wonnx on the GPU and fall back to tract on the CPU if it is not available
+    Auto,
+    /// Use wonnx on the GPU and fail if it is not available
+    Gpu,
+    /// Use tract on the CPU
+    Cpu,
+}
+
+impl BackendPreference {
+    /// Resolve the preference from an explicit choice, the environment and the default
+    pub fn resolve(explicit: Option<Self>) -> Result<Self, ModelRunnerError> {
+        Self::resolve_with_env(explicit, std::env::var(BACKEND_ENV_VAR).ok().as_deref())
+    }
+
+    fn resolve_with_env(
+        explicit: Option<Self>,
+        env: Option<&str>,
+    ) -> Result<Self, ModelRunnerError> {
+        match (explicit, env) {
+            (Some(preference), _) => Ok(preference),
+            (None, Some(env)) => env.parse(),
+            (None, None) => Ok(Self::Auto),
+        }
+    }
+}
+
+impl std::str::FromStr for BackendPreference {
+    type Err = ModelRunnerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "gpu" => Ok(Self::Gpu),
+            "cpu" => Ok(Self::Cpu),
+            _ => Err(ModelRunnerError::InvalidBackend(s.to_owned())),
+        }
+    }
+}
+
+/// Selects the model output that is used as the processed image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputSelector {
+    /// The first output with the shape of the input, or else the first one with an integer
+    /// multiple of its width and height
+    Auto,
+    /// The output at this position among the model outputs
+    Index(usize),
+    /// The output with this name
+    Name(String),
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ModelChannelOrder {
     /// Batch, Channel, Height, Width order, this is the natural order for NeuraTable
@@ -33,10 +106,28 @@ impl ModelChannelOrder {
         }
     }
 
-    fn scratchpad_buffer_layout(&self, chunksize: ChunkSize) -> (usize, usize, usize) {
+    /// Zeroed input buffers for inputs with the given channel counts
+    fn scratchpad_buffers(
+        &self,
+        chunksize: ChunkSize,
+        input_channels: &[usize],
+    ) -> Vec<ndarray::Array3<f32>> {
+        input_channels
+            .iter()
+            .map(|&channels| {
+                ndarray::Array3::zeros(self.scratchpad_buffer_layout(chunksize, channels))
+            })
+            .collect()
+    }
+
+    fn scratchpad_buffer_layout(
+        &self,
+        chunksize: ChunkSize,
+        channels: usize,
+    ) -> (usize, usize, usize) {
         match self {
-            ModelChannelOrder::NCHW => (3, chunksize.height, chunksize.width),
-            ModelChannelOrder::NHWC => (chunksize.height, chunksize.width, 3),
+            ModelChannelOrder::NCHW => (channels, chunksize.height, chunksize.width),
+            ModelChannelOrder::NHWC => (chunksize.height, chunksize.width, channels),
         }
     }
 
@@ -104,42 +195,307 @@ impl ModelChannelOrder {
     }
 }
 
+/// The errors of loading and running a model
+///
+/// Loading a model fails with `ReadError`, `ParseError`, `ModelInputError`,
+/// `InvalidInputShape`, `DynamicInputShape`, `UnsupportedChannelCount`, `AuxiliaryInputMismatch`,
+/// `ModelParameterError`, `NoSuitableOutput`, `UnsuitableOutput`, `UnknownOutput`,
+/// `OutputIndexOutOfRange` or `TractCompilationFailed`.
 #[derive(Debug, Error)]
 pub enum ModelRunnerError {
-    #[error("The model has too many inputs")]
-    ModelInputError,
-    #[error("The models input is unsupported. A [1,3,h,w] or [1,h,w,3] shaped input is required (NCHW or NHWC).")]
+    #[error("The model has {0} inputs, but an image input is required")]
+    ModelInputError(usize),
+    #[error("The models input {0:?} is unsupported. A [1,c,h,w] or [1,h,w,c] shaped input is required (NCHW or NHWC).")]
     InvalidInputShape(Shape),
-    #[error("Could not read model parameters")]
+    #[error("The model input {0} has a dynamic shape, a chunksize is needed to load it")]
+    DynamicInputShape(String),
+    #[error(
+        "The model has no input with {channel_counts:?} channels, its input shape is {shape:?}"
+    )]
+    UnsupportedChannelCount {
+        shape: Shape,
+        channel_counts: Vec<usize>,
+    },
+    #[error("The auxiliary input {name} has the shape {shape:?}, which does not match the width and height of the image input")]
+    AuxiliaryInputMismatch { name: String, shape: Shape },
+    #[error("Could not read model parameters: {0}")]
     ModelParameterError(#[from] DataTypeError),
-    #[error("The model has no suitable output")]
+    #[error("The model has no output with the shape of the input or an integer multiple of it")]
     NoSuitableOutput,
-    #[error("The model is not parseable")]
+    #[error("The output {0} does not have the shape of the input or an integer multiple of it")]
+    UnsuitableOutput(String),
+    #[error("The model has no output named {name:?}, its outputs are {outputs:?}")]
+    UnknownOutput { name: String, outputs: Vec<String> },
+    #[error("The model has {count} outputs, there is no output with the index {index}")]
+    OutputIndexOutOfRange { index: usize, count: usize },
+    #[error("The model is not parseable: {0}")]
     ParseError(#[from] protobuf::ProtobufError),
+    #[error("tract could not compile the model: {0}")]
+    TractCompilationFailed(String),
+    #[error("Unknown backend {0:?}, expected one of auto, gpu or cpu")]
+    InvalidBackend(String),
+    #[error("The GPU backend is not available: {0}")]
+    GpuUnavailable(String),
+    #[error("Inference failed: {0}")]
+    InferenceFailed(String),
+    #[error("The model input has the fixed size {0:?} that can not be changed")]
+    FixedInputShape(ChunkSize),
+    #[error("The model could not be read")]
+    ReadError(#[from] std::io::Error),
+    #[error("The model expects NHWC input, but strict shapes only allow NCHW models")]
+    PermutationNotAllowed,
+}
+
+/// The result of a single check of `ModelRunner::check_compatibility`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Pass,
+    /// The model works, but with limitations
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompatibilityCheck {
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl CompatibilityCheck {
+    fn new(status: CheckStatus, detail: impl Into<String>) -> Self {
+        Self {
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Describes if and how well a model is supported, see `ModelRunner::check_compatibility`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompatibilityReport {
+    pub input: CompatibilityCheck,
+    pub output: CompatibilityCheck,
+    /// If the GPU backend can run the model
+    pub wonnx: CompatibilityCheck,
+    /// If the CPU backend can run the model, this is a lot slower than the GPU backend
+    pub tract: CompatibilityCheck,
+}
+
+impl CompatibilityReport {
+    /// Check if the model can be processed by at least one backend
+    pub fn is_compatible(&self) -> bool {
+        self.input.status != CheckStatus::Fail
+            && self.output.status != CheckStatus::Fail
+            && (self.wonnx.status == CheckStatus::Pass || self.tract.status == CheckStatus::Pass)
+    }
+}
+
+impl std::fmt::Display for CompatibilityReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, check) in [
+            ("input", &self.input),
+            ("output", &self.output),
+            ("wonnx", &self.wonnx),
+            ("tract", &self.tract),
+        ] {
+            writeln!(f, "{:<7} {:?}: {}", name, check.status, check.detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// A model input besides the image, e.g. a mask or a noise level map
+///
+/// Auxiliary inputs have the width and height of the image input. Their data is passed to the
+/// model as it is, the color model and value range of the image do not apply to them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuxiliaryInput {
+    pub name: String,
+    pub channels: usize,
+}
+
+/// The inputs of a model graph, see `ModelRunner::get_graph_input`
+struct GraphInputs {
+    /// The shape of the image input
+    shape: Shape,
+    name: String,
+    channel_order: ModelChannelOrder,
+    /// The position of the image input among all model inputs
+    image_index: usize,
+    auxiliary: Vec<AuxiliaryInput>,
+}
+
+/// The output of a model graph that is used as the processed image
+struct GraphOutput {
+    /// The position of the output among all model outputs
+    index: usize,
+    name: String,
+    scale: usize,
 }
 
 pub struct WonnxRunner {
-    session: Session,
-    input_name: String,
+    /// One session per chunk that can be in flight, see `ModelRunner::set_pipeline_depth`
+    sessions: Vec<Session>,
+    /// The ONNX model, used to create additional sessions
+    model_bytes: Arc<[u8]>,
+    /// The names of the image input and the auxiliary inputs
+    input_names: Vec<String>,
     output_name: String,
-    input_scratchpad: ndarray::Array3<f32>,
+    input_scratchpads: Vec<ndarray::Array3<f32>>,
 }
 
+type TractModel = dyn Fn(&[ndarray::Array3<f32>], &[usize]) -> Result<ndarray::Array3<f32>, ModelRunnerError>
+    + Send
+    + Sync;
+
 pub struct TractRunner {
-    model: Box<dyn Fn(&ndarray::Array3<f32>, &[usize]) -> ndarray::Array3<f32>>,
-    input_scratchpad: ndarray::Array3<f32>,
+    model: Box<TractModel>,
+    input_scratchpads: Vec<ndarray::Array3<f32>>,
+}
+
+/// A runner backed by an arbitrary function, used as a stand-in for real models in tests
+#[cfg(test)]
+pub(crate) struct StubRunner {
+    model: Box<
+        dyn FnMut(
+            ndarray::ArrayView3<f32>,
+            &[usize],
+        ) -> Result<ndarray::Array3<f32>, ModelRunnerError>,
+    >,
 }
 
 pub enum ModelRunnerBackend {
     WonnxRunner(WonnxRunner),
     TractRunner(TractRunner),
+    #[cfg(test)]
+    StubRunner(StubRunner),
+}
+
+/// The state of the tract backend that re-runs chunks for which the primary backend failed
+enum TractFallback {
+    /// No fallback is possible, e.g. because tract already is the primary backend
+    Unavailable,
+    /// The ONNX model, the tract backend is only built when it is first needed
+    Pending(Arc<[u8]>),
+    Ready(TractRunner),
+}
+
+/// The backend that runs a model and how often it needed the tract fallback
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BackendInfo {
+    /// The name of the backend, see `ModelRunner::backend_name`
+    pub name: String,
+    /// The kind of device the backend runs on, "GPU" or "CPU"
+    ///
+    /// wonnx picks the GPU adapter itself and does not report which one it chose.
+    pub device: String,
+    /// The number of chunks that were re-run with the tract fallback
+    pub fallback_chunks: usize,
+}
+
+/// How the tract backend runs the chunks that are processed together
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ProcessingConcurrency {
+    /// Run one chunk after another
+    Sequential,
+    /// Run as many chunks at the same time as there are CPU cores
+    Parallel,
+}
+
+/// The filter that scales the output of a super resolution model down to the input size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DownscaleFilter {
+    /// The average of each block of scale x scale pixels, fast but prone to aliasing
+    BoxAverage,
+    /// A linear ramp over two input pixels per output pixel
+    Triangle,
+    /// A windowed sinc with three lobes, the sharpest of the filters
+    Lanczos3,
+}
+
+impl DownscaleFilter {
+    /// The distance from the center beyond which the kernel is zero, in output pixels
+    fn support(&self) -> f32 {
+        match self {
+            DownscaleFilter::BoxAverage => 0.5,
+            DownscaleFilter::Triangle => 1.0,
+            DownscaleFilter::Lanczos3 => 3.0,
+        }
+    }
+
+    /// The unnormalized weight at a distance of `x` output pixels from the center
+    fn kernel(&self, x: f32) -> f32 {
+        let x = x.abs();
+        match self {
+            DownscaleFilter::BoxAverage => (x < 0.5) as u8 as f32,
+            DownscaleFilter::Triangle => (1.0 - x).max(0.0),
+            DownscaleFilter::Lanczos3 if x < 3.0 => sinc(x) * sinc(x / 3.0),
+            DownscaleFilter::Lanczos3 => 0.0,
+        }
+    }
+
+    /// The first input index and the normalized weights of each of `output_len` output pixels
+    ///
+    /// Kernel footprints that extend beyond the `input_len` input pixels are clamped to them and
+    /// the remaining weights are normalized to sum up to one.
+    fn weights(&self, input_len: usize, output_len: usize, scale: usize) -> Vec<(usize, Vec<f32>)> {
+        let reach = self.support() * scale as f32;
+        (0..output_len)
+            .map(|o| {
+                let center = (o as f32 + 0.5) * scale as f32 - 0.5;
+                let start = (center - reach).ceil().max(0.0) as usize;
+                let end = ((center + reach).floor() as usize).min(input_len - 1);
+                let weights: Vec<f32> = (start..=end)
+                    .map(|i| self.kernel((i as f32 - center) / scale as f32))
+                    .collect();
+                let sum: f32 = weights.iter().sum();
+                (start, weights.iter().map(|w| w / sum).collect())
+            })
+            .collect()
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        let x = std::f32::consts::PI * x;
+        x.sin() / x
+    }
 }
 
 pub struct ModelRunner {
     backend: ModelRunnerBackend,
     chunksize: ChunkSize,
     model_channel_order: ModelChannelOrder,
+    channels: usize,
+    auxiliary_inputs: Vec<AuxiliaryInput>,
+    image_input_index: usize,
+    output_index: usize,
     model_scale: usize,
+    keep_scale: bool,
+    downscale_filter: DownscaleFilter,
+    recommended_padding: Option<usize>,
+    dynamic_input_shape: bool,
+    /// The model with its dynamic dimensions, the backends are rebuilt from it by `set_chunksize`
+    dynamic_model: Option<Arc<[u8]>>,
+    tract_fallback: bool,
+    fallback: TractFallback,
+    fallback_chunks: usize,
+    pipeline_depth: usize,
+    concurrency: ProcessingConcurrency,
+}
+
+/// A chunk split into the model inputs, see `ModelRunner::prepare_chunk`
+struct PreparedChunk<'a> {
+    /// The whole chunk in the channel order of the model
+    model_order_input: ndarray::ArrayView3<'a, f32>,
+    /// The image input followed by the auxiliary inputs
+    inputs: Vec<ndarray::ArrayView3<'a, f32>>,
+    output_shape: Vec<usize>,
 }
 
 impl ModelRunner {
@@ -147,32 +503,462 @@ impl ModelRunner {
         self.chunksize
     }
 
+    /// Re-run chunks with tract if the primary backend returns NaN or infinite values
+    ///
+    /// The tract backend is only built when it is first needed. This has no effect if tract
+    /// already is the primary backend.
+    pub fn set_tract_fallback(&mut self, tract_fallback: bool) {
+        self.tract_fallback = tract_fallback;
+    }
+
+    pub fn with_tract_fallback(mut self, tract_fallback: bool) -> Self {
+        self.set_tract_fallback(tract_fallback);
+        self
+    }
+
+    /// Get the fallback backend, building it if necessary
+    fn fallback_runner(&mut self) -> Option<&mut TractRunner> {
+        if let TractFallback::Pending(model_bytes) = &self.fallback {
+            log::info!("Building the tract fallback backend");
+            let runner = TractRunner::new(
+                model_bytes,
+                self.model_channel_order,
+                self.chunksize,
+                &self.input_channels(),
+                self.image_input_index,
+                self.output_index,
+            );
+            // Do not retry building the fallback for every chunk
+            self.fallback = match runner {
+                Ok(runner) => TractFallback::Ready(runner),
+                Err(err) => {
+                    log::error!("The tract fallback is not available: {}", err);
+                    TractFallback::Unavailable
+                }
+            };
+        }
+        match &mut self.fallback {
+            TractFallback::Ready(runner) => Some(runner),
+            _ => None,
+        }
+    }
+
+    /// The name of the backend that runs the model
+    pub fn backend_name(&self) -> &'static str {
+        match self.backend {
+            ModelRunnerBackend::WonnxRunner(_) => "wonnx",
+            ModelRunnerBackend::TractRunner(_) => "tract",
+            #[cfg(test)]
+            ModelRunnerBackend::StubRunner(_) => "stub",
+        }
+    }
+
+    /// The backend that runs the model, the fallback chunks are counted since the runner was
+    /// created
+    pub fn backend_info(&self) -> BackendInfo {
+        let device = match self.backend {
+            ModelRunnerBackend::WonnxRunner(_) => "GPU",
+            _ => "CPU",
+        };
+        BackendInfo {
+            name: self.backend_name().to_owned(),
+            device: device.to_owned(),
+            fallback_chunks: self.fallback_chunks,
+        }
+    }
+
+    /// Let up to `depth` chunks be in flight on the GPU before their results are awaited
+    ///
+    /// Submitting the next chunks while the GPU still computes the previous one keeps the GPU
+    /// busy, but every chunk in flight needs its own wonnx session and GPU memory. The default
+    /// of 1 runs one chunk after another. Only the wonnx backend runs chunks concurrently, the
+    /// other backends ignore the depth.
+    pub async fn set_pipeline_depth(&mut self, depth: usize) -> Result<(), ModelRunnerError> {
+        let depth = depth.max(1);
+        if let ModelRunnerBackend::WonnxRunner(runner) = &mut self.backend {
+            runner.create_sessions(depth).await?;
+        }
+        self.pipeline_depth = depth;
+        Ok(())
+    }
+
+    /// Create the wonnx sessions again after `set_chunksize` dropped them
+    ///
+    /// A failure is reported as a failed inference, so that the chunksize can be reduced further.
+    async fn restore_sessions(&mut self) -> Result<(), ModelRunnerError> {
+        match &mut self.backend {
+            ModelRunnerBackend::WonnxRunner(runner) if runner.sessions.is_empty() => runner
+                .create_sessions(self.pipeline_depth)
+                .await
+                .map_err(|err| ModelRunnerError::InferenceFailed(err.to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    /// The number of chunks that may be in flight at the same time, see `set_pipeline_depth`
+    pub fn pipeline_depth(&self) -> usize {
+        self.pipeline_depth
+    }
+
+    /// Run the chunks of `process_chunks` on all CPU cores with the tract backend
+    ///
+    /// Each chunk gets its own copy of the inputs, the compiled model is shared. The results do
+    /// not depend on the concurrency. The other backends ignore it, see `set_pipeline_depth` for
+    /// wonnx.
+    pub fn set_concurrency(&mut self, concurrency: ProcessingConcurrency) {
+        self.concurrency = concurrency;
+    }
+
+    pub fn with_concurrency(mut self, concurrency: ProcessingConcurrency) -> Self {
+        self.set_concurrency(concurrency);
+        self
+    }
+
+    /// The number of chunks that `process_chunks` runs at the same time
+    pub fn concurrent_chunks(&self) -> usize {
+        match (&self.backend, self.concurrency) {
+            (ModelRunnerBackend::TractRunner(_), ProcessingConcurrency::Parallel) => {
+                rayon::current_num_threads()
+            }
+            _ => self.pipeline_depth,
+        }
+    }
+
+    /// The number of channels of the model input and output
+    pub fn get_channels(&self) -> usize {
+        self.channels
+    }
+
+    /// The inputs of the model besides the image, in the order in which `process_chunk` expects
+    /// their channels
+    pub fn auxiliary_inputs(&self) -> &[AuxiliaryInput] {
+        &self.auxiliary_inputs
+    }
+
+    /// The channel counts of the image input and the auxiliary inputs
+    fn input_channels(&self) -> Vec<usize> {
+        std::iter::once(self.channels)
+            .chain(self.auxiliary_inputs.iter().map(|input| input.channels))
+            .collect()
+    }
+
+    /// The factor by which the model scales its input, e.g. 2 for a 2x super resolution model
+    pub fn get_model_scale(&self) -> usize {
+        self.model_scale
+    }
+
+    /// Keep the enlarged output of a super resolution model instead of scaling it back down
+    ///
+    /// By default the output of each chunk is scaled down to the size of the input chunk.
+    pub fn set_keep_scale(&mut self, keep_scale: bool) {
+        self.keep_scale = keep_scale;
+    }
+
+    pub fn with_keep_scale(mut self, keep_scale: bool) -> Self {
+        self.set_keep_scale(keep_scale);
+        self
+    }
+
+    /// Select the filter that scales the output of a super resolution model down to the input
+    /// size, the default is `DownscaleFilter::Lanczos3`
+    pub fn set_downscale_filter(&mut self, downscale_filter: DownscaleFilter) {
+        self.downscale_filter = downscale_filter;
+    }
+
+    pub fn with_downscale_filter(mut self, downscale_filter: DownscaleFilter) -> Self {
+        self.set_downscale_filter(downscale_filter);
+        self
+    }
+
+    pub fn get_downscale_filter(&self) -> DownscaleFilter {
+        self.downscale_filter
+    }
+
+    /// The factor by which the processed chunks are larger than the input chunks
+    ///
+    /// This is the model scale with `set_keep_scale`, and 1 otherwise.
+    pub fn get_scale(&self) -> usize {
+        if self.keep_scale {
+            self.model_scale
+        } else {
+            1
+        }
+    }
+
+    /// The chunk padding that covers the estimated receptive field of the model
+    ///
+    /// This is `None` if the receptive field could not be estimated from the model graph.
+    pub fn recommended_padding(&self) -> Option<usize> {
+        self.recommended_padding
+    }
+
+    /// Change the size of the chunks that are passed to the model
+    ///
+    /// This is only possible for models with a dynamic input shape. The input shape is compiled
+    /// into the backends, so tract compiles the model again and the wonnx sessions are created
+    /// again before the next chunk is processed.
+    pub fn set_chunksize(&mut self, chunksize: ChunkSize) -> Result<(), ModelRunnerError> {
+        if !self.dynamic_input_shape {
+            return Err(ModelRunnerError::FixedInputShape(self.chunksize));
+        }
+        if chunksize == self.chunksize {
+            return Ok(());
+        }
+
+        if let Some(dynamic_model) = &self.dynamic_model {
+            let model_bytes: Arc<[u8]> = Self::fixed_model_bytes(dynamic_model, chunksize)?.into();
+            let input_channels = self.input_channels();
+            match &mut self.backend {
+                ModelRunnerBackend::WonnxRunner(runner) => {
+                    runner.sessions.clear();
+                    runner.model_bytes = model_bytes.clone();
+                    runner.input_scratchpads = self
+                        .model_channel_order
+                        .scratchpad_buffers(chunksize, &input_channels);
+                }
+                ModelRunnerBackend::TractRunner(runner) => {
+                    *runner = TractRunner::new(
+                        &model_bytes,
+                        self.model_channel_order,
+                        chunksize,
+                        &input_channels,
+                        self.image_input_index,
+                        self.output_index,
+                    )?;
+                }
+                #[cfg(test)]
+                ModelRunnerBackend::StubRunner(_) => {}
+            }
+            if !matches!(self.fallback, TractFallback::Unavailable) {
+                self.fallback = TractFallback::Pending(model_bytes);
+            }
+        }
+        self.chunksize = chunksize;
+
+        Ok(())
+    }
+
+    /// Find the image input of the model, all other inputs are auxiliary inputs
+    ///
+    /// The image input is the first input with one of the channel counts. Auxiliary inputs must
+    /// have the same channel order and the same width and height.
     fn get_graph_input(
         graph: &GraphProto,
-    ) -> Result<(Shape, String, ModelChannelOrder), ModelRunnerError> {
+        channel_counts: &[usize],
+    ) -> Result<GraphInputs, ModelRunnerError> {
         let inputs = graph.get_input();
 
-        if inputs.len() > 1 {
-            return Err(ModelRunnerError::ModelInputError);
+        if inputs.is_empty() {
+            return Err(ModelRunnerError::ModelInputError(0));
         }
-        let input_shape = inputs[0].get_shape()?;
-        let input_name = inputs[0].get_name().to_owned();
-
-        if input_shape.dim(0) != 1 {
-            return Err(ModelRunnerError::InvalidInputShape(input_shape));
+        let mut shapes = Vec::with_capacity(inputs.len());
+        for input in inputs {
+            let shape = input.get_shape()?;
+            if shape.rank() != 4 || shape.dim(0) != 1 {
+                return Err(ModelRunnerError::InvalidInputShape(shape));
+            }
+            shapes.push(shape);
         }
 
-        let channel_order = if input_shape.dim(1) == 3 {
-            log::debug!("NCHW model detected!");
-            ModelChannelOrder::NCHW
-        } else if input_shape.dim(3) == 3 {
-            log::debug!("NHWC model detected!");
-            ModelChannelOrder::NHWC
-        } else {
-            return Err(ModelRunnerError::InvalidInputShape(input_shape));
+        let detect_channel_order = |shape: &Shape| {
+            if channel_counts.contains(&(shape.dim(1) as usize)) {
+                Some(ModelChannelOrder::NCHW)
+            } else if channel_counts.contains(&(shape.dim(3) as usize)) {
+                Some(ModelChannelOrder::NHWC)
+            } else {
+                None
+            }
+        };
+        let (image_index, channel_order) = shapes
+            .iter()
+            .enumerate()
+            .find_map(|(i, shape)| detect_channel_order(shape).map(|order| (i, order)))
+            .ok_or_else(|| ModelRunnerError::UnsupportedChannelCount {
+                shape: shapes[0].clone(),
+                channel_counts: channel_counts.to_vec(),
+            })?;
+        log::debug!("{:?} model detected!", channel_order);
+
+        let spatial_size = |shape: &Shape| {
+            (
+                channel_order.get_width(shape),
+                channel_order.get_height(shape),
+            )
         };
+        let mut auxiliary = Vec::new();
+        for (i, (input, shape)) in inputs.iter().zip(&shapes).enumerate() {
+            if i == image_index {
+                continue;
+            }
+            if spatial_size(shape) != spatial_size(&shapes[image_index]) {
+                return Err(ModelRunnerError::AuxiliaryInputMismatch {
+                    name: input.get_name().to_owned(),
+                    shape: shape.clone(),
+                });
+            }
+            auxiliary.push(AuxiliaryInput {
+                name: input.get_name().to_owned(),
+                channels: channel_order.get_channels(shape).unwrap_or_default(),
+            });
+        }
+
+        Ok(GraphInputs {
+            shape: shapes.swap_remove(image_index),
+            name: inputs[image_index].get_name().to_owned(),
+            channel_order,
+            image_index,
+            auxiliary,
+        })
+    }
+
+    /// Estimate the padding that covers the receptive field of the conv and pool layers
+    ///
+    /// All layers are treated as one path, so this overestimates the receptive field of models
+    /// with parallel branches. Strides are only taken into account for downsampling layers.
+    fn estimate_recommended_padding(graph: &GraphProto) -> Option<usize> {
+        fn attribute_ints<'a>(node: &'a NodeProto, name: &str) -> Option<&'a [i64]> {
+            node.get_attribute()
+                .iter()
+                .find(|attribute| attribute.get_name() == name)
+                .map(|attribute| attribute.get_ints())
+        }
+        fn max_attribute(node: &NodeProto, name: &str) -> Option<usize> {
+            attribute_ints(node, name)?
+                .iter()
+                .max()
+                .map(|&v| v as usize)
+        }
+
+        let mut receptive_field = 1;
+        // The distance of neighbouring activations in input pixels
+        let mut jump = 1;
+        let mut found_layer = false;
+        for node in graph.get_node() {
+            if !matches!(node.get_op_type(), "Conv" | "MaxPool" | "AveragePool") {
+                continue;
+            }
+            // The kernel shape of a Conv may be omitted, it then follows from the weights
+            let kernel = max_attribute(node, "kernel_shape").or_else(|| {
+                let weights = node.get_input().get(1)?;
+                let initializer = graph
+                    .get_initializer()
+                    .iter()
+                    .find(|initializer| initializer.get_name() == weights)?;
+                initializer.get_dims().last().map(|&v| v as usize)
+            })?;
+            let dilation = max_attribute(node, "dilations").unwrap_or(1);
+            let stride = max_attribute(node, "strides").unwrap_or(1);
+
+            receptive_field += kernel.saturating_sub(1) * dilation * jump;
+            jump *= stride.max(1);
+            found_layer = true;
+        }
+
+        found_layer.then_some(receptive_field / 2)
+    }
 
-        Ok((input_shape, input_name, channel_order))
+    /// Whether a dimension of a model input or output is symbolic or 0
+    fn is_unknown_dimension(dim: &TensorShapeProto_Dimension) -> bool {
+        !dim.has_dim_value() || dim.get_dim_value() <= 0
+    }
+
+    /// Set the unknown batch dimension of the rank 4 model inputs and outputs to 1
+    ///
+    /// Returns whether a dimension was replaced.
+    fn fix_batch_dimensions(graph: &mut GraphProto) -> bool {
+        fn fix(values: &mut [ValueInfoProto]) -> bool {
+            let mut fixed = false;
+            for value in values {
+                if !value.get_field_type().has_tensor_type() {
+                    continue;
+                }
+                let dims = value
+                    .mut_field_type()
+                    .mut_tensor_type()
+                    .mut_shape()
+                    .mut_dim();
+                if dims.len() == 4 && ModelRunner::is_unknown_dimension(&dims[0]) {
+                    dims[0].set_dim_value(1);
+                    fixed = true;
+                }
+            }
+            fixed
+        }
+
+        let inputs_fixed = fix(graph.mut_input());
+        let outputs_fixed = fix(graph.mut_output());
+        inputs_fixed || outputs_fixed
+    }
+
+    /// Replace the unknown height and width of the rank 4 model inputs and outputs, see
+    /// `new_with_dynamic_chunksize`
+    ///
+    /// Dimensions are unknown if they are symbolic or 0. The height and width are the two unknown
+    /// dimensions next to a known channel dimension, so [?, 3, ?, ?] is NCHW and [?, ?, ?, 3] is
+    /// NHWC. The batch dimension is not considered, see `fix_batch_dimensions`. Returns whether
+    /// the height and width of an input were replaced, only then the chunksize can be changed.
+    fn fix_dynamic_dimensions(
+        graph: &mut GraphProto,
+        chunksize: Option<ChunkSize>,
+    ) -> Result<bool, ModelRunnerError> {
+        fn fix(values: &mut [ValueInfoProto], chunksize: ChunkSize) -> bool {
+            let mut fixed = false;
+            for value in values {
+                if !value.get_field_type().has_tensor_type() {
+                    continue;
+                }
+                let dims = value
+                    .mut_field_type()
+                    .mut_tensor_type()
+                    .mut_shape()
+                    .mut_dim();
+                let unknown: Vec<_> = dims.iter().map(ModelRunner::is_unknown_dimension).collect();
+                let (height, width) = match unknown[..] {
+                    [_, false, true, true] => (2, 3),
+                    [_, true, true, false] => (1, 2),
+                    _ => continue,
+                };
+                dims[height].set_dim_value(chunksize.height as i64);
+                dims[width].set_dim_value(chunksize.width as i64);
+                fixed = true;
+            }
+            fixed
+        }
+
+        let dynamic_input = graph
+            .get_input()
+            .iter()
+            .find(|input| {
+                let dims = input
+                    .get_field_type()
+                    .get_tensor_type()
+                    .get_shape()
+                    .get_dim();
+                // A rank 4 input can have any batch size, it is set to 1
+                let skip = if dims.len() == 4 { 1 } else { 0 };
+                dims.iter().skip(skip).any(Self::is_unknown_dimension)
+            })
+            .map(|input| input.get_name().to_owned());
+        match (dynamic_input, chunksize) {
+            (None, _) => Ok(false),
+            (Some(name), None) => Err(ModelRunnerError::DynamicInputShape(name)),
+            (Some(_), Some(chunksize)) => {
+                let inputs_fixed = fix(graph.mut_input(), chunksize);
+                fix(graph.mut_output(), chunksize);
+                Ok(inputs_fixed)
+            }
+        }
+    }
+
+    /// Encode the model again with its dynamic dimensions replaced by the chunksize
+    fn fixed_model_bytes(
+        model_bytes: &[u8],
+        chunksize: ChunkSize,
+    ) -> Result<Vec<u8>, ModelRunnerError> {
+        let mut model = wonnx::onnx::ModelProto::parse_from_bytes(model_bytes)?;
+        Self::fix_batch_dimensions(model.mut_graph());
+        Self::fix_dynamic_dimensions(model.mut_graph(), Some(chunksize))?;
+        Ok(model.write_to_bytes()?)
     }
 
     fn get_scale_factor(
@@ -209,165 +995,701 @@ impl ModelRunner {
         graph: &GraphProto,
         input_shape: &Shape,
         channel_order: ModelChannelOrder,
-    ) -> Result<(String, usize), ModelRunnerError> {
-        let exact_match = graph
-            .get_output()
-            .iter()
-            .filter(|o| o.get_shape().map(|s| &s == input_shape).unwrap_or_default())
-            .next()
-            .map(|o| (o.get_name().to_owned(), 1));
-
-        exact_match
-            .or_else(|| {
-                graph
-                    .get_output()
-                    .iter()
-                    .find_map(|output| match output.get_shape() {
-                        Ok(output_shape) => {
-                            Self::get_scale_factor(input_shape, channel_order, &output_shape)
-                                .map(|scale| (output.get_name().to_owned(), scale))
-                        }
-                        Err(_) => None,
-                    })
+        selector: &OutputSelector,
+    ) -> Result<GraphOutput, ModelRunnerError> {
+        let outputs = graph.get_output();
+        let scale = |output: &ValueInfoProto| match output.get_shape() {
+            Ok(output_shape) if &output_shape == input_shape => Some(1),
+            Ok(output_shape) => Self::get_scale_factor(input_shape, channel_order, &output_shape),
+            Err(_) => None,
+        };
+        let selected = |index: usize| -> Result<GraphOutput, ModelRunnerError> {
+            let output = &outputs[index];
+            let scale = scale(output)
+                .ok_or_else(|| ModelRunnerError::UnsuitableOutput(output.get_name().to_owned()))?;
+            Ok(GraphOutput {
+                index,
+                name: output.get_name().to_owned(),
+                scale,
             })
-            .ok_or_else(|| ModelRunnerError::NoSuitableOutput)
+        };
+
+        match selector {
+            OutputSelector::Index(index) if *index < outputs.len() => selected(*index),
+            OutputSelector::Index(index) => Err(ModelRunnerError::OutputIndexOutOfRange {
+                index: *index,
+                count: outputs.len(),
+            }),
+            OutputSelector::Name(name) => outputs
+                .iter()
+                .position(|output| output.get_name() == name)
+                .ok_or_else(|| ModelRunnerError::UnknownOutput {
+                    name: name.clone(),
+                    outputs: outputs
+                        .iter()
+                        .map(|output| output.get_name().to_owned())
+                        .collect(),
+                })
+                .and_then(selected),
+            OutputSelector::Auto => {
+                let exact_match = outputs
+                    .iter()
+                    .position(|o| o.get_shape().map(|s| &s == input_shape).unwrap_or_default());
+                exact_match
+                    .or_else(|| outputs.iter().position(|output| scale(output).is_some()))
+                    .ok_or(ModelRunnerError::NoSuitableOutput)
+                    .and_then(selected)
+            }
+        }
     }
 
+    /// Load an ONNX model, the model may be compressed with gzip or zstd
+    ///
+    /// The model input must have one of the `DEFAULT_CHANNEL_COUNTS`. Without `force_tract`, the
+    /// backend is selected by the environment, see `BackendPreference`.
     pub async fn new<R>(input: &mut R, force_tract: bool) -> Result<Self, ModelRunnerError>
     where
-        R: std::io::Read + std::io::Seek,
+        R: Read + Seek,
+    {
+        Self::new_with_channel_counts(input, force_tract, DEFAULT_CHANNEL_COUNTS).await
+    }
+
+    /// Load an ONNX model whose input has one of the given channel counts
+    ///
+    /// The first axis after the batch axis that matches one of the counts is used as the channel
+    /// axis, so NCHW is preferred for ambiguous shapes.
+    pub async fn new_with_channel_counts<R>(
+        input: &mut R,
+        force_tract: bool,
+        channel_counts: &[usize],
+    ) -> Result<Self, ModelRunnerError>
+    where
+        R: Read + Seek,
+    {
+        let backend = BackendPreference::resolve(force_tract.then_some(BackendPreference::Cpu))?;
+        Self::new_with_backend(input, backend, channel_counts).await
+    }
+
+    /// Load an ONNX model with the given backend, ignoring the environment
+    pub async fn new_with_backend<R>(
+        input: &mut R,
+        backend: BackendPreference,
+        channel_counts: &[usize],
+    ) -> Result<Self, ModelRunnerError>
+    where
+        R: Read + Seek,
+    {
+        Self::new_with_output(input, backend, channel_counts, &OutputSelector::Auto).await
+    }
+
+    /// Load an ONNX model with the given backend, using the selected output as the processed image
+    ///
+    /// This is useful if the automatic selection picks the wrong one of several outputs with the
+    /// same shape.
+    pub async fn new_with_output<R>(
+        input: &mut R,
+        backend: BackendPreference,
+        channel_counts: &[usize],
+        output: &OutputSelector,
+    ) -> Result<Self, ModelRunnerError>
+    where
+        R: Read + Seek,
+    {
+        Self::new_with_strict_shapes(input, backend, channel_counts, output, false).await
+    }
+
+    /// Like `new_with_output`, but with `strict_shapes` only NCHW models are accepted
+    ///
+    /// The chunks of NHWC models are permuted before and after inference. With `strict_shapes`,
+    /// loading an NHWC model fails with `ModelRunnerError::PermutationNotAllowed` instead.
+    pub async fn new_with_strict_shapes<R>(
+        input: &mut R,
+        backend: BackendPreference,
+        channel_counts: &[usize],
+        output: &OutputSelector,
+        strict_shapes: bool,
+    ) -> Result<Self, ModelRunnerError>
+    where
+        R: Read + Seek,
+    {
+        Self::new_with_dynamic_chunksize(
+            input,
+            backend,
+            channel_counts,
+            output,
+            strict_shapes,
+            None,
+        )
+        .await
+    }
+
+    /// Like `new_with_strict_shapes`, but models with a dynamic input shape are loaded with the
+    /// given chunksize
+    ///
+    /// Fully convolutional models are often exported with a symbolic or 0 height and width.
+    /// These are replaced by the chunksize, and an unknown batch size by 1. The scale of an
+    /// output with an unknown height and width can not be detected, so it is assumed to have the
+    /// size of the input. Without a chunksize, loading such a model fails with
+    /// `ModelRunnerError::DynamicInputShape`. The chunksize is ignored for fixed input shapes.
+    pub async fn new_with_dynamic_chunksize<R>(
+        input: &mut R,
+        backend: BackendPreference,
+        channel_counts: &[usize],
+        output: &OutputSelector,
+        strict_shapes: bool,
+        dynamic_chunksize: Option<ChunkSize>,
+    ) -> Result<Self, ModelRunnerError>
+    where
+        R: Read + Seek,
+    {
+        let model_bytes = Self::read_model_bytes(input)?;
+        Self::from_model_bytes(
+            model_bytes,
+            backend,
+            channel_counts,
+            output,
+            strict_shapes,
+            dynamic_chunksize,
+        )
+        .await
+    }
+
+    /// Load an ONNX model from memory, the model may be compressed with gzip or zstd
+    pub async fn from_bytes(bytes: &[u8], force_tract: bool) -> Result<Self, ModelRunnerError> {
+        Self::new(&mut Cursor::new(bytes), force_tract).await
+    }
+
+    /// Read an ONNX model and decompress it if it is compressed with gzip or zstd
+    fn read_model_bytes<R>(input: &mut R) -> Result<Vec<u8>, ModelRunnerError>
+    where
+        R: Read + Seek,
+    {
+        let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+        input
+            .by_ref()
+            .take(ZSTD_MAGIC.len() as u64)
+            .read_to_end(&mut magic)?;
+        input.rewind()?;
+
+        let mut model_bytes = Vec::new();
+        if magic.starts_with(&GZIP_MAGIC) {
+            log::info!("Decompressing gzip compressed model");
+            flate2::read::GzDecoder::new(input).read_to_end(&mut model_bytes)?;
+        } else if magic.starts_with(&ZSTD_MAGIC) {
+            log::info!("Decompressing zstd compressed model");
+            zstd::stream::read::Decoder::new(input)?.read_to_end(&mut model_bytes)?;
+        } else {
+            input.read_to_end(&mut model_bytes)?;
+        }
+        Ok(model_bytes)
+    }
+
+    /// Check if an ONNX model is supported without processing any data
+    ///
+    /// This detects the input and output like `new` and tries to create a wonnx session and to
+    /// compile the model with tract. Only read and parse errors are returned as errors. With
+    /// `force_tract`, the wonnx session is not created, e.g. on systems without a GPU.
+    pub async fn check_compatibility<R>(
+        input: &mut R,
+        force_tract: bool,
+    ) -> Result<CompatibilityReport, ModelRunnerError>
+    where
+        R: Read + Seek,
     {
-        let wonnx_model = wonnx::onnx::ModelProto::parse_from_reader(input)?;
+        use CheckStatus::*;
+
+        let model_bytes = Self::read_model_bytes(input)?;
+        let wonnx_model = wonnx::onnx::ModelProto::parse_from_bytes(&model_bytes)?;
+        let graph = wonnx_model.get_graph();
+
+        let detected_input = match Self::get_graph_input(graph, DEFAULT_CHANNEL_COUNTS) {
+            Err(ModelRunnerError::UnsupportedChannelCount { .. }) => {
+                Self::get_graph_input(graph, &[4]).map(|input| (input, Warn))
+            }
+            detected_input => detected_input.map(|input| (input, Pass)),
+        };
+        let (input_check, output_check) = match detected_input {
+            Ok((inputs, status)) => {
+                let input_check = CompatibilityCheck::new(
+                    status,
+                    match status {
+                        Warn => format!(
+                            "RGBA input {:?}, load it with 4 channels to process RGBA images",
+                            inputs.shape
+                        ),
+                        _ if inputs.auxiliary.is_empty() => {
+                            format!("{:?} input {:?}", inputs.channel_order, inputs.shape)
+                        }
+                        _ => format!(
+                            "{:?} input {:?} with the auxiliary inputs {:?}",
+                            inputs.channel_order,
+                            inputs.shape,
+                            inputs
+                                .auxiliary
+                                .iter()
+                                .map(|input| input.name.as_str())
+                                .collect::<Vec<_>>()
+                        ),
+                    },
+                );
+                let output_check = match Self::get_matching_output(
+                    graph,
+                    &inputs.shape,
+                    inputs.channel_order,
+                    &OutputSelector::Auto,
+                ) {
+                    Ok(GraphOutput { name, scale: 1, .. }) => {
+                        CompatibilityCheck::new(Pass, format!("output {}", name))
+                    }
+                    Ok(GraphOutput { name, scale, .. }) => CompatibilityCheck::new(
+                        Warn,
+                        format!(
+                            "output {} is {}x the input size; scaled down unless upscaling is enabled",
+                            name, scale
+                        ),
+                    ),
+                    Err(err) => CompatibilityCheck::new(Fail, err.to_string()),
+                };
+                (input_check, output_check)
+            }
+            Err(err) => (
+                CompatibilityCheck::new(Fail, err.to_string()),
+                CompatibilityCheck::new(Fail, "the input is not supported"),
+            ),
+        };
+
+        let wonnx_check = if force_tract {
+            CompatibilityCheck::new(Warn, "not checked")
+        } else {
+            match Session::from_model(wonnx_model).await {
+                Ok(_) => CompatibilityCheck::new(Pass, "a session was created"),
+                Err(err) => CompatibilityCheck::new(Fail, err.to_string()),
+            }
+        };
+        let tract_check = match tract_onnx::onnx()
+            .model_for_read(&mut Cursor::new(&model_bytes))
+            .and_then(|model| model.into_optimized())
+            .and_then(|model| model.into_runnable())
+        {
+            Ok(_) => CompatibilityCheck::new(Pass, "the model was compiled"),
+            Err(err) => CompatibilityCheck::new(Fail, format!("{:#}", err)),
+        };
+
+        Ok(CompatibilityReport {
+            input: input_check,
+            output: output_check,
+            wonnx: wonnx_check,
+            tract: tract_check,
+        })
+    }
+
+    async fn from_model_bytes(
+        model_bytes: Vec<u8>,
+        backend: BackendPreference,
+        channel_counts: &[usize],
+        output_selector: &OutputSelector,
+        strict_shapes: bool,
+        dynamic_chunksize: Option<ChunkSize>,
+    ) -> Result<Self, ModelRunnerError> {
+        let mut wonnx_model = wonnx::onnx::ModelProto::parse_from_bytes(&model_bytes)?;
+        let batch_fixed = Self::fix_batch_dimensions(wonnx_model.mut_graph());
+        let dynamic_input_shape =
+            Self::fix_dynamic_dimensions(wonnx_model.mut_graph(), dynamic_chunksize)?;
+        // Both backends get the model with the fixed dimensions
+        let (model_bytes, dynamic_model): (Arc<[u8]>, Option<Arc<[u8]>>) = if dynamic_input_shape {
+            log::info!(
+                "The model has a dynamic shape, using the chunksize {:?}",
+                dynamic_chunksize
+            );
+            (
+                wonnx_model.write_to_bytes()?.into(),
+                Some(model_bytes.into()),
+            )
+        } else if batch_fixed {
+            (wonnx_model.write_to_bytes()?.into(), None)
+        } else {
+            (model_bytes.into(), None)
+        };
 
         let graph = wonnx_model.get_graph();
-        let (input_shape, input_name, model_channel_order) = Self::get_graph_input(graph)?;
+        let inputs = Self::get_graph_input(graph, channel_counts)?;
+        let input_shape = inputs.shape;
+        let model_channel_order = inputs.channel_order;
+        if strict_shapes && model_channel_order == ModelChannelOrder::NHWC {
+            return Err(ModelRunnerError::PermutationNotAllowed);
+        }
         log::info!("Detected model input shape: {:?}", input_shape);
-        let (output_name, model_scale) =
-            Self::get_matching_output(graph, &input_shape, model_channel_order)?;
+        for input in &inputs.auxiliary {
+            log::info!(
+                "Detected auxiliary input {} with {} channels",
+                input.name,
+                input.channels
+            );
+        }
+        let output =
+            Self::get_matching_output(graph, &input_shape, model_channel_order, output_selector)?;
         log::info!(
             "Using output {} with {}x scaling",
-            &output_name,
-            model_scale
+            &output.name,
+            output.scale
         );
+        let model_scale = output.scale;
+        let channels = model_channel_order
+            .get_channels(&input_shape)
+            .ok_or_else(|| ModelRunnerError::InvalidInputShape(input_shape.clone()))?;
         let chunksize = model_channel_order.translate_shape_to_chunksize(input_shape);
+        let recommended_padding = Self::estimate_recommended_padding(graph);
+        log::info!("Recommended chunk padding: {:?}", recommended_padding);
+        let input_channels: Vec<_> = std::iter::once(channels)
+            .chain(inputs.auxiliary.iter().map(|input| input.channels))
+            .collect();
 
-        if !force_tract {
+        log::info!("Backend preference: {:?}", backend);
+        if backend != BackendPreference::Cpu {
             match Session::from_model(wonnx_model).await {
                 Ok(session) => {
                     return Ok(Self {
                         backend: ModelRunnerBackend::WonnxRunner(WonnxRunner {
-                            session,
-                            input_name,
-                            output_name,
-                            input_scratchpad: ndarray::Array3::<f32>::zeros(
-                                model_channel_order.scratchpad_buffer_layout(chunksize),
-                            ),
+                            sessions: vec![session],
+                            model_bytes: model_bytes.clone(),
+                            input_names: std::iter::once(inputs.name)
+                                .chain(inputs.auxiliary.iter().map(|input| input.name.clone()))
+                                .collect(),
+                            output_name: output.name,
+                            input_scratchpads: model_channel_order
+                                .scratchpad_buffers(chunksize, &input_channels),
                         }),
                         chunksize,
                         model_channel_order,
+                        channels,
+                        auxiliary_inputs: inputs.auxiliary,
+                        image_input_index: inputs.image_index,
+                        output_index: output.index,
                         model_scale,
+                        keep_scale: false,
+                        downscale_filter: DownscaleFilter::Lanczos3,
+                        recommended_padding,
+                        dynamic_input_shape,
+                        dynamic_model,
+                        tract_fallback: false,
+                        fallback: TractFallback::Pending(model_bytes),
+                        fallback_chunks: 0,
+                        pipeline_depth: 1,
+                        concurrency: ProcessingConcurrency::Sequential,
                     })
                 }
+                Err(err) if backend == BackendPreference::Gpu => {
+                    return Err(ModelRunnerError::GpuUnavailable(err.to_string()))
+                }
                 Err(err) => {
                     log::error!("Failed to create wonnx session: {}", err);
                     log::error!("Either wonnx doesn't support your model right now or you don't have Vulkan available. We will fall back to tract, but this will be slow!");
                 }
             }
         }
-        input.rewind().unwrap();
-
-        let tract_model = tract_onnx::onnx()
-            .model_for_read(input)
-            .unwrap()
-            .into_optimized()
-            .unwrap()
-            .into_runnable()
-            .unwrap();
-
-        let infer = move |input: &ndarray::Array3<f32>, output_shape: &[usize]| {
-            let shape = input.shape().clone();
-            let mut result = tract_model
-                .run(tvec![Into::<Tensor>::into(
-                    input
-                        .clone()
-                        .into_shape((1, shape[0], shape[1], shape[2]))
-                        .unwrap()
-                )
-                .into()])
-                .unwrap();
-            result
-                .remove(0)
-                .into_tensor()
-                .into_array()
-                .unwrap()
-                .into_shape((output_shape[0], output_shape[1], output_shape[2]))
-                .unwrap()
-        };
 
         Ok(Self {
-            backend: ModelRunnerBackend::TractRunner(TractRunner {
-                model: Box::new(infer),
-                input_scratchpad: ndarray::Array3::<f32>::zeros(
-                    model_channel_order.scratchpad_buffer_layout(chunksize),
-                ),
-            }),
+            backend: ModelRunnerBackend::TractRunner(TractRunner::new(
+                &model_bytes,
+                model_channel_order,
+                chunksize,
+                &input_channels,
+                inputs.image_index,
+                output.index,
+            )?),
             chunksize,
             model_channel_order,
+            channels,
+            auxiliary_inputs: inputs.auxiliary,
+            image_input_index: inputs.image_index,
+            output_index: output.index,
             model_scale,
+            keep_scale: false,
+            downscale_filter: DownscaleFilter::Lanczos3,
+            recommended_padding,
+            dynamic_input_shape,
+            dynamic_model,
+            tract_fallback: false,
+            fallback: TractFallback::Unavailable,
+            fallback_chunks: 0,
+            pipeline_depth: 1,
+            concurrency: ProcessingConcurrency::Sequential,
         })
     }
 
-    /// Scale down a chunk of image data by the given scale factor in the x and y dimension
+    /// Create a runner for an NCHW model that is implemented by the given function
     ///
-    /// The image chunk should be in CHW channel order.
-    /// The downscaling is done via simple averaging, so this should be considered a temporary
-    /// solution!
-    fn scale_chunk(mut chunk: ndarray::Array3<f32>, scale: usize) -> ndarray::Array3<f32> {
-        let shape: Vec<_> = chunk.shape().iter().cloned().collect();
-
-        for c in 0..shape[0] {
-            for y in 0..shape[1] / scale {
-                for x in 0..shape[2] / scale {
-                    let mut nv = 0f32;
-                    for dy in 0..scale {
-                        for dx in 0..scale {
-                            nv += chunk[(c, (scale * y) + dy, (scale * x) + dx)];
-                        }
-                    }
-                    chunk[(c, y, x)] = nv / (scale * scale) as f32;
-                }
-            }
+    /// The function receives the chunk in CHW order and the expected output shape.
+    #[cfg(test)]
+    pub(crate) fn from_stub<F>(chunksize: ChunkSize, model_scale: usize, model: F) -> Self
+    where
+        F: FnMut(
+                ndarray::ArrayView3<f32>,
+                &[usize],
+            ) -> Result<ndarray::Array3<f32>, ModelRunnerError>
+            + 'static,
+    {
+        Self {
+            backend: ModelRunnerBackend::StubRunner(StubRunner {
+                model: Box::new(model),
+            }),
+            chunksize,
+            model_channel_order: ModelChannelOrder::NCHW,
+            channels: 3,
+            auxiliary_inputs: Vec::new(),
+            image_input_index: 0,
+            output_index: 0,
+            model_scale,
+            keep_scale: false,
+            downscale_filter: DownscaleFilter::Lanczos3,
+            recommended_padding: None,
+            dynamic_input_shape: false,
+            dynamic_model: None,
+            tract_fallback: false,
+            fallback: TractFallback::Unavailable,
+            fallback_chunks: 0,
+            pipeline_depth: 1,
+            concurrency: ProcessingConcurrency::Sequential,
         }
+    }
+
+    /// Allow changing the chunksize of a stub runner
+    #[cfg(test)]
+    pub(crate) fn with_dynamic_input_shape(mut self) -> Self {
+        self.dynamic_input_shape = true;
+        self
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_recommended_padding(mut self, padding: usize) -> Self {
+        self.recommended_padding = Some(padding);
+        self
+    }
+
+    /// Add an auxiliary input to a stub runner, the stub receives its channels after the image
+    #[cfg(test)]
+    pub(crate) fn with_auxiliary_input(mut self, name: &str, channels: usize) -> Self {
+        self.auxiliary_inputs.push(AuxiliaryInput {
+            name: name.to_owned(),
+            channels,
+        });
+        self
+    }
+
+    /// Use the given ONNX model as the tract fallback of a stub runner
+    #[cfg(test)]
+    pub(crate) fn with_fallback_model(mut self, model_bytes: Vec<u8>) -> Self {
+        self.fallback = TractFallback::Pending(model_bytes.into());
+        self
+    }
+
+    /// Scale down a chunk of image data by the given scale factor in the x and y dimension
+    ///
+    /// The image chunk should be in CHW channel order. Each channel is resampled with the filter,
+    /// first along the rows and then along the columns. Rows and columns that do not fill a whole
+    /// output pixel at the end are only used by the kernels that reach them.
+    fn scale_chunk(
+        chunk: ndarray::Array3<f32>,
+        scale: usize,
+        filter: DownscaleFilter,
+    ) -> ndarray::Array3<f32> {
+        let (channels, height, width) = chunk.dim();
+        let (output_height, output_width) = (height / scale, width / scale);
+        let column_weights = filter.weights(width, output_width, scale);
+        let row_weights = filter.weights(height, output_height, scale);
 
-        chunk.slice_move(ndarray::s![.., ..shape[1] / scale, ..shape[2] / scale])
+        let mut rows_scaled = ndarray::Array3::zeros((channels, height, output_width));
+        for ((c, y, x), value) in rows_scaled.indexed_iter_mut() {
+            let (start, weights) = &column_weights[x];
+            *value = weights
+                .iter()
+                .enumerate()
+                .map(|(i, w)| w * chunk[(c, y, start + i)])
+                .sum();
+        }
+        let mut scaled = ndarray::Array3::zeros((channels, output_height, output_width));
+        for ((c, y, x), value) in scaled.indexed_iter_mut() {
+            let (start, weights) = &row_weights[y];
+            *value = weights
+                .iter()
+                .enumerate()
+                .map(|(i, w)| w * rows_scaled[(c, start + i, x)])
+                .sum();
+        }
+        scaled
     }
 
+    /// Run the model on a chunk in CHW order
+    ///
+    /// The chunk holds the image channels followed by the channels of the `auxiliary_inputs`.
+    /// The output only has the image channels.
     pub async fn process_chunk<'a>(
         &mut self,
         input: ndarray::ArrayView3<'a, f32>,
     ) -> Result<ndarray::Array3<f32>, ModelRunnerError> {
-
-        // Input will be an ArrayView to an array of shape (CHW)
-        let model_order_input = match self.model_channel_order {
-            ModelChannelOrder::NCHW => input,
-            ModelChannelOrder::NHWC => input.permuted_axes([1, 2, 0]),
-        };
-
-        let mut model_output_shape: Vec<_> = model_order_input.shape().iter().cloned().collect();
-        model_output_shape[self.model_channel_order.get_width_idx(false)] *= self.model_scale;
-        model_output_shape[self.model_channel_order.get_height_idx(false)] *= self.model_scale;
-
+        self.restore_sessions().await?;
+        let chunk = self.prepare_chunk(input)?;
         let model_output = match &mut self.backend {
             ModelRunnerBackend::WonnxRunner(runner) => {
                 runner
-                    .process_chunk(model_order_input, model_output_shape.as_slice())
+                    .process_chunk(&chunk.inputs, chunk.output_shape.as_slice())
                     .await?
             }
             ModelRunnerBackend::TractRunner(runner) => {
                 runner
-                    .process_chunk(model_order_input, model_output_shape.as_slice())
+                    .process_chunk(&chunk.inputs, chunk.output_shape.as_slice())
                     .await?
             }
+            #[cfg(test)]
+            ModelRunnerBackend::StubRunner(runner) => {
+                (runner.model)(chunk.model_order_input, chunk.output_shape.as_slice())?
+            }
+        };
+        self.finish_chunk(&chunk, model_output).await
+    }
+
+    /// Run the model on several chunks, see `process_chunk`
+    ///
+    /// The wonnx backend submits up to `pipeline_depth` chunks before it awaits their results,
+    /// tract runs `concurrent_chunks` chunks in parallel with `ProcessingConcurrency::Parallel`.
+    /// Otherwise the chunks are processed one after another. The results are in the order of the
+    /// inputs.
+    pub async fn process_chunks<'a>(
+        &mut self,
+        inputs: &[ndarray::ArrayView3<'a, f32>],
+    ) -> Vec<Result<ndarray::Array3<f32>, ModelRunnerError>> {
+        let concurrent = match self.backend {
+            ModelRunnerBackend::WonnxRunner(_) => true,
+            ModelRunnerBackend::TractRunner(_) => {
+                self.concurrency == ProcessingConcurrency::Parallel
+            }
+            #[cfg(test)]
+            ModelRunnerBackend::StubRunner(_) => false,
+        };
+        if let Err(err) = self.restore_sessions().await {
+            let reason = err.to_string();
+            return inputs
+                .iter()
+                .map(|_| Err(ModelRunnerError::InferenceFailed(reason.clone())))
+                .collect();
+        }
+        let mut results = Vec::with_capacity(inputs.len());
+        for batch in inputs.chunks(self.concurrent_chunks()) {
+            if batch.len() > 1 && concurrent {
+                results.extend(self.process_chunks_pipelined(batch).await);
+            } else {
+                for input in batch {
+                    results.push(self.process_chunk(input.view()).await);
+                }
+            }
+        }
+        results
+    }
+
+    /// Run up to `concurrent_chunks` chunks concurrently with wonnx or tract
+    async fn process_chunks_pipelined<'a>(
+        &mut self,
+        inputs: &[ndarray::ArrayView3<'a, f32>],
+    ) -> Vec<Result<ndarray::Array3<f32>, ModelRunnerError>> {
+        let chunks: Vec<_> = inputs
+            .iter()
+            .map(|input| self.prepare_chunk(input.view()))
+            .collect();
+        let runnable: Vec<_> = chunks
+            .iter()
+            .filter_map(|chunk| chunk.as_ref().ok())
+            .collect();
+        let mut model_outputs = match &self.backend {
+            ModelRunnerBackend::WonnxRunner(runner) => runner.process_chunks(&runnable).await,
+            ModelRunnerBackend::TractRunner(runner) => runner.process_chunks_parallel(&runnable),
+            #[cfg(test)]
+            ModelRunnerBackend::StubRunner(_) => unreachable!("Stubs run one chunk at a time"),
+        }
+        .into_iter();
+
+        let mut results = Vec::with_capacity(chunks.len());
+        for chunk in chunks {
+            results.push(match chunk {
+                Ok(chunk) => match model_outputs.next() {
+                    Some(Ok(model_output)) => self.finish_chunk(&chunk, model_output).await,
+                    Some(Err(err)) => Err(err),
+                    None => unreachable!("Every runnable chunk has a model output"),
+                },
+                Err(err) => Err(err),
+            });
+        }
+        results
+    }
+
+    /// Split a CHW chunk into the inputs of the model, in the channel order of the model
+    fn prepare_chunk<'a>(
+        &self,
+        input: ndarray::ArrayView3<'a, f32>,
+    ) -> Result<PreparedChunk<'a>, ModelRunnerError> {
+        // Input will be an ArrayView to an array of shape (CHW)
+        let model_order_input = match self.model_channel_order {
+            ModelChannelOrder::NCHW => input,
+            ModelChannelOrder::NHWC => input.permuted_axes([1, 2, 0]),
+        };
+
+        let input_channels = self.input_channels();
+        let channel_idx = self.model_channel_order.get_channel_idx(false);
+        if model_order_input.shape()[channel_idx] != input_channels.iter().sum::<usize>() {
+            return Err(ModelRunnerError::InferenceFailed(format!(
+                "The chunk has {} channels, but the model inputs have {:?} channels",
+                model_order_input.shape()[channel_idx],
+                input_channels
+            )));
+        }
+        let mut start = 0;
+        let inputs: Vec<_> = input_channels
+            .iter()
+            .map(|&channels| {
+                let input = model_order_input.slice_axis(
+                    ndarray::Axis(channel_idx),
+                    ndarray::Slice::from(start..start + channels),
+                );
+                start += channels;
+                input
+            })
+            .collect();
+
+        let mut output_shape: Vec<_> = model_order_input.shape().iter().cloned().collect();
+        output_shape[channel_idx] = self.channels;
+        output_shape[self.model_channel_order.get_width_idx(false)] *= self.model_scale;
+        output_shape[self.model_channel_order.get_height_idx(false)] *= self.model_scale;
+
+        Ok(PreparedChunk {
+            model_order_input,
+            inputs,
+            output_shape,
+        })
+    }
+
+    /// Re-run a chunk with the tract fallback if needed and convert the model output to CHW
+    async fn finish_chunk(
+        &mut self,
+        chunk: &PreparedChunk<'_>,
+        model_output: ndarray::Array3<f32>,
+    ) -> Result<ndarray::Array3<f32>, ModelRunnerError> {
+        let model_output = if self.tract_fallback && model_output.iter().any(|v| !v.is_finite()) {
+            let backend_name = self.backend_name();
+            match self.fallback_runner() {
+                Some(runner) => {
+                    log::warn!(
+                        "{} returned invalid values for a chunk, retrying it with tract",
+                        backend_name
+                    );
+                    let output = runner
+                        .process_chunk(&chunk.inputs, chunk.output_shape.as_slice())
+                        .await?;
+                    self.fallback_chunks += 1;
+                    output
+                }
+                None => model_output,
+            }
+        } else {
+            model_output
         };
 
         let mut nchw_output = match self.model_channel_order {
@@ -375,8 +1697,8 @@ impl ModelRunner {
             ModelChannelOrder::NHWC => model_output.permuted_axes([2, 0, 1]),
         };
 
-        if self.model_scale > 1 {
-            nchw_output = Self::scale_chunk(nchw_output, self.model_scale)
+        if self.model_scale > 1 && !self.keep_scale {
+            nchw_output = Self::scale_chunk(nchw_output, self.model_scale, self.downscale_filter)
         }
 
         Ok(nchw_output)
@@ -384,6 +1706,19 @@ impl ModelRunner {
 }
 
 impl WonnxRunner {
+    /// Create sessions until there is one for each of `depth` chunks in flight
+    async fn create_sessions(&mut self, depth: usize) -> Result<(), ModelRunnerError> {
+        while self.sessions.len() < depth {
+            let model = wonnx::onnx::ModelProto::parse_from_bytes(&self.model_bytes)?;
+            let session = Session::from_model(model)
+                .await
+                .map_err(|err| ModelRunnerError::GpuUnavailable(err.to_string()))?;
+            self.sessions.push(session);
+        }
+        self.sessions.truncate(depth);
+        Ok(())
+    }
+
     fn get_output_tensor(
         &self,
         network_result: &mut HashMap<String, OutputTensor>,
@@ -398,27 +1733,728 @@ impl WonnxRunner {
 
     pub async fn process_chunk<'a>(
         &mut self,
-        input: ndarray::ArrayView3<'a, f32>,
+        inputs: &[ndarray::ArrayView3<'a, f32>],
         output_shape: &[usize],
     ) -> Result<ndarray::Array3<f32>, ModelRunnerError> {
-        input.assign_to(&mut self.input_scratchpad);
-        let input_map = HashMap::from([(
-            self.input_name.clone(),
-            self.input_scratchpad.as_slice().unwrap().into(),
-        )]);
-        let mut result = self.session.run(&input_map).await.unwrap();
+        for (input, scratchpad) in inputs.iter().zip(&mut self.input_scratchpads) {
+            input.assign_to(scratchpad);
+        }
+        let input_map: HashMap<String, InputTensor> = self
+            .input_names
+            .iter()
+            .cloned()
+            .zip(
+                self.input_scratchpads
+                    .iter()
+                    .map(|scratchpad| scratchpad.as_slice().unwrap().into()),
+            )
+            .collect();
+        let mut result = self.sessions[0]
+            .run(&input_map)
+            .await
+            .map_err(|err| ModelRunnerError::InferenceFailed(err.to_string()))?;
 
         Ok(self.get_output_tensor(&mut result, output_shape))
     }
+
+    /// Run each chunk on its own session, so that all of them are in flight at the same time
+    async fn process_chunks(
+        &self,
+        chunks: &[&PreparedChunk<'_>],
+    ) -> Vec<Result<ndarray::Array3<f32>, ModelRunnerError>> {
+        debug_assert!(chunks.len() <= self.sessions.len());
+        // The scratchpads can only hold one chunk, so each chunk gets a contiguous copy
+        let input_data: Vec<Vec<Vec<f32>>> = chunks
+            .iter()
+            .map(|chunk| {
+                chunk
+                    .inputs
+                    .iter()
+                    .map(|input| input.iter().copied().collect())
+                    .collect()
+            })
+            .collect();
+        let runs = self.sessions.iter().zip(&input_data).zip(chunks).map(
+            |((session, data), chunk)| async move {
+                let input_map: HashMap<String, InputTensor> = self
+                    .input_names
+                    .iter()
+                    .cloned()
+                    .zip(data.iter().map(|data| data.as_slice().into()))
+                    .collect();
+                let mut result = session
+                    .run(&input_map)
+                    .await
+                    .map_err(|err| ModelRunnerError::InferenceFailed(err.to_string()))?;
+                Ok(self.get_output_tensor(&mut result, &chunk.output_shape))
+            },
+        );
+        futures::future::join_all(runs).await
+    }
 }
 
 impl TractRunner {
+    /// Compile a model with tract
+    ///
+    /// `input_channels` are the channel counts of the image input and the auxiliary inputs, the
+    /// image input is at `image_input_index` among the model inputs. The processed image is the
+    /// model output at `output_index`.
+    fn new(
+        model_bytes: &[u8],
+        model_channel_order: ModelChannelOrder,
+        chunksize: ChunkSize,
+        input_channels: &[usize],
+        image_input_index: usize,
+        output_index: usize,
+    ) -> Result<Self, ModelRunnerError> {
+        // The alternate format includes the causes, e.g. the node that could not be translated
+        let tract_model = tract_onnx::onnx()
+            .model_for_read(&mut Cursor::new(model_bytes))
+            .and_then(|model| model.into_optimized())
+            .and_then(|model| model.into_runnable())
+            .map_err(|err| ModelRunnerError::TractCompilationFailed(format!("{:#}", err)))?;
+
+        let infer = move |inputs: &[ndarray::Array3<f32>],
+                          output_shape: &[usize]|
+              -> Result<ndarray::Array3<f32>, ModelRunnerError> {
+            let mut tensors = inputs
+                .iter()
+                .map(|input| {
+                    let shape = input.shape();
+                    let input = input
+                        .clone()
+                        .into_shape((1, shape[0], shape[1], shape[2]))
+                        .map_err(|err| ModelRunnerError::InferenceFailed(err.to_string()))?;
+                    Ok(Into::<Tensor>::into(input))
+                })
+                .collect::<Result<Vec<_>, ModelRunnerError>>()?;
+            // The image comes first, but it may be any of the model inputs
+            let image = tensors.remove(0);
+            tensors.insert(image_input_index, image);
+            let mut result = tract_model
+                .run(tensors.into_iter().map(|tensor| tensor.into()).collect())
+                .map_err(|err| ModelRunnerError::InferenceFailed(format!("{:#}", err)))?;
+            result
+                .remove(output_index)
+                .into_tensor()
+                .into_array()
+                .map_err(|err| ModelRunnerError::InferenceFailed(format!("{:#}", err)))?
+                .into_shape((output_shape[0], output_shape[1], output_shape[2]))
+                .map_err(|err| ModelRunnerError::InferenceFailed(err.to_string()))
+        };
+
+        Ok(TractRunner {
+            model: Box::new(infer),
+            input_scratchpads: model_channel_order.scratchpad_buffers(chunksize, input_channels),
+        })
+    }
+
     pub async fn process_chunk<'a>(
         &mut self,
-        input: ndarray::ArrayView3<'a, f32>,
+        inputs: &[ndarray::ArrayView3<'a, f32>],
         output_shape: &[usize],
     ) -> Result<ndarray::Array3<f32>, ModelRunnerError> {
-        input.assign_to(&mut self.input_scratchpad);
-        Ok((self.model)(&self.input_scratchpad, output_shape))
+        for (input, scratchpad) in inputs.iter().zip(&mut self.input_scratchpads) {
+            input.assign_to(scratchpad);
+        }
+        (self.model)(&self.input_scratchpads, output_shape)
+    }
+
+    /// Run the chunks in parallel on the rayon thread pool
+    fn process_chunks_parallel(
+        &self,
+        chunks: &[&PreparedChunk<'_>],
+    ) -> Vec<Result<ndarray::Array3<f32>, ModelRunnerError>> {
+        // The scratchpads can only hold one chunk, so each chunk gets a contiguous copy
+        chunks
+            .par_iter()
+            .map(|chunk| {
+                let inputs: Vec<_> = chunk
+                    .inputs
+                    .iter()
+                    .map(|input| input.as_standard_layout().into_owned())
+                    .collect();
+                (self.model)(&inputs, &chunk.output_shape)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::test_image::{identity_model_bytes, identity_model_bytes_with_channels};
+    use std::io::Write;
+    use wonnx::utils::{attribute, graph, model, node, tensor};
+
+    /// An NCHW model with a fixed input size of 32x32 that averages each 3x3 neighbourhood
+    pub(crate) fn blur_model_bytes() -> Vec<u8> {
+        let model = model(graph(
+            vec![tensor("input", &[1, 3, 32, 32])],
+            vec![tensor("output", &[1, 3, 32, 32])],
+            vec![],
+            vec![],
+            vec![node(
+                vec!["input"],
+                vec!["output"],
+                "blur",
+                "AveragePool",
+                vec![
+                    attribute("kernel_shape", vec![3, 3]),
+                    attribute("pads", vec![1, 1, 1, 1]),
+                ],
+            )],
+        ));
+        model.write_to_bytes().unwrap()
+    }
+
+    /// An NCHW identity model with a symbolic batch size, height and width
+    pub(crate) fn dynamic_identity_model_bytes() -> Vec<u8> {
+        symbolic_identity_model_bytes(&[(0, "batch"), (2, "height"), (3, "width")])
+    }
+
+    /// An NCHW identity model of 1x1 pixels whose dimensions at the given indices are symbolic
+    fn symbolic_identity_model_bytes(symbolic_dims: &[(usize, &str)]) -> Vec<u8> {
+        let symbolic = |name: &str| {
+            let mut value = tensor(name, &[1, 3, 1, 1]);
+            let dims = value
+                .mut_field_type()
+                .mut_tensor_type()
+                .mut_shape()
+                .mut_dim();
+            for &(index, param) in symbolic_dims {
+                dims[index].set_dim_param(param.to_owned());
+            }
+            value
+        };
+        model(graph(
+            vec![symbolic("input")],
+            vec![symbolic("output")],
+            vec![],
+            vec![],
+            vec![node(
+                vec!["input"],
+                vec!["output"],
+                "identity",
+                "Identity",
+                vec![],
+            )],
+        ))
+        .write_to_bytes()
+        .unwrap()
+    }
+
+    fn load(bytes: &[u8]) -> ModelRunner {
+        pollster::block_on(ModelRunner::from_bytes(bytes, true)).unwrap()
+    }
+
+    #[test]
+    fn test_load_compressed_models() {
+        let raw = identity_model_bytes();
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&raw).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let zstd = zstd::encode_all(raw.as_slice(), 0).unwrap();
+
+        for bytes in [&raw, &gzip, &zstd] {
+            let runner = load(bytes);
+            assert_eq!(
+                runner.get_chunksize(),
+                ChunkSize {
+                    width: 32,
+                    height: 32
+                }
+            );
+            assert_eq!(runner.get_model_scale(), 1);
+            assert_eq!(runner.model_channel_order, ModelChannelOrder::NCHW);
+        }
+    }
+
+    #[test]
+    fn test_load_invalid_model() {
+        assert!(pollster::block_on(ModelRunner::from_bytes(&[0x1f, 0x8b, 0, 0], true)).is_err());
+    }
+
+    fn load_model(
+        inputs: &[&[i64]],
+        outputs: &[&[i64]],
+        op_type: &str,
+    ) -> Result<ModelRunner, ModelRunnerError> {
+        let input_names: Vec<_> = (0..inputs.len()).map(|i| format!("input{}", i)).collect();
+        let model = model(graph(
+            inputs
+                .iter()
+                .zip(&input_names)
+                .map(|(shape, name)| tensor(name, shape))
+                .collect(),
+            outputs
+                .iter()
+                .map(|shape| tensor("output", shape))
+                .collect(),
+            vec![],
+            vec![],
+            vec![node(
+                input_names.iter().map(String::as_str).collect(),
+                vec!["output"],
+                "node",
+                op_type,
+                vec![],
+            )],
+        ));
+        pollster::block_on(ModelRunner::from_bytes(
+            &model.write_to_bytes().unwrap(),
+            true,
+        ))
+    }
+
+    #[test]
+    fn test_model_load_errors() {
+        let rgb: &[i64] = &[1, 3, 32, 32];
+
+        assert!(matches!(
+            pollster::block_on(ModelRunner::from_bytes(b"not a model", true)),
+            Err(ModelRunnerError::ParseError(_))
+        ));
+        assert!(matches!(
+            load_model(&[], &[rgb], "Identity"),
+            Err(ModelRunnerError::ModelInputError(0))
+        ));
+        assert!(matches!(
+            load_model(&[rgb, &[1, 1, 16, 16]], &[rgb], "Mul"),
+            Err(ModelRunnerError::AuxiliaryInputMismatch { name, .. }) if name == "input1"
+        ));
+        assert!(matches!(
+            load_model(&[&[2, 3, 32, 32]], &[&[2, 3, 32, 32]], "Identity"),
+            Err(ModelRunnerError::InvalidInputShape(_))
+        ));
+        assert!(matches!(
+            load_model(&[&[1, 3, 32]], &[&[1, 3, 32]], "Identity"),
+            Err(ModelRunnerError::InvalidInputShape(_))
+        ));
+        assert!(matches!(
+            load_model(&[&[1, 1, 32, 32]], &[&[1, 1, 32, 32]], "Identity"),
+            Err(ModelRunnerError::UnsupportedChannelCount { .. })
+        ));
+        assert!(matches!(
+            load_model(&[rgb], &[&[1, 3, 16, 20]], "Identity"),
+            Err(ModelRunnerError::NoSuitableOutput)
+        ));
+        assert!(matches!(
+            load_model(&[rgb], &[rgb], "NotAnOperator"),
+            Err(ModelRunnerError::TractCompilationFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_auxiliary_inputs() {
+        let rgb: &[i64] = &[1, 3, 32, 32];
+        let mask: &[i64] = &[1, 1, 32, 32];
+        let input = ndarray::Array3::from_shape_fn((4, 32, 32), |(c, y, x)| match c {
+            3 => (x % 2) as f32,
+            _ => (c + y) as f32 / 100.0,
+        });
+        let expected = ndarray::Array3::from_shape_fn((3, 32, 32), |(c, y, x)| {
+            input[(c, y, x)] * input[(3, y, x)]
+        });
+
+        // The image input is detected regardless of its position among the inputs
+        for (inputs, mask_name) in [([rgb, mask], "input1"), ([mask, rgb], "input0")] {
+            let mut runner = load_model(&inputs, &[rgb], "Mul").unwrap();
+            assert_eq!(runner.get_channels(), 3);
+            assert_eq!(
+                runner.auxiliary_inputs(),
+                &[AuxiliaryInput {
+                    name: mask_name.to_owned(),
+                    channels: 1
+                }]
+            );
+
+            let output = pollster::block_on(runner.process_chunk(input.view())).unwrap();
+            assert_eq!(output, expected);
+            assert!(matches!(
+                pollster::block_on(runner.process_chunk(input.slice(ndarray::s![..3, .., ..]))),
+                Err(ModelRunnerError::InferenceFailed(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_output_selector() {
+        let rgb: &[i64] = &[1, 3, 32, 32];
+        let model_bytes = model(graph(
+            vec![tensor("input", rgb)],
+            vec![tensor("identity", rgb), tensor("negated", rgb)],
+            vec![],
+            vec![],
+            vec![
+                node(vec!["input"], vec!["identity"], "copy", "Identity", vec![]),
+                node(vec!["input"], vec!["negated"], "negate", "Neg", vec![]),
+            ],
+        ))
+        .write_to_bytes()
+        .unwrap();
+        let load = |selector: OutputSelector| {
+            pollster::block_on(ModelRunner::new_with_output(
+                &mut Cursor::new(&model_bytes),
+                BackendPreference::Cpu,
+                DEFAULT_CHANNEL_COUNTS,
+                &selector,
+            ))
+        };
+        let input = ndarray::Array3::from_shape_fn((3, 32, 32), |(c, y, x)| (c + y + x) as f32);
+
+        let mut runner = load(OutputSelector::Auto).unwrap();
+        let output = pollster::block_on(runner.process_chunk(input.view())).unwrap();
+        assert_eq!(output, input);
+
+        for selector in [
+            OutputSelector::Index(1),
+            OutputSelector::Name("negated".to_owned()),
+        ] {
+            let mut runner = load(selector).unwrap();
+            let output = pollster::block_on(runner.process_chunk(input.view())).unwrap();
+            assert_eq!(output, -&input);
+        }
+
+        assert!(matches!(
+            load(OutputSelector::Index(2)),
+            Err(ModelRunnerError::OutputIndexOutOfRange { index: 2, count: 2 })
+        ));
+        assert!(matches!(
+            load(OutputSelector::Name("output".to_owned())),
+            Err(ModelRunnerError::UnknownOutput { .. })
+        ));
+    }
+
+    #[test]
+    fn test_tract_fallback_for_invalid_chunks() {
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let stub = |fallback| {
+            let mut calls = 0;
+            ModelRunner::from_stub(chunksize, 1, move |input, _| {
+                calls += 1;
+                if calls == 2 {
+                    Ok(ndarray::Array3::from_elem(input.raw_dim(), f32::NAN))
+                } else {
+                    Ok(input.to_owned())
+                }
+            })
+            .with_fallback_model(identity_model_bytes())
+            .with_tract_fallback(fallback)
+        };
+        let input =
+            ndarray::Array3::from_shape_fn((3, 32, 32), |(c, y, x)| (c + y + x) as f32 / 100.0);
+
+        let mut runner = stub(true);
+        for _ in 0..3 {
+            let output = pollster::block_on(runner.process_chunk(input.view())).unwrap();
+            assert_eq!(output, input);
+        }
+        assert!(matches!(runner.fallback, TractFallback::Ready(_)));
+        assert_eq!(runner.backend_info().fallback_chunks, 1);
+
+        let mut runner = stub(false);
+        pollster::block_on(runner.process_chunk(input.view())).unwrap();
+        let output = pollster::block_on(runner.process_chunk(input.view())).unwrap();
+        assert!(output.iter().all(|v| v.is_nan()));
+    }
+
+    #[test]
+    fn test_estimate_recommended_padding() {
+        let conv_stack = graph(
+            vec![tensor("input", &[1, 3, 64, 64])],
+            vec![tensor("output", &[1, 3, 32, 32])],
+            vec![],
+            vec![],
+            vec![
+                node(
+                    vec!["input", "w1"],
+                    vec!["conv1"],
+                    "conv1",
+                    "Conv",
+                    vec![attribute("kernel_shape", vec![3, 3])],
+                ),
+                node(vec!["conv1"], vec!["relu"], "relu", "Relu", vec![]),
+                node(
+                    vec!["relu"],
+                    vec!["pool"],
+                    "pool",
+                    "MaxPool",
+                    vec![
+                        attribute("kernel_shape", vec![2, 2]),
+                        attribute("strides", vec![2, 2]),
+                    ],
+                ),
+                node(
+                    vec!["pool", "w2"],
+                    vec!["output"],
+                    "conv2",
+                    "Conv",
+                    vec![
+                        attribute("kernel_shape", vec![3, 3]),
+                        attribute("dilations", vec![2, 2]),
+                    ],
+                ),
+            ],
+        );
+        // 1 + 2 (conv1) + 1 (pool) + 2 * 2 * 2 (dilated conv2 after the stride) = 12
+        assert_eq!(
+            ModelRunner::estimate_recommended_padding(&conv_stack),
+            Some(6)
+        );
+
+        let runner = load(&identity_model_bytes());
+        assert_eq!(runner.recommended_padding(), None);
+    }
+
+    #[test]
+    fn test_check_compatibility() {
+        let check = |bytes: Vec<u8>| {
+            pollster::block_on(ModelRunner::check_compatibility(
+                &mut Cursor::new(bytes),
+                true,
+            ))
+            .unwrap()
+        };
+
+        let report = check(identity_model_bytes());
+        assert_eq!(report.input.status, CheckStatus::Pass);
+        assert_eq!(report.output.status, CheckStatus::Pass);
+        assert_eq!(report.tract.status, CheckStatus::Pass);
+        assert!(report.is_compatible());
+
+        let report = check(identity_model_bytes_with_channels(4));
+        assert_eq!(report.input.status, CheckStatus::Warn);
+        assert!(report.is_compatible());
+
+        let bad_model = model(graph(
+            vec![tensor("input", &[1, 1, 32, 32])],
+            vec![tensor("output", &[1, 1, 32, 32])],
+            vec![],
+            vec![],
+            vec![node(
+                vec!["input"],
+                vec!["output"],
+                "node",
+                "NotAnOperator",
+                vec![],
+            )],
+        ));
+        let report = check(bad_model.write_to_bytes().unwrap());
+        assert_eq!(report.input.status, CheckStatus::Fail);
+        assert_eq!(report.output.status, CheckStatus::Fail);
+        assert_eq!(report.tract.status, CheckStatus::Fail);
+        assert!(!report.is_compatible());
+    }
+
+    #[test]
+    fn test_backend_preference() {
+        use BackendPreference::*;
+
+        assert_eq!(
+            BackendPreference::resolve_with_env(None, None).unwrap(),
+            Auto
+        );
+        assert_eq!(
+            BackendPreference::resolve_with_env(None, Some("CPU")).unwrap(),
+            Cpu
+        );
+        assert_eq!(
+            BackendPreference::resolve_with_env(Some(Auto), Some("cpu")).unwrap(),
+            Auto
+        );
+        assert_eq!(
+            BackendPreference::resolve_with_env(Some(Gpu), Some("bad")).unwrap(),
+            Gpu
+        );
+        assert!(matches!(
+            BackendPreference::resolve_with_env(None, Some("bad")),
+            Err(ModelRunnerError::InvalidBackend(_))
+        ));
+
+        // No other test loads a model without forcing tract, so changing the environment is safe
+        std::env::set_var(BACKEND_ENV_VAR, "cpu");
+        let runner = pollster::block_on(ModelRunner::from_bytes(&identity_model_bytes(), false));
+        std::env::remove_var(BACKEND_ENV_VAR);
+        assert_eq!(runner.unwrap().backend_name(), "tract");
+    }
+
+    #[test]
+    fn test_channel_counts() {
+        let rgba = identity_model_bytes_with_channels(4);
+
+        assert!(matches!(
+            pollster::block_on(ModelRunner::from_bytes(&rgba, true)),
+            Err(ModelRunnerError::UnsupportedChannelCount { channel_counts, .. })
+                if channel_counts == DEFAULT_CHANNEL_COUNTS
+        ));
+        let mut runner = pollster::block_on(ModelRunner::new_with_channel_counts(
+            &mut Cursor::new(&rgba),
+            true,
+            &[3, 4],
+        ))
+        .unwrap();
+        assert_eq!(runner.get_channels(), 4);
+
+        let input =
+            ndarray::Array3::from_shape_fn((4, 32, 32), |(c, y, x)| (c + y + x) as f32 / 100.0);
+        let output = pollster::block_on(runner.process_chunk(input.view())).unwrap();
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn test_strict_shapes() {
+        let nhwc = model(graph(
+            vec![tensor("input", &[1, 32, 32, 3])],
+            vec![tensor("output", &[1, 32, 32, 3])],
+            vec![],
+            vec![],
+            vec![node(
+                vec!["input"],
+                vec!["output"],
+                "identity",
+                "Identity",
+                vec![],
+            )],
+        ))
+        .write_to_bytes()
+        .unwrap();
+        let load = |model_bytes: &[u8], strict_shapes| {
+            pollster::block_on(ModelRunner::new_with_strict_shapes(
+                &mut Cursor::new(model_bytes),
+                BackendPreference::Cpu,
+                DEFAULT_CHANNEL_COUNTS,
+                &OutputSelector::Auto,
+                strict_shapes,
+            ))
+        };
+
+        assert!(matches!(
+            load(&nhwc, true),
+            Err(ModelRunnerError::PermutationNotAllowed)
+        ));
+        assert!(load(&nhwc, false).is_ok());
+        assert!(load(&identity_model_bytes(), true).is_ok());
+    }
+
+    #[test]
+    fn test_dynamic_input_shape() {
+        let model_bytes = dynamic_identity_model_bytes();
+        let load = |chunksize| {
+            pollster::block_on(ModelRunner::new_with_dynamic_chunksize(
+                &mut Cursor::new(&model_bytes),
+                BackendPreference::Cpu,
+                DEFAULT_CHANNEL_COUNTS,
+                &OutputSelector::Auto,
+                false,
+                chunksize,
+            ))
+        };
+
+        assert!(matches!(
+            load(None),
+            Err(ModelRunnerError::DynamicInputShape(name)) if name == "input"
+        ));
+        let chunksize = ChunkSize {
+            width: 48,
+            height: 40,
+        };
+        let mut runner = load(Some(chunksize)).unwrap();
+        assert_eq!(runner.get_chunksize(), chunksize);
+        assert_eq!(runner.get_model_scale(), 1);
+        assert_eq!(runner.model_channel_order, ModelChannelOrder::NCHW);
+        let input = ndarray::Array3::from_shape_fn((3, 40, 48), |(c, y, x)| (c + y + x) as f32);
+        let output = pollster::block_on(runner.process_chunk(input.view())).unwrap();
+        assert_eq!(output, input);
+
+        // The model is compiled again for another chunksize
+        let smaller = ChunkSize {
+            width: 24,
+            height: 20,
+        };
+        runner.set_chunksize(smaller).unwrap();
+        assert_eq!(runner.get_chunksize(), smaller);
+        let input = ndarray::Array3::from_shape_fn((3, 20, 24), |(c, y, x)| (c + y + x) as f32);
+        let output = pollster::block_on(runner.process_chunk(input.view())).unwrap();
+        assert_eq!(output, input);
+
+        // Fixed input shapes do not use the chunksize
+        let mut runner = pollster::block_on(ModelRunner::new_with_dynamic_chunksize(
+            &mut Cursor::new(identity_model_bytes()),
+            BackendPreference::Cpu,
+            DEFAULT_CHANNEL_COUNTS,
+            &OutputSelector::Auto,
+            false,
+            Some(chunksize),
+        ))
+        .unwrap();
+        assert_eq!(runner.get_chunksize().width, 32);
+        assert!(matches!(
+            runner.set_chunksize(smaller),
+            Err(ModelRunnerError::FixedInputShape(_))
+        ));
+
+        // A symbolic batch dimension alone is set to 1, the input shape stays fixed
+        let batch_only = symbolic_identity_model_bytes(&[(0, "batch")]);
+        for dynamic_chunksize in [None, Some(chunksize)] {
+            let mut runner = pollster::block_on(ModelRunner::new_with_dynamic_chunksize(
+                &mut Cursor::new(&batch_only),
+                BackendPreference::Cpu,
+                DEFAULT_CHANNEL_COUNTS,
+                &OutputSelector::Auto,
+                false,
+                dynamic_chunksize,
+            ))
+            .unwrap();
+            assert_eq!(
+                runner.get_chunksize(),
+                ChunkSize {
+                    width: 1,
+                    height: 1
+                }
+            );
+            assert!(matches!(
+                runner.set_chunksize(smaller),
+                Err(ModelRunnerError::FixedInputShape(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_downscale_filters() {
+        // Gradients along both axes, the height is not divisible by the scales
+        let chunk = ndarray::Array3::from_shape_fn((2, 37, 64), |(c, y, x)| match c {
+            0 => x as f32 / 63.0,
+            _ => y as f32 / 36.0,
+        });
+        let energy = |data: &ndarray::Array3<f32>| data.mapv(|v| v * v).mean().unwrap();
+        for scale in [2, 3] {
+            let baseline =
+                ModelRunner::scale_chunk(chunk.clone(), scale, DownscaleFilter::BoxAverage);
+            assert_eq!(baseline.dim(), (2, 37 / scale, 64 / scale));
+
+            for filter in [DownscaleFilter::Triangle, DownscaleFilter::Lanczos3] {
+                let scaled = ModelRunner::scale_chunk(chunk.clone(), scale, filter);
+
+                assert_eq!(scaled.dim(), baseline.dim());
+                assert!((scaled.mean().unwrap() - baseline.mean().unwrap()).abs() < 1e-3);
+                assert!((energy(&scaled) - energy(&baseline)).abs() < 1e-3);
+                // Away from the borders, both filters sample a gradient at the block centers
+                let interior = ndarray::s![.., 3..37 / scale - 3, 3..64 / scale - 3];
+                assert!(scaled
+                    .slice(interior)
+                    .iter()
+                    .zip(baseline.slice(interior))
+                    .all(|(a, b)| (a - b).abs() < 1e-4));
+            }
+        }
+
+        // The weights of kernels that are clamped at the borders still add up to one
+        let flat = ndarray::Array3::from_elem((1, 9, 10), 0.25);
+        let scaled = ModelRunner::scale_chunk(flat, 2, DownscaleFilter::Lanczos3);
+        assert!(scaled.iter().all(|v| (v - 0.25).abs() < 1e-6));
     }
 }
@@ -3,7 +3,13 @@ use std::{
     str::FromStr,
 };
 
+use ndarray::{Array3, Axis};
+
+/// The maximum values of common model value ranges, see `ModelValueRange::fitting`
+const COMMON_MAX_VALUES: [f32; 3] = [1.0, 255.0, 65535.0];
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 enum ModelValueMode {
     /// Values are centered on 0 (and have a negative and positive part)
     Symmetric,
@@ -12,9 +18,16 @@ enum ModelValueMode {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ModelValueRange {
     value_mode: ModelValueMode,
     max_abs_value: f32,
+    /// The ranges of the individual channels if they differ, see `per_channel`
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Vec::is_empty")
+    )]
+    channel_ranges: Vec<ModelValueRange>,
 }
 
 impl ModelValueRange {
@@ -23,6 +36,7 @@ impl ModelValueRange {
         Self {
             value_mode: ModelValueMode::Symmetric,
             max_abs_value,
+            channel_ranges: Vec::new(),
         }
     }
 
@@ -31,12 +45,128 @@ impl ModelValueRange {
         Self {
             value_mode: ModelValueMode::Asymmetric,
             max_abs_value,
+            channel_ranges: Vec::new(),
+        }
+    }
+
+    /// Create a range with an independent range for each channel, e.g. `[r, g, b]`
+    ///
+    /// The channels are in RGB order, also for BGR models. Channels without a range of their own
+    /// use the range of the first channel, as do the methods for single values. If all ranges are
+    /// equal, this is the same as the single range. Panics if `ranges` is empty.
+    pub fn per_channel(ranges: impl Into<Vec<ModelValueRange>>) -> Self {
+        let ranges: Vec<_> = ranges.into().iter().map(|range| range.channel(0)).collect();
+        assert!(!ranges.is_empty(), "A per channel range needs a channel");
+        if ranges.iter().all(|range| *range == ranges[0]) {
+            return ranges[0].clone();
+        }
+        Self {
+            channel_ranges: ranges.clone(),
+            ..ranges[0].clone()
         }
     }
 
+    /// Check if the channels have different ranges, see `per_channel`
+    pub fn is_per_channel(&self) -> bool {
+        !self.channel_ranges.is_empty()
+    }
+
+    /// The range of a single channel
+    pub fn channel(&self, channel: usize) -> ModelValueRange {
+        match self.channel_ranges.get(channel) {
+            Some(range) => range.clone(),
+            None => Self {
+                value_mode: self.value_mode,
+                max_abs_value: self.max_abs_value,
+                channel_ranges: Vec::new(),
+            },
+        }
+    }
+
+    /// Find a value range that fits the measured minimum and maximum of model values
+    ///
+    /// Models usually use one of a few common ranges, e.g. [0,255], so the smallest common range
+    /// that contains the measured values is used. Negative values result in a symmetric range.
+    pub fn fitting(min: f32, max: f32) -> Self {
+        let magnitude = max.max(-min);
+        let max_abs_value = COMMON_MAX_VALUES
+            .iter()
+            .copied()
+            .find(|&common| magnitude <= common)
+            .unwrap_or(magnitude);
+        if min < 0.0 {
+            Self::symmetric(max_abs_value)
+        } else {
+            Self::asymmetric(max_abs_value)
+        }
+    }
+
+    /// The smallest and largest value of the range, of all channels for per channel ranges
+    pub fn bounds(&self) -> (f32, f32) {
+        if self.is_per_channel() {
+            return self
+                .channel_ranges
+                .iter()
+                .map(ModelValueRange::bounds)
+                .fold(
+                    (f32::INFINITY, f32::NEG_INFINITY),
+                    |(min, max), (lower, upper)| (min.min(lower), max.max(upper)),
+                );
+        }
+        match self.value_mode {
+            ModelValueMode::Symmetric => (-self.max_abs_value, self.max_abs_value),
+            ModelValueMode::Asymmetric => (0.0, self.max_abs_value),
+        }
+    }
+
+    /// Check if the measured minimum and maximum of each channel are plausible for its range
+    ///
+    /// Values may exceed the range by half its size, since models do not clamp their output. If
+    /// the values only use a hundredth of the range, the model most likely uses a smaller range.
+    pub fn is_plausible(&self, extremes: &[(f32, f32)]) -> bool {
+        extremes
+            .iter()
+            .enumerate()
+            .all(|(channel, &(min, max))| self.channel(channel).is_plausible_channel(min, max))
+    }
+
+    /// Keep the ranges of the channels with plausible values and fit the others to their values
+    ///
+    /// See `is_plausible` and `fitting`. Dark or low-contrast values also fit a smaller range than
+    /// the one of the model, so this is `None` if the values of a channel that is fitted span less
+    /// than a quarter of the fitting range.
+    pub fn adapted_to(&self, extremes: &[(f32, f32)]) -> Option<Self> {
+        let ranges = extremes
+            .iter()
+            .enumerate()
+            .map(|(channel, &(min, max))| {
+                let range = self.channel(channel);
+                if range.is_plausible_channel(min, max) {
+                    return Some(range);
+                }
+                let fitting = Self::fitting(min, max);
+                let (lower, upper) = fitting.bounds();
+                (max - min >= (upper - lower) / 4.0).then_some(fitting)
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self::per_channel(ranges))
+    }
+
+    /// Like `is_plausible` for a single channel range
+    fn is_plausible_channel(&self, min: f32, max: f32) -> bool {
+        let (lower, upper) = self.bounds();
+        let size = upper - lower;
+        min >= lower - size / 2.0 && max <= upper + size / 2.0 && max - min >= size / 100.0
+    }
+
     /// Transform a single value in the u16 range to a f32 value in the range specified by self
     pub fn pixel_value_to_model(&self, pixel_value: u16) -> f32 {
-        let asymmetric_value = ((pixel_value as f32) / (u16::MAX as f32)) * self.max_abs_value;
+        self.normalized_value_to_model((pixel_value as f32) / (u16::MAX as f32))
+    }
+
+    /// Transform a single value in the [0,1] range to a value in the range specified by self
+    pub fn normalized_value_to_model(&self, value: f32) -> f32 {
+        let asymmetric_value = value * self.max_abs_value;
         match self.value_mode {
             ModelValueMode::Symmetric => (asymmetric_value * 2.0) - self.max_abs_value,
             ModelValueMode::Asymmetric => asymmetric_value,
@@ -55,12 +185,34 @@ impl ModelValueRange {
 
         *model_value /= self.max_abs_value;
     }
+
+    /// Transform data in the [0,1] range to the range of each channel along `channel_axis`
+    pub fn normalized_values_to_model(&self, data: &mut Array3<f32>, channel_axis: Axis) {
+        for (channel, mut values) in data.axis_iter_mut(channel_axis).enumerate() {
+            let range = self.channel(channel);
+            values.mapv_inplace(|v| range.normalized_value_to_model(v));
+        }
+    }
+
+    /// Transform data in the range of each channel along `channel_axis` into the [0,1] range
+    pub fn normalize_model_values(&self, data: &mut Array3<f32>, channel_axis: Axis) {
+        for (channel, mut values) in data.axis_iter_mut(channel_axis).enumerate() {
+            self.channel(channel).normalize_model_value(&mut values);
+        }
+    }
 }
 
 impl FromStr for ModelValueRange {
     type Err = std::num::ParseFloatError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains(',') {
+            return s
+                .split(',')
+                .map(|channel| channel.trim().parse())
+                .collect::<Result<Vec<_>, _>>()
+                .map(ModelValueRange::per_channel);
+        }
         if s.starts_with("+-") {
             s[2..].parse().map(|max| ModelValueRange::symmetric(max))
         } else {
@@ -79,9 +231,116 @@ mod test {
         assert_eq!(parsed, ModelValueRange::symmetric(123.0));
     }
 
+    #[test]
+    fn test_fitting_ranges() {
+        let byte_range = ModelValueRange::asymmetric(255.0);
+        assert!(!byte_range.is_plausible(&[(0.0, 1.0)]));
+        assert!(byte_range.is_plausible(&[(3.0, 250.0)]));
+        assert!(!ModelValueRange::asymmetric(1.0).is_plausible(&[(0.0, 250.0)]));
+
+        assert_eq!(
+            ModelValueRange::fitting(0.01, 0.9),
+            ModelValueRange::asymmetric(1.0)
+        );
+        assert_eq!(ModelValueRange::fitting(0.0, 250.0), byte_range);
+        assert_eq!(
+            ModelValueRange::fitting(-0.8, 0.95),
+            ModelValueRange::symmetric(1.0)
+        );
+        assert_eq!(
+            ModelValueRange::fitting(0.0, 1e6),
+            ModelValueRange::asymmetric(1e6)
+        );
+    }
+
     #[test]
     fn test_parse_asymmetric() {
         let parsed = ModelValueRange::from_str("1000.00").unwrap();
         assert_eq!(parsed, ModelValueRange::asymmetric(1000.0));
     }
+
+    #[test]
+    fn test_parse_per_channel() {
+        let parsed = ModelValueRange::from_str("1.0,1.0,0.5").unwrap();
+        assert_eq!(
+            parsed,
+            ModelValueRange::per_channel([
+                ModelValueRange::asymmetric(1.0),
+                ModelValueRange::asymmetric(1.0),
+                ModelValueRange::asymmetric(0.5)
+            ])
+        );
+        assert!(parsed.is_per_channel());
+        assert_eq!(parsed.channel(2), ModelValueRange::asymmetric(0.5));
+        // Channels without a range of their own use the first one
+        assert_eq!(parsed.channel(3), ModelValueRange::asymmetric(1.0));
+
+        let mixed = ModelValueRange::from_str("+-1, 255,+-2").unwrap();
+        assert_eq!(mixed.channel(0), ModelValueRange::symmetric(1.0));
+        assert_eq!(mixed.channel(1), ModelValueRange::asymmetric(255.0));
+        assert_eq!(mixed.bounds(), (-2.0, 255.0));
+
+        // Equal ranges are the same as a single range
+        assert_eq!(
+            ModelValueRange::from_str("+-1,+-1,+-1").unwrap(),
+            ModelValueRange::symmetric(1.0)
+        );
+        assert!(ModelValueRange::from_str("1.0,,0.5").is_err());
+    }
+
+    #[test]
+    fn test_per_channel_values() {
+        let range = ModelValueRange::per_channel([
+            ModelValueRange::asymmetric(255.0),
+            ModelValueRange::symmetric(1.0),
+            ModelValueRange::asymmetric(1.0),
+        ]);
+        let normalized = Array3::from_shape_fn((2, 2, 3), |(y, x, _)| (y * 2 + x) as f32 / 3.0);
+        let mut data = normalized.clone();
+        range.normalized_values_to_model(&mut data, Axis(2));
+        assert_eq!(data[(1, 1, 0)], 255.0);
+        assert_eq!(data[(0, 0, 1)], -1.0);
+        assert_eq!(data[(1, 1, 2)], 1.0);
+
+        // CxHxW data has the channels along the first axis
+        let mut planar = data.clone().permuted_axes([2, 0, 1]);
+        range.normalize_model_values(&mut planar, Axis(0));
+        range.normalize_model_values(&mut data, Axis(2));
+        assert_eq!(planar.permuted_axes([1, 2, 0]), data);
+        assert!(data
+            .iter()
+            .zip(normalized.iter())
+            .all(|(a, b)| (a - b).abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_per_channel_plausibility() {
+        let range = ModelValueRange::per_channel([
+            ModelValueRange::asymmetric(255.0),
+            ModelValueRange::symmetric(1.0),
+            ModelValueRange::asymmetric(1.0),
+        ]);
+        // Each channel is compared to its own range, not to the bounds of all channels
+        assert!(range.is_plausible(&[(3.0, 250.0), (-0.9, 0.8), (0.1, 0.9)]));
+        assert!(!range.is_plausible(&[(3.0, 250.0), (-0.9, 0.8), (3.0, 250.0)]));
+        assert!(!range.is_plausible(&[(0.1, 0.9), (-0.9, 0.8), (0.1, 0.9)]));
+
+        assert_eq!(
+            range.adapted_to(&[(3.0, 250.0), (-0.9, 0.8), (3.0, 250.0)]),
+            Some(ModelValueRange::per_channel([
+                ModelValueRange::asymmetric(255.0),
+                ModelValueRange::symmetric(1.0),
+                ModelValueRange::asymmetric(255.0),
+            ]))
+        );
+        assert_eq!(
+            ModelValueRange::asymmetric(1.0).adapted_to(&[(3.0, 250.0); 3]),
+            Some(ModelValueRange::asymmetric(255.0))
+        );
+        // Dark values of a [0, 255] model also fit [0, 1], they do not tell the range
+        assert_eq!(
+            ModelValueRange::asymmetric(255.0).adapted_to(&[(0.5, 0.65); 3]),
+            None
+        );
+    }
 }
@@ -0,0 +1,398 @@
+use std::str::FromStr;
+
+use ndarray::{Array3, Axis};
+use thiserror::Error;
+
+use crate::image_processor::{AutoLevel, ChannelAdjustment, ImageProcessingError};
+use crate::image_tensor::BAYER_4X4;
+
+#[derive(Debug, Error)]
+pub enum PostProcessParseError {
+    #[error(
+        "Post-process {0} not known, must be one of (sharpen, autolevel, gain, offset, reinhard, aces, dither)"
+    )]
+    Unknown(String),
+    #[error("The parameters of the post-process {0} are not valid")]
+    InvalidParameters(String),
+}
+
+/// A step of the post-processing chain, applied to the HxWxC output with values in [0,1]
+///
+/// The steps are parsed from strings like `sharpen:0.5,1.0`, with the parameters after the
+/// colon. Parameters can be left out for all steps except `gain` and `offset`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PostProcess {
+    /// An unsharp mask, adds `amount` times the difference to a Gaussian blur of `radius` pixels
+    Sharpen {
+        amount: f32,
+        radius: f32,
+    },
+    AutoLevel(AutoLevel),
+    ChannelAdjustment(ChannelAdjustment),
+    /// Compress the values above `knee` into the range up to one with a tone mapping operator,
+    /// instead of clamping them when the output is quantized
+    ToneMap {
+        operator: ToneMap,
+        knee: f32,
+    },
+    /// Round to `bits` bits per channel with an ordered dither, which avoids banding when the
+    /// output is saved with that many bits
+    Dither {
+        bits: u32,
+    },
+}
+
+impl PostProcess {
+    /// Whether the step only depends on each pixel and its position, so that it gives the same
+    /// result when it is applied to parts of an image
+    pub fn is_pixelwise(&self) -> bool {
+        match self {
+            PostProcess::Sharpen { .. } | PostProcess::AutoLevel(_) => false,
+            PostProcess::ChannelAdjustment(_)
+            | PostProcess::ToneMap { .. }
+            | PostProcess::Dither { .. } => true,
+        }
+    }
+
+    pub fn apply(&self, data: &mut Array3<f32>) -> Result<(), ImageProcessingError> {
+        match self {
+            PostProcess::Sharpen { amount, radius } => sharpen(data, *amount, *radius),
+            PostProcess::AutoLevel(auto_level) => auto_level.apply(data),
+            PostProcess::ChannelAdjustment(adjustment) => adjustment.apply(data)?,
+            PostProcess::ToneMap { operator, knee } => operator.apply(data, *knee),
+            PostProcess::Dither { bits } => dither(data, *bits),
+        }
+        Ok(())
+    }
+}
+
+/// Apply the steps of a chain in order, an empty chain does not change the data
+pub fn apply_chain(
+    chain: &[PostProcess],
+    data: &mut Array3<f32>,
+) -> Result<(), ImageProcessingError> {
+    for step in chain {
+        step.apply(data)?;
+    }
+    Ok(())
+}
+
+/// The value above which `PostProcess::ToneMap` compresses the output if no knee is given
+pub const DEFAULT_TONE_MAP_KNEE: f32 = 0.8;
+
+/// A tone mapping operator for outputs with values above one, see `PostProcess::ToneMap`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ToneMap {
+    /// The Reinhard operator x / (1 + x), a soft roll-off
+    Reinhard,
+    /// The shoulder of the filmic ACES curve fit of Narkowicz, which saturates earlier
+    Aces,
+}
+
+impl ToneMap {
+    /// Compress the values above `knee` into the range from `knee` to one with the shoulder of
+    /// the operator, which keeps detail in the highlights
+    ///
+    /// Values up to the knee are not changed, the knee must be in [0,1).
+    pub fn apply(&self, data: &mut Array3<f32>, knee: f32) {
+        let shoulder: fn(f32) -> f32 = match self {
+            ToneMap::Reinhard => |t| t / (1.0 + t),
+            ToneMap::Aces => aces_shoulder,
+        };
+        let headroom = 1.0 - knee;
+        data.mapv_inplace(|v| {
+            if v > knee {
+                // Limit the argument, so that infinite values map to one instead of NaN
+                let t = ((v - knee) / headroom).min(1e6);
+                knee + headroom * shoulder(t)
+            } else {
+                v
+            }
+        });
+    }
+}
+
+/// The shoulder of the ACES curve fit of Narkowicz (2015), scaled to a slope of one at zero and
+/// a limit of one
+///
+/// The curve starts with a toe that is steeper than one, so only its part after the steepest
+/// point is used. This keeps the mapped values below the input values.
+fn aces_shoulder(t: f32) -> f32 {
+    const A: f32 = 2.51;
+    const B: f32 = 0.03;
+    const C: f32 = 2.43;
+    const D: f32 = 0.59;
+    const E: f32 = 0.14;
+    // The steepest point of the curve
+    const START: f32 = 0.1203;
+    let numerator = |x: f32| x * (A * x + B);
+    let denominator = |x: f32| x * (C * x + D) + E;
+    let curve = |x: f32| numerator(x) / denominator(x);
+    let slope = ((2.0 * A * START + B) * denominator(START)
+        - numerator(START) * (2.0 * C * START + D))
+        / denominator(START).powi(2);
+    let range = A / C - curve(START);
+    (curve(START + t * range / slope) - curve(START)) / range
+}
+
+impl FromStr for PostProcess {
+    type Err = PostProcessParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, parameters) = match s.split_once(':') {
+            Some((name, parameters)) => (name, Some(parameters)),
+            None => (s, None),
+        };
+        let invalid = || PostProcessParseError::InvalidParameters(s.to_owned());
+        let values = match parameters {
+            Some(parameters) => parameters
+                .split(',')
+                .map(|value| value.trim().parse::<f32>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| invalid())?,
+            None => Vec::new(),
+        };
+
+        Ok(match (name.trim().to_lowercase().as_str(), &values[..]) {
+            ("sharpen", []) => PostProcess::Sharpen {
+                amount: 0.5,
+                radius: 1.0,
+            },
+            ("sharpen", &[amount, radius]) if radius > 0.0 => {
+                PostProcess::Sharpen { amount, radius }
+            }
+            ("autolevel", []) => PostProcess::AutoLevel(AutoLevel::default()),
+            ("autolevel", &[low, high]) if 0.0 <= low && low < high && high <= 100.0 => {
+                PostProcess::AutoLevel(AutoLevel {
+                    low_percentile: low,
+                    high_percentile: high,
+                })
+            }
+            ("gain", gain) if !gain.is_empty() => {
+                PostProcess::ChannelAdjustment(ChannelAdjustment {
+                    offset: vec![0.0; gain.len()],
+                    gain: gain.to_vec(),
+                })
+            }
+            ("offset", offset) if !offset.is_empty() => {
+                PostProcess::ChannelAdjustment(ChannelAdjustment {
+                    gain: vec![1.0; offset.len()],
+                    offset: offset.to_vec(),
+                })
+            }
+            (operator @ ("reinhard" | "aces"), []) => PostProcess::ToneMap {
+                operator: tone_map_operator(operator),
+                knee: DEFAULT_TONE_MAP_KNEE,
+            },
+            (operator @ ("reinhard" | "aces"), &[knee]) if (0.0..1.0).contains(&knee) => {
+                PostProcess::ToneMap {
+                    operator: tone_map_operator(operator),
+                    knee,
+                }
+            }
+            ("dither", []) => PostProcess::Dither { bits: 8 },
+            ("dither", &[bits]) if bits.fract() == 0.0 && (1.0..=16.0).contains(&bits) => {
+                PostProcess::Dither { bits: bits as u32 }
+            }
+            ("sharpen" | "autolevel" | "gain" | "offset" | "reinhard" | "aces" | "dither", _) => {
+                return Err(invalid())
+            }
+            _ => return Err(PostProcessParseError::Unknown(name.to_owned())),
+        })
+    }
+}
+
+/// The tone mapping operator of a lowercase post-process step name, reinhard or aces
+fn tone_map_operator(name: &str) -> ToneMap {
+    match name {
+        "aces" => ToneMap::Aces,
+        _ => ToneMap::Reinhard,
+    }
+}
+
+fn sharpen(data: &mut Array3<f32>, amount: f32, radius: f32) {
+    let blurred = gaussian_blur(data, radius);
+    data.zip_mut_with(&blurred, |v, &blurred| *v += amount * (*v - blurred));
+}
+
+/// Blur each channel with a Gaussian of standard deviation `sigma`, the borders are extended
+fn gaussian_blur(data: &Array3<f32>, sigma: f32) -> Array3<f32> {
+    let reach = (3.0 * sigma).ceil() as isize;
+    let kernel: Vec<f32> = (-reach..=reach)
+        .map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let sum: f32 = kernel.iter().sum();
+    let kernel: Vec<f32> = kernel.iter().map(|w| w / sum).collect();
+
+    let mut blurred = data.clone();
+    for axis in [Axis(0), Axis(1)] {
+        let source = blurred.clone();
+        let len = source.len_of(axis) as isize;
+        for (mut target, source) in blurred.lanes_mut(axis).into_iter().zip(source.lanes(axis)) {
+            for (i, target) in target.iter_mut().enumerate() {
+                *target = kernel
+                    .iter()
+                    .enumerate()
+                    .map(|(k, w)| {
+                        w * source[(i as isize + k as isize - reach).clamp(0, len - 1) as usize]
+                    })
+                    .sum();
+            }
+        }
+    }
+    blurred
+}
+
+fn dither(data: &mut Array3<f32>, bits: u32) {
+    let levels = ((1u32 << bits) - 1) as f32;
+    for ((y, x, _), v) in data.indexed_iter_mut() {
+        // Shift the rounding threshold by less than half a step, the shifts average to zero
+        let threshold = BAYER_4X4[y % 4][x % 4] as f32;
+        let offset = (threshold + 0.5) / 16.0 - 0.5;
+        *v = (*v * levels + offset).round().clamp(0.0, levels) / levels;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn gradient() -> Array3<f32> {
+        Array3::from_shape_fn((16, 20, 3), |(y, x, c)| {
+            (x + y + c) as f32 / (16 + 20 + 3) as f32
+        })
+    }
+
+    #[test]
+    fn test_chain_order() {
+        let mut unchanged = gradient();
+        apply_chain(&[], &mut unchanged).unwrap();
+        assert_eq!(unchanged, gradient());
+
+        let gain: PostProcess = "gain:2,2,2".parse().unwrap();
+        let offset: PostProcess = "offset:-0.5,-0.5,-0.5".parse().unwrap();
+        let mut output = gradient();
+        apply_chain(&[gain.clone(), offset.clone()], &mut output).unwrap();
+        assert_eq!(output, gradient().mapv(|v| 2.0 * v - 0.5));
+        let mut output = gradient();
+        apply_chain(&[offset, gain], &mut output).unwrap();
+        assert_eq!(output, gradient().mapv(|v| 2.0 * (v - 0.5)));
+    }
+
+    #[test]
+    fn test_parse_post_process() {
+        assert_eq!(
+            "sharpen:0.5,2".parse::<PostProcess>().unwrap(),
+            PostProcess::Sharpen {
+                amount: 0.5,
+                radius: 2.0
+            }
+        );
+        assert_eq!(
+            "AutoLevel".parse::<PostProcess>().unwrap(),
+            PostProcess::AutoLevel(AutoLevel::default())
+        );
+        assert_eq!(
+            "dither".parse::<PostProcess>().unwrap(),
+            PostProcess::Dither { bits: 8 }
+        );
+        assert!(matches!(
+            "autolevel:99,1".parse::<PostProcess>(),
+            Err(PostProcessParseError::InvalidParameters(_))
+        ));
+        assert!(matches!(
+            "blur:1".parse::<PostProcess>(),
+            Err(PostProcessParseError::Unknown(_))
+        ));
+    }
+
+    #[test]
+    fn test_sharpen_and_dither() {
+        // Sharpening does not change flat regions
+        let mut flat = Array3::from_elem((8, 8, 3), 0.25);
+        PostProcess::Sharpen {
+            amount: 1.0,
+            radius: 1.0,
+        }
+        .apply(&mut flat)
+        .unwrap();
+        assert!(flat.iter().all(|v| (v - 0.25).abs() < 1e-6));
+
+        let mut dithered = gradient();
+        PostProcess::Dither { bits: 8 }
+            .apply(&mut dithered)
+            .unwrap();
+        assert!(dithered.iter().zip(gradient().iter()).all(|(a, b)| {
+            (a * 255.0 - (a * 255.0).round()).abs() < 1e-3 && (a - b).abs() <= 1.0 / 255.0
+        }));
+    }
+
+    #[test]
+    fn test_tone_map() {
+        let values = Array3::from_shape_fn((1, 201, 1), |(_, x, _)| x as f32 / 50.0);
+        // The shoulders at the knee, half way to one and at twice the headroom
+        for (operator, shoulders) in [
+            (ToneMap::Reinhard, [0.0, 1.0 / 3.0, 0.5]),
+            (ToneMap::Aces, [0.0, 0.3944, 0.5891]),
+        ] {
+            for knee in [DEFAULT_TONE_MAP_KNEE, 0.5] {
+                let headroom = 1.0 - knee;
+                let mut mapped = values.clone();
+                PostProcess::ToneMap { operator, knee }
+                    .apply(&mut mapped)
+                    .unwrap();
+                for (value, mapped) in values.iter().zip(mapped.iter()) {
+                    if *value <= knee {
+                        assert_eq!(value, mapped);
+                    } else {
+                        assert!(knee < *mapped && mapped < value && *mapped < 1.0);
+                    }
+                }
+                for (t, shoulder) in [0.0, 0.5, 1.0].into_iter().zip(shoulders) {
+                    let mut value = Array3::from_elem((1, 1, 1), knee + t * headroom);
+                    operator.apply(&mut value, knee);
+                    let expected = knee + headroom * shoulder;
+                    assert!(
+                        (value[(0, 0, 0)] - expected).abs() < 1e-4,
+                        "{:?} with knee {} maps {} to {} instead of {}",
+                        operator,
+                        knee,
+                        knee + t * headroom,
+                        value[(0, 0, 0)],
+                        expected
+                    );
+                }
+                // The highlights keep their order
+                assert!(mapped
+                    .windows((1, 2, 1))
+                    .into_iter()
+                    .all(|w| w[(0, 0, 0)] < w[(0, 1, 0)]));
+
+                let mut infinite = Array3::from_elem((1, 1, 1), f32::INFINITY);
+                operator.apply(&mut infinite, knee);
+                assert!((infinite[(0, 0, 0)] - 1.0).abs() < 1e-3);
+            }
+        }
+
+        assert_eq!(
+            "aces:0.9".parse::<PostProcess>().unwrap(),
+            PostProcess::ToneMap {
+                operator: ToneMap::Aces,
+                knee: 0.9
+            }
+        );
+        assert_eq!(
+            "Reinhard".parse::<PostProcess>().unwrap(),
+            PostProcess::ToneMap {
+                operator: ToneMap::Reinhard,
+                knee: DEFAULT_TONE_MAP_KNEE
+            }
+        );
+        assert!(matches!(
+            "reinhard:1".parse::<PostProcess>(),
+            Err(PostProcessParseError::InvalidParameters(_))
+        ));
+    }
+}
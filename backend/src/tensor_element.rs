@@ -0,0 +1,84 @@
+use ndarray::{Array3, ArrayView3, ArrayViewMut3, CowArray, Ix3, Zip};
+use num_traits::{FromPrimitive, Num};
+use std::fmt::Debug;
+
+/// Element types that can be used to hold image data while it is processed
+///
+/// Models always work on `f32` data, other types are converted at the model boundary.
+pub trait TensorElement: Copy + Debug + Num + FromPrimitive + PartialOrd + 'static {
+    fn from_f32(value: f32) -> Self;
+
+    fn to_f32(self) -> f32;
+
+    /// Convert an array to `f32`, this avoids a copy if the array already is `f32`
+    fn array_to_f32(array: Array3<Self>) -> Array3<f32> {
+        array.mapv(Self::to_f32)
+    }
+
+    /// Convert a view to `f32`, this avoids a copy if the view already is `f32`
+    fn view_to_f32(view: ArrayView3<Self>) -> CowArray<f32, Ix3> {
+        CowArray::from(view.mapv(Self::to_f32))
+    }
+
+    /// Add `f32` values to an array of this type
+    fn accumulate(mut target: ArrayViewMut3<Self>, values: ArrayView3<f32>) {
+        Zip::from(&mut target)
+            .and(values)
+            .for_each(|t, &v| *t = Self::from_f32(t.to_f32() + v));
+    }
+
+    /// Replace values in an array if the new `f32` value has a larger magnitude
+    fn accumulate_max(mut target: ArrayViewMut3<Self>, values: ArrayView3<f32>) {
+        Zip::from(&mut target).and(values).for_each(|t, &v| {
+            if v.abs() > t.to_f32().abs() {
+                *t = Self::from_f32(v);
+            }
+        });
+    }
+}
+
+impl TensorElement for f32 {
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn array_to_f32(array: Array3<Self>) -> Array3<f32> {
+        array
+    }
+
+    fn view_to_f32(view: ArrayView3<Self>) -> CowArray<f32, Ix3> {
+        CowArray::from(view)
+    }
+}
+
+impl TensorElement for f64 {
+    fn from_f32(value: f32) -> Self {
+        value as f64
+    }
+
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn accumulate(mut target: ArrayViewMut3<Self>, values: ArrayView3<f32>) {
+        // Add in double precision instead of converting the sum to f32 for every value
+        Zip::from(&mut target)
+            .and(values)
+            .for_each(|t, &v| *t += v as f64);
+    }
+}
+
+#[cfg(feature = "half")]
+impl TensorElement for half::f16 {
+    fn from_f32(value: f32) -> Self {
+        half::f16::from_f32(value)
+    }
+
+    fn to_f32(self) -> f32 {
+        half::f16::to_f32(self)
+    }
+}
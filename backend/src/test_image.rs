@@ -0,0 +1,109 @@
+use image::{ImageBuffer, Rgb};
+use protobuf::Message;
+use wonnx::utils::{graph, model, node, tensor};
+
+/// The content of an image created by `test_image`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestPattern {
+    /// Red increases to the right, green to the bottom and blue along the diagonal
+    Gradient,
+    /// Dark and bright gray squares of 8x8 pixels
+    Checkerboard,
+    /// Uniform pseudo random noise, the same for every call
+    Noise,
+}
+
+/// The edge length of the checkerboard squares
+const CHECKERBOARD_SQUARE: u32 = 8;
+
+/// Create a deterministic image, e.g. to check a setup end to end without an image file
+pub fn test_image(
+    width: u32,
+    height: u32,
+    pattern: TestPattern,
+) -> ImageBuffer<Rgb<u16>, Vec<u16>> {
+    // Map 0..size to the full value range
+    let scale =
+        |value: u32, size: u32| (value as u64 * u16::MAX as u64 / (size.max(2) - 1) as u64) as u16;
+    match pattern {
+        TestPattern::Gradient => ImageBuffer::from_fn(width, height, |x, y| {
+            Rgb([
+                scale(x, width),
+                scale(y, height),
+                scale(x + y, width + height - 1),
+            ])
+        }),
+        TestPattern::Checkerboard => ImageBuffer::from_fn(width, height, |x, y| {
+            let bright = (x / CHECKERBOARD_SQUARE + y / CHECKERBOARD_SQUARE) % 2 == 1;
+            let value = if bright {
+                3 * (u16::MAX / 4)
+            } else {
+                u16::MAX / 4
+            };
+            Rgb([value; 3])
+        }),
+        TestPattern::Noise => {
+            // A xorshift generator with a fixed seed
+            let mut state = 0x2545_f491u32;
+            ImageBuffer::from_fn(width, height, |_, _| {
+                Rgb([(); 3].map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    (state >> 16) as u16
+                }))
+            })
+        }
+    }
+}
+
+/// An NCHW identity model with a fixed input size of 32x32, to check a setup without a model file
+pub fn identity_model_bytes() -> Vec<u8> {
+    identity_model_bytes_with_channels(3)
+}
+
+/// An NCHW identity model with a fixed input size of 32x32 and the given channel count
+pub fn identity_model_bytes_with_channels(channels: i64) -> Vec<u8> {
+    let model = model(graph(
+        vec![tensor("input", &[1, channels, 32, 32])],
+        vec![tensor("output", &[1, channels, 32, 32])],
+        vec![],
+        vec![],
+        vec![node(
+            vec!["input"],
+            vec!["output"],
+            "identity",
+            "Identity",
+            vec![],
+        )],
+    ));
+    model.write_to_bytes().unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_patterns_are_deterministic() {
+        for pattern in [
+            TestPattern::Gradient,
+            TestPattern::Checkerboard,
+            TestPattern::Noise,
+        ] {
+            let image = test_image(37, 21, pattern);
+            assert_eq!(image.dimensions(), (37, 21));
+            assert_eq!(image, test_image(37, 21, pattern));
+            // Every pattern has some structure
+            assert!(image.pixels().any(|pixel| pixel != image.get_pixel(0, 0)));
+        }
+
+        let gradient = test_image(64, 32, TestPattern::Gradient);
+        assert_eq!(gradient.get_pixel(0, 0), &Rgb([0, 0, 0]));
+        assert_eq!(gradient.get_pixel(63, 31), &Rgb([u16::MAX; 3]));
+        let checkerboard = test_image(16, 16, TestPattern::Checkerboard);
+        assert_ne!(checkerboard.get_pixel(0, 0), checkerboard.get_pixel(8, 0));
+        assert_eq!(checkerboard.get_pixel(0, 0), checkerboard.get_pixel(8, 8));
+        assert_eq!(test_image(0, 0, TestPattern::Noise).dimensions(), (0, 0));
+    }
+}
@@ -0,0 +1,249 @@
+use std::fs::File;
+use std::io::{BufWriter, Cursor};
+use std::path::Path;
+
+use anyhow::Context;
+use backend::image_processor::ImageProcessor;
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::png::PngDecoder;
+use image::{AnimationDecoder, DynamicImage, Frame, Frames, ImageFormat, RgbaImage};
+
+/// The animation formats that can be processed frame by frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AnimationFormat {
+    Gif,
+    Apng,
+}
+
+/// Check if a file is an animated GIF or an APNG
+///
+/// GIFs are only considered animated if they have more than one frame.
+pub fn is_animated<P: AsRef<Path>>(path: P) -> anyhow::Result<bool> {
+    let path = path.as_ref();
+    Ok(match ImageFormat::from_path(path) {
+        Ok(ImageFormat::Gif) => {
+            let data = read(path)?;
+            GifDecoder::new(Cursor::new(&data))?
+                .into_frames()
+                .take(2)
+                .count()
+                > 1
+        }
+        Ok(ImageFormat::Png) => PngDecoder::new(File::open(path)?)?.is_apng(),
+        _ => false,
+    })
+}
+
+/// Process all frames of an animated GIF or APNG and save them as an animation of the same
+/// format, keeping the frame delays, the loop count and the alpha channel
+///
+/// Frames are decoded, processed and encoded one at a time, so only a single frame is held in
+/// memory. Returns the number of processed frames.
+pub async fn process_animation<P: AsRef<Path>, Q: AsRef<Path>>(
+    processor: &mut ImageProcessor,
+    input_path: P,
+    output_path: Q,
+    passes: usize,
+) -> anyhow::Result<usize> {
+    let (input_path, output_path) = (input_path.as_ref(), output_path.as_ref());
+    if processor.output_scale() != 1 {
+        anyhow::bail!("Animations can only be processed at their own resolution");
+    }
+    let format = animation_format(input_path)?;
+    if animation_format(output_path)? != format {
+        anyhow::bail!(
+            "The output of an animated {:?} must use the same format",
+            format
+        );
+    }
+
+    let data = read(input_path)?;
+    let output = BufWriter::new(
+        File::create(output_path)
+            .with_context(|| format!("Could not create {}", output_path.display()))?,
+    );
+    match format {
+        AnimationFormat::Gif => {
+            let frames = GifDecoder::new(Cursor::new(&data))?.into_frames();
+            let mut encoder = GifEncoder::new(output);
+            if let Some(repeat) = gif_repeat(&data)? {
+                encoder.set_repeat(repeat)?;
+            }
+            process_frames(processor, frames, passes, |frame| {
+                Ok(encoder.encode_frame(frame)?)
+            })
+            .await
+        }
+        AnimationFormat::Apng => {
+            let (num_frames, num_plays) = png::Decoder::new(Cursor::new(&data))
+                .read_info()?
+                .info()
+                .animation_control
+                .as_ref()
+                .map(|control| (control.num_frames, control.num_plays))
+                .context("The PNG file is not animated")?;
+            let frames = PngDecoder::new(Cursor::new(&data))?.apng().into_frames();
+            let (width, height) = image::image_dimensions(input_path)?;
+            let mut encoder = png::Encoder::new(output, width, height);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_animated(num_frames, num_plays)?;
+            let mut writer = encoder.write_header()?;
+            let frame_count = process_frames(processor, frames, passes, |frame| {
+                let (numerator, denominator) = frame.delay().numer_denom_ms();
+                let delay_ms = (numerator as f64 / denominator as f64).round();
+                writer.set_frame_delay(delay_ms.min(u16::MAX as f64) as u16, 1000)?;
+                writer.write_image_data(frame.buffer().as_raw())?;
+                Ok(())
+            })
+            .await?;
+            writer.finish()?;
+            Ok(frame_count)
+        }
+    }
+}
+
+async fn process_frames(
+    processor: &mut ImageProcessor,
+    frames: Frames<'_>,
+    passes: usize,
+    mut write_frame: impl FnMut(Frame) -> anyhow::Result<()>,
+) -> anyhow::Result<usize> {
+    let mut frame_count = 0;
+    for frame in frames {
+        let frame = frame?;
+        log::info!("Processing frame {}", frame_count);
+        let (left, top, delay) = (frame.left(), frame.top(), frame.delay());
+        let input = frame.into_buffer();
+        let output = processor
+            .process_image_passes(DynamicImage::ImageRgba8(input.clone()).into_rgb16(), passes)
+            .await?;
+        let mut output = DynamicImage::ImageRgb16(output).into_rgba8();
+        copy_alpha(&input, &mut output);
+        write_frame(Frame::from_parts(output, left, top, delay))?;
+        frame_count += 1;
+    }
+    Ok(frame_count)
+}
+
+/// Copy the alpha channel of the unprocessed frame, the model only processes color data
+fn copy_alpha(source: &RgbaImage, destination: &mut RgbaImage) {
+    for (source, destination) in source.pixels().zip(destination.pixels_mut()) {
+        destination[3] = source[3];
+    }
+}
+
+fn animation_format(path: &Path) -> anyhow::Result<AnimationFormat> {
+    match ImageFormat::from_path(path)? {
+        ImageFormat::Gif => Ok(AnimationFormat::Gif),
+        ImageFormat::Png => Ok(AnimationFormat::Apng),
+        _ => anyhow::bail!("{} is not a GIF or PNG file", path.display()),
+    }
+}
+
+fn read(path: &Path) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("Could not read {}", path.display()))
+}
+
+/// The loop count of a GIF from its NETSCAPE2.0 application extension
+///
+/// The extension may follow any frame, so the decoder reads all of them. GIFs without this
+/// extension are played once.
+fn gif_repeat(data: &[u8]) -> anyhow::Result<Option<Repeat>> {
+    let mut decoder = gif::DecodeOptions::new().read_info(Cursor::new(data))?;
+    while decoder.next_frame_info()?.is_some() {}
+    Ok(match decoder.repeat() {
+        gif::Repeat::Infinite => Some(Repeat::Infinite),
+        gif::Repeat::Finite(0) => None,
+        gif::Repeat::Finite(count) => Some(Repeat::Finite(count)),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::identity_processor;
+    use image::{Delay, Rgba};
+
+    fn frame(index: u32, delay_ms: u32) -> Frame {
+        let buffer = RgbaImage::from_fn(40, 30, |x, y| {
+            let alpha = if x < 5 { 0 } else { 255 };
+            Rgba([(x * 6) as u8, (y * 8) as u8, (index * 80) as u8, alpha])
+        });
+        Frame::from_parts(buffer, 0, 0, Delay::from_numer_denom_ms(delay_ms, 1))
+    }
+
+    fn delays(path: &Path) -> Vec<(u32, u32)> {
+        GifDecoder::new(File::open(path).unwrap())
+            .unwrap()
+            .into_frames()
+            .map(|frame| frame.unwrap().delay().numer_denom_ms())
+            .collect()
+    }
+
+    #[test]
+    fn test_process_animated_gif() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.gif");
+        let output = dir.path().join("output.gif");
+        {
+            let mut encoder = GifEncoder::new(File::create(&input).unwrap());
+            encoder.set_repeat(Repeat::Finite(3)).unwrap();
+            for (index, delay) in [100, 200, 300].into_iter().enumerate() {
+                encoder.encode_frame(frame(index as u32, delay)).unwrap();
+            }
+        }
+        let mut processor = identity_processor();
+
+        assert!(is_animated(&input).unwrap());
+        let frame_count =
+            pollster::block_on(process_animation(&mut processor, &input, &output, 1)).unwrap();
+
+        assert_eq!(frame_count, 3);
+        assert_eq!(delays(&output), delays(&input));
+        assert_eq!(
+            gif_repeat(&std::fs::read(&output).unwrap()).unwrap(),
+            Some(Repeat::Finite(3))
+        );
+    }
+
+    #[test]
+    fn test_gif_repeat() {
+        let encode = |repeat: Option<Repeat>| {
+            let mut data = Vec::new();
+            {
+                let mut encoder = GifEncoder::new(&mut data);
+                if let Some(repeat) = repeat {
+                    encoder.set_repeat(repeat).unwrap();
+                }
+                encoder.encode_frame(frame(0, 100)).unwrap();
+            }
+            data
+        };
+        assert_eq!(gif_repeat(&encode(None)).unwrap(), None);
+        assert_eq!(
+            gif_repeat(&encode(Some(Repeat::Infinite))).unwrap(),
+            Some(Repeat::Infinite)
+        );
+        assert_eq!(
+            gif_repeat(&encode(Some(Repeat::Finite(261)))).unwrap(),
+            Some(Repeat::Finite(261))
+        );
+
+        // The extension after the first frame is found as well
+        let mut data = Vec::new();
+        {
+            let mut encoder =
+                gif::Encoder::new(&mut data, 2, 2, &[0, 0, 0, 255, 255, 255]).unwrap();
+            let frame = gif::Frame {
+                width: 2,
+                height: 2,
+                buffer: std::borrow::Cow::Borrowed(&[0, 1, 1, 0]),
+                ..Default::default()
+            };
+            encoder.write_frame(&frame).unwrap();
+            encoder.set_repeat(gif::Repeat::Finite(5)).unwrap();
+        }
+        assert_eq!(gif_repeat(&data).unwrap(), Some(Repeat::Finite(5)));
+    }
+}
@@ -0,0 +1,259 @@
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use anyhow::Context;
+
+/// The order in which the files of a batch are processed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputOrder {
+    /// By file name, numbers in names are compared by value, so "2.png" comes before "10.png"
+    Name,
+    /// Oldest modification time first
+    Mtime,
+    /// Smallest file first
+    Size,
+}
+
+impl FromStr for InputOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_ref() {
+            "name" => InputOrder::Name,
+            "mtime" => InputOrder::Mtime,
+            "size" => InputOrder::Size,
+            _ => anyhow::bail!(
+                "Sort order {} not known, must be one of (name, mtime, size)",
+                s
+            ),
+        })
+    }
+}
+
+/// Find the files of a batch input directory in the given order
+///
+/// Files that compare equal, e.g. with the same size, are ordered by name, so the order is the
+/// same for every run. With a `pattern`, only files whose name matches it are returned, see
+/// `matches_pattern`. With a `limit`, only the first `limit` files are returned, so that a test
+/// run on a large directory always picks the same files.
+pub fn collect_inputs(
+    input_dir: &Path,
+    pattern: Option<&str>,
+    order: InputOrder,
+    limit: Option<usize>,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut inputs = input_dir
+        .read_dir()
+        .with_context(|| format!("Could not read the directory {}", input_dir.display()))?
+        .filter_map(|maybe_entry| maybe_entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .filter(|path| {
+            pattern.map_or(true, |pattern| {
+                matches_pattern(
+                    pattern,
+                    &path.file_name().unwrap_or_default().to_string_lossy(),
+                )
+            })
+        })
+        .map(|path| {
+            let metadata = path
+                .metadata()
+                .with_context(|| format!("Could not read the metadata of {}", path.display()))?;
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            Ok((path, modified, metadata.len()))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    inputs.sort_by(|(a, a_modified, a_size), (b, b_modified, b_size)| {
+        let by_name = || natural_cmp(&a.to_string_lossy(), &b.to_string_lossy());
+        match order {
+            InputOrder::Name => by_name(),
+            InputOrder::Mtime => a_modified.cmp(b_modified).then_with(by_name),
+            InputOrder::Size => a_size.cmp(b_size).then_with(by_name),
+        }
+    });
+    if let Some(limit) = limit {
+        inputs.truncate(limit);
+    }
+    Ok(inputs.into_iter().map(|(path, _, _)| path).collect())
+}
+
+/// Check if a file name matches a glob pattern like "*.tif" or "IMG_????.jpg"
+///
+/// `*` matches any number of characters and `?` matches a single character. Other characters
+/// have to match exactly.
+pub fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    // The position after the last `*` and the name position it was tried at
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` match one more character
+                Some((star_p, star_n)) => {
+                    backtrack = Some((star_p, star_n + 1));
+                    p = star_p;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Compare strings with runs of digits compared by their value
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        let (a_digits, b_digits) = (leading_digits(a), leading_digits(b));
+        let ordering = if !a_digits.is_empty() && !b_digits.is_empty() {
+            let (a_value, b_value) = (
+                a_digits.trim_start_matches('0'),
+                b_digits.trim_start_matches('0'),
+            );
+            a = &a[a_digits.len()..];
+            b = &b[b_digits.len()..];
+            a_value
+                .len()
+                .cmp(&b_value.len())
+                .then_with(|| a_value.cmp(b_value))
+                // "01" and "1" only differ in their zeros, keep an order between them
+                .then_with(|| a_digits.cmp(b_digits))
+        } else {
+            let mut a_chars = a.chars();
+            let mut b_chars = b.chars();
+            let ordering = a_chars.next().cmp(&b_chars.next());
+            if ordering == Ordering::Equal && a.is_empty() {
+                return Ordering::Equal;
+            }
+            a = a_chars.as_str();
+            b = b_chars.as_str();
+            ordering
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+fn leading_digits(s: &str) -> &str {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    &s[..end]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+    use std::time::Duration;
+
+    #[test]
+    fn test_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in (0..10).rev() {
+            std::fs::write(dir.path().join(format!("{}.png", i)), "").unwrap();
+        }
+        std::fs::create_dir(dir.path().join("subdir")).unwrap();
+
+        assert_eq!(
+            collect_inputs(dir.path(), None, InputOrder::Name, None)
+                .unwrap()
+                .len(),
+            10
+        );
+        let limited = collect_inputs(dir.path(), None, InputOrder::Name, Some(3)).unwrap();
+        assert_eq!(
+            limited,
+            ["0.png", "1.png", "2.png"]
+                .iter()
+                .map(|name| dir.path().join(name))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            collect_inputs(dir.path(), None, InputOrder::Name, Some(3)).unwrap(),
+            limited
+        );
+    }
+
+    #[test]
+    fn test_input_order() {
+        let dir = tempfile::tempdir().unwrap();
+        // (name, size, age in seconds)
+        let files = [
+            ("img10.png", 1, 30),
+            ("img2.png", 3, 10),
+            ("img1.png", 2, 20),
+        ];
+        for (name, size, age) in files {
+            let file = File::create(dir.path().join(name)).unwrap();
+            file.set_len(size).unwrap();
+            file.set_modified(SystemTime::now() - Duration::from_secs(age))
+                .unwrap();
+        }
+        let names = |order| {
+            collect_inputs(dir.path(), None, order, None)
+                .unwrap()
+                .into_iter()
+                .map(|path| path.file_name().unwrap().to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            names(InputOrder::Name),
+            ["img1.png", "img2.png", "img10.png"]
+        );
+        assert_eq!(names(InputOrder::Name), names(InputOrder::Name));
+        assert_eq!(
+            names(InputOrder::Mtime),
+            ["img10.png", "img1.png", "img2.png"]
+        );
+        assert_eq!(
+            names(InputOrder::Size),
+            ["img10.png", "img1.png", "img2.png"]
+        );
+    }
+
+    #[test]
+    fn test_input_pattern() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["IMG_0001.tif", "IMG_0002.TIF", "IMG_10.tif", "notes.txt"] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+        let names = |pattern| {
+            collect_inputs(dir.path(), Some(pattern), InputOrder::Name, None)
+                .unwrap()
+                .into_iter()
+                .map(|path| path.file_name().unwrap().to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names("*.tif"), ["IMG_0001.tif", "IMG_10.tif"]);
+        assert_eq!(names("IMG_????.*"), ["IMG_0001.tif", "IMG_0002.TIF"]);
+        assert_eq!(names("*"), names("**"));
+        assert!(names("*.png").is_empty());
+        assert!(matches_pattern("a*b*c", "aXbYbZc"));
+        assert!(!matches_pattern("a*b", "aXbY"));
+    }
+
+    #[test]
+    fn test_natural_cmp() {
+        assert_eq!(natural_cmp("a2", "a10"), Ordering::Less);
+        assert_eq!(natural_cmp("a10b", "a10a"), Ordering::Greater);
+        assert_eq!(natural_cmp("a01", "a1"), Ordering::Less);
+        assert_eq!(natural_cmp("a", "a1"), Ordering::Less);
+        assert_eq!(natural_cmp("same", "same"), Ordering::Equal);
+    }
+}
@@ -0,0 +1,434 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::Context;
+use backend::image_processor::ImageProcessor;
+
+/// Stops a running batch after the current file, clones share the same state
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// An input file of a batch and the path its output is written to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchJob {
+    pub input: PathBuf,
+    pub output: PathBuf,
+}
+
+/// What happened to a single file of a batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    Processed,
+    /// The output already exists and existing outputs are skipped
+    SkippedExisting,
+    /// The checkpoint of an earlier run lists the input as done
+    SkippedCheckpoint,
+    /// The output is at least as new as the input and outdated outputs are updated
+    SkippedUpToDate,
+    Failed,
+}
+
+/// The progress of a batch, passed to the progress sink after every file
+#[derive(Debug, Clone, Copy)]
+pub struct BatchProgress<'a> {
+    /// The number of files that were handled, including this one
+    pub done: usize,
+    pub total: usize,
+    pub job: &'a BatchJob,
+    pub status: FileStatus,
+}
+
+/// The outcome of `BatchProcessor::run`
+#[derive(Debug, Default)]
+pub struct BatchReport {
+    pub processed: Vec<PathBuf>,
+    pub skipped: Vec<PathBuf>,
+    /// The inputs that could not be processed and why
+    pub failed: Vec<(PathBuf, anyhow::Error)>,
+    /// Whether the batch was cancelled before all files were handled
+    pub cancelled: bool,
+}
+
+impl BatchReport {
+    /// Check if all files were handled without errors
+    pub fn is_complete(&self) -> bool {
+        !self.cancelled && self.failed.is_empty()
+    }
+}
+
+/// Drives a batch of files through an `ImageProcessor`
+///
+/// A failing file does not stop the batch, its error is collected in the `BatchReport`. With a
+/// checkpoint file, every processed input is recorded, so a cancelled or crashed batch can be
+/// resumed without processing those inputs again. The checkpoint is removed once a batch is
+/// complete.
+pub struct BatchProcessor<'a> {
+    jobs: Vec<BatchJob>,
+    checkpoint: Option<PathBuf>,
+    skip_existing: bool,
+    skip_up_to_date: bool,
+    cancel_token: CancelToken,
+    progress: Option<Box<dyn FnMut(&BatchProgress) + 'a>>,
+}
+
+impl<'a> BatchProcessor<'a> {
+    pub fn new(jobs: Vec<BatchJob>) -> Self {
+        Self {
+            jobs,
+            checkpoint: None,
+            skip_existing: false,
+            skip_up_to_date: false,
+            cancel_token: CancelToken::new(),
+            progress: None,
+        }
+    }
+
+    /// Record the processed inputs in `checkpoint` and skip the inputs it already lists
+    pub fn set_checkpoint(&mut self, checkpoint: Option<PathBuf>) {
+        self.checkpoint = checkpoint;
+    }
+
+    pub fn with_checkpoint(mut self, checkpoint: Option<PathBuf>) -> Self {
+        self.set_checkpoint(checkpoint);
+        self
+    }
+
+    /// Skip inputs whose output already exists
+    pub fn set_skip_existing(&mut self, skip_existing: bool) {
+        self.skip_existing = skip_existing;
+    }
+
+    pub fn with_skip_existing(mut self, skip_existing: bool) -> Self {
+        self.set_skip_existing(skip_existing);
+        self
+    }
+
+    /// Only process inputs that were modified after their output was written, like `make`
+    ///
+    /// Inputs without an output are processed as well.
+    pub fn set_skip_up_to_date(&mut self, skip_up_to_date: bool) {
+        self.skip_up_to_date = skip_up_to_date;
+    }
+
+    pub fn with_skip_up_to_date(mut self, skip_up_to_date: bool) -> Self {
+        self.set_skip_up_to_date(skip_up_to_date);
+        self
+    }
+
+    pub fn set_cancel_token(&mut self, cancel_token: CancelToken) {
+        self.cancel_token = cancel_token;
+    }
+
+    pub fn with_cancel_token(mut self, cancel_token: CancelToken) -> Self {
+        self.set_cancel_token(cancel_token);
+        self
+    }
+
+    /// Call `progress` after every file, e.g. to update a progress bar
+    pub fn with_progress<F: FnMut(&BatchProgress) + 'a>(mut self, progress: F) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Process all jobs with `process`, which writes the output of an input file
+    ///
+    /// Cancellation is checked before each file, so the file that is being processed is always
+    /// completed. Only errors of the checkpoint file stop the batch.
+    pub fn run<F>(
+        &mut self,
+        processor: &mut ImageProcessor,
+        mut process: F,
+    ) -> anyhow::Result<BatchReport>
+    where
+        F: FnMut(&mut ImageProcessor, &Path, &Path) -> anyhow::Result<()>,
+    {
+        let done_inputs = match &self.checkpoint {
+            Some(checkpoint) => read_checkpoint(checkpoint)?,
+            None => HashSet::new(),
+        };
+        if !done_inputs.is_empty() {
+            log::info!(
+                "Resuming the batch, {} inputs are already processed",
+                done_inputs.len()
+            );
+        }
+        let mut checkpoint = match &self.checkpoint {
+            Some(checkpoint) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(checkpoint)
+                    .with_context(|| {
+                        format!("Could not open the checkpoint {}", checkpoint.display())
+                    })?,
+            ),
+            None => None,
+        };
+
+        let mut report = BatchReport::default();
+        let total = self.jobs.len();
+        for (index, job) in self.jobs.iter().enumerate() {
+            if self.cancel_token.is_cancelled() {
+                log::info!(
+                    "The batch was cancelled, {} of {} files are left",
+                    total - index,
+                    total
+                );
+                report.cancelled = true;
+                break;
+            }
+
+            let status = if done_inputs.contains(&job.input) {
+                FileStatus::SkippedCheckpoint
+            } else if self.skip_existing && job.output.exists() {
+                log::info!(
+                    "Skipping {} since the output file for it already exists.",
+                    job.input.display()
+                );
+                FileStatus::SkippedExisting
+            } else if self.skip_up_to_date && is_up_to_date(job) {
+                log::info!(
+                    "Skipping {} since its output is up to date.",
+                    job.input.display()
+                );
+                FileStatus::SkippedUpToDate
+            } else {
+                match process(processor, &job.input, &job.output) {
+                    Ok(()) => {
+                        if let Some(checkpoint) = &mut checkpoint {
+                            writeln!(checkpoint, "{}", job.input.display())?;
+                            checkpoint.flush()?;
+                        }
+                        FileStatus::Processed
+                    }
+                    Err(err) => {
+                        log::error!("Could not process {}: {:#}", job.input.display(), err);
+                        report.failed.push((job.input.clone(), err));
+                        FileStatus::Failed
+                    }
+                }
+            };
+            match status {
+                FileStatus::Processed => report.processed.push(job.input.clone()),
+                FileStatus::SkippedExisting
+                | FileStatus::SkippedCheckpoint
+                | FileStatus::SkippedUpToDate => report.skipped.push(job.input.clone()),
+                FileStatus::Failed => {}
+            }
+
+            if let Some(progress) = &mut self.progress {
+                progress(&BatchProgress {
+                    done: index + 1,
+                    total,
+                    job,
+                    status,
+                });
+            }
+        }
+
+        if report.is_complete() {
+            if let Some(checkpoint) = &self.checkpoint {
+                std::fs::remove_file(checkpoint).with_context(|| {
+                    format!("Could not remove the checkpoint {}", checkpoint.display())
+                })?;
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Check if the output of a job exists and was modified after its input
+///
+/// Outputs with the same modification time as their input count as up to date, since the
+/// modification time of the input is copied to the output when metadata is kept.
+fn is_up_to_date(job: &BatchJob) -> bool {
+    let modified = |path: &Path| path.metadata().and_then(|metadata| metadata.modified());
+    match (modified(&job.input), modified(&job.output)) {
+        (Ok(input), Ok(output)) => input <= output,
+        _ => false,
+    }
+}
+
+/// Read the inputs listed in a checkpoint, a missing checkpoint lists no inputs
+fn read_checkpoint(checkpoint: &Path) -> anyhow::Result<HashSet<PathBuf>> {
+    if !checkpoint.exists() {
+        return Ok(HashSet::new());
+    }
+    let file = File::open(checkpoint)
+        .with_context(|| format!("Could not read the checkpoint {}", checkpoint.display()))?;
+    BufReader::new(file)
+        .lines()
+        .map(|line| Ok(PathBuf::from(line?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::image_utils::{load_image, save_image, ColorManagement, Rgb16Image};
+    use crate::test_utils::identity_processor;
+
+    fn process(processor: &mut ImageProcessor, input: &Path, output: &Path) -> anyhow::Result<()> {
+        let image = load_image(input, ColorManagement::AssumeSrgb)?;
+        save_image(&pollster::block_on(processor.process_image(image))?, output)
+    }
+
+    #[test]
+    fn test_batch_processor() {
+        let inputs = tempfile::tempdir().unwrap();
+        let outputs = tempfile::tempdir().unwrap();
+        let checkpoint = outputs.path().join("batch.checkpoint");
+        let jobs: Vec<_> = ["a.png", "b.png", "broken.png", "c.png", "d.png"]
+            .iter()
+            .map(|name| BatchJob {
+                input: inputs.path().join(name),
+                output: outputs.path().join(name),
+            })
+            .collect();
+        for job in &jobs {
+            Rgb16Image::new(64, 48).save(&job.input).unwrap();
+        }
+        std::fs::write(inputs.path().join("broken.png"), "not an image").unwrap();
+        // The output of d.png exists from an earlier run
+        Rgb16Image::new(64, 48).save(&jobs[4].output).unwrap();
+        let mut processor = identity_processor();
+
+        // Cancel after the first file
+        let cancel_token = CancelToken::new();
+        let report = BatchProcessor::new(jobs.clone())
+            .with_checkpoint(Some(checkpoint.clone()))
+            .with_skip_existing(true)
+            .with_cancel_token(cancel_token.clone())
+            .with_progress(|_| cancel_token.cancel())
+            .run(&mut processor, process)
+            .unwrap();
+        assert!(report.cancelled);
+        assert_eq!(report.processed, vec![jobs[0].input.clone()]);
+        assert!(jobs[0].output.exists());
+        assert!(!jobs[1].output.exists());
+
+        // Resume, the first file is not processed again
+        let mut progress = Vec::new();
+        let mut calls = 0;
+        let report = BatchProcessor::new(jobs.clone())
+            .with_checkpoint(Some(checkpoint.clone()))
+            .with_skip_existing(true)
+            .with_progress(|progress_update| {
+                progress.push((progress_update.done, progress_update.status))
+            })
+            .run(&mut processor, |processor, input, output| {
+                calls += 1;
+                process(processor, input, output)
+            })
+            .unwrap();
+        assert!(!report.cancelled);
+        assert_eq!(calls, 3);
+        assert_eq!(
+            progress,
+            vec![
+                (1, FileStatus::SkippedCheckpoint),
+                (2, FileStatus::Processed),
+                (3, FileStatus::Failed),
+                (4, FileStatus::Processed),
+                (5, FileStatus::SkippedExisting),
+            ]
+        );
+        assert_eq!(
+            report.processed,
+            vec![jobs[1].input.clone(), jobs[3].input.clone()]
+        );
+        assert_eq!(
+            report.skipped,
+            vec![jobs[0].input.clone(), jobs[4].input.clone()]
+        );
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, jobs[2].input);
+        assert!(!report.is_complete());
+        // The failed input is retried by the next run
+        assert_eq!(
+            read_checkpoint(&checkpoint).unwrap(),
+            HashSet::from([
+                jobs[0].input.clone(),
+                jobs[1].input.clone(),
+                jobs[3].input.clone()
+            ])
+        );
+
+        Rgb16Image::new(64, 48).save(&jobs[2].input).unwrap();
+        let report = BatchProcessor::new(jobs.clone())
+            .with_checkpoint(Some(checkpoint.clone()))
+            .with_skip_existing(true)
+            .run(&mut processor, process)
+            .unwrap();
+        assert!(report.is_complete());
+        assert_eq!(report.processed, vec![jobs[2].input.clone()]);
+        assert!(!checkpoint.exists());
+    }
+
+    #[test]
+    fn test_update_stale_outputs() {
+        let inputs = tempfile::tempdir().unwrap();
+        let outputs = tempfile::tempdir().unwrap();
+        let jobs: Vec<_> = ["stale.png", "current.png", "missing.png", "same_time.png"]
+            .iter()
+            .map(|name| BatchJob {
+                input: inputs.path().join(name),
+                output: outputs.path().join(name),
+            })
+            .collect();
+        let set_mtime = |path: &Path, seconds| {
+            filetime::set_file_mtime(path, filetime::FileTime::from_unix_time(seconds, 0)).unwrap()
+        };
+        // (input mtime, output mtime), the third output does not exist
+        let times = [(2000, 1000), (1000, 2000), (1000, 0), (1500, 1500)];
+        for (job, (input_time, output_time)) in jobs.iter().zip(times) {
+            Rgb16Image::new(16, 16).save(&job.input).unwrap();
+            set_mtime(&job.input, input_time);
+            if output_time > 0 {
+                Rgb16Image::new(16, 16).save(&job.output).unwrap();
+                set_mtime(&job.output, output_time);
+            }
+        }
+        let mut processor = identity_processor();
+
+        let mut statuses = Vec::new();
+        let report = BatchProcessor::new(jobs.clone())
+            .with_skip_up_to_date(true)
+            .with_progress(|progress| statuses.push(progress.status))
+            .run(&mut processor, process)
+            .unwrap();
+        assert!(report.is_complete());
+        assert_eq!(
+            statuses,
+            vec![
+                FileStatus::Processed,
+                FileStatus::SkippedUpToDate,
+                FileStatus::Processed,
+                FileStatus::SkippedUpToDate,
+            ]
+        );
+        assert_eq!(
+            report.processed,
+            vec![jobs[0].input.clone(), jobs[2].input.clone()]
+        );
+        assert!(jobs[2].output.exists());
+    }
+}
@@ -0,0 +1,469 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io::Cursor;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use backend::image_processor::{ChunkStage, ImageColorModel, ImageProcessor};
+use backend::model_runner::ModelRunner;
+use backend::model_value_range::ModelValueRange;
+use backend::test_image::{test_image, TestPattern};
+use backend::{image_chunk_iterator::ChunkGeometryReport, ChunkSize};
+
+use crate::image_utils::Rgb16Image;
+use crate::model_error::describe_model_error;
+use crate::streaming_source::{read_chunk_regions, StreamingImageSource};
+
+/// An image size that can be parsed from strings like "4000x3000"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for ImageSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (width, height) = s
+            .split_once('x')
+            .ok_or_else(|| anyhow::anyhow!("Invalid size {}, expected e.g. 4000x3000", s))?;
+        Ok(ImageSize {
+            width: width.trim().parse()?,
+            height: height.trim().parse()?,
+        })
+    }
+}
+
+/// The timing results of a benchmark run
+#[derive(Debug, Clone)]
+pub struct BenchmarkReport {
+    pub size: ImageSize,
+    pub backend: &'static str,
+    pub chunks: usize,
+    pub model_loading: Duration,
+    pub processing: Duration,
+    /// The part of `processing` that was spent running the model
+    pub inference: Duration,
+}
+
+impl BenchmarkReport {
+    pub fn megapixels(&self) -> f64 {
+        self.size.width as f64 * self.size.height as f64 / 1e6
+    }
+
+    pub fn megapixels_per_second(&self) -> f64 {
+        self.megapixels() / self.processing.as_secs_f64()
+    }
+}
+
+impl fmt::Display for BenchmarkReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Image size:    {}x{} ({:.1} MP)",
+            self.size.width,
+            self.size.height,
+            self.megapixels()
+        )?;
+        writeln!(f, "Backend:       {}", self.backend)?;
+        writeln!(f, "Chunks:        {}", self.chunks)?;
+        writeln!(f, "Model loading: {:.3}s", self.model_loading.as_secs_f64())?;
+        writeln!(
+            f,
+            "Processing:    {:.3}s (inference {:.3}s)",
+            self.processing.as_secs_f64(),
+            self.inference.as_secs_f64()
+        )?;
+        write!(f, "Throughput:    {:.3} MP/s", self.megapixels_per_second())
+    }
+}
+
+/// The time and number of allocations needed to process a series of frames
+#[derive(Debug, Clone, Copy)]
+pub struct FrameTiming {
+    pub duration: Duration,
+    /// `None` if the allocations were not counted
+    pub allocations: Option<usize>,
+}
+
+/// The results of processing the same frames with and without reusing the output buffers
+#[derive(Debug, Clone)]
+pub struct FrameBenchmarkReport {
+    pub frames: usize,
+    /// Processing with `process_image`
+    pub allocating: FrameTiming,
+    /// Processing with `process_image_into`
+    pub reusing: FrameTiming,
+}
+
+impl fmt::Display for FrameBenchmarkReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Frames:        {}", self.frames)?;
+        for (name, timing) in [("Allocating:", self.allocating), ("Reusing:", self.reusing)] {
+            let allocations = match timing.allocations {
+                Some(allocations) => format!("{} allocations/frame", allocations / self.frames),
+                None => "allocations not counted".to_owned(),
+            };
+            writeln!(
+                f,
+                "{:<14} {:.3}s/frame, {}",
+                name,
+                timing.duration.as_secs_f64() / self.frames as f64,
+                allocations
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The processing times of the same image with one chunk and with several chunks in flight
+#[derive(Debug, Clone)]
+pub struct PipelineBenchmarkReport {
+    pub depth: usize,
+    pub sequential: Duration,
+    pub pipelined: Duration,
+}
+
+impl PipelineBenchmarkReport {
+    /// How much faster the pipelined processing was, above 1 if it was faster
+    pub fn speedup(&self) -> f64 {
+        self.sequential.as_secs_f64() / self.pipelined.as_secs_f64()
+    }
+}
+
+impl fmt::Display for PipelineBenchmarkReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Depth 1:       {:.3}s", self.sequential.as_secs_f64())?;
+        writeln!(
+            f,
+            "Depth {:<8} {:.3}s",
+            format!("{}:", self.depth),
+            self.pipelined.as_secs_f64()
+        )?;
+        write!(f, "Speedup:       {:.2}x", self.speedup())
+    }
+}
+
+/// The times needed to read and process all chunks of an image from a slow source
+#[derive(Debug, Clone)]
+pub struct PrefetchBenchmarkReport {
+    pub chunks: usize,
+    pub depth: usize,
+    pub synchronous: Duration,
+    pub prefetching: Duration,
+}
+
+impl fmt::Display for PrefetchBenchmarkReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Chunks:        {}", self.chunks)?;
+        writeln!(f, "Synchronous:   {:.3}s", self.synchronous.as_secs_f64())?;
+        write!(
+            f,
+            "Prefetch {:<5} {:.3}s",
+            format!("{}:", self.depth),
+            self.prefetching.as_secs_f64()
+        )
+    }
+}
+
+/// A source that waits for `latency` before every read, like a network or a slow disk
+pub struct SlowSource<S> {
+    pub source: S,
+    pub latency: Duration,
+}
+
+impl<S: StreamingImageSource> StreamingImageSource for SlowSource<S> {
+    fn dimensions(&self) -> (u32, u32) {
+        self.source.dimensions()
+    }
+
+    fn read_region(&self, x: u32, y: u32, width: u32, height: u32) -> anyhow::Result<Rgb16Image> {
+        std::thread::sleep(self.latency);
+        self.source.read_region(x, y, width, height)
+    }
+}
+
+async fn load_processor(model_bytes: &[u8], force_cpu: bool) -> anyhow::Result<ImageProcessor> {
+    let runner = ModelRunner::new(&mut Cursor::new(model_bytes), force_cpu)
+        .await
+        .map_err(|err| anyhow::anyhow!(describe_model_error(&err)))?;
+    Ok(ImageProcessor::new(
+        runner,
+        ImageColorModel::RGB,
+        ModelValueRange::asymmetric(1.0),
+        ModelValueRange::asymmetric(1.0),
+    )
+    .await?)
+}
+
+/// Load a model and process a synthetic image of the given size with it
+pub async fn run_benchmark(
+    model_bytes: &[u8],
+    size: ImageSize,
+    force_cpu: bool,
+) -> anyhow::Result<BenchmarkReport> {
+    let start = Instant::now();
+    let mut processor = load_processor(model_bytes, force_cpu).await?;
+    let model_loading = start.elapsed();
+
+    // Count the chunks and measure the time between the pre and post inference hook calls
+    let timing = Rc::new(RefCell::new((None, Duration::ZERO, 0)));
+    let hook_timing = timing.clone();
+    processor.set_chunk_hook(move |stage, _| {
+        let mut timing = hook_timing.borrow_mut();
+        let (inference_start, inference, chunks) = &mut *timing;
+        match stage {
+            ChunkStage::PreInference => *inference_start = Some(Instant::now()),
+            ChunkStage::PostInference => {
+                if let Some(inference_start) = inference_start.take() {
+                    *inference += inference_start.elapsed();
+                }
+                *chunks += 1;
+            }
+        }
+    });
+
+    let image = test_image(size.width, size.height, TestPattern::Gradient);
+    let start = Instant::now();
+    processor.process_image(image).await?;
+    let processing = start.elapsed();
+
+    let (_, inference, chunks) = *timing.borrow();
+    Ok(BenchmarkReport {
+        size,
+        backend: processor.backend_name(),
+        chunks,
+        model_loading,
+        processing,
+        inference,
+    })
+}
+
+/// Process `frames` synthetic frames with `process_image` and with `process_image_into`
+///
+/// `allocation_count` should return the number of allocations so far, e.g. from a counting
+/// global allocator. Without it, the allocations are reported as not counted.
+pub async fn run_frame_benchmark(
+    model_bytes: &[u8],
+    size: ImageSize,
+    frames: usize,
+    force_cpu: bool,
+    allocation_count: Option<fn() -> usize>,
+) -> anyhow::Result<FrameBenchmarkReport> {
+    if frames == 0 {
+        anyhow::bail!("The frame benchmark needs at least one frame");
+    }
+    let mut processor = load_processor(model_bytes, force_cpu).await?;
+    let frame = test_image(size.width, size.height, TestPattern::Gradient);
+
+    let count = || allocation_count.map(|allocation_count| allocation_count());
+    let measure_start = || (Instant::now(), count());
+    let measure_end = |(start, allocations): (Instant, Option<usize>)| FrameTiming {
+        duration: start.elapsed(),
+        allocations: count().zip(allocations).map(|(end, start)| end - start),
+    };
+
+    let start = measure_start();
+    for _ in 0..frames {
+        processor.process_image(frame.clone()).await?;
+    }
+    let allocating = measure_end(start);
+
+    let mut output = Rgb16Image::new(size.width, size.height);
+    let start = measure_start();
+    for _ in 0..frames {
+        processor
+            .process_image_into(frame.clone(), &mut output)
+            .await?;
+    }
+    let reusing = measure_end(start);
+
+    Ok(FrameBenchmarkReport {
+        frames,
+        allocating,
+        reusing,
+    })
+}
+
+/// Process a synthetic image with a GPU pipeline depth of 1 and of `depth`
+///
+/// The model is loaded once and the image is processed once before measuring, so that the
+/// shader compilation is not part of the measurement.
+pub async fn run_pipeline_benchmark(
+    model_bytes: &[u8],
+    size: ImageSize,
+    depth: usize,
+    force_cpu: bool,
+) -> anyhow::Result<PipelineBenchmarkReport> {
+    let mut processor = load_processor(model_bytes, force_cpu).await?;
+    let image = test_image(size.width, size.height, TestPattern::Gradient);
+    processor.process_image(image.clone()).await?;
+
+    let mut timings = Vec::with_capacity(2);
+    for depth in [1, depth] {
+        processor.set_pipeline_depth(depth).await?;
+        let start = Instant::now();
+        processor.process_image(image.clone()).await?;
+        timings.push(start.elapsed());
+    }
+
+    Ok(PipelineBenchmarkReport {
+        depth,
+        sequential: timings[0],
+        pipelined: timings[1],
+    })
+}
+
+/// Read the chunks of a synthetic image from a `SlowSource`, with and without prefetching
+///
+/// Processing a chunk is simulated by waiting for `latency` as well, so with prefetching the
+/// reads of the next chunks overlap with the processing of the current one.
+pub fn run_prefetch_benchmark(
+    size: ImageSize,
+    latency: Duration,
+    depth: usize,
+) -> anyhow::Result<PrefetchBenchmarkReport> {
+    let source = Arc::new(SlowSource {
+        source: test_image(size.width, size.height, TestPattern::Gradient),
+        latency,
+    });
+    let chunksize = ChunkSize {
+        width: 256,
+        height: 256,
+    };
+    let geometry = ChunkGeometryReport::new(
+        (size.width as usize, size.height as usize),
+        chunksize,
+        16,
+        4,
+    )?;
+
+    let mut timings = Vec::with_capacity(2);
+    for depth in [0, depth] {
+        let start = Instant::now();
+        for region in read_chunk_regions(source.clone(), &geometry, depth) {
+            region?;
+            std::thread::sleep(latency);
+        }
+        timings.push(start.elapsed());
+    }
+
+    Ok(PrefetchBenchmarkReport {
+        chunks: geometry.chunk_count(),
+        depth,
+        synchronous: timings[0],
+        prefetching: timings[1],
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::identity_model_bytes;
+
+    #[test]
+    fn test_parse_image_size() {
+        assert_eq!(
+            "4000x3000".parse::<ImageSize>().unwrap(),
+            ImageSize {
+                width: 4000,
+                height: 3000
+            }
+        );
+        assert!("4000".parse::<ImageSize>().is_err());
+        assert!("4000xabc".parse::<ImageSize>().is_err());
+    }
+
+    #[test]
+    fn test_benchmark() {
+        let size = ImageSize {
+            width: 80,
+            height: 60,
+        };
+
+        let report =
+            pollster::block_on(run_benchmark(&identity_model_bytes(), size, true)).unwrap();
+
+        assert_eq!(report.backend, "tract");
+        assert!(report.chunks > 0);
+        assert!(report.inference <= report.processing);
+        assert!(report.megapixels_per_second() > 0.0);
+    }
+
+    #[test]
+    fn test_frame_benchmark() {
+        let size = ImageSize {
+            width: 40,
+            height: 30,
+        };
+
+        let report = pollster::block_on(run_frame_benchmark(
+            &identity_model_bytes(),
+            size,
+            3,
+            true,
+            None,
+        ))
+        .unwrap();
+
+        assert_eq!(report.frames, 3);
+        assert_eq!(report.reusing.allocations, None);
+        assert!(report.to_string().contains("allocations not counted"));
+
+        let report = pollster::block_on(run_frame_benchmark(
+            &identity_model_bytes(),
+            size,
+            2,
+            true,
+            Some(|| 7),
+        ))
+        .unwrap();
+        assert_eq!(report.allocating.allocations, Some(0));
+        assert!(report.to_string().contains("0 allocations/frame"));
+
+        assert!(pollster::block_on(run_frame_benchmark(
+            &identity_model_bytes(),
+            size,
+            0,
+            true,
+            None,
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_pipeline_benchmark() {
+        let size = ImageSize {
+            width: 40,
+            height: 30,
+        };
+
+        let report = pollster::block_on(run_pipeline_benchmark(
+            &identity_model_bytes(),
+            size,
+            4,
+            true,
+        ))
+        .unwrap();
+
+        assert_eq!(report.depth, 4);
+        assert!(report.speedup() > 0.0);
+        assert!(report.to_string().contains("Depth 4:"));
+    }
+
+    #[test]
+    fn test_prefetch_benchmark() {
+        let size = ImageSize {
+            width: 600,
+            height: 300,
+        };
+
+        let report = run_prefetch_benchmark(size, Duration::from_millis(1), 2).unwrap();
+
+        assert_eq!(report.chunks, 6);
+        assert!(report.to_string().contains("Prefetch 2:"));
+    }
+}
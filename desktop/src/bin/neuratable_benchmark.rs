@@ -0,0 +1,96 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use argh::FromArgs;
+use desktop::benchmark::{
+    run_benchmark, run_frame_benchmark, run_pipeline_benchmark, run_prefetch_benchmark, ImageSize,
+};
+use desktop::path_expansion::expand_path;
+
+/// Counts allocations to compare the allocating and buffer reusing frame processing
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Process a synthetic image to measure the performance of a model on this machine
+struct Benchmark {
+    /// the ONNX model to benchmark
+    #[argh(option)]
+    model: String,
+    /// the size of the synthetic image, e.g. 4000x3000
+    #[argh(option, default = "ImageSize { width: 4000, height: 3000 }")]
+    size: ImageSize,
+    /// whether or not to force CPU processing
+    #[argh(switch)]
+    force_cpu: bool,
+    /// process this many frames of the synthetic image with and without reusing output buffers,
+    /// must be at least 1
+    #[argh(option)]
+    frames: Option<usize>,
+    /// compare processing with this GPU pipeline depth to processing one chunk at a time
+    #[argh(option)]
+    pipeline_depth: Option<usize>,
+    /// compare reading the chunks from a slow source with this prefetch depth to reading them
+    /// synchronously
+    #[argh(option)]
+    prefetch_depth: Option<usize>,
+    /// the simulated latency of reading and of processing a chunk in the prefetch benchmark
+    #[argh(option, default = "20")]
+    read_latency_ms: u64,
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let args: Benchmark = argh::from_env();
+    if args.frames == Some(0) {
+        anyhow::bail!("--frames must be at least 1");
+    }
+
+    let model_bytes = std::fs::read(expand_path(&args.model)?)?;
+    let report = pollster::block_on(run_benchmark(&model_bytes, args.size, args.force_cpu))?;
+    println!("{}", report);
+
+    if let Some(frames) = args.frames {
+        let report = pollster::block_on(run_frame_benchmark(
+            &model_bytes,
+            args.size,
+            frames,
+            args.force_cpu,
+            Some(|| ALLOCATIONS.load(Ordering::Relaxed)),
+        ))?;
+        println!("{}", report);
+    }
+
+    if let Some(depth) = args.pipeline_depth {
+        let report = pollster::block_on(run_pipeline_benchmark(
+            &model_bytes,
+            args.size,
+            depth,
+            args.force_cpu,
+        ))?;
+        println!("{}", report);
+    }
+
+    if let Some(depth) = args.prefetch_depth {
+        let latency = Duration::from_millis(args.read_latency_ms);
+        let report = run_prefetch_benchmark(args.size, latency, depth)?;
+        println!("{}", report);
+    }
+    Ok(())
+}
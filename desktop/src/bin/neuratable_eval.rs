@@ -0,0 +1,123 @@
+use anyhow::Context;
+use argh::FromArgs;
+use backend::image_chunk_iterator::PadMode;
+use backend::image_processor::{ImageColorModel, ImageProcessor};
+use backend::model_runner::ModelRunner;
+use backend::model_value_range::ModelValueRange;
+use desktop::evaluation::{error_map, evaluate, match_reference_size};
+use desktop::image_utils::{load_image, ColorManagement};
+use desktop::model_error::describe_model_error;
+use desktop::path_expansion::expand_path;
+use desktop::processing_args::{ArgColorModel, ArgPadMode};
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Process an image and compare the result to a reference image, printing PSNR and SSIM and
+/// saving a false color map of the error
+struct Eval {
+    #[argh(positional)]
+    onnx_model: String,
+    #[argh(positional)]
+    input_image: String,
+    /// the path of the error map
+    #[argh(positional)]
+    error_map: String,
+    /// the ground truth image to compare the processed image to
+    #[argh(option)]
+    reference: String,
+    /// the expected color channel order of the model
+    #[argh(option, default = "ArgColorModel(ImageColorModel::RGB)")]
+    model_channel_order: ArgColorModel,
+    /// whether or not to force CPU processing
+    #[argh(switch)]
+    force_cpu: bool,
+    /// the value range for input values. Can be a positive float number for [0,x] ranges or "+-x"
+    /// for [-x,x] ranges, or a comma separated range per RGB channel like "1,1,0.5"
+    #[argh(option, default = "ModelValueRange::asymmetric(1.0)")]
+    input_range: ModelValueRange,
+    /// the value range for output values. Can be a positive float number for [0,x] ranges or "+-x"
+    /// for [-x,x] ranges, or a comma separated range per RGB channel like "1,1,0.5"
+    #[argh(option, default = "ModelValueRange::asymmetric(1.0)")]
+    output_range: ModelValueRange,
+    /// treat the input and reference images as sRGB and ignore embedded ICC profiles (this is
+    /// the default)
+    #[argh(switch)]
+    assume_srgb: bool,
+    /// convert the input and reference images with an embedded ICC profile to sRGB
+    #[argh(switch)]
+    color_manage: bool,
+    /// the context in pixels that is passed to the model on each side of a chunk. Defaults to the
+    /// padding the model needs, or a seventh of the chunksize if that is not known
+    #[argh(option)]
+    chunk_padding: Option<usize>,
+    /// the width in pixels of the region where neighbouring chunks are blended. Defaults to a
+    /// tenth of the chunk padding
+    #[argh(option)]
+    overlap: Option<usize>,
+    /// how the image content is continued beyond its borders, one of (reflect, wrap, edge, mean,
+    /// constant:<value>). Defaults to reflect
+    #[argh(option, default = "ArgPadMode(PadMode::Reflect)")]
+    pad_mode: ArgPadMode,
+}
+
+impl Eval {
+    fn color_management(&self) -> anyhow::Result<ColorManagement> {
+        if self.assume_srgb && self.color_manage {
+            anyhow::bail!("--assume-srgb and --color-manage can not be used together");
+        }
+        Ok(if self.color_manage {
+            ColorManagement::ColorManage
+        } else {
+            ColorManagement::AssumeSrgb
+        })
+    }
+}
+
+async fn run(mut args: Eval) -> anyhow::Result<()> {
+    for path in [
+        &mut args.onnx_model,
+        &mut args.input_image,
+        &mut args.error_map,
+        &mut args.reference,
+    ] {
+        *path = expand_path(path)?;
+    }
+    let color_management = args.color_management()?;
+    let model_bytes = std::fs::read(&args.onnx_model)
+        .with_context(|| format!("Could not read {}", args.onnx_model))?;
+    let runner = ModelRunner::new(&mut std::io::Cursor::new(&model_bytes), args.force_cpu)
+        .await
+        .map_err(|err| anyhow::anyhow!(describe_model_error(&err)))?;
+    let mut processor = ImageProcessor::new(
+        runner,
+        args.model_channel_order.0,
+        args.input_range,
+        args.output_range,
+    )
+    .await?
+    .with_pad_mode(args.pad_mode.0);
+    if let Some(padding) = args.chunk_padding {
+        processor.set_padding(padding);
+    }
+    if let Some(overlap) = args.overlap {
+        processor.set_overlap(overlap);
+    }
+    processor
+        .check_chunk_settings()
+        .context("Invalid --chunk-padding or --overlap")?;
+
+    let input_image = load_image(&args.input_image, color_management)?;
+    let output_image = processor.process_image(input_image).await?;
+    let reference = load_image(&args.reference, color_management)?;
+    let reference = match_reference_size(reference, output_image.width(), output_image.height())?;
+
+    println!("{}", evaluate(&output_image, &reference));
+    error_map(&output_image, &reference)
+        .save(&args.error_map)
+        .with_context(|| format!("Could not save the error map to {}", args.error_map))
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let args: Eval = argh::from_env();
+    pollster::block_on(run(args))
+}
@@ -1,27 +1,238 @@
 use std::str::FromStr;
 
+use anyhow::Context;
 use argh::FromArgs;
-use backend::image_processor::{ImageColorModel, ImageProcessor};
+use backend::image_chunk_iterator::{PadMode, DEFAULT_CHUNKSIZE};
+use backend::image_processor::{
+    AccumulatorPrecision, AutoLevel, BlendMode, ChannelAdjustment, ChunkErrorPolicy,
+    ImageColorModel, ImageProcessor, NonFinitePolicy, OutputRangeCheck,
+};
+use backend::model_runner::{
+    BackendPreference, DownscaleFilter, ModelRunner, OutputSelector, ProcessingConcurrency,
+    DEFAULT_CHANNEL_COUNTS,
+};
 use backend::model_value_range::ModelValueRange;
-use std::path::Path;
-use std::process::Command;
+use backend::post_process::PostProcess;
+use backend::ChunkSize;
+use desktop::animation::{is_animated, process_animation};
+use desktop::batch_inputs::{collect_inputs, InputOrder};
+use desktop::batch_processor::{BatchJob, BatchProcessor, CancelToken};
+use desktop::byte_size::ByteSize;
+use desktop::image_utils::{
+    load_image, load_image_f32, load_image_rgba, load_mask, model_hash, provenance_tag,
+    save_image_f32, save_image_rgba, save_image_with_fallback, write_image, BitDepthFallback,
+    ColorManagement, MetadataHandler,
+};
+use desktop::model_error::describe_model_error;
+use desktop::model_selector::{
+    InputCharacteristics, ModelProfile, ModelSelector, DEFAULT_NOISE_THRESHOLD,
+};
+use desktop::montage::{write_montage, MontageLayout};
+use desktop::noise_level::{set_noise_level, NoiseLevel};
+use desktop::npy_tensor::{is_npy, load_npy_tensor, save_npy_tensor, TensorLayout};
+use desktop::output_pattern::{
+    model_name, render_output_pattern, CollisionPolicy, OutputPaths, RunInfo,
+};
+use desktop::path_expansion::{expand_optional_path, expand_path};
+use desktop::processing_args::{ArgColorModel, ArgPadMode};
+use desktop::sidecar::Sidecar;
+#[cfg(feature = "mmap")]
+use desktop::streaming_source::{process_streaming, MmapTiffSource};
+use desktop::tile_overlay::{draw_tile_overlay, draw_timing_heatmap};
+use desktop::video::{is_video, process_video};
+use image::ImageFormat;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone, PartialEq)]
-struct ArgColorModel(ImageColorModel);
+struct ArgBlendMode(BlendMode);
 
-impl FromStr for ArgColorModel {
+impl FromStr for ArgBlendMode {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let uppercase = s.to_uppercase();
-        Ok(match uppercase.as_ref() {
-            "BGR" => ArgColorModel(ImageColorModel::BGR),
-            "RGB" => ArgColorModel(ImageColorModel::RGB),
-            _ => anyhow::bail!("Color model {} not known, must be one of (RGB, BGR)", s),
+        Ok(match s.to_lowercase().as_ref() {
+            "average" => ArgBlendMode(BlendMode::Average),
+            "feather" => ArgBlendMode(BlendMode::Feather),
+            "cosine" => ArgBlendMode(BlendMode::Cosine),
+            "max" => ArgBlendMode(BlendMode::Max),
+            _ => anyhow::bail!(
+                "Blend mode {} not known, must be one of (average, feather, cosine, max)",
+                s
+            ),
         })
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+struct ArgAccumulatorPrecision(AccumulatorPrecision);
+
+impl FromStr for ArgAccumulatorPrecision {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_ref() {
+            #[cfg(feature = "half")]
+            "f16" => ArgAccumulatorPrecision(AccumulatorPrecision::F16),
+            #[cfg(not(feature = "half"))]
+            "f16" => anyhow::bail!("f16 requires NeuraTable to be built with the \"half\" feature"),
+            "f32" => ArgAccumulatorPrecision(AccumulatorPrecision::F32),
+            "f64" => ArgAccumulatorPrecision(AccumulatorPrecision::F64),
+            _ => anyhow::bail!(
+                "Accumulator precision {} not known, must be one of (f16, f32, f64)",
+                s
+            ),
+        })
+    }
+}
+
+/// The format of the output images
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// Determined by the extension of the output file
+    Auto,
+    /// 32 bit floating point TIFF, the image data is kept in floating point format like with
+    /// --float
+    TiffFloat,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_ref() {
+            "auto" => OutputFormat::Auto,
+            "tiff-float" => OutputFormat::TiffFloat,
+            _ => anyhow::bail!(
+                "Output format {} not known, must be one of (auto, tiff-float)",
+                s
+            ),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ArgChannelValues(Vec<f32>);
+
+impl FromStr for ArgChannelValues {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|value| value.trim().parse::<f32>())
+            .collect::<Result<_, _>>()
+            .map(ArgChannelValues)
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "Channel values {} not valid, must be comma separated numbers like 1,1,1.1",
+                    s
+                )
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ArgAutoLevel(AutoLevel);
+
+impl FromStr for ArgAutoLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ArgChannelValues(percentiles) = s.parse::<ArgChannelValues>()?;
+        match percentiles[..] {
+            [low, high] if 0.0 <= low && low < high && high <= 100.0 => {
+                Ok(ArgAutoLevel(AutoLevel {
+                    low_percentile: low,
+                    high_percentile: high,
+                }))
+            }
+            _ => anyhow::bail!(
+                "Auto level {} not valid, must be two percentiles like 0.5,99.5",
+                s
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ArgNonFinitePolicy(NonFinitePolicy);
+
+impl FromStr for ArgNonFinitePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_ref() {
+            "error" => ArgNonFinitePolicy(NonFinitePolicy::Error),
+            "zero" => ArgNonFinitePolicy(NonFinitePolicy::Zero),
+            "clamp" => ArgNonFinitePolicy(NonFinitePolicy::Clamp),
+            _ => anyhow::bail!(
+                "NaN policy {} not known, must be one of (error, zero, clamp)",
+                s
+            ),
+        })
+    }
+}
+
+/// A tone mapping step given as "none", "reinhard" or "aces", optionally with a knee like
+/// "aces:0.9"
+#[derive(Debug, Clone, PartialEq)]
+struct ArgToneMap(Option<PostProcess>);
+
+impl FromStr for ArgToneMap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("none") {
+            return Ok(ArgToneMap(None));
+        }
+        match s.parse() {
+            Ok(step @ PostProcess::ToneMap { .. }) => Ok(ArgToneMap(Some(step))),
+            _ => anyhow::bail!(
+                "Tone mapping {} not known, must be one of (none, reinhard, aces), optionally \
+                 with a knee below one like aces:0.9",
+                s
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ArgDownscaleFilter(DownscaleFilter);
+
+impl FromStr for ArgDownscaleFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_ref() {
+            "box" => ArgDownscaleFilter(DownscaleFilter::BoxAverage),
+            "triangle" => ArgDownscaleFilter(DownscaleFilter::Triangle),
+            "lanczos3" => ArgDownscaleFilter(DownscaleFilter::Lanczos3),
+            _ => anyhow::bail!(
+                "Downscale filter {} not known, must be one of (box, triangle, lanczos3)",
+                s
+            ),
+        })
+    }
+}
+
+/// A chunksize given as "512" or "512x384" (width x height)
+#[derive(Debug, Clone, PartialEq)]
+struct ArgChunkSize(ChunkSize);
+
+impl FromStr for ArgChunkSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (width, height) = s.split_once('x').unwrap_or((s, s));
+        match (width.trim().parse(), height.trim().parse()) {
+            (Ok(width), Ok(height)) if width > 0 && height > 0 => {
+                Ok(ArgChunkSize(ChunkSize { width, height }))
+            }
+            _ => anyhow::bail!("Chunksize {} not valid, must be like 512 or 512x384", s),
+        }
+    }
+}
+
 #[derive(FromArgs, PartialEq, Debug)]
 /// Run a 1:1 ONNX model in chunked mode
 struct RunOnnx {
@@ -34,9 +245,71 @@ struct RunOnnx {
     /// the expected color channel order of the model
     #[argh(option, default = "ArgColorModel(ImageColorModel::RGB)")]
     model_channel_order: ArgColorModel,
-    /// whether or not to force CPU processing
+    /// whether or not to force CPU processing, same as "--backend cpu"
     #[argh(switch)]
     force_cpu: bool,
+    /// the backend that runs the model, one of (auto, gpu, cpu). Overrides the NEURATABLE_BACKEND
+    /// environment variable, the default is auto
+    #[argh(option)]
+    backend: Option<BackendPreference>,
+    /// a channel count the model input may have, can be given multiple times. Defaults to 3,
+    /// models with 4 channels process RGBA images
+    #[argh(option)]
+    model_channels: Vec<usize>,
+    /// re-run chunks for which the GPU backend returns NaN or infinite values on the CPU
+    #[argh(switch)]
+    tract_fallback: bool,
+    /// only accept models with NCHW input, NHWC models need their chunks permuted
+    #[argh(switch)]
+    strict_shapes: bool,
+    /// the chunksize for models with a dynamic input shape, e.g. 512 or 512x384. The default is
+    /// 440, models with a fixed input shape ignore it
+    #[argh(option, default = "ArgChunkSize(DEFAULT_CHUNKSIZE)")]
+    dynamic_chunksize: ArgChunkSize,
+    /// the number of chunks that may be in flight on the GPU at the same time. Higher values
+    /// keep the GPU busy, but need more GPU memory. The default is 1
+    #[argh(option, default = "1")]
+    gpu_pipeline_depth: usize,
+    /// run chunks on all CPU cores at the same time when the model runs on the CPU. The output
+    /// is the same as without this option
+    #[argh(switch)]
+    parallel_chunks: bool,
+    /// the position of the model output to use as the processed image, by default the first
+    /// output with the shape of the input is used
+    #[argh(option)]
+    output_index: Option<usize>,
+    /// the name of the model output to use as the processed image
+    #[argh(option)]
+    output_name: Option<String>,
+    /// write the output image to stdout instead of a file, this is also done if the output
+    /// image is "-". Only possible for a single 16 bit image
+    #[argh(switch)]
+    stdout: bool,
+    /// additionally save a dithered 8 bit version of the output to this path, e.g. a JPEG for
+    /// the web. Only possible for a single 16 bit image
+    #[argh(option)]
+    preview: Option<String>,
+    /// additionally save the input with the chunk grid, the chunk padding and the blended overlap
+    /// drawn on it to this path, to debug seams. Only possible for a single image
+    #[argh(option)]
+    tile_overlay: Option<String>,
+    /// additionally save the output with each chunk tinted by its inference time to this path,
+    /// from blue for the fastest to red for the slowest chunk. Only possible for a single image
+    #[argh(option)]
+    timing_heatmap: Option<String>,
+    /// the format of the image written to stdout, given as file extension
+    #[argh(option, default = "String::from(\"png\")")]
+    stdout_format: String,
+    /// abort an image if the model fails on one of its chunks. This is the default
+    #[argh(switch)]
+    strict: bool,
+    /// pass chunks on which the model fails through unprocessed instead of aborting the image
+    #[argh(switch)]
+    keep_going_on_model_error: bool,
+    /// what to do with NaN or infinite values in the model output, one of (error, zero, clamp).
+    /// clamp replaces infinite values with the nearest bound of the output range
+    #[argh(option, default = "ArgNonFinitePolicy(NonFinitePolicy::Error)")]
+    on_nan: ArgNonFinitePolicy,
     /// if enabled, input_image and output_image should be directories and NeuraTable will process
     /// all images in the input directory to a file in the output directory
     #[argh(switch, short = 'b')]
@@ -44,113 +317,1038 @@ struct RunOnnx {
     /// a string that will be appended to the filename of batch-processed files
     #[argh(option, short = 's')]
     batch_process_output_suffix: Option<String>,
+    /// the file name pattern for batch-processed files. Supports the tokens %NAME%, %EXT%, %DIR%,
+    /// %INDEX% (%INDEX:04% for zero padded indices), %MODEL%, %SCALE% and %BACKEND%. Overrides
+    /// the output suffix
+    #[argh(option, short = 'p')]
+    output_pattern: Option<String>,
+    /// what to do if multiple batch-processed files result in the same output file name. Must be
+    /// one of (error, overwrite, suffix-number)
+    #[argh(option, default = "CollisionPolicy::Error")]
+    on_collision: CollisionPolicy,
+    /// only process the first N files of the input directory in batch mode, in the order of
+    /// --sort. Useful to check the settings before processing a large directory
+    #[argh(option)]
+    limit: Option<usize>,
+    /// the order in which batch-processed files are processed, one of (name, mtime, size).
+    /// Names are sorted with numbers compared by value
+    #[argh(option, default = "InputOrder::Name")]
+    sort: InputOrder,
     /// if enabled, batch processing will only consider images where the output image does not exist
     #[argh(switch, short = 'n')]
     no_overwrite: bool,
+    /// only process the files of the input directory whose name matches this pattern in batch
+    /// mode, e.g. "*.tif". * matches any characters and ? matches a single character
+    #[argh(option)]
+    input_pattern: Option<String>,
+    /// if enabled, batch processing only processes inputs that were modified after their output
+    /// was written or have no output yet
+    #[argh(switch)]
+    update: bool,
+    /// a file in which batch processing records the processed inputs. A cancelled batch that is
+    /// started again with the same checkpoint skips these inputs. The file is removed once all
+    /// inputs were processed
+    #[argh(option)]
+    checkpoint: Option<String>,
+    /// write thumbnails of all batch outputs into a grid image at this path after the batch.
+    /// Batches that need more than 8 rows are split into several numbered images
+    #[argh(option)]
+    montage: Option<String>,
+    /// the number of thumbnails per row of the montage
+    #[argh(option, default = "6")]
+    montage_columns: u32,
+    /// the largest width and height of the thumbnails of the montage in pixels
+    #[argh(option, default = "256")]
+    montage_thumbnail_size: u32,
     /// the value range for input values. Can be a positive float number for [0,x] ranges or "+-x"
-    /// for [-x,x] ranges
+    /// for [-x,x] ranges, or a comma separated range per RGB channel like "1,1,0.5"
     #[argh(option, default = "ModelValueRange::asymmetric(1.0)")]
     input_range: ModelValueRange,
     #[argh(option, default = "ModelValueRange::asymmetric(1.0)")]
     /// the value range for output values. Can be a positive float number for [0,x] ranges or "+-x"
-    /// for [-x,x] ranges
+    /// for [-x,x] ranges, or a comma separated range per RGB channel like "1,1,0.5"
     output_range: ModelValueRange,
+    /// treat all input images as sRGB and ignore embedded ICC profiles (this is the default)
+    #[argh(switch)]
+    assume_srgb: bool,
+    /// convert input images with an embedded ICC profile to sRGB before processing
+    #[argh(switch)]
+    color_manage: bool,
+    /// how often the model is applied to each image, the output of each pass is the input of the
+    /// next one. Must be at least 1
+    #[argh(option, default = "1")]
+    passes: usize,
+    /// do not record the NeuraTable version and model hash in the metadata of processed images
+    #[argh(switch)]
+    no_provenance: bool,
+    /// give each output the modification time of its input, e.g. to keep views sorted by date
+    #[argh(switch)]
+    preserve_mtime: bool,
+    /// keep the image data in 32 bit floating point format from loading to saving. Output images
+    /// must be .exr or .tif files
+    #[argh(switch)]
+    float: bool,
+    /// the format of the output images, one of (auto, tiff-float). tiff-float writes 32 bit
+    /// floating point TIFFs for HDR editing, batch outputs get a .tif extension. By default the
+    /// format is determined by the file extension
+    #[argh(option, default = "OutputFormat::Auto")]
+    format: OutputFormat,
+    /// retry with smaller chunks if inference fails, e.g. because the GPU is out of memory. Only
+    /// works for models with a dynamic input shape
+    #[argh(switch)]
+    auto_chunksize: bool,
+    /// run a blank chunk through the model before loading any image, to fail early if the model
+    /// does not accept the chunksize
+    #[argh(switch)]
+    safe_mode: bool,
+    /// the noise standard deviation for non-blind denoisers, relative to the full value range,
+    /// e.g. 0.05. It is passed to the second input of the model
+    #[argh(option)]
+    noise_sigma: Option<f32>,
+    /// a noise map with the size of the input images for non-blind denoisers, white is a
+    /// standard deviation of 1
+    #[argh(option)]
+    noise_map: Option<String>,
+    /// a second model for noisy inputs. Lossy formats (jpg, jpeg, webp) and images whose
+    /// estimated noise level is above --noise-threshold are processed with it, all other images
+    /// with the main model
+    #[argh(option)]
+    denoise_model: Option<String>,
+    /// the value range for input values of --denoise-model, like --input-range. Defaults to
+    /// --input-range
+    #[argh(option)]
+    denoise_input_range: Option<ModelValueRange>,
+    /// the value range for output values of --denoise-model, like --output-range. Defaults to
+    /// --output-range
+    #[argh(option)]
+    denoise_output_range: Option<ModelValueRange>,
+    /// the estimated noise standard deviation, relative to the full value range, above which
+    /// --denoise-model is used
+    #[argh(option, default = "DEFAULT_NOISE_THRESHOLD")]
+    noise_threshold: f32,
+    /// hold the image data in 16 bit floating point format while processing to halve the memory
+    /// usage. Requires a build with the "half" feature
+    #[argh(switch)]
+    half: bool,
+    /// the precision in which overlapping chunk outputs are summed up, one of (f16, f32, f64).
+    /// f16 saves memory and requires a build with the "half" feature, f64 avoids rounding errors
+    #[argh(option, default = "ArgAccumulatorPrecision(AccumulatorPrecision::F32)")]
+    accumulator: ArgAccumulatorPrecision,
+    /// reduce the chunksize until the estimated peak memory usage stays below this size, e.g.
+    /// "2GiB". Only works for models with a dynamic input shape
+    #[argh(option)]
+    memory_budget: Option<ByteSize>,
+    /// reduce the chunksize until each image is split into at least this many chunks. Only works
+    /// for models with a dynamic input shape
+    #[argh(option)]
+    min_tiles: Option<usize>,
+    /// reduce the chunksize until each chunk has at most this many megapixels. Only works for
+    /// models with a dynamic input shape
+    #[argh(option)]
+    max_tile_megapixels: Option<f64>,
+    /// the context in pixels that is passed to the model on each side of a chunk. Defaults to the
+    /// padding the model needs, or a seventh of the chunksize if that is not known
+    #[argh(option)]
+    chunk_padding: Option<usize>,
+    /// the width in pixels of the region where neighbouring chunks are blended. Defaults to a
+    /// tenth of the chunk padding
+    #[argh(option)]
+    overlap: Option<usize>,
+    /// how overlapping chunks are combined, one of (average, feather, cosine, max). Feather and
+    /// cosine work best for super resolution models
+    #[argh(option, default = "ArgBlendMode(BlendMode::Average)")]
+    overlap_blend: ArgBlendMode,
+    /// skip inference for chunks where each channel varies by at most this tolerance (relative to
+    /// the full value range) and copy them to the output unchanged
+    #[argh(option)]
+    skip_uniform: Option<f32>,
+    /// write a .json file with all processing parameters next to each output image
+    #[argh(switch)]
+    sidecar: bool,
+    /// how the image content is continued beyond its borders, one of (reflect, wrap, edge, mean,
+    /// constant:<value>). Use wrap for seamless textures, so that the output stays seamless. Mean
+    /// pads with the mean color of the image, some models are trained with this kind of padding.
+    /// The constant value is in the model input range and defaults to 0. Defaults to reflect
+    #[argh(option)]
+    pad_mode: Option<ArgPadMode>,
+    /// stretch the output of each image so that the given low and high percentiles of its values
+    /// become black and white, e.g. 0.5,99.5 for low contrast outputs. The percentiles are
+    /// computed over the whole image
+    #[argh(option)]
+    auto_level: Option<ArgAutoLevel>,
+    /// multiply each output channel by a factor before the output is quantized, e.g. 1,1,0.95
+    /// to reduce a blue color cast
+    #[argh(option)]
+    gain: Option<ArgChannelValues>,
+    /// add a value to each output channel after the gain, relative to the full value range, e.g.
+    /// 0,0,-0.02
+    #[argh(option)]
+    offset: Option<ArgChannelValues>,
+    /// a post-processing step for the output, can be repeated and the steps are applied in
+    /// order after --auto-level, --gain and --offset. One of sharpen:AMOUNT,RADIUS,
+    /// autolevel:LOW,HIGH, gain:R,G,B, offset:R,G,B, reinhard:KNEE, aces:KNEE or dither:BITS, e.g.
+    /// --post sharpen:0.5,1.0 --post dither
+    #[argh(option)]
+    post: Vec<PostProcess>,
+    /// compress output values above a knee into the range up to one with a tone mapping
+    /// operator instead of clamping them, one of (none, reinhard, aces). The knee can be given
+    /// like reinhard:0.9 and defaults to 0.8. Runs after --gain and --offset and before --post
+    #[argh(option, default = "ArgToneMap(None)")]
+    tonemap: ArgToneMap,
+    /// only change the image where this mask image is white and keep the input where it is
+    /// black, gray values blend both. Images with an alpha channel use it as the mask. The mask
+    /// must have the size of the processed images
+    #[argh(option)]
+    mask: Option<String>,
+    /// if a 16 bit TIFF can not be written with any of the supported compressions, save it with
+    /// 8 bits per channel instead of failing
+    #[argh(switch)]
+    allow_8bit_fallback: bool,
+    /// if the output of the first image with enough contrast does not fit the output range, use
+    /// a range that fits it for all images. Without this option only a warning is logged
+    #[argh(switch)]
+    auto_output_range: bool,
+    /// copy the outermost N rows and columns of the input to the output unchanged, so padding
+    /// artifacts can not alter the frame edge
+    #[argh(option, default = "0")]
+    preserve_border: usize,
+    /// run the model on a copy of the image scaled by this factor, e.g. 0.5, and add the
+    /// upscaled change to the full resolution image. Faster for models that remove low frequency
+    /// noise, fine detail is kept
+    #[argh(option, default = "1.0")]
+    infer_scale: f32,
+    /// keep the enlarged output of super resolution models instead of scaling it back down to the
+    /// size of the input
+    #[argh(switch)]
+    upscale: bool,
+    /// the filter that scales the output of super resolution models back down to the size of
+    /// the input, one of (box, triangle, lanczos3)
+    #[argh(option, default = "ArgDownscaleFilter(DownscaleFilter::Lanczos3)")]
+    downscale_filter: ArgDownscaleFilter,
+    /// read the input images region by region from a memory map instead of decoding them at
+    /// once, so only the chunks in flight are held in memory. The inputs must be uncompressed 16
+    /// bit RGB TIFFs. Requires a build with the "mmap" feature
+    #[argh(switch)]
+    stream: bool,
+    /// the number of chunk regions that --stream reads ahead on a background thread while the
+    /// model processes the current chunks. 0 reads each region when it is needed
+    #[argh(option, default = "2")]
+    prefetch: usize,
+    /// the axis order of .npy input files, one of (chw, hwc, nchw, nhwc). Values are passed to the
+    /// model like normalized image data
+    #[argh(option, default = "TensorLayout::Hwc")]
+    input_layout: TensorLayout,
+    /// the axis order of .npy output files, defaults to the input layout
+    #[argh(option)]
+    output_layout: Option<TensorLayout>,
 }
 
-async fn run(args: RunOnnx) {
-    let mut r = std::fs::File::open(&args.onnx_model).unwrap();
+impl RunOnnx {
+    /// Expand `~` and environment variables in the path arguments
+    fn expand_paths(&mut self) -> anyhow::Result<()> {
+        self.onnx_model = expand_path(&self.onnx_model)?;
+        self.input_image = expand_path(&self.input_image)?;
+        self.output_image = expand_path(&self.output_image)?;
+        expand_optional_path(&mut self.output_pattern)?;
+        expand_optional_path(&mut self.noise_map)?;
+        expand_optional_path(&mut self.denoise_model)?;
+        expand_optional_path(&mut self.mask)?;
+        expand_optional_path(&mut self.preview)?;
+        expand_optional_path(&mut self.tile_overlay)?;
+        expand_optional_path(&mut self.timing_heatmap)?;
+        expand_optional_path(&mut self.checkpoint)?;
+        expand_optional_path(&mut self.montage)?;
+        Ok(())
+    }
 
-    let runner = backend::model_runner::ModelRunner::new(&mut r, args.force_cpu)
-        .await
-        .unwrap();
+    /// Check the arguments that can not be checked while they are parsed
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.half && !cfg!(feature = "half") {
+            anyhow::bail!("--half requires NeuraTable to be built with the \"half\" feature");
+        }
+        if self.stream && !cfg!(feature = "mmap") {
+            anyhow::bail!("--stream requires NeuraTable to be built with the \"mmap\" feature");
+        }
+        if self.stream && (self.passes > 1 || self.keeps_float() || self.preview.is_some()) {
+            anyhow::bail!("--stream can only process 16 bit images in a single pass");
+        }
+        if self.stream && (self.color_manage || self.writes_to_stdout()) {
+            anyhow::bail!("--stream can not be used with --color-manage or --stdout");
+        }
+        if self.stream && self.denoise_model.is_some() {
+            anyhow::bail!("--stream can not be used with --denoise-model");
+        }
+        Ok(())
+    }
+
+    /// Whether the image data is kept in floating point format, see --float
+    fn keeps_float(&self) -> bool {
+        self.float || self.format == OutputFormat::TiffFloat
+    }
+
+    fn bit_depth_fallback(&self) -> BitDepthFallback {
+        if self.allow_8bit_fallback {
+            BitDepthFallback::Downgrade
+        } else {
+            BitDepthFallback::Fail
+        }
+    }
+
+    fn writes_to_stdout(&self) -> bool {
+        self.stdout || self.output_image == "-"
+    }
+
+    fn noise_level(&self) -> Option<NoiseLevel> {
+        match (self.noise_sigma, &self.noise_map) {
+            (Some(_), Some(_)) => panic!("--noise-sigma and --noise-map can not be used together!"),
+            (Some(sigma), None) => Some(NoiseLevel::Constant(sigma)),
+            (None, Some(path)) => Some(NoiseLevel::Map(path.clone())),
+            (None, None) => None,
+        }
+    }
+
+    /// The main model with its value ranges
+    fn main_profile(&self) -> ModelProfile {
+        ModelProfile {
+            model: PathBuf::from(&self.onnx_model),
+            input_range: self.input_range.clone(),
+            output_range: self.output_range.clone(),
+        }
+    }
+
+    /// Picks --denoise-model or the main model for each input, see `ModelSelector::by_noise`
+    fn model_selector(&self) -> Option<ModelSelector> {
+        let denoise_model = self.denoise_model.as_ref()?;
+        let denoise = ModelProfile {
+            model: PathBuf::from(denoise_model),
+            input_range: self
+                .denoise_input_range
+                .clone()
+                .unwrap_or_else(|| self.input_range.clone()),
+            output_range: self
+                .denoise_output_range
+                .clone()
+                .unwrap_or_else(|| self.output_range.clone()),
+        };
+        Some(ModelSelector::by_noise(
+            denoise,
+            self.main_profile(),
+            self.noise_threshold,
+        ))
+    }
+
+    fn channel_adjustment(&self, channels: usize) -> Option<ChannelAdjustment> {
+        if self.gain.is_none() && self.offset.is_none() {
+            return None;
+        }
+        let identity = ChannelAdjustment::identity(channels);
+        Some(ChannelAdjustment {
+            gain: self
+                .gain
+                .as_ref()
+                .map_or(identity.gain, |gain| gain.0.clone()),
+            offset: self
+                .offset
+                .as_ref()
+                .map_or(identity.offset, |offset| offset.0.clone()),
+        })
+    }
+
+    /// The post-process chain, --auto-level, --gain, --offset and --tonemap run before the
+    /// steps of --post
+    fn post_processes(&self, channels: usize) -> Vec<PostProcess> {
+        let auto_level = self
+            .auto_level
+            .as_ref()
+            .map(|auto_level| PostProcess::AutoLevel(auto_level.0));
+        let channel_adjustment = self
+            .channel_adjustment(channels)
+            .map(PostProcess::ChannelAdjustment);
+        auto_level
+            .into_iter()
+            .chain(channel_adjustment)
+            .chain(self.tonemap.0.clone())
+            .chain(self.post.iter().cloned())
+            .collect()
+    }
+
+    fn pad_mode(&self) -> PadMode {
+        match &self.pad_mode {
+            Some(pad_mode) => pad_mode.0,
+            None => PadMode::Reflect,
+        }
+    }
+
+    fn chunk_error_policy(&self) -> ChunkErrorPolicy {
+        if self.strict && self.keep_going_on_model_error {
+            panic!("--strict and --keep-going-on-model-error can not be used together!");
+        }
+        if self.keep_going_on_model_error {
+            ChunkErrorPolicy::KeepGoing
+        } else {
+            ChunkErrorPolicy::Strict
+        }
+    }
+
+    fn backend(&self) -> BackendPreference {
+        let explicit = match (self.force_cpu, self.backend) {
+            (true, Some(backend)) if backend != BackendPreference::Cpu => {
+                panic!(
+                    "--force-cpu and --backend {:?} can not be used together!",
+                    backend
+                )
+            }
+            (true, _) => Some(BackendPreference::Cpu),
+            (false, backend) => backend,
+        };
+        BackendPreference::resolve(explicit).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        })
+    }
+
+    fn output_selector(&self) -> OutputSelector {
+        match (self.output_index, &self.output_name) {
+            (Some(_), Some(_)) => {
+                panic!("--output-index and --output-name can not be used together!")
+            }
+            (Some(index), None) => OutputSelector::Index(index),
+            (None, Some(name)) => OutputSelector::Name(name.clone()),
+            (None, None) => OutputSelector::Auto,
+        }
+    }
+
+    fn color_management(&self) -> ColorManagement {
+        if self.assume_srgb && self.color_manage {
+            panic!("--assume-srgb and --color-manage can not be used together!");
+        }
+        if self.color_manage {
+            ColorManagement::ColorManage
+        } else {
+            ColorManagement::AssumeSrgb
+        }
+    }
+}
+
+async fn process_file(
+    processor: &mut ImageProcessor,
+    args: &RunOnnx,
+    input_path: &Path,
+    output_path: &Path,
+    model_hash: &str,
+) -> anyhow::Result<()> {
+    let start = std::time::Instant::now();
+    let fallback_chunks_before = processor.backend_info().fallback_chunks;
+    let color_management = args.color_management();
+    if is_npy(input_path) {
+        if !is_npy(output_path) {
+            anyhow::bail!("The output of a .npy input must be a .npy file as well");
+        }
+        let tensor = load_npy_tensor(input_path, args.input_layout)?;
+        let output = processor.process_tensor_passes(tensor, args.passes).await?;
+        save_npy_tensor(
+            output,
+            output_path,
+            args.output_layout.unwrap_or(args.input_layout),
+        )?;
+    } else if is_animated(input_path)? {
+        let frame_count =
+            process_animation(processor, input_path, output_path, args.passes).await?;
+        log::info!("Processed {} frames", frame_count);
+    } else if is_video(input_path) {
+        if !is_video(output_path) {
+            anyhow::bail!("The output of a video must be a video file as well");
+        }
+        let frame_count = process_video(processor, input_path, output_path, args.passes).await?;
+        log::info!("Processed {} frames", frame_count);
+    } else if args.stream {
+        // `validate` rejects --stream without the mmap feature
+        #[cfg(feature = "mmap")]
+        {
+            let source = std::sync::Arc::new(MmapTiffSource::open(input_path)?);
+            let output_image = process_streaming(processor, source, args.prefetch).await?;
+            save_image_with_fallback(&output_image, output_path, args.bit_depth_fallback())?;
+        }
+    } else if processor.channels() == 4 {
+        if args.keeps_float() || args.preview.is_some() {
+            anyhow::bail!(
+                "Models with an alpha channel only support 16 bit images without preview"
+            );
+        }
+        let input_image = load_image_rgba(input_path, color_management)?;
+        let output_image = processor
+            .process_image_rgba_passes(input_image, args.passes)
+            .await?;
+        save_image_rgba(&output_image, output_path)?;
+    } else if args.keeps_float() {
+        if args.preview.is_some() {
+            anyhow::bail!("Previews can only be saved for 16 bit images");
+        }
+        if args.format == OutputFormat::TiffFloat
+            && ImageFormat::from_path(output_path).ok() != Some(ImageFormat::Tiff)
+        {
+            anyhow::bail!(
+                "--format tiff-float can not write {}, use a .tif file",
+                output_path.display()
+            );
+        }
+        let input_image = load_image_f32(input_path, color_management)?;
+        let output_image = processor
+            .process_image_f32_passes(input_image, args.passes)
+            .await?;
+        save_image_f32(&output_image, output_path)?;
+    } else if let Some(preview_path) = &args.preview {
+        let input_image = load_image(input_path, color_management)?;
+        let (output_image, preview) = processor
+            .process_image_passes_with_preview(input_image, args.passes)
+            .await?;
+        save_image_with_fallback(&output_image, output_path, args.bit_depth_fallback())?;
+        preview
+            .save(preview_path)
+            .with_context(|| format!("Could not save the preview to {}", preview_path))?;
+    } else {
+        let input_image = load_image(input_path, color_management)?;
+        let output_image = processor
+            .process_image_passes(input_image, args.passes)
+            .await?;
+        save_image_with_fallback(&output_image, output_path, args.bit_depth_fallback())?;
+    }
+
+    let mut backend = processor.backend_info();
+    backend.fallback_chunks -= fallback_chunks_before;
+    log::info!(
+        "{} was processed by {} on the {}, {} chunks were re-run with the tract fallback",
+        input_path.display(),
+        backend.name,
+        backend.device,
+        backend.fallback_chunks
+    );
+
+    if args.sidecar {
+        Sidecar::new(
+            model_hash.to_owned(),
+            input_path,
+            processor.settings(),
+            backend,
+            args.passes,
+            start.elapsed(),
+        )
+        .write(output_path)?;
+    }
+    Ok(())
+}
+
+/// Process a single image and write the encoded result to stdout
+async fn process_to_stdout(
+    processor: &mut ImageProcessor,
+    args: &RunOnnx,
+    input_path: &Path,
+) -> anyhow::Result<()> {
+    if args.batch_process {
+        anyhow::bail!("Batch processing writes multiple images, they can not be written to stdout");
+    }
+    if args.sidecar
+        || args.keeps_float()
+        || is_npy(input_path)
+        || is_video(input_path)
+        || is_animated(input_path)?
+    {
+        anyhow::bail!("Only single 16 bit images without sidecar can be written to stdout");
+    }
+    let format = ImageFormat::from_extension(&args.stdout_format)
+        .with_context(|| format!("Unknown output format {}", args.stdout_format))?;
+
+    let input_image = load_image(input_path, args.color_management())?;
+    let output_image = processor
+        .process_image_passes(input_image, args.passes)
+        .await?;
+    write_image(&output_image, &mut std::io::stdout().lock(), format)
+}
+
+/// Save the chunk grid that was used for the last processed image on top of the input image
+fn save_tile_overlay(
+    processor: &ImageProcessor,
+    args: &RunOnnx,
+    input_path: &Path,
+    overlay_path: &Path,
+) -> anyhow::Result<()> {
+    if is_npy(input_path) {
+        anyhow::bail!("Tile overlays can only be saved for images");
+    }
+    let input_image = load_image(input_path, args.color_management())?;
+    let geometry =
+        processor.chunk_geometry(input_image.width() as usize, input_image.height() as usize)?;
+    draw_tile_overlay(&input_image, &geometry)
+        .save(overlay_path)
+        .with_context(|| {
+            format!(
+                "Could not save the tile overlay to {}",
+                overlay_path.display()
+            )
+        })
+}
+
+/// Save the inference time of each chunk of the last processed image on top of an image
+///
+/// The image is the output, or the input if the output was written to stdout.
+fn save_timing_heatmap(
+    processor: &ImageProcessor,
+    args: &RunOnnx,
+    input_path: &Path,
+    image_path: &Path,
+    heatmap_path: &Path,
+) -> anyhow::Result<()> {
+    if is_npy(input_path) || is_npy(image_path) {
+        anyhow::bail!("Timing heatmaps can only be saved for images");
+    }
+    let input_image = image::image_dimensions(input_path)
+        .with_context(|| format!("Could not read {}", input_path.display()))?;
+    let geometry = processor.chunk_geometry(input_image.0 as usize, input_image.1 as usize)?;
+    let image = load_image(image_path, args.color_management())?;
+    draw_timing_heatmap(&image, &geometry, processor.chunk_timings())
+        .save(heatmap_path)
+        .with_context(|| {
+            format!(
+                "Could not save the timing heatmap to {}",
+                heatmap_path.display()
+            )
+        })
+}
+
+/// A model loaded with the processing settings of the arguments, see `load_model`
+struct LoadedModel {
+    processor: ImageProcessor,
+    bytes: Vec<u8>,
+    /// The factor by which the model scales its input
+    scale: usize,
+}
+
+/// Load a model and set up a processor for it with the processing settings of the arguments
+///
+/// Setup errors are printed and exit the program.
+async fn load_model(args: &RunOnnx, profile: &ModelProfile) -> LoadedModel {
+    let model_bytes = std::fs::read(&profile.model).unwrap();
+
+    let channel_counts = if args.model_channels.is_empty() {
+        DEFAULT_CHANNEL_COUNTS
+    } else {
+        args.model_channels.as_slice()
+    };
+    let runner = ModelRunner::new_with_dynamic_chunksize(
+        &mut std::io::Cursor::new(&model_bytes),
+        args.backend(),
+        channel_counts,
+        &args.output_selector(),
+        args.strict_shapes,
+        Some(args.dynamic_chunksize.0),
+    )
+    .await
+    .unwrap_or_else(|err| {
+        eprintln!(
+            "Could not load {}: {}",
+            profile.model.display(),
+            describe_model_error(&err)
+        );
+        std::process::exit(1);
+    })
+    .with_tract_fallback(args.tract_fallback)
+    .with_downscale_filter(args.downscale_filter.0)
+    .with_concurrency(if args.parallel_chunks {
+        ProcessingConcurrency::Parallel
+    } else {
+        ProcessingConcurrency::Sequential
+    });
+    let model_scale = runner.get_model_scale();
 
     let mut processor = ImageProcessor::new(
         runner,
         args.model_channel_order.0,
-        args.input_range,
-        args.output_range,
+        profile.input_range.clone(),
+        profile.output_range.clone(),
     )
     .await
-    .unwrap();
-
-    let has_exiftool = Command::new("exiftool").arg("-ver").output().is_ok();
-    if !has_exiftool {
-        log::error!("exiftool could not be executed! Image metadata will be lost after processing!")
-    }
-    let copy_metadata = |source: &str, destination: &str| -> () {
-        if has_exiftool {
-            if Command::new("exiftool")
-                .args(["-overwrite_original", "-tagsFromFile", source, destination])
-                .output()
-                .is_err()
+    .unwrap()
+    .with_auto_chunksize(args.auto_chunksize)
+    .with_memory_budget(args.memory_budget.map(|budget| budget.0))
+    .with_min_tiles(args.min_tiles)
+    .with_max_tile_pixels(
+        args.max_tile_megapixels
+            .map(|megapixels| (megapixels * 1_000_000.0) as usize),
+    )
+    .with_blend_mode(args.overlap_blend.0)
+    .with_skip_uniform(args.skip_uniform)
+    .with_pad_mode(args.pad_mode())
+    .with_preserve_border(args.preserve_border)
+    .with_upscale(args.upscale)
+    .with_output_range_check(if args.auto_output_range {
+        OutputRangeCheck::Adopt
+    } else {
+        OutputRangeCheck::Warn
+    })
+    .with_chunk_error_policy(args.chunk_error_policy())
+    .with_non_finite_policy(args.on_nan.0)
+    .with_accumulator_precision(args.accumulator.0);
+    if let Some(padding) = args.chunk_padding {
+        processor.set_padding(padding);
+    }
+    if let Some(overlap) = args.overlap {
+        processor.set_overlap(overlap);
+    }
+    if let Err(err) = processor.set_infer_scale(args.infer_scale) {
+        eprintln!("Invalid --infer-scale: {}", err);
+        std::process::exit(1);
+    }
+    if let Err(err) = processor.check_chunk_settings() {
+        eprintln!("Invalid --chunk-padding or --overlap: {}", err);
+        std::process::exit(1);
+    }
+    processor.set_post_processes(args.post_processes(processor.channels()));
+    processor
+        .set_pipeline_depth(args.gpu_pipeline_depth)
+        .await
+        .unwrap_or_else(|err| {
+            eprintln!("Could not set the GPU pipeline depth: {}", err);
+            std::process::exit(1);
+        });
+    if let Some(mask) = &args.mask {
+        processor.set_mask(Some(load_mask(mask).unwrap_or_else(|err| {
+            eprintln!("Could not load the mask {}: {:#}", mask, err);
+            std::process::exit(1);
+        })));
+    }
+    #[cfg(feature = "half")]
+    processor.set_half_precision(args.half);
+    if let Some(noise_level) = args.noise_level() {
+        set_noise_level(&mut processor, &noise_level).unwrap_or_else(|err| {
+            eprintln!("{:#}", err);
+            std::process::exit(1);
+        });
+    }
+    if args.safe_mode {
+        processor.validate().await.unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        });
+    }
+    LoadedModel {
+        processor,
+        bytes: model_bytes,
+        scale: model_scale,
+    }
+}
+
+/// A processor with the hash and the metadata handler of its model
+struct SelectableModel {
+    processor: ImageProcessor,
+    hash: String,
+    metadata_handler: MetadataHandler,
+}
+
+/// The processor, model hash and metadata handler that an input is processed with
+type ModelHandles<'a> = (&'a mut ImageProcessor, &'a str, &'a MetadataHandler);
+
+/// The models that --denoise-model adds next to the main model, picked per input
+struct ModelChoice {
+    selector: ModelSelector,
+    /// The selectable models other than the main model by path
+    models: HashMap<PathBuf, SelectableModel>,
+}
+
+impl ModelChoice {
+    async fn load(args: &RunOnnx, selector: ModelSelector) -> Self {
+        let mut models = HashMap::new();
+        for profile in selector.profiles() {
+            if profile.model != Path::new(&args.onnx_model) && !models.contains_key(&profile.model)
             {
-                log::error!("Failed to run exiftool for {}", source);
+                let model = load_model(args, profile).await;
+                let selectable = SelectableModel {
+                    hash: model_hash(&model.bytes),
+                    metadata_handler: metadata_handler(args, &model.bytes),
+                    processor: model.processor,
+                };
+                models.insert(profile.model.clone(), selectable);
             }
         }
+        Self { selector, models }
+    }
+
+    /// The model handles for an input
+    ///
+    /// Inputs that are not single images and inputs that no model accepts use the main model.
+    fn select<'a>(
+        &'a mut self,
+        args: &RunOnnx,
+        input_path: &Path,
+        main: ModelHandles<'a>,
+    ) -> anyhow::Result<ModelHandles<'a>> {
+        if is_npy(input_path) || is_video(input_path) || is_animated(input_path)? {
+            return Ok(main);
+        }
+        let image = load_image(input_path, args.color_management())?;
+        let input = InputCharacteristics::measure(input_path, &image);
+        let model = self.selector.select(&input).map(|profile| &profile.model);
+        log::info!(
+            "Selected {} for {} with a noise level of {}",
+            model
+                .map_or(Path::new(&args.onnx_model), |model| model.as_path())
+                .display(),
+            input_path.display(),
+            input.noise_level
+        );
+        Ok(match model.and_then(|model| self.models.get_mut(model)) {
+            Some(model) => (
+                &mut model.processor,
+                model.hash.as_str(),
+                &model.metadata_handler,
+            ),
+            None => main,
+        })
+    }
+}
+
+/// The model handles for an input, `main` unless --denoise-model picks another model
+fn select_model<'a>(
+    model_choice: Option<&'a mut ModelChoice>,
+    args: &RunOnnx,
+    input_path: &Path,
+    main: ModelHandles<'a>,
+) -> anyhow::Result<ModelHandles<'a>> {
+    match model_choice {
+        Some(model_choice) => model_choice.select(args, input_path, main),
+        None => Ok(main),
+    }
+}
+
+/// The metadata handler for the outputs of a model
+fn metadata_handler(args: &RunOnnx, model_bytes: &[u8]) -> MetadataHandler {
+    let metadata_handler = MetadataHandler::new().with_preserved_mtime(args.preserve_mtime);
+    if args.no_provenance {
+        metadata_handler
+    } else {
+        metadata_handler.with_provenance(provenance_tag(model_bytes))
+    }
+}
+
+async fn run(args: RunOnnx) {
+    if args.montage.is_some() && !args.batch_process {
+        panic!("--montage can only be used for batch processing!");
+    }
+    if args.passes == 0 {
+        eprintln!("--passes must be at least 1");
+        std::process::exit(1);
+    }
+    let LoadedModel {
+        mut processor,
+        bytes: model_bytes,
+        scale: model_scale,
+    } = load_model(&args, &args.main_profile()).await;
+    let mut model_choice = match args.model_selector() {
+        Some(selector) => Some(ModelChoice::load(&args, selector).await),
+        None => None,
     };
 
-    if !args.batch_process {
-        let input_image = image::open(&args.input_image).unwrap().to_rgb16();
-        let output_image = processor.process_image(input_image).await.unwrap();
+    let model_hash = model_hash(&model_bytes);
+    let metadata_handler = metadata_handler(&args, &model_bytes);
 
-        // FIXME: For JPG Output, we need to scale the image data back to 8 Bit RGB
-        // We need to find a generic way to solve this issue
-        output_image.save(&args.output_image).unwrap();
-        copy_metadata(&args.input_image, &args.output_image);
+    if args.writes_to_stdout() {
+        let input_path = Path::new(&args.input_image);
+        let (processor, _, _) = select_model(
+            model_choice.as_mut(),
+            &args,
+            input_path,
+            (&mut processor, &model_hash, &metadata_handler),
+        )
+        .unwrap();
+        process_to_stdout(processor, &args, input_path)
+            .await
+            .unwrap();
+        if let Some(overlay_path) = &args.tile_overlay {
+            save_tile_overlay(
+                processor,
+                &args,
+                Path::new(&args.input_image),
+                Path::new(overlay_path),
+            )
+            .unwrap();
+        }
+        if let Some(heatmap_path) = &args.timing_heatmap {
+            save_timing_heatmap(
+                processor,
+                &args,
+                Path::new(&args.input_image),
+                Path::new(&args.input_image),
+                Path::new(heatmap_path),
+            )
+            .unwrap();
+        }
+    } else if !args.batch_process {
+        let input_path = Path::new(&args.input_image);
+        let (processor, model_hash, metadata_handler) = select_model(
+            model_choice.as_mut(),
+            &args,
+            input_path,
+            (&mut processor, &model_hash, &metadata_handler),
+        )
+        .unwrap();
+        process_file(
+            processor,
+            &args,
+            input_path,
+            Path::new(&args.output_image),
+            model_hash,
+        )
+        .await
+        .unwrap();
+        metadata_handler.copy_metadata(input_path, Path::new(&args.output_image));
+        if let Some(overlay_path) = &args.tile_overlay {
+            save_tile_overlay(
+                processor,
+                &args,
+                Path::new(&args.input_image),
+                Path::new(overlay_path),
+            )
+            .unwrap();
+        }
+        if let Some(heatmap_path) = &args.timing_heatmap {
+            save_timing_heatmap(
+                processor,
+                &args,
+                Path::new(&args.input_image),
+                Path::new(&args.output_image),
+                Path::new(heatmap_path),
+            )
+            .unwrap();
+        }
     } else {
         let input_dir = Path::new(&args.input_image);
         let output_dir = Path::new(&args.output_image);
         if !output_dir.is_dir() {
             panic!("Output directory path is not a directory!");
         }
-        for maybe_entry in input_dir
-            .read_dir()
-            .expect("Could not read input directory")
-        {
-            if let Ok(entry) = maybe_entry {
-                if entry.path().is_file() {
-                    // TODO: We need to check if the input is actually an image!
-                    let output_image_filename =
-                        if let Some(suffix) = &args.batch_process_output_suffix {
-                            format!(
-                                "{}{}.{}",
-                                entry.path().file_stem().unwrap().to_string_lossy(),
-                                suffix,
-                                entry.path().extension().unwrap().to_string_lossy()
-                            )
-                        } else {
-                            entry
-                                .path()
-                                .file_name()
-                                .unwrap()
-                                .to_string_lossy()
-                                .to_string()
-                        };
-                    let output_image_path = output_dir.join(output_image_filename);
-                    if !args.no_overwrite || !output_image_path.exists() {
-                        let input_image = image::open(entry.path()).unwrap().to_rgb16();
-                        let output_image = processor.process_image(input_image).await.unwrap();
-                        output_image.save(&output_image_path).unwrap();
-
-                        copy_metadata(
-                            entry.path().to_string_lossy().as_ref(),
-                            output_image_path.to_string_lossy().as_ref(),
-                        )
-                    } else {
-                        log::info!(
-                            "Skipping {} since the output file for it already exists.",
-                            entry.path().to_string_lossy()
-                        );
-                    }
+        if args.preview.is_some() {
+            panic!("--preview can not be used for batch processing!");
+        }
+        if args.tile_overlay.is_some() {
+            panic!("--tile-overlay can not be used for batch processing!");
+        }
+        if args.timing_heatmap.is_some() {
+            panic!("--timing-heatmap can not be used for batch processing!");
+        }
+        let output_pattern = args.output_pattern.clone().unwrap_or_else(|| {
+            format!(
+                "%NAME%{}.{}",
+                args.batch_process_output_suffix
+                    .as_deref()
+                    .unwrap_or_default(),
+                match args.format {
+                    OutputFormat::Auto => "%EXT%",
+                    OutputFormat::TiffFloat => "tif",
                 }
+            )
+        });
+        if args.update && args.no_overwrite {
+            panic!("--update and --no-overwrite can not be used together!");
+        }
+        let input_files = collect_inputs(
+            input_dir,
+            args.input_pattern.as_deref(),
+            args.sort,
+            args.limit,
+        )
+        .unwrap();
+        let run_info = RunInfo {
+            model: model_name(Path::new(&args.onnx_model)),
+            scale: model_scale,
+            backend: processor.backend_name().to_owned(),
+        };
+        let mut output_paths = OutputPaths::new(args.on_collision);
+        let mut jobs = Vec::new();
+        for (index, input_path) in input_files.into_iter().enumerate() {
+            // TODO: We need to check if the input is actually an image!
+            match output_paths.claim(output_dir.join(render_output_pattern(
+                &output_pattern,
+                &input_path,
+                index,
+                &run_info,
+            ))) {
+                Ok(output) => jobs.push(BatchJob {
+                    input: input_path,
+                    output,
+                }),
+                Err(err) => log::error!("Skipping {}: {}", input_path.display(), err),
             }
         }
+
+        // The first Ctrl-C finishes the current image, the second one exits immediately
+        let cancel_token = CancelToken::new();
+        let handler_token = cancel_token.clone();
+        ctrlc::set_handler(move || {
+            if handler_token.is_cancelled() {
+                std::process::exit(130);
+            }
+            eprintln!("Cancelling after the current image, press Ctrl-C again to exit now");
+            handler_token.cancel();
+        })
+        .expect("Could not set the Ctrl-C handler");
+
+        let outputs: Vec<_> = jobs.iter().map(|job| job.output.clone()).collect();
+        let report = BatchProcessor::new(jobs)
+            .with_checkpoint(args.checkpoint.as_ref().map(PathBuf::from))
+            .with_skip_existing(args.no_overwrite)
+            .with_skip_up_to_date(args.update)
+            .with_cancel_token(cancel_token)
+            .run(
+                &mut processor,
+                |processor, input_path, output_image_path| {
+                    let (processor, model_hash, metadata_handler) = select_model(
+                        model_choice.as_mut(),
+                        &args,
+                        input_path,
+                        (processor, &model_hash, &metadata_handler),
+                    )?;
+                    pollster::block_on(process_file(
+                        processor,
+                        &args,
+                        input_path,
+                        output_image_path,
+                        model_hash,
+                    ))?;
+                    metadata_handler.copy_metadata(input_path, output_image_path);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        for (input_path, err) in &report.failed {
+            eprintln!("Could not process {}: {:#}", input_path.display(), err);
+        }
+        if report.cancelled {
+            eprintln!("The batch was cancelled");
+        }
+        if let Some(montage) = &args.montage {
+            let layout = MontageLayout {
+                columns: args.montage_columns,
+                thumbnail_size: args.montage_thumbnail_size,
+                ..MontageLayout::default()
+            };
+            let outputs: Vec<_> = outputs.into_iter().filter(|path| path.exists()).collect();
+            if let Err(err) = write_montage(&outputs, Path::new(montage), layout) {
+                eprintln!("{:#}", err);
+                std::process::exit(1);
+            }
+        }
+        if !report.is_complete() {
+            std::process::exit(1);
+        }
     }
 }
 
 fn main() {
     env_logger::init();
     log::debug!("Test");
-    let args: RunOnnx = argh::from_env();
+    let mut args: RunOnnx = argh::from_env();
+    if let Err(err) = args.expand_paths().and_then(|_| args.validate()) {
+        eprintln!("{:#}", err);
+        std::process::exit(1);
+    }
     pollster::block_on(run(args));
 }
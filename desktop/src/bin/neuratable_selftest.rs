@@ -0,0 +1,51 @@
+use std::str::FromStr;
+
+use argh::FromArgs;
+use backend::test_image::TestPattern;
+use desktop::selftest::run_selftest;
+
+#[derive(Debug, Clone, PartialEq)]
+struct ArgTestPattern(TestPattern);
+
+impl FromStr for ArgTestPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_ref() {
+            "gradient" => ArgTestPattern(TestPattern::Gradient),
+            "checkerboard" => ArgTestPattern(TestPattern::Checkerboard),
+            "noise" => ArgTestPattern(TestPattern::Noise),
+            _ => anyhow::bail!(
+                "Test pattern {} not known, must be one of (gradient, checkerboard, noise)",
+                s
+            ),
+        })
+    }
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Process a generated image with a built-in identity model to check that NeuraTable works on
+/// this machine, without a model or image file. Exits with an error if the check fails
+struct Selftest {
+    /// the content of the generated image, one of (gradient, checkerboard, noise)
+    #[argh(option, default = "ArgTestPattern(TestPattern::Gradient)")]
+    pattern: ArgTestPattern,
+    /// whether or not to force CPU processing
+    #[argh(switch)]
+    force_cpu: bool,
+}
+
+fn main() {
+    env_logger::init();
+    let args: Selftest = argh::from_env();
+    match pollster::block_on(run_selftest(args.pattern.0, args.force_cpu)) {
+        Ok(report) => {
+            println!("{}", report);
+            println!("The self test passed");
+        }
+        Err(err) => {
+            eprintln!("The self test failed: {:#}", err);
+            std::process::exit(1);
+        }
+    }
+}
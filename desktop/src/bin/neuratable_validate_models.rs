@@ -0,0 +1,42 @@
+use argh::FromArgs;
+use desktop::model_validation::validate_models;
+use desktop::path_expansion::expand_path;
+use std::path::Path;
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Check that all ONNX models of a directory load and compile with tract, without a GPU and
+/// without processing any images. Exits with an error if any model fails
+struct ValidateModels {
+    #[argh(positional)]
+    model_dir: String,
+}
+
+async fn run(args: ValidateModels) -> anyhow::Result<bool> {
+    let model_dir = expand_path(&args.model_dir)?;
+    let validations = validate_models(Path::new(&model_dir)).await?;
+    if validations.is_empty() {
+        anyhow::bail!("There are no ONNX models in {}", model_dir);
+    }
+    for validation in &validations {
+        print!("{}", validation);
+    }
+    let failed = validations
+        .iter()
+        .filter(|validation| !validation.passed())
+        .count();
+    println!(
+        "{} of {} models passed",
+        validations.len() - failed,
+        validations.len()
+    );
+    Ok(failed == 0)
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let args: ValidateModels = argh::from_env();
+    if !pollster::block_on(run(args))? {
+        std::process::exit(1);
+    }
+    Ok(())
+}
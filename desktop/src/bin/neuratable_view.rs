@@ -0,0 +1,36 @@
+use argh::FromArgs;
+use desktop::path_expansion::{expand_optional_path, expand_path};
+use desktop::viewer::{collect_entries, view_images, ViewMode};
+use std::path::Path;
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Show the processed images of a directory in the terminal, or list them if the output is not
+/// a terminal
+struct View {
+    #[argh(positional)]
+    output_dir: String,
+    /// the directory of the input images, each input is shown next to the output of the same
+    /// file name
+    #[argh(option)]
+    inputs: Option<String>,
+    /// the width of each image in terminal columns
+    #[argh(option, default = "60")]
+    width: u32,
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let mut args: View = argh::from_env();
+    args.output_dir = expand_path(&args.output_dir)?;
+    expand_optional_path(&mut args.inputs)?;
+
+    let entries = collect_entries(
+        Path::new(&args.output_dir),
+        args.inputs.as_deref().map(Path::new),
+    )?;
+    view_images(
+        &mut std::io::stdout().lock(),
+        &entries,
+        ViewMode::detect(args.width),
+    )
+}
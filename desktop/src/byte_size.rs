@@ -0,0 +1,58 @@
+use std::str::FromStr;
+
+/// A size in bytes that can be parsed from strings like "512MB" or "2GiB"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub usize);
+
+/// Known units and their size in bytes, longer units first so that suffixes are matched correctly
+const UNITS: &[(&str, usize)] = &[
+    ("kib", 1 << 10),
+    ("mib", 1 << 20),
+    ("gib", 1 << 30),
+    ("tib", 1 << 40),
+    ("kb", 1_000),
+    ("mb", 1_000_000),
+    ("gb", 1_000_000_000),
+    ("tb", 1_000_000_000_000),
+    ("b", 1),
+];
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lowercase = s.trim().to_lowercase();
+        let (number, unit_size) = UNITS
+            .iter()
+            .find_map(|(unit, size)| {
+                lowercase
+                    .strip_suffix(unit)
+                    .map(|number| (number.trim(), *size))
+            })
+            .unwrap_or((lowercase.as_str(), 1));
+
+        let number: f64 = number
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid size {}, expected e.g. 512MB or 2GiB", s))?;
+        if number < 0.0 {
+            anyhow::bail!("Size {} must not be negative", s);
+        }
+        Ok(ByteSize((number * unit_size as f64) as usize))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_byte_size() {
+        assert_eq!("1024".parse::<ByteSize>().unwrap(), ByteSize(1024));
+        assert_eq!("2GiB".parse::<ByteSize>().unwrap(), ByteSize(2 << 30));
+        assert_eq!("512 MB".parse::<ByteSize>().unwrap(), ByteSize(512_000_000));
+        assert_eq!("1.5kib".parse::<ByteSize>().unwrap(), ByteSize(1536));
+        assert_eq!("10B".parse::<ByteSize>().unwrap(), ByteSize(10));
+        assert!("GiB".parse::<ByteSize>().is_err());
+        assert!("-1GB".parse::<ByteSize>().is_err());
+    }
+}
@@ -0,0 +1,239 @@
+use std::fmt;
+
+use image::imageops::FilterType;
+use image::{Rgb, RgbImage};
+
+use crate::image_utils::Rgb16Image;
+
+/// The side length of the square windows that SSIM is computed on
+const SSIM_WINDOW: u32 = 8;
+/// Stabilizes the SSIM luminance term for dark windows
+const SSIM_C1: f64 = 0.01 * 0.01;
+/// Stabilizes the SSIM contrast term for flat windows
+const SSIM_C2: f64 = 0.03 * 0.03;
+
+/// Quality metrics of a processed image compared to a reference image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EvaluationReport {
+    /// The peak signal-to-noise ratio in dB, infinite for identical images
+    pub psnr: f64,
+    /// The mean structural similarity of the luma channel, 1.0 for identical images
+    pub ssim: f64,
+    /// The largest mean absolute error of a pixel, this is the maximum of the error map
+    pub max_error: f64,
+}
+
+impl fmt::Display for EvaluationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PSNR: {:.2} dB, SSIM: {:.4}, max error: {:.4}",
+            self.psnr, self.ssim, self.max_error
+        )
+    }
+}
+
+/// Compare a processed image to a reference image of the same size
+pub fn evaluate(image: &Rgb16Image, reference: &Rgb16Image) -> EvaluationReport {
+    EvaluationReport {
+        psnr: psnr(image, reference),
+        ssim: ssim(image, reference),
+        max_error: pixel_errors(image, reference).fold(0.0, f64::max),
+    }
+}
+
+/// Resize the reference image to the given size if it has the same aspect ratio
+///
+/// This allows comparing the output of a model to a reference with a different resolution, e.g.
+/// a high resolution ground truth of a super resolution model.
+pub fn match_reference_size(
+    reference: Rgb16Image,
+    width: u32,
+    height: u32,
+) -> anyhow::Result<Rgb16Image> {
+    let (reference_width, reference_height) = reference.dimensions();
+    if (reference_width, reference_height) == (width, height) {
+        return Ok(reference);
+    }
+    // Allow a rounding error of one pixel for odd sizes
+    let scaled_height = reference_height as f64 * width as f64 / reference_width as f64;
+    if (scaled_height - height as f64).abs() > 1.0 {
+        anyhow::bail!(
+            "The reference image has the size {}x{}, which does not match the aspect ratio of {}x{}",
+            reference_width,
+            reference_height,
+            width,
+            height
+        );
+    }
+    log::warn!(
+        "Resizing the reference image from {}x{} to {}x{}",
+        reference_width,
+        reference_height,
+        width,
+        height
+    );
+    Ok(image::imageops::resize(
+        &reference,
+        width,
+        height,
+        FilterType::Lanczos3,
+    ))
+}
+
+/// A false color map of the mean absolute error of each pixel
+///
+/// The errors are scaled to the largest error, so small errors are visible as well. Black means
+/// no error, the colors go through red and yellow to white for the largest error.
+pub fn error_map(image: &Rgb16Image, reference: &Rgb16Image) -> RgbImage {
+    let max_error = pixel_errors(image, reference).fold(0.0, f64::max);
+    let mut errors = pixel_errors(image, reference);
+    RgbImage::from_fn(image.width(), image.height(), |_, _| {
+        let error = errors.next().unwrap_or_default();
+        let t = if max_error > 0.0 {
+            error / max_error
+        } else {
+            0.0
+        };
+        let channel = |offset: f64| ((3.0 * t - offset).clamp(0.0, 1.0) * 255.0).round() as u8;
+        Rgb([channel(0.0), channel(1.0), channel(2.0)])
+    })
+}
+
+/// The peak signal-to-noise ratio in dB of two images of the same size
+pub fn psnr(image: &Rgb16Image, reference: &Rgb16Image) -> f64 {
+    let squared_error: f64 = image
+        .as_raw()
+        .iter()
+        .zip(reference.as_raw())
+        .map(|(&a, &b)| (normalized(a) - normalized(b)).powi(2))
+        .sum();
+    let mse = squared_error / image.as_raw().len().max(1) as f64;
+    -10.0 * mse.log10()
+}
+
+/// The mean structural similarity of the luma channel of two images of the same size
+///
+/// SSIM is computed on non-overlapping windows of 8x8 pixels, partial windows at the right and
+/// bottom border are ignored unless the image is smaller than a window.
+pub fn ssim(image: &Rgb16Image, reference: &Rgb16Image) -> f64 {
+    let window_width = SSIM_WINDOW.min(image.width());
+    let window_height = SSIM_WINDOW.min(image.height());
+    let window_origins =
+        |size: u32, window: u32| (0..=size.saturating_sub(window)).step_by(window.max(1) as usize);
+    let mut sum = 0.0;
+    let mut windows = 0;
+    for y in window_origins(image.height(), window_height) {
+        for x in window_origins(image.width(), window_width) {
+            let pixels: Vec<_> = (y..y + window_height)
+                .flat_map(|y| (x..x + window_width).map(move |x| (x, y)))
+                .map(|(x, y)| (luma(image.get_pixel(x, y)), luma(reference.get_pixel(x, y))))
+                .collect();
+            if pixels.is_empty() {
+                continue;
+            }
+            let count = pixels.len() as f64;
+            let mean_a = pixels.iter().map(|p| p.0).sum::<f64>() / count;
+            let mean_b = pixels.iter().map(|p| p.1).sum::<f64>() / count;
+            let (mut var_a, mut var_b, mut covariance) = (0.0, 0.0, 0.0);
+            for (a, b) in &pixels {
+                var_a += (a - mean_a).powi(2) / count;
+                var_b += (b - mean_b).powi(2) / count;
+                covariance += (a - mean_a) * (b - mean_b) / count;
+            }
+            sum += ((2.0 * mean_a * mean_b + SSIM_C1) * (2.0 * covariance + SSIM_C2))
+                / ((mean_a.powi(2) + mean_b.powi(2) + SSIM_C1) * (var_a + var_b + SSIM_C2));
+            windows += 1;
+        }
+    }
+    if windows == 0 {
+        1.0
+    } else {
+        sum / windows as f64
+    }
+}
+
+/// The mean absolute error of each pixel in row-major order, in the [0,1] range
+fn pixel_errors<'a>(
+    image: &'a Rgb16Image,
+    reference: &'a Rgb16Image,
+) -> impl Iterator<Item = f64> + 'a {
+    image.pixels().zip(reference.pixels()).map(|(a, b)| {
+        (0..3)
+            .map(|c| (normalized(a[c]) - normalized(b[c])).abs())
+            .sum::<f64>()
+            / 3.0
+    })
+}
+
+fn normalized(value: u16) -> f64 {
+    value as f64 / u16::MAX as f64
+}
+
+fn luma(pixel: &Rgb<u16>) -> f64 {
+    0.299 * normalized(pixel[0]) + 0.587 * normalized(pixel[1]) + 0.114 * normalized(pixel[2])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::identity_processor;
+
+    fn gradient(width: u32, height: u32) -> Rgb16Image {
+        Rgb16Image::from_fn(width, height, |x, y| {
+            Rgb([(x * 500) as u16, (y * 500) as u16, 20000])
+        })
+    }
+
+    #[test]
+    fn test_evaluate_known_error() {
+        let reference = gradient(64, 48);
+        // An error of 1% on every value, except for one pixel with a larger error
+        let mut noisy = Rgb16Image::from_fn(64, 48, |x, y| {
+            let pixel = reference.get_pixel(x, y);
+            Rgb([pixel[0] + 655, pixel[1] + 655, pixel[2] + 655])
+        });
+        noisy.put_pixel(10, 20, Rgb([40000, 655, 20655]));
+
+        let mut processor = identity_processor();
+        let output = pollster::block_on(processor.process_image(noisy)).unwrap();
+        let report = evaluate(&output, &reference);
+
+        // About 40 dB for the 1% error, the pixel with a larger error lowers the PSNR
+        let small_error = 3071.0 * 3.0 * normalized(655).powi(2);
+        let large_error: f64 = [35000, 9345, 655]
+            .iter()
+            .map(|&e| normalized(e).powi(2))
+            .sum();
+        let expected_psnr = -10.0 * ((small_error + large_error) / (3072.0 * 3.0)).log10();
+        assert!((report.psnr - expected_psnr).abs() < 0.1, "{}", report);
+        assert!(report.ssim > 0.9 && report.ssim < 1.0, "{}", report);
+
+        let map = error_map(&output, &reference);
+        assert_eq!(map.get_pixel(10, 20), &Rgb([255, 255, 255]));
+        let pixel = map.get_pixel(0, 0);
+        assert!(pixel[0] < 50 && pixel[1] == 0 && pixel[2] == 0);
+    }
+
+    #[test]
+    fn test_identical_images() {
+        let image = gradient(20, 10);
+        let report = evaluate(&image, &image);
+        assert!(report.psnr.is_infinite());
+        assert!((report.ssim - 1.0).abs() < 1e-9);
+        assert_eq!(report.max_error, 0.0);
+    }
+
+    #[test]
+    fn test_match_reference_size() {
+        let reference = gradient(64, 48);
+
+        let resized = match_reference_size(reference.clone(), 32, 24).unwrap();
+        assert_eq!(resized.dimensions(), (32, 24));
+        assert_eq!(
+            match_reference_size(reference.clone(), 64, 48).unwrap(),
+            reference
+        );
+        assert!(match_reference_size(reference, 64, 64).is_err());
+    }
+}
@@ -0,0 +1,1146 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::Context;
+use image::codecs::{jpeg::JpegDecoder, png::PngDecoder, tiff::TiffDecoder};
+use image::{
+    DynamicImage, ImageBuffer, ImageDecoder, ImageError, ImageFormat, Rgb, Rgb32FImage, Rgba,
+};
+use little_exif::exif_tag::ExifTag;
+use little_exif::metadata::Metadata;
+use ndarray::Array2;
+
+pub type Rgb16Image = ImageBuffer<Rgb<u16>, Vec<u16>>;
+pub type Rgba16Image = ImageBuffer<Rgba<u16>, Vec<u16>>;
+
+/// The TIFF tag that holds an embedded ICC profile
+const TIFF_ICC_PROFILE_TAG: u16 = 34675;
+/// The file extension of Adobe DNG files
+const DNG_EXTENSION: &str = "dng";
+
+/// Defines how the color space of input images is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorManagement {
+    /// Treat all pixel data as sRGB and ignore embedded ICC profiles
+    AssumeSrgb,
+    /// Convert images with an embedded ICC profile to sRGB before processing
+    ///
+    /// This costs an additional pass over the image data.
+    ColorManage,
+}
+
+/// Load an image as 16 bit RGB data
+pub fn load_image<P: AsRef<Path>>(
+    path: P,
+    color_management: ColorManagement,
+) -> anyhow::Result<Rgb16Image> {
+    Ok(load_dynamic_image(path.as_ref(), color_management)?.into_rgb16())
+}
+
+/// Load an image as 16 bit RGBA data, images without alpha channel are opaque
+pub fn load_image_rgba<P: AsRef<Path>>(
+    path: P,
+    color_management: ColorManagement,
+) -> anyhow::Result<Rgba16Image> {
+    Ok(load_dynamic_image(path.as_ref(), color_management)?.into_rgba16())
+}
+
+/// Load an image as floating point RGB data without quantizing it to 16 bits
+///
+/// TIFFs with 32 or 64 bit samples keep their full precision, see `load_wide_tiff`.
+pub fn load_image_f32<P: AsRef<Path>>(
+    path: P,
+    color_management: ColorManagement,
+) -> anyhow::Result<Rgb32FImage> {
+    Ok(load_dynamic_image(path.as_ref(), color_management)?.into_rgb32f())
+}
+
+/// Load a mask as HxW data in [0,1], see `ImageProcessor::set_mask`
+///
+/// Images with an alpha channel use it as the mask, other images use their brightness.
+pub fn load_mask<P: AsRef<Path>>(path: P) -> anyhow::Result<Array2<f32>> {
+    let image = load_dynamic_image(path.as_ref(), ColorManagement::AssumeSrgb)?;
+    let mask = if image.color().has_alpha() {
+        let image = image.into_rgba16();
+        Array2::from_shape_fn(
+            (image.height() as usize, image.width() as usize),
+            |(y, x)| image.get_pixel(x as u32, y as u32)[3],
+        )
+    } else {
+        let image = image.into_luma16();
+        Array2::from_shape_fn(
+            (image.height() as usize, image.width() as usize),
+            |(y, x)| image.get_pixel(x as u32, y as u32)[0],
+        )
+    };
+    Ok(mask.mapv(|v| v as f32 / u16::MAX as f32))
+}
+
+fn load_dynamic_image(
+    path: &Path,
+    color_management: ColorManagement,
+) -> anyhow::Result<DynamicImage> {
+    let format = image_format(path)?;
+    if format == ImageFormat::Tiff {
+        if let Some(image) = load_cmyk_tiff(path, color_management)? {
+            return Ok(DynamicImage::ImageRgb16(image));
+        }
+        if let Some(image) = load_wide_tiff(path)? {
+            return Ok(image);
+        }
+    }
+
+    let indexed_png = if format == ImageFormat::Png {
+        load_indexed_png(path)?
+    } else {
+        None
+    };
+    let image = match indexed_png {
+        Some(image) => image,
+        None => {
+            let mut reader = image::io::Reader::open(path)
+                .with_context(|| format!("Could not open {}", path.display()))?;
+            reader.set_format(format);
+            reader.decode().with_context(|| {
+                if is_dng(path) {
+                    format!(
+                        "{} can not be read as TIFF, only DNGs with uncompressed RGB data are supported",
+                        path.display()
+                    )
+                } else {
+                    format!("Could not decode {}", path.display())
+                }
+            })?
+        }
+    };
+
+    match color_management {
+        ColorManagement::AssumeSrgb => Ok(image),
+        ColorManagement::ColorManage => match read_icc_profile(path)? {
+            Some(icc) => convert_to_srgb(image, &icc),
+            None => {
+                log::debug!(
+                    "{} has no embedded ICC profile, assuming sRGB",
+                    path.display()
+                );
+                Ok(image)
+            }
+        },
+    }
+}
+
+/// Defines what `save_image_with_fallback` does if a 16 bit TIFF can not be written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitDepthFallback {
+    /// Return the error, so no precision is lost silently
+    Fail,
+    /// Save the image with 8 bits per channel instead
+    Downgrade,
+}
+
+/// The compressions that are tried in order when saving a 16 bit TIFF
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TiffCompression {
+    Uncompressed,
+    Lzw,
+    Deflate,
+}
+
+const TIFF_COMPRESSIONS: [TiffCompression; 3] = [
+    TiffCompression::Uncompressed,
+    TiffCompression::Lzw,
+    TiffCompression::Deflate,
+];
+
+/// Save 16 bit RGB data
+///
+/// Formats that can not store 16 bit data (like JPEG) are saved with 8 bits per channel.
+/// Like all `save_*` functions, this writes a temporary file first, so `path` either holds the
+/// complete image or is not touched at all.
+pub fn save_image<P: AsRef<Path>>(image: &Rgb16Image, path: P) -> anyhow::Result<()> {
+    save_image_with_fallback(image, path, BitDepthFallback::Fail)
+}
+
+/// Save 16 bit RGB data, see `save_image`
+///
+/// TIFFs are written with each of `TIFF_COMPRESSIONS` until one succeeds. Only if all of them
+/// fail, `fallback` decides if the image is saved with 8 bits per channel.
+pub fn save_image_with_fallback<P: AsRef<Path>>(
+    image: &Rgb16Image,
+    path: P,
+    fallback: BitDepthFallback,
+) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    if ImageFormat::from_path(path).ok() != Some(ImageFormat::Tiff) {
+        return write_atomically(path, |temp_path| {
+            match image.save(temp_path) {
+                Err(ImageError::Unsupported(_)) => {
+                    log::warn!(
+                        "{} can not store 16 bit data, saving with 8 bits per channel",
+                        path.display()
+                    );
+                    save_image_8_bit(image, temp_path)?;
+                }
+                result => result?,
+            }
+            Ok(())
+        });
+    }
+
+    write_atomically(path, |temp_path| {
+        let result = save_with_first_working(temp_path, &TIFF_COMPRESSIONS, |path, compression| {
+            save_tiff_16_bit(image, path, *compression)
+        });
+        match (result, fallback) {
+            (Ok(()), _) => Ok(()),
+            (Err(err), BitDepthFallback::Downgrade) => {
+                log::warn!(
+                    "{} could not be saved with 16 bits per channel, saving with 8 bits: {:#}",
+                    path.display(),
+                    err
+                );
+                save_image_8_bit(image, temp_path)
+            }
+            (Err(err), BitDepthFallback::Fail) => Err(err.context(format!(
+                "Could not save {} with 16 bits per channel",
+                path.display()
+            ))),
+        }
+    })
+}
+
+/// Call `save` with each option in order until it succeeds, returns the last error otherwise
+fn save_with_first_working<T: std::fmt::Debug>(
+    path: &Path,
+    options: &[T],
+    mut save: impl FnMut(&Path, &T) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut last_error = anyhow::anyhow!("No encoding to save {} with", path.display());
+    for option in options {
+        match save(path, option) {
+            Ok(()) => return Ok(()),
+            Err(err) => {
+                log::debug!(
+                    "Could not save {} with {:?}: {:#}",
+                    path.display(),
+                    option,
+                    err
+                );
+                last_error = err;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+fn save_tiff_16_bit(
+    image: &Rgb16Image,
+    path: &Path,
+    compression: TiffCompression,
+) -> anyhow::Result<()> {
+    use tiff::encoder::{colortype::RGB16, compression};
+
+    let mut encoder = tiff::encoder::TiffEncoder::new(BufWriter::new(File::create(path)?))?;
+    let (width, height) = image.dimensions();
+    match compression {
+        TiffCompression::Uncompressed => encoder.write_image_with_compression::<RGB16, _>(
+            width,
+            height,
+            compression::Uncompressed,
+            image.as_raw(),
+        )?,
+        TiffCompression::Lzw => encoder.write_image_with_compression::<RGB16, _>(
+            width,
+            height,
+            compression::Lzw,
+            image.as_raw(),
+        )?,
+        TiffCompression::Deflate => encoder.write_image_with_compression::<RGB16, _>(
+            width,
+            height,
+            compression::Deflate::default(),
+            image.as_raw(),
+        )?,
+    }
+    Ok(())
+}
+
+fn save_image_8_bit(image: &Rgb16Image, path: &Path) -> anyhow::Result<()> {
+    Ok(DynamicImage::ImageRgb16(image.clone())
+        .into_rgb8()
+        .save(path)?)
+}
+
+/// The temporary file that `write_atomically` writes to, it keeps the extension of `path` so
+/// the format is still detected from it
+fn temp_path(path: &Path) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut temp_path = path.with_file_name(format!(".{}.{}.partial", stem, std::process::id()));
+    if let Some(extension) = path.extension() {
+        temp_path.set_extension(extension);
+    }
+    temp_path
+}
+
+/// Let `write` create a temporary file next to `path` and move it to `path` once it succeeded
+///
+/// If the temporary file can not be renamed, e.g. because `path` is on another file system
+/// than its directory, it is copied instead, which is not atomic.
+fn write_atomically<F>(path: &Path, write: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&Path) -> anyhow::Result<()>,
+{
+    let temp_path = temp_path(path);
+    if let Err(err) = write(&temp_path) {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(err);
+    }
+
+    if let Err(err) = std::fs::rename(&temp_path, path) {
+        log::debug!(
+            "Could not rename {} to {}, copying it instead: {}",
+            temp_path.display(),
+            path.display(),
+            err
+        );
+        let copied = std::fs::copy(&temp_path, path);
+        let _ = std::fs::remove_file(&temp_path);
+        copied.with_context(|| format!("Could not write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Encode an image in the given format and write it to `writer`, e.g. stdout
+///
+/// Like `save_image`, 8 bit data is written for formats that can not store 16 bit data.
+pub fn write_image<W: Write>(
+    image: &Rgb16Image,
+    writer: &mut W,
+    format: ImageFormat,
+) -> anyhow::Result<()> {
+    // Most encoders need to seek, so the image is encoded in memory first
+    let mut encoded = Cursor::new(Vec::new());
+    match image.write_to(&mut encoded, format) {
+        Err(ImageError::Unsupported(_)) => {
+            log::warn!(
+                "{:?} can not store 16 bit data, writing 8 bits per channel",
+                format
+            );
+            encoded = Cursor::new(Vec::new());
+            DynamicImage::ImageRgb16(image.clone())
+                .into_rgb8()
+                .write_to(&mut encoded, format)?;
+        }
+        result => result?,
+    }
+    writer.write_all(encoded.get_ref())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Save 16 bit RGBA data, the format has to support an alpha channel
+pub fn save_image_rgba<P: AsRef<Path>>(image: &Rgba16Image, path: P) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    write_atomically(path, |temp_path| {
+        image
+            .save(temp_path)
+            .with_context(|| format!("Could not save RGBA image {}", path.display()))
+    })
+}
+
+/// Save floating point RGB data to an OpenEXR or floating point TIFF file
+pub fn save_image_f32<P: AsRef<Path>>(image: &Rgb32FImage, path: P) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let format = ImageFormat::from_path(path)?;
+    if !matches!(format, ImageFormat::Tiff | ImageFormat::OpenExr) {
+        anyhow::bail!(
+            "{} can not store floating point data, use an .exr or .tif file",
+            path.display()
+        );
+    }
+    write_atomically(path, |temp_path| {
+        if format == ImageFormat::Tiff {
+            tiff::encoder::TiffEncoder::new(BufWriter::new(File::create(temp_path)?))?
+                .write_image::<tiff::encoder::colortype::RGB32Float>(
+                image.width(),
+                image.height(),
+                image.as_raw(),
+            )?;
+        } else {
+            image.save(temp_path)?;
+        }
+        Ok(())
+    })
+}
+
+fn is_dng(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| extension.eq_ignore_ascii_case(DNG_EXTENSION))
+        .unwrap_or_default()
+}
+
+/// Determine the format of an image file from its extension
+///
+/// DNG files are TIFF files, so uncompressed DNGs can be read by the TIFF decoder.
+fn image_format(path: &Path) -> anyhow::Result<ImageFormat> {
+    if is_dng(path) {
+        Ok(ImageFormat::Tiff)
+    } else {
+        Ok(ImageFormat::from_path(path)?)
+    }
+}
+
+fn read_icc_profile(path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+    let reader = BufReader::new(File::open(path)?);
+    Ok(match image_format(path)? {
+        ImageFormat::Png => PngDecoder::new(reader)?.icc_profile(),
+        ImageFormat::Jpeg => JpegDecoder::new(reader)?.icc_profile(),
+        ImageFormat::Tiff => TiffDecoder::new(reader)?.icc_profile(),
+        _ => None,
+    })
+}
+
+/// Load a CMYK TIFF and convert it to RGB
+///
+/// Returns `None` if the TIFF is not a CMYK image.
+/// With `ColorManagement::ColorManage` and an embedded ICC profile, the profile is used to convert
+/// to sRGB, otherwise a naive conversion without any color management is done.
+fn load_cmyk_tiff(
+    path: &Path,
+    color_management: ColorManagement,
+) -> anyhow::Result<Option<Rgb16Image>> {
+    let mut decoder = tiff::decoder::Decoder::new(BufReader::new(File::open(path)?))?;
+    let bits = match decoder.colortype()? {
+        tiff::ColorType::CMYK(bits) => bits,
+        _ => return Ok(None),
+    };
+    let (width, height) = decoder.dimensions()?;
+    let icc = match color_management {
+        ColorManagement::AssumeSrgb => None,
+        ColorManagement::ColorManage => decoder
+            .find_tag(tiff::tags::Tag::from_u16_exhaustive(TIFF_ICC_PROFILE_TAG))?
+            .map(|value| value.into_u8_vec())
+            .transpose()?,
+    };
+    let cmyk: Vec<u16> = match decoder.read_image()? {
+        tiff::decoder::DecodingResult::U8(data) => {
+            data.into_iter().map(|v| v as u16 * 257).collect()
+        }
+        tiff::decoder::DecodingResult::U16(data) => data,
+        _ => anyhow::bail!("CMYK TIFFs with {} bit samples are not supported", bits),
+    };
+    if cmyk.len() != width as usize * height as usize * 4 {
+        anyhow::bail!("{} has an unexpected number of samples", path.display());
+    }
+
+    let mut rgb = vec![0u16; width as usize * height as usize * 3];
+    if let Some(icc) = icc {
+        let source_profile = lcms2::Profile::new_icc(&icc)?;
+        let target_profile = lcms2::Profile::new_srgb();
+        let transform: lcms2::Transform<[u16; 4], [u16; 3]> = lcms2::Transform::new(
+            &source_profile,
+            lcms2::PixelFormat::CMYK_16,
+            &target_profile,
+            lcms2::PixelFormat::RGB_16,
+            lcms2::Intent::Perceptual,
+        )?;
+        transform.transform_pixels(
+            bytemuck::cast_slice(&cmyk),
+            bytemuck::cast_slice_mut(&mut rgb),
+        );
+        log::info!(
+            "Converted CMYK image {} to sRGB using its ICC profile",
+            path.display()
+        );
+    } else {
+        for (rgb_pixel, cmyk_pixel) in rgb.chunks_exact_mut(3).zip(cmyk.chunks_exact(4)) {
+            let key = 1.0 - cmyk_pixel[3] as f32 / u16::MAX as f32;
+            for c in 0..3 {
+                let ink = 1.0 - cmyk_pixel[c] as f32 / u16::MAX as f32;
+                rgb_pixel[c] = (ink * key * u16::MAX as f32).round() as u16;
+            }
+        }
+        log::info!(
+            "Converted CMYK image {} to RGB without color management",
+            path.display()
+        );
+    }
+
+    ImageBuffer::from_raw(width, height, rgb)
+        .map(Some)
+        .ok_or_else(|| anyhow::anyhow!("{} has an unexpected number of samples", path.display()))
+}
+
+/// Load a gray, RGB or RGBA TIFF with 32 or 64 bit samples as floating point data
+///
+/// Returns `None` for TIFFs with up to 16 bit samples. Float samples are passed through
+/// unchanged, so 1.0 is white and values outside of [0,1] are kept. Integer samples are scaled
+/// by the maximum of their type, e.g. `u32::MAX` is white.
+fn load_wide_tiff(path: &Path) -> anyhow::Result<Option<DynamicImage>> {
+    let mut decoder = tiff::decoder::Decoder::new(BufReader::new(File::open(path)?))?;
+    let channels = match decoder.colortype()? {
+        tiff::ColorType::Gray(bits) if bits > 16 => 1,
+        tiff::ColorType::RGB(bits) if bits > 16 => 3,
+        tiff::ColorType::RGBA(bits) if bits > 16 => 4,
+        _ => return Ok(None),
+    };
+    let (width, height) = decoder.dimensions()?;
+    let samples: Vec<f32> = match decoder.read_image()? {
+        tiff::decoder::DecodingResult::F32(data) => data,
+        tiff::decoder::DecodingResult::F64(data) => data.into_iter().map(|v| v as f32).collect(),
+        tiff::decoder::DecodingResult::U32(data) => data
+            .into_iter()
+            .map(|v| (v as f64 / u32::MAX as f64) as f32)
+            .collect(),
+        tiff::decoder::DecodingResult::U64(data) => data
+            .into_iter()
+            .map(|v| (v as f64 / u64::MAX as f64) as f32)
+            .collect(),
+        _ => anyhow::bail!(
+            "{} has a sample format that is not supported",
+            path.display()
+        ),
+    };
+    log::debug!(
+        "Loaded {} with more than 16 bits per sample as floating point data",
+        path.display()
+    );
+
+    let image = match channels {
+        1 => ImageBuffer::from_raw(
+            width,
+            height,
+            samples.into_iter().flat_map(|v| [v, v, v]).collect(),
+        )
+        .map(DynamicImage::ImageRgb32F),
+        3 => ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgb32F),
+        _ => ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgba32F),
+    };
+    image
+        .map(Some)
+        .ok_or_else(|| anyhow::anyhow!("{} has an unexpected number of samples", path.display()))
+}
+
+/// Load a palette PNG and expand its palette to 8 bit RGB, or RGBA if it has transparency
+///
+/// Returns `None` if the PNG is not a palette image.
+fn load_indexed_png(path: &Path) -> anyhow::Result<Option<DynamicImage>> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder
+        .read_info()
+        .with_context(|| format!("Could not decode {}", path.display()))?;
+    let bit_depth = match reader.info() {
+        info if info.color_type == png::ColorType::Indexed => info.bit_depth as u8,
+        _ => return Ok(None),
+    };
+
+    let mut data = vec![0; reader.output_buffer_size()];
+    let frame = reader
+        .next_frame(&mut data)
+        .with_context(|| format!("Could not decode {}", path.display()))?;
+    data.truncate(frame.buffer_size());
+    let image = match frame.color_type {
+        png::ColorType::Rgb => {
+            ImageBuffer::from_raw(frame.width, frame.height, data).map(DynamicImage::ImageRgb8)
+        }
+        png::ColorType::Rgba => {
+            ImageBuffer::from_raw(frame.width, frame.height, data).map(DynamicImage::ImageRgba8)
+        }
+        color_type => anyhow::bail!(
+            "The palette of {} was expanded to {:?} instead of RGB",
+            path.display(),
+            color_type
+        ),
+    }
+    .with_context(|| format!("Could not decode {}", path.display()))?;
+    log::info!(
+        "Expanded the {} bit palette of {} to {}",
+        bit_depth,
+        path.display(),
+        if image.color().has_alpha() {
+            "RGBA"
+        } else {
+            "RGB"
+        }
+    );
+
+    Ok(Some(image))
+}
+
+fn convert_to_srgb(image: DynamicImage, icc: &[u8]) -> anyhow::Result<DynamicImage> {
+    let source_profile = lcms2::Profile::new_icc(icc)?;
+    let target_profile = lcms2::Profile::new_srgb();
+
+    let converted = match image {
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => {
+            let transform: lcms2::Transform<[f32; 3], [f32; 3]> = lcms2::Transform::new(
+                &source_profile,
+                lcms2::PixelFormat::RGB_FLT,
+                &target_profile,
+                lcms2::PixelFormat::RGB_FLT,
+                lcms2::Intent::Perceptual,
+            )?;
+            let mut image = image.into_rgb32f();
+            transform.transform_in_place(bytemuck::cast_slice_mut(&mut image[..]));
+            DynamicImage::ImageRgb32F(image)
+        }
+        _ => {
+            let transform: lcms2::Transform<[u16; 3], [u16; 3]> = lcms2::Transform::new(
+                &source_profile,
+                lcms2::PixelFormat::RGB_16,
+                &target_profile,
+                lcms2::PixelFormat::RGB_16,
+                lcms2::Intent::Perceptual,
+            )?;
+            let mut image = image.into_rgb16();
+            transform.transform_in_place(bytemuck::cast_slice_mut(&mut image[..]));
+            DynamicImage::ImageRgb16(image)
+        }
+    };
+    log::info!("Converted image with embedded ICC profile to sRGB");
+
+    Ok(converted)
+}
+
+/// Build the provenance information that is recorded in processed images
+///
+/// This contains the NeuraTable version and the SHA256 hash of the ONNX model.
+pub fn provenance_tag(model_bytes: &[u8]) -> String {
+    format!(
+        "NeuraTable {}; Model={}",
+        backend::version(),
+        model_hash(model_bytes)
+    )
+}
+
+/// The SHA256 hash of an ONNX model as a hex string
+pub fn model_hash(model_bytes: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+
+    format!("{:x}", Sha256::digest(model_bytes))
+}
+
+/// The tool that copies the metadata of images, see `MetadataHandler`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataBackend {
+    /// The exiftool command, which copies all tags including XMP and maker notes
+    Exiftool,
+    /// little_exif, which copies the EXIF tags in-process, e.g. the orientation, the capture
+    /// date and the camera tags
+    Native,
+}
+
+/// Copies metadata from input images to processed images
+pub struct MetadataHandler {
+    backend: MetadataBackend,
+    provenance: Option<String>,
+    preserve_mtime: bool,
+}
+
+impl MetadataHandler {
+    /// Create a handler that uses exiftool if it can be executed, and the native backend
+    /// otherwise
+    pub fn new() -> Self {
+        let backend = if Command::new("exiftool").arg("-ver").output().is_ok() {
+            MetadataBackend::Exiftool
+        } else {
+            log::warn!(
+                "exiftool could not be executed, only the EXIF tags of images will be copied"
+            );
+            MetadataBackend::Native
+        };
+
+        Self {
+            backend,
+            provenance: None,
+            preserve_mtime: false,
+        }
+    }
+
+    /// Copy the metadata with the given backend instead of the detected one
+    pub fn with_backend(mut self, backend: MetadataBackend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub fn backend(&self) -> MetadataBackend {
+        self.backend
+    }
+
+    /// Write the given provenance information to all outputs
+    ///
+    /// exiftool writes it to the ProcessingSoftware tag, the native backend to the Software tag.
+    pub fn with_provenance(mut self, provenance: String) -> Self {
+        self.provenance = Some(provenance);
+        self
+    }
+
+    /// Give outputs the access and modification time of their input
+    ///
+    /// This works without exiftool. Creation times are not copied, they can not be set on most
+    /// platforms.
+    pub fn with_preserved_mtime(mut self, preserve_mtime: bool) -> Self {
+        self.preserve_mtime = preserve_mtime;
+        self
+    }
+
+    pub fn copy_metadata(&self, source: &Path, destination: &Path) {
+        match self.backend {
+            MetadataBackend::Exiftool => self.copy_tags(source, destination),
+            MetadataBackend::Native => {
+                if let Err(err) = self.copy_exif(source, destination) {
+                    log::warn!(
+                        "Could not copy the EXIF tags of {}: {}",
+                        source.display(),
+                        err
+                    );
+                }
+            }
+        }
+        // Writing tags changes the modification time, so it is copied last
+        if self.preserve_mtime {
+            if let Err(err) = copy_file_times(source, destination) {
+                log::error!(
+                    "Failed to copy the file times of {}: {}",
+                    source.display(),
+                    err
+                );
+            }
+        }
+    }
+
+    fn copy_tags(&self, source: &Path, destination: &Path) {
+        if Command::new("exiftool")
+            .args(["-overwrite_original", "-tagsFromFile"])
+            .arg(source)
+            .arg(destination)
+            .output()
+            .is_err()
+        {
+            log::error!("Failed to run exiftool for {}", source.display());
+        }
+
+        if let Some(provenance) = &self.provenance {
+            if Command::new("exiftool")
+                .arg("-overwrite_original")
+                .arg(format!("-ProcessingSoftware={}", provenance))
+                .arg(destination)
+                .output()
+                .is_err()
+            {
+                log::error!(
+                    "Failed to write provenance information to {}",
+                    destination.display()
+                );
+            }
+        }
+    }
+
+    /// Copy the EXIF tags with little_exif, the formats it supports include JPEG, PNG, TIFF and
+    /// WebP
+    fn copy_exif(&self, source: &Path, destination: &Path) -> std::io::Result<()> {
+        let mut metadata = Metadata::new_from_path(source)?;
+        if let Some(provenance) = &self.provenance {
+            metadata.set_tag(ExifTag::Software(provenance.clone()));
+        }
+        metadata.write_to_file(destination)
+    }
+}
+
+fn copy_file_times(source: &Path, destination: &Path) -> std::io::Result<()> {
+    let metadata = source.metadata()?;
+    filetime::set_file_times(
+        destination,
+        filetime::FileTime::from_last_access_time(&metadata),
+        filetime::FileTime::from_last_modification_time(&metadata),
+    )
+}
+
+impl Default for MetadataHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Write a 2x1 PNG tagged with an AdobeRGB-like ICC profile
+    fn write_adobe_rgb_png(path: &Path, pixels: &[u8]) {
+        let white_point = lcms2::CIExyY {
+            x: 0.3127,
+            y: 0.3290,
+            Y: 1.0,
+        };
+        let primaries = lcms2::CIExyYTRIPLE {
+            Red: lcms2::CIExyY {
+                x: 0.64,
+                y: 0.33,
+                Y: 1.0,
+            },
+            Green: lcms2::CIExyY {
+                x: 0.21,
+                y: 0.71,
+                Y: 1.0,
+            },
+            Blue: lcms2::CIExyY {
+                x: 0.15,
+                y: 0.06,
+                Y: 1.0,
+            },
+        };
+        let gamma = lcms2::ToneCurve::new(2.2);
+        let profile =
+            lcms2::Profile::new_rgb(&white_point, &primaries, &[&*gamma, &*gamma, &*gamma])
+                .unwrap();
+
+        let mut iccp = b"AdobeRGB\0\0".to_vec();
+        let mut compressor =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        compressor.write_all(&profile.icc().unwrap()).unwrap();
+        iccp.extend(compressor.finish().unwrap());
+
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(path).unwrap()), 2, 1);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_chunk(png::chunk::iCCP, &iccp).unwrap();
+        writer.write_image_data(pixels).unwrap();
+    }
+
+    #[test]
+    fn test_color_management_modes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("adobe_rgb.png");
+        write_adobe_rgb_png(&path, &[200, 100, 50, 255, 255, 255]);
+
+        let assumed = load_image(&path, ColorManagement::AssumeSrgb).unwrap();
+        assert_eq!(
+            assumed.get_pixel(0, 0),
+            &Rgb([200 * 257, 100 * 257, 50 * 257])
+        );
+
+        let managed = load_image(&path, ColorManagement::ColorManage).unwrap();
+        assert_ne!(managed.get_pixel(0, 0), assumed.get_pixel(0, 0));
+        for c in 0..3 {
+            // White is the same in both color spaces
+            assert!(managed.get_pixel(1, 0)[c] > u16::MAX - 256);
+        }
+    }
+
+    #[test]
+    fn test_load_indexed_png() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("indexed.png");
+        let palette = [255, 0, 0, 0, 255, 0, 0, 0, 255, 10, 20, 30];
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(&path).unwrap()), 4, 1);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Four);
+        encoder.set_palette(palette.to_vec());
+        let mut writer = encoder.write_header().unwrap();
+        // Two pixels per byte with the indices 3, 2, 1, 0
+        writer.write_image_data(&[0x32, 0x10]).unwrap();
+
+        let image = load_image(&path, ColorManagement::AssumeSrgb).unwrap();
+
+        assert_eq!(image.dimensions(), (4, 1));
+        for (x, index) in [3, 2, 1, 0].into_iter().enumerate() {
+            let entry = &palette[index * 3..index * 3 + 3];
+            assert_eq!(
+                image.get_pixel(x as u32, 0),
+                &Rgb([
+                    entry[0] as u16 * 257,
+                    entry[1] as u16 * 257,
+                    entry[2] as u16 * 257
+                ])
+            );
+        }
+    }
+
+    #[test]
+    fn test_interrupted_save_leaves_no_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output.png");
+
+        let result = write_atomically(&path, |temp_path| {
+            std::fs::write(temp_path, b"\x89PNG truncated")?;
+            anyhow::bail!("interrupted")
+        });
+        assert!(result.is_err());
+        assert!(!path.exists());
+        assert_eq!(dir.path().read_dir().unwrap().count(), 0);
+
+        let image = Rgb16Image::from_pixel(3, 2, Rgb([1000, 2000, 3000]));
+        save_image(&image, &path).unwrap();
+        assert_eq!(
+            load_image(&path, ColorManagement::AssumeSrgb).unwrap(),
+            image
+        );
+        assert_eq!(dir.path().read_dir().unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_preserve_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.png");
+        let output = dir.path().join("output.png");
+        std::fs::write(&input, "").unwrap();
+        let input_mtime = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+        filetime::set_file_mtime(&input, input_mtime).unwrap();
+        let handler = |preserve_mtime| MetadataHandler {
+            backend: MetadataBackend::Native,
+            provenance: None,
+            preserve_mtime,
+        };
+        let output_mtime =
+            || filetime::FileTime::from_last_modification_time(&output.metadata().unwrap());
+
+        std::fs::write(&output, "").unwrap();
+        handler(false).copy_metadata(&input, &output);
+        assert_ne!(output_mtime(), input_mtime);
+
+        handler(true).copy_metadata(&input, &output);
+        assert_eq!(output_mtime(), input_mtime);
+    }
+
+    #[test]
+    fn test_load_cmyk_tiff() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cmyk.tif");
+        tiff::encoder::TiffEncoder::new(File::create(&path).unwrap())
+            .unwrap()
+            .write_image::<tiff::encoder::colortype::CMYK8>(
+                3,
+                1,
+                &[0, 0, 0, 0, 255, 0, 0, 0, 0, 0, 0, 255],
+            )
+            .unwrap();
+
+        let image = load_image(&path, ColorManagement::AssumeSrgb).unwrap();
+
+        assert_eq!(image.dimensions(), (3, 1));
+        assert_eq!(image.get_pixel(0, 0), &Rgb([u16::MAX, u16::MAX, u16::MAX]));
+        assert_eq!(image.get_pixel(1, 0), &Rgb([0, u16::MAX, u16::MAX]));
+        assert_eq!(image.get_pixel(2, 0), &Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn test_cmyk_tiff_color_management() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cmyk.tif");
+        let mut encoder = tiff::encoder::TiffEncoder::new(File::create(&path).unwrap()).unwrap();
+        let mut image = encoder
+            .new_image::<tiff::encoder::colortype::CMYK8>(1, 1)
+            .unwrap();
+        // A profile that can not be parsed shows whether it is used
+        image
+            .encoder()
+            .write_tag(
+                tiff::tags::Tag::from_u16_exhaustive(TIFF_ICC_PROFILE_TAG),
+                &b"not a profile"[..],
+            )
+            .unwrap();
+        image.write_data(&[0, 255, 0, 0]).unwrap();
+
+        let image = load_image(&path, ColorManagement::AssumeSrgb).unwrap();
+        assert_eq!(image.get_pixel(0, 0), &Rgb([u16::MAX, 0, u16::MAX]));
+        assert!(load_image(&path, ColorManagement::ColorManage).is_err());
+    }
+
+    #[test]
+    fn test_load_float_tiff() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("float.tif");
+        // Values that do not fit into 16 bits, including values outside of [0,1]
+        let samples = [0.123_456_7, 1e-7, 2.5, -0.25, 0.5, 1.0];
+        tiff::encoder::TiffEncoder::new(File::create(&path).unwrap())
+            .unwrap()
+            .write_image::<tiff::encoder::colortype::RGB32Float>(2, 1, &samples)
+            .unwrap();
+
+        let image = load_image_f32(&path, ColorManagement::AssumeSrgb).unwrap();
+
+        assert_eq!(image.dimensions(), (2, 1));
+        assert_eq!(image.as_raw().as_slice(), &samples);
+    }
+
+    #[test]
+    fn test_save_float_tiff() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output.tif");
+        let image = Rgb32FImage::from_fn(5, 3, |x, y| {
+            Rgb([x as f32 / 7.0, y as f32 * 1.5, -(x as f32) * 1e-6])
+        });
+
+        save_image_f32(&image, &path).unwrap();
+
+        assert_eq!(
+            load_image_f32(&path, ColorManagement::AssumeSrgb).unwrap(),
+            image
+        );
+    }
+
+    #[test]
+    fn test_tiff_alternative_encoding() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output.tif");
+        let image = Rgb16Image::from_fn(5, 3, |x, y| Rgb([x as u16 * 1001, y as u16 * 7, 65535]));
+
+        // The first encoding fails, the next one is used instead of falling back to 8 bits
+        let mut attempts = Vec::new();
+        save_with_first_working(&path, &TIFF_COMPRESSIONS, |path, compression| {
+            attempts.push(*compression);
+            match compression {
+                TiffCompression::Uncompressed => anyhow::bail!("Unsupported tag combination"),
+                _ => save_tiff_16_bit(&image, path, *compression),
+            }
+        })
+        .unwrap();
+
+        assert_eq!(
+            attempts,
+            vec![TiffCompression::Uncompressed, TiffCompression::Lzw]
+        );
+        match image::open(&path).unwrap() {
+            DynamicImage::ImageRgb16(saved) => assert_eq!(saved, image),
+            other => panic!("Expected a 16 bit image, got {:?}", other.color()),
+        }
+
+        save_image(&image, &path).unwrap();
+        assert_eq!(
+            load_image(&path, ColorManagement::AssumeSrgb).unwrap(),
+            image
+        );
+    }
+
+    #[test]
+    fn test_load_u32_tiff() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("u32.tif");
+        tiff::encoder::TiffEncoder::new(File::create(&path).unwrap())
+            .unwrap()
+            .write_image::<tiff::encoder::colortype::Gray32>(2, 1, &[u32::MAX, 100])
+            .unwrap();
+
+        let image = load_image_f32(&path, ColorManagement::AssumeSrgb).unwrap();
+
+        assert_eq!(image.get_pixel(0, 0), &Rgb([1.0, 1.0, 1.0]));
+        // This is below the smallest step of 16 bit data
+        let dark = image.get_pixel(1, 0)[0];
+        assert!(dark > 0.0 && dark < 1.0 / u16::MAX as f32);
+    }
+
+    #[test]
+    fn test_load_mask() {
+        let dir = tempfile::tempdir().unwrap();
+        let gray = dir.path().join("gray.png");
+        let alpha = dir.path().join("alpha.png");
+        image::GrayImage::from_fn(4, 2, |x, _| image::Luma([if x < 2 { 255 } else { 0 }]))
+            .save(&gray)
+            .unwrap();
+        image::RgbaImage::from_fn(4, 2, |x, _| Rgba([0, 0, 0, if x < 2 { 0 } else { 255 }]))
+            .save(&alpha)
+            .unwrap();
+
+        let mask = load_mask(&gray).unwrap();
+        assert_eq!(mask.shape(), &[2, 4]);
+        assert_eq!(mask.row(0).to_vec(), vec![1.0, 1.0, 0.0, 0.0]);
+        assert_eq!(load_mask(&alpha).unwrap(), mask.mapv(|v| 1.0 - v));
+    }
+
+    #[test]
+    fn test_provenance_is_written() {
+        let handler = MetadataHandler::new().with_provenance(provenance_tag(b"model"));
+        if handler.backend() != MetadataBackend::Exiftool {
+            return;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.png");
+        let output = dir.path().join("output.png");
+        Rgb16Image::new(4, 4).save(&input).unwrap();
+        Rgb16Image::new(4, 4).save(&output).unwrap();
+
+        handler.copy_metadata(&input, &output);
+
+        let tag = Command::new("exiftool")
+            .args(["-s3", "-ProcessingSoftware"])
+            .arg(&output)
+            .output()
+            .unwrap();
+        let tag = String::from_utf8_lossy(&tag.stdout);
+        assert!(tag.starts_with(&format!("NeuraTable {}; Model=", backend::version())));
+    }
+
+    #[test]
+    fn test_native_metadata_copy() {
+        const DATE: &str = "2021:06:05 14:30:00";
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.jpg");
+        let output = dir.path().join("output.jpg");
+        image::RgbImage::new(8, 8).save(&input).unwrap();
+        image::RgbImage::new(8, 8).save(&output).unwrap();
+        let mut metadata = Metadata::new();
+        metadata.set_tag(ExifTag::DateTimeOriginal(DATE.to_owned()));
+        metadata.set_tag(ExifTag::Orientation(vec![6]));
+        metadata.set_tag(ExifTag::Model("Test Camera".to_owned()));
+        metadata.write_to_file(&input).unwrap();
+
+        MetadataHandler::new()
+            .with_backend(MetadataBackend::Native)
+            .with_provenance(provenance_tag(b"model"))
+            .copy_metadata(&input, &output);
+
+        let copied = Metadata::new_from_path(&output).unwrap();
+        let tag = |tag: ExifTag| copied.get_tag(&tag).next().cloned();
+        assert!(matches!(
+            tag(ExifTag::DateTimeOriginal(String::new())),
+            Some(ExifTag::DateTimeOriginal(date)) if date.trim_end_matches('\0') == DATE
+        ));
+        assert!(matches!(
+            tag(ExifTag::Orientation(Vec::new())),
+            Some(ExifTag::Orientation(orientation)) if orientation == [6]
+        ));
+        assert!(matches!(
+            tag(ExifTag::Software(String::new())),
+            Some(ExifTag::Software(software)) if software.starts_with("NeuraTable")
+        ));
+        // The image data is not touched
+        assert_eq!(image::open(&output).unwrap().to_rgb8().dimensions(), (8, 8));
+    }
+
+    #[test]
+    fn test_load_linear_dng() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("linear.dng");
+        let image = Rgb16Image::from_fn(3, 2, |x, y| Rgb([x as u16 * 1000, y as u16 * 2000, 42]));
+        image.save_with_format(&path, ImageFormat::Tiff).unwrap();
+
+        let loaded = load_image(&path, ColorManagement::AssumeSrgb).unwrap();
+
+        assert_eq!(loaded, image);
+    }
+
+    #[test]
+    fn test_write_image() {
+        let image = Rgb16Image::from_fn(7, 5, |x, y| Rgb([x as u16 * 9000, y as u16 * 9000, 1234]));
+
+        let mut png = Vec::new();
+        write_image(&image, &mut png, ImageFormat::Png).unwrap();
+        let decoded = image::load_from_memory_with_format(&png, ImageFormat::Png).unwrap();
+        assert_eq!(decoded.into_rgb16(), image);
+
+        let mut jpeg = Vec::new();
+        write_image(&image, &mut jpeg, ImageFormat::Jpeg).unwrap();
+        let decoded = image::load_from_memory_with_format(&jpeg, ImageFormat::Jpeg).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::Rgb8);
+        assert_eq!((decoded.width(), decoded.height()), (7, 5));
+    }
+}
@@ -0,0 +1,25 @@
+pub mod animation;
+pub mod batch_inputs;
+pub mod batch_processor;
+pub mod benchmark;
+pub mod byte_size;
+pub mod evaluation;
+pub mod image_utils;
+pub mod model_error;
+pub mod model_selector;
+pub mod model_validation;
+pub mod montage;
+pub mod noise_level;
+pub mod npy_tensor;
+pub mod output_pattern;
+pub mod path_expansion;
+pub mod processing_args;
+pub mod selftest;
+pub mod sidecar;
+pub mod streaming_source;
+pub mod tile_overlay;
+pub mod video;
+pub mod viewer;
+
+#[cfg(test)]
+mod test_utils;
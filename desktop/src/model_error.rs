@@ -0,0 +1,63 @@
+use backend::model_runner::ModelRunnerError;
+
+/// A description of a model load error, with a hint on how to fix it if there is one
+pub fn describe_model_error(err: &ModelRunnerError) -> String {
+    match model_error_hint(err) {
+        Some(hint) => format!("{}\n{}", err, hint),
+        None => err.to_string(),
+    }
+}
+
+fn model_error_hint(err: &ModelRunnerError) -> Option<&'static str> {
+    match err {
+        ModelRunnerError::ReadError(_) | ModelRunnerError::ParseError(_) => {
+            Some("Make sure the file is an ONNX model, optionally compressed with gzip or zstd.")
+        }
+        ModelRunnerError::ModelInputError(_) => {
+            Some("Only image to image models with an image input are supported.")
+        }
+        ModelRunnerError::AuxiliaryInputMismatch { .. } => Some(
+            "Inputs besides the image, e.g. masks, must have the same width and height as the image input.",
+        ),
+        ModelRunnerError::InvalidInputShape(_) => Some(
+            "Export the model with a batch size of 1 and a fixed image size, e.g. [1,3,256,256].",
+        ),
+        ModelRunnerError::DynamicInputShape(_) => Some(
+            "Use --dynamic-chunksize to choose the size of the chunks that are passed to the model.",
+        ),
+        ModelRunnerError::UnsupportedChannelCount { .. } => {
+            Some("Use --model-channels to load models with other channel counts, e.g. 4 for RGBA.")
+        }
+        ModelRunnerError::NoSuitableOutput => Some(
+            "The output must have the same channels as the input and the same size or an integer multiple of it.",
+        ),
+        ModelRunnerError::UnsuitableOutput(_) => Some(
+            "Select an output with the same channels as the input and the same size or an integer multiple of it.",
+        ),
+        ModelRunnerError::TractCompilationFailed(_) => Some(
+            "The model uses an operator that tract does not support. Try a different opset when exporting it, or run it on the GPU without --force-cpu.",
+        ),
+        ModelRunnerError::PermutationNotAllowed => Some(
+            "Export the model with NCHW input, e.g. [1,3,256,256], or leave out --strict-shapes.",
+        ),
+        ModelRunnerError::InvalidBackend(_) | ModelRunnerError::GpuUnavailable(_) => Some(
+            "Check the NEURATABLE_BACKEND environment variable, or use --backend cpu to run the model on the CPU.",
+        ),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_describe_model_error() {
+        let description = describe_model_error(&ModelRunnerError::ModelInputError(0));
+        assert!(description.starts_with("The model has 0 inputs"));
+        assert!(description.ends_with("with an image input are supported."));
+
+        let description = describe_model_error(&ModelRunnerError::InferenceFailed("oom".into()));
+        assert_eq!(description, "Inference failed: oom");
+    }
+}
@@ -0,0 +1,187 @@
+use std::path::{Path, PathBuf};
+
+use backend::model_value_range::ModelValueRange;
+
+use crate::image_utils::Rgb16Image;
+
+/// The noise level above which `ModelSelector::by_noise` uses the denoising model
+pub const DEFAULT_NOISE_THRESHOLD: f32 = 0.01;
+
+/// The file extensions of lossy formats, whose images usually need denoising
+const LOSSY_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "webp"];
+
+/// A model and the value ranges it is run with
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelProfile {
+    pub model: PathBuf,
+    pub input_range: ModelValueRange,
+    pub output_range: ModelValueRange,
+}
+
+/// The properties of an input image that models are selected by
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputCharacteristics {
+    /// The lowercase file extension, empty if the file has none
+    pub extension: String,
+    pub width: u32,
+    pub height: u32,
+    /// The estimated standard deviation of the noise, see `estimate_noise`
+    pub noise_level: f32,
+}
+
+impl InputCharacteristics {
+    pub fn measure(path: &Path, image: &Rgb16Image) -> Self {
+        Self {
+            extension: path
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_lowercase())
+                .unwrap_or_default(),
+            width: image.width(),
+            height: image.height(),
+            noise_level: estimate_noise(image),
+        }
+    }
+}
+
+type Predicate = Box<dyn Fn(&InputCharacteristics) -> bool>;
+
+/// Picks the model for each input of a batch from a list of registered models
+///
+/// The models are checked in the order in which they were added, the first model whose
+/// predicate accepts the input is used.
+#[derive(Default)]
+pub struct ModelSelector {
+    models: Vec<(Predicate, ModelProfile)>,
+}
+
+impl ModelSelector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use `profile` for inputs that `predicate` accepts and no earlier model accepted
+    pub fn with_model<F>(mut self, predicate: F, profile: ModelProfile) -> Self
+    where
+        F: Fn(&InputCharacteristics) -> bool + 'static,
+    {
+        self.models.push((Box::new(predicate), profile));
+        self
+    }
+
+    /// Use `denoise` for lossy formats and noisy images and `clean` for everything else
+    pub fn by_noise(denoise: ModelProfile, clean: ModelProfile, noise_threshold: f32) -> Self {
+        Self::new()
+            .with_model(
+                move |input| {
+                    LOSSY_EXTENSIONS.contains(&input.extension.as_str())
+                        || input.noise_level > noise_threshold
+                },
+                denoise,
+            )
+            .with_model(|_| true, clean)
+    }
+
+    /// The profiles of all registered models in the order in which they are checked
+    pub fn profiles(&self) -> impl Iterator<Item = &ModelProfile> {
+        self.models.iter().map(|(_, profile)| profile)
+    }
+
+    /// The profile of the first model that accepts the input, if any
+    pub fn select(&self, input: &InputCharacteristics) -> Option<&ModelProfile> {
+        self.models
+            .iter()
+            .find(|(predicate, _)| predicate(input))
+            .map(|(_, profile)| profile)
+    }
+}
+
+/// Estimate the standard deviation of the noise of an image, relative to the full value range
+///
+/// This uses the method of Immerkær (1996) on the mean of the color channels: a Laplacian
+/// difference filter cancels out smooth image content, what is left is mostly noise. Fine
+/// texture is counted as noise as well, so this is only a rough estimate.
+pub fn estimate_noise(image: &Rgb16Image) -> f32 {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+    let gray: Vec<f64> = image
+        .pixels()
+        .map(|pixel| pixel.0.iter().map(|&v| v as f64).sum::<f64>() / (3.0 * u16::MAX as f64))
+        .collect();
+    let value = |x: usize, y: usize| gray[y * width + x];
+
+    let mut sum = 0.0;
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let corners = value(x - 1, y - 1)
+                + value(x + 1, y - 1)
+                + value(x - 1, y + 1)
+                + value(x + 1, y + 1);
+            let edges = value(x, y - 1) + value(x - 1, y) + value(x + 1, y) + value(x, y + 1);
+            sum += (corners - 2.0 * edges + 4.0 * value(x, y)).abs();
+        }
+    }
+    let sigma = (std::f64::consts::PI / 2.0).sqrt() * sum
+        / (6.0 * (width - 2) as f64 * (height - 2) as f64);
+    sigma as f32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::Rgb;
+
+    fn profile(model: &str) -> ModelProfile {
+        ModelProfile {
+            model: PathBuf::from(model),
+            input_range: ModelValueRange::asymmetric(1.0),
+            output_range: ModelValueRange::asymmetric(1.0),
+        }
+    }
+
+    #[test]
+    fn test_select_by_noise() {
+        let clean = Rgb16Image::from_fn(64, 48, |x, y| {
+            let value = (x * 500 + y * 300) as u16;
+            Rgb([value, value, value])
+        });
+        // Pseudo random noise with a standard deviation of about 5% of the value range
+        let mut state = 12345u32;
+        let noisy = Rgb16Image::from_fn(64, 48, |x, y| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let noise = (state >> 16) as f32 / u16::MAX as f32 - 0.5;
+            let value = (30000.0 + (x * 100 + y * 100) as f32 + noise * 11000.0) as u16;
+            Rgb([value, value, value])
+        });
+        assert!(estimate_noise(&clean) < 0.001);
+        assert!((estimate_noise(&noisy) - 0.05).abs() < 0.02);
+
+        let selector = ModelSelector::by_noise(
+            profile("denoise.onnx"),
+            profile("sharpen.onnx"),
+            DEFAULT_NOISE_THRESHOLD,
+        );
+        assert_eq!(
+            selector.profiles().cloned().collect::<Vec<_>>(),
+            vec![profile("denoise.onnx"), profile("sharpen.onnx")]
+        );
+        let select = |path: &str, image: &Rgb16Image| {
+            selector
+                .select(&InputCharacteristics::measure(Path::new(path), image))
+                .unwrap()
+                .model
+                .clone()
+        };
+        assert_eq!(select("clean.tif", &clean), PathBuf::from("sharpen.onnx"));
+        assert_eq!(select("noisy.tif", &noisy), PathBuf::from("denoise.onnx"));
+        // JPEGs are denoised even if they look clean
+        assert_eq!(select("clean.JPG", &clean), PathBuf::from("denoise.onnx"));
+
+        let selector =
+            ModelSelector::new().with_model(|input| input.width > 1000, profile("large.onnx"));
+        assert!(selector
+            .select(&InputCharacteristics::measure(Path::new("a.png"), &clean))
+            .is_none());
+    }
+}
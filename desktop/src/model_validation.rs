@@ -0,0 +1,101 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use backend::model_runner::{CompatibilityReport, ModelRunner, ModelRunnerError};
+
+use crate::model_error::describe_model_error;
+
+/// The file name suffixes of the models `validate_models` checks
+const MODEL_SUFFIXES: [&str; 3] = [".onnx", ".onnx.gz", ".onnx.zst"];
+
+/// The result of checking a single model file with tract
+pub struct ModelValidation {
+    pub path: PathBuf,
+    /// The compatibility report, or the error if the model could not be read or parsed
+    pub result: Result<CompatibilityReport, ModelRunnerError>,
+}
+
+impl ModelValidation {
+    /// Check if the input and output were detected and tract compiled the model
+    pub fn passed(&self) -> bool {
+        self.result
+            .as_ref()
+            .map_or(false, |report| report.is_compatible())
+    }
+}
+
+impl std::fmt::Display for ModelValidation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = if self.passed() { "PASS" } else { "FAIL" };
+        writeln!(f, "{} {}", status, self.path.display())?;
+        match &self.result {
+            Ok(report) => write!(f, "{}", report),
+            Err(err) => writeln!(f, "  {}", describe_model_error(err)),
+        }
+    }
+}
+
+/// Check that a model loads and compiles with tract, without running any inference
+///
+/// The GPU backend is not checked, so this works on machines without a GPU.
+pub async fn validate_model(path: &Path) -> ModelValidation {
+    let result = match File::open(path) {
+        Ok(file) => ModelRunner::check_compatibility(&mut BufReader::new(file), true).await,
+        Err(err) => Err(err.into()),
+    };
+    ModelValidation {
+        path: path.to_owned(),
+        result,
+    }
+}
+
+/// Validate all ONNX models in a directory, sorted by path, see `validate_model`
+pub async fn validate_models(model_dir: &Path) -> anyhow::Result<Vec<ModelValidation>> {
+    let mut paths: Vec<_> = model_dir
+        .read_dir()
+        .with_context(|| format!("Could not read the directory {}", model_dir.display()))?
+        .filter_map(|maybe_entry| maybe_entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && is_model_file(path))
+        .collect();
+    paths.sort();
+
+    let mut validations = Vec::with_capacity(paths.len());
+    for path in paths {
+        validations.push(validate_model(&path).await);
+    }
+    Ok(validations)
+}
+
+fn is_model_file(path: &Path) -> bool {
+    path.file_name().map_or(false, |name| {
+        let name = name.to_string_lossy().to_lowercase();
+        MODEL_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::identity_model_bytes;
+
+    #[test]
+    fn test_validate_models() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("good.onnx"), identity_model_bytes()).unwrap();
+        std::fs::write(dir.path().join("broken.onnx"), b"not a model").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "not a model either").unwrap();
+
+        let validations = pollster::block_on(validate_models(dir.path())).unwrap();
+        assert_eq!(validations.len(), 2);
+        let (broken, good) = (&validations[0], &validations[1]);
+        assert_eq!(broken.path, dir.path().join("broken.onnx"));
+        assert!(!broken.passed());
+        assert!(broken.to_string().starts_with("FAIL "));
+        assert_eq!(good.path, dir.path().join("good.onnx"));
+        assert!(good.passed());
+        assert!(good.to_string().starts_with("PASS "));
+    }
+}
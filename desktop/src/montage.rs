@@ -0,0 +1,259 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use image::{imageops, Rgb, RgbImage};
+
+/// The space around each thumbnail
+const MARGIN: u32 = 4;
+/// The size of a pixel of the label font
+const FONT_SCALE: u32 = 2;
+/// The horizontal distance of two label characters
+const CHARACTER_ADVANCE: u32 = 4 * FONT_SCALE;
+const LABEL_HEIGHT: u32 = 5 * FONT_SCALE + 2 * MARGIN;
+const BACKGROUND: Rgb<u8> = Rgb([32, 32, 32]);
+const LABEL_COLOR: Rgb<u8> = Rgb([220, 220, 220]);
+
+/// The grid of the contact sheets written by `write_montage`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MontageLayout {
+    pub columns: u32,
+    /// The largest width and height of a thumbnail
+    pub thumbnail_size: u32,
+    /// The most rows of a sheet, larger batches are split into several sheets
+    pub max_rows: u32,
+}
+
+impl Default for MontageLayout {
+    fn default() -> Self {
+        Self {
+            columns: 6,
+            thumbnail_size: 256,
+            max_rows: 8,
+        }
+    }
+}
+
+impl MontageLayout {
+    fn cell_size(&self) -> (u32, u32) {
+        (
+            self.thumbnail_size + 2 * MARGIN,
+            self.thumbnail_size + 2 * MARGIN + LABEL_HEIGHT,
+        )
+    }
+}
+
+/// Assemble thumbnails of the images into contact sheets, labeled with their file names
+///
+/// The images are placed in rows of `layout.columns` in the given order. If they need more than
+/// `layout.max_rows` rows, several sheets are written with a number appended to the file name,
+/// e.g. sheet-1.jpg and sheet-2.jpg. Images that can not be read get an empty cell. Returns the
+/// paths of the written sheets.
+pub fn write_montage(
+    images: &[PathBuf],
+    sheet_path: &Path,
+    layout: MontageLayout,
+) -> anyhow::Result<Vec<PathBuf>> {
+    if layout.columns == 0 || layout.max_rows == 0 || layout.thumbnail_size == 0 {
+        anyhow::bail!("The montage needs at least one column, one row and one pixel");
+    }
+    let cells_per_sheet = (layout.columns * layout.max_rows) as usize;
+    let sheet_count = (images.len() + cells_per_sheet - 1) / cells_per_sheet;
+
+    let mut sheet_paths = Vec::with_capacity(sheet_count);
+    for (index, sheet_images) in images.chunks(cells_per_sheet).enumerate() {
+        let path = if sheet_count == 1 {
+            sheet_path.to_owned()
+        } else {
+            numbered_path(sheet_path, index + 1)
+        };
+        build_sheet(sheet_images, layout)
+            .save(&path)
+            .with_context(|| format!("Could not write the montage {}", path.display()))?;
+        sheet_paths.push(path);
+    }
+    Ok(sheet_paths)
+}
+
+/// Append "-number" to the file stem
+fn numbered_path(path: &Path, number: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, number, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, number),
+    };
+    path.with_file_name(name)
+}
+
+fn build_sheet(images: &[PathBuf], layout: MontageLayout) -> RgbImage {
+    let (cell_width, cell_height) = layout.cell_size();
+    let columns = layout.columns.min(images.len() as u32);
+    let rows = (images.len() as u32 + layout.columns - 1) / layout.columns;
+    let mut sheet = RgbImage::from_pixel(columns * cell_width, rows * cell_height, BACKGROUND);
+
+    for (index, path) in images.iter().enumerate() {
+        let (x, y) = (
+            (index as u32 % layout.columns) * cell_width,
+            (index as u32 / layout.columns) * cell_height,
+        );
+        match image::open(path) {
+            Ok(image) => {
+                let thumbnail = image
+                    .thumbnail(layout.thumbnail_size, layout.thumbnail_size)
+                    .to_rgb8();
+                // Center the thumbnail in its cell
+                let offset_x = (layout.thumbnail_size - thumbnail.width()) / 2;
+                let offset_y = (layout.thumbnail_size - thumbnail.height()) / 2;
+                imageops::replace(
+                    &mut sheet,
+                    &thumbnail,
+                    (x + MARGIN + offset_x) as i64,
+                    (y + MARGIN + offset_y) as i64,
+                );
+            }
+            Err(err) => log::warn!("Could not add {} to the montage: {}", path.display(), err),
+        }
+        let label = path.file_name().unwrap_or_default().to_string_lossy();
+        let max_characters = (layout.thumbnail_size / CHARACTER_ADVANCE) as usize;
+        draw_label(
+            &mut sheet,
+            &label.chars().take(max_characters).collect::<String>(),
+            x + MARGIN,
+            y + layout.thumbnail_size + 3 * MARGIN,
+        );
+    }
+    sheet
+}
+
+/// Draw text with a 3x5 pixel font, lowercase letters are drawn as uppercase
+fn draw_label(sheet: &mut RgbImage, text: &str, x: u32, y: u32) {
+    for (index, character) in text.chars().enumerate() {
+        let glyph_x = x + index as u32 * CHARACTER_ADVANCE;
+        for (row, bits) in glyph(character.to_ascii_uppercase()).iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) == 0 {
+                    continue;
+                }
+                for dy in 0..FONT_SCALE {
+                    for dx in 0..FONT_SCALE {
+                        let px = glyph_x + column * FONT_SCALE + dx;
+                        let py = y + row as u32 * FONT_SCALE + dy;
+                        if px < sheet.width() && py < sheet.height() {
+                            sheet.put_pixel(px, py, LABEL_COLOR);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The rows of a character, the highest of the three bits is the left pixel
+fn glyph(character: char) -> [u8; 5] {
+    match character {
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b110, 0b001, 0b010, 0b100, 0b111],
+        '3' => [0b110, 0b001, 0b010, 0b001, 0b110],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b110, 0b001, 0b110],
+        '6' => [0b011, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b110],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        ' ' => [0b000; 5],
+        _ => [0b110, 0b001, 0b010, 0b000, 0b010],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_montage_grid() {
+        let dir = tempfile::tempdir().unwrap();
+        let colors: Vec<_> = (0..6u8).map(|i| Rgb([i * 40, 255 - i * 40, 128])).collect();
+        let images: Vec<_> = colors
+            .iter()
+            .enumerate()
+            .map(|(i, color)| {
+                let path = dir.path().join(format!("output_{}.png", i));
+                RgbImage::from_pixel(40, 30, *color).save(&path).unwrap();
+                path
+            })
+            .collect();
+        let layout = MontageLayout {
+            columns: 4,
+            thumbnail_size: 20,
+            max_rows: 8,
+        };
+        let (cell_width, cell_height) = layout.cell_size();
+
+        let sheet_path = dir.path().join("sheet.png");
+        let sheets = write_montage(&images, &sheet_path, layout).unwrap();
+        assert_eq!(sheets, vec![sheet_path.clone()]);
+        let sheet = image::open(&sheet_path).unwrap().to_rgb8();
+        assert_eq!(sheet.dimensions(), (4 * cell_width, 2 * cell_height));
+        for (index, color) in colors.iter().enumerate() {
+            let (column, row) = (index as u32 % 4, index as u32 / 4);
+            let center = sheet.get_pixel(
+                column * cell_width + MARGIN + 10,
+                row * cell_height + MARGIN + 10,
+            );
+            assert_eq!(center, color);
+        }
+        // The cells after the last image stay empty
+        assert_eq!(
+            sheet.get_pixel(2 * cell_width + MARGIN + 10, cell_height + MARGIN + 10),
+            &BACKGROUND
+        );
+
+        // Larger batches are split into several sheets
+        let layout = MontageLayout {
+            max_rows: 1,
+            ..layout
+        };
+        let sheets = write_montage(&images, &sheet_path, layout).unwrap();
+        assert_eq!(
+            sheets,
+            vec![
+                dir.path().join("sheet-1.png"),
+                dir.path().join("sheet-2.png")
+            ]
+        );
+        let last_sheet = image::open(&sheets[1]).unwrap();
+        assert_eq!(
+            (last_sheet.width(), last_sheet.height()),
+            (2 * cell_width, cell_height)
+        );
+    }
+}
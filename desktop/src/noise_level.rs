@@ -0,0 +1,146 @@
+use std::path::Path;
+
+use backend::image_processor::ImageProcessor;
+use ndarray::Array3;
+
+use crate::image_utils::{load_image, ColorManagement};
+
+/// The noise level that non-blind denoisers receive as a second input
+#[derive(Debug, Clone, PartialEq)]
+pub enum NoiseLevel {
+    /// The same standard deviation for the whole image, relative to the full value range
+    Constant(f32),
+    /// A noise map with the size of the processed images, white is a standard deviation of 1
+    Map(String),
+}
+
+/// Pass the noise level to the noise level input of the model
+///
+/// The model must have exactly one input besides the image, which receives the noise level. A
+/// constant noise level is scaled from the full value range to the size of the model input
+/// range, e.g. 0.05 becomes 12.75 for models with a [0,255] input range. Per channel input
+/// ranges use the range of the first channel.
+pub fn set_noise_level(processor: &mut ImageProcessor, level: &NoiseLevel) -> anyhow::Result<()> {
+    let input = match processor.auxiliary_inputs() {
+        [input] => input.clone(),
+        [] => anyhow::bail!(
+            "The model has no noise level input, only non-blind denoisers with a second input accept a noise level"
+        ),
+        inputs => anyhow::bail!(
+            "The model has {} inputs besides the image, it is unclear which one is the noise level",
+            inputs.len()
+        ),
+    };
+
+    match level {
+        NoiseLevel::Constant(sigma) => {
+            let (lower, upper) = processor.settings().input_range.channel(0).bounds();
+            processor.set_auxiliary_constant(&input.name, sigma * (upper - lower))?
+        }
+        NoiseLevel::Map(path) => {
+            let map = load_noise_map(Path::new(path), input.channels)?;
+            processor.set_auxiliary_input(&input.name, map)?
+        }
+    }
+    log::info!("Passing the noise level to the model input {}", input.name);
+    Ok(())
+}
+
+/// Load a noise map as HxWxC data in [0,1]
+///
+/// Single channel maps use the mean of the color channels, so gray images work for both.
+pub fn load_noise_map(path: &Path, channels: usize) -> anyhow::Result<Array3<f32>> {
+    let image = load_image(path, ColorManagement::AssumeSrgb)?;
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let normalized = |v: u16| v as f32 / u16::MAX as f32;
+    match channels {
+        1 => Ok(Array3::from_shape_fn((height, width, 1), |(y, x, _)| {
+            let pixel = image.get_pixel(x as u32, y as u32);
+            pixel.0.iter().map(|&v| normalized(v)).sum::<f32>() / 3.0
+        })),
+        3 => Ok(Array3::from_shape_fn((height, width, 3), |(y, x, c)| {
+            normalized(image.get_pixel(x as u32, y as u32)[c])
+        })),
+        _ => anyhow::bail!(
+            "The noise level input has {} channels, only noise maps with 1 or 3 channels are supported",
+            channels
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use backend::image_processor::ImageColorModel;
+    use backend::model_runner::ModelRunner;
+    use backend::model_value_range::ModelValueRange;
+    use image::{Rgb, RgbImage};
+    use protobuf::Message;
+    use wonnx::utils::{graph, model, node, tensor};
+
+    /// A non-blind denoiser stub with a fixed input size of 32x32 that subtracts the noise level
+    fn subtract_sigma_model_bytes() -> Vec<u8> {
+        model(graph(
+            vec![
+                tensor("input", &[1, 3, 32, 32]),
+                tensor("sigma", &[1, 1, 32, 32]),
+            ],
+            vec![tensor("output", &[1, 3, 32, 32])],
+            vec![],
+            vec![],
+            vec![node(
+                vec!["input", "sigma"],
+                vec!["output"],
+                "subtract",
+                "Sub",
+                vec![],
+            )],
+        ))
+        .write_to_bytes()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_noise_sigma_is_relative_to_the_value_range() {
+        let input = Array3::from_elem((40, 50, 3), 0.5);
+        for range in [
+            ModelValueRange::asymmetric(1.0),
+            ModelValueRange::asymmetric(255.0),
+            ModelValueRange::symmetric(1.0),
+        ] {
+            let runner =
+                pollster::block_on(ModelRunner::from_bytes(&subtract_sigma_model_bytes(), true))
+                    .unwrap();
+            let mut processor = pollster::block_on(ImageProcessor::new(
+                runner,
+                ImageColorModel::RGB,
+                range.clone(),
+                range,
+            ))
+            .unwrap();
+            set_noise_level(&mut processor, &NoiseLevel::Constant(0.1)).unwrap();
+
+            let output = pollster::block_on(processor.process_tensor(input.clone())).unwrap();
+            assert!(output.iter().all(|v| (v - 0.4).abs() < 1e-4));
+        }
+    }
+
+    #[test]
+    fn test_load_noise_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sigma.png");
+        RgbImage::from_fn(4, 2, |x, _| Rgb([x as u8 * 85, 0, 255]))
+            .save(&path)
+            .unwrap();
+
+        let gray = load_noise_map(&path, 1).unwrap();
+        assert_eq!(gray.shape(), &[2, 4, 1]);
+        assert!((gray[(0, 0, 0)] - 1.0 / 3.0).abs() < 1e-4);
+        assert!((gray[(1, 3, 0)] - 2.0 / 3.0).abs() < 1e-4);
+
+        let color = load_noise_map(&path, 3).unwrap();
+        assert_eq!(color.shape(), &[2, 4, 3]);
+        assert_eq!(color[(0, 3, 0)], 1.0);
+        assert!(load_noise_map(&path, 2).is_err());
+    }
+}
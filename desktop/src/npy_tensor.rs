@@ -0,0 +1,162 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::Context;
+use ndarray::{Array3, ArrayD, Axis, Ix3};
+
+/// The file extension of NumPy arrays
+pub const NPY_EXTENSION: &str = "npy";
+
+/// The axis order of a tensor stored in a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TensorLayout {
+    Chw,
+    Hwc,
+    /// Like `Chw` with a batch axis of size 1
+    Nchw,
+    /// Like `Hwc` with a batch axis of size 1
+    Nhwc,
+}
+
+impl FromStr for TensorLayout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_ref() {
+            "chw" => TensorLayout::Chw,
+            "hwc" => TensorLayout::Hwc,
+            "nchw" => TensorLayout::Nchw,
+            "nhwc" => TensorLayout::Nhwc,
+            _ => anyhow::bail!(
+                "Tensor layout {} not known, must be one of (chw, hwc, nchw, nhwc)",
+                s
+            ),
+        })
+    }
+}
+
+impl TensorLayout {
+    fn has_batch_axis(&self) -> bool {
+        matches!(self, TensorLayout::Nchw | TensorLayout::Nhwc)
+    }
+
+    fn is_channels_first(&self) -> bool {
+        matches!(self, TensorLayout::Chw | TensorLayout::Nchw)
+    }
+}
+
+/// Check if a path points to a NumPy array file
+pub fn is_npy(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| extension.eq_ignore_ascii_case(NPY_EXTENSION))
+        .unwrap_or(false)
+}
+
+/// Load a float tensor from a .npy file as a HxWxC tensor
+pub fn load_npy_tensor(path: &Path, layout: TensorLayout) -> anyhow::Result<Array3<f32>> {
+    let mut tensor: ArrayD<f32> = ndarray_npy::read_npy(path)
+        .with_context(|| format!("Could not read tensor from {}", path.display()))?;
+
+    if layout.has_batch_axis() {
+        if tensor.ndim() != 4 || tensor.shape()[0] != 1 {
+            anyhow::bail!(
+                "Expected a tensor with a batch size of 1, found shape {:?}",
+                tensor.shape()
+            );
+        }
+        tensor = tensor.index_axis_move(Axis(0), 0);
+    }
+    let tensor = tensor
+        .into_dimensionality::<Ix3>()
+        .context("Expected a tensor with 3 dimensions")?;
+    let tensor = if layout.is_channels_first() {
+        tensor.permuted_axes([1, 2, 0])
+    } else {
+        tensor
+    };
+
+    if tensor.shape()[2] != 3 {
+        anyhow::bail!(
+            "Expected a tensor with 3 channels in {:?} layout, found shape {:?}",
+            layout,
+            tensor.shape()
+        );
+    }
+    Ok(tensor.as_standard_layout().into_owned())
+}
+
+/// Save a HxWxC tensor to a .npy file in the given layout
+pub fn save_npy_tensor(
+    tensor: Array3<f32>,
+    path: &Path,
+    layout: TensorLayout,
+) -> anyhow::Result<()> {
+    let tensor = if layout.is_channels_first() {
+        tensor.permuted_axes([2, 0, 1])
+    } else {
+        tensor
+    };
+    let mut tensor = tensor.into_dyn();
+    if layout.has_batch_axis() {
+        tensor = tensor.insert_axis(Axis(0));
+    }
+
+    ndarray_npy::write_npy(path, &tensor.as_standard_layout())
+        .with_context(|| format!("Could not write tensor to {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::identity_processor;
+
+    #[test]
+    fn test_npy_round_trip() {
+        let mut processor = identity_processor();
+        let dir = tempfile::tempdir().unwrap();
+
+        for layout in [
+            TensorLayout::Chw,
+            TensorLayout::Hwc,
+            TensorLayout::Nchw,
+            TensorLayout::Nhwc,
+        ] {
+            let input_path = dir.path().join("input.npy");
+            let output_path = dir.path().join("output.npy");
+            let shape = match layout {
+                TensorLayout::Chw => vec![3, 20, 30],
+                TensorLayout::Hwc => vec![20, 30, 3],
+                TensorLayout::Nchw => vec![1, 3, 20, 30],
+                TensorLayout::Nhwc => vec![1, 20, 30, 3],
+            };
+            let input = ArrayD::from_shape_fn(shape, |index| {
+                index.slice().iter().sum::<usize>() as f32 / 100.0
+            });
+            ndarray_npy::write_npy(&input_path, &input).unwrap();
+
+            let tensor = load_npy_tensor(&input_path, layout).unwrap();
+            assert_eq!(tensor.shape(), &[20, 30, 3]);
+            let output = pollster::block_on(processor.process_tensor(tensor)).unwrap();
+            save_npy_tensor(output, &output_path, layout).unwrap();
+
+            let output: ArrayD<f32> = ndarray_npy::read_npy(&output_path).unwrap();
+            assert_eq!(output.shape(), input.shape());
+            assert!(output
+                .iter()
+                .zip(input.iter())
+                .all(|(a, b)| (a - b).abs() < 1e-6));
+        }
+    }
+
+    #[test]
+    fn test_invalid_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.npy");
+        ndarray_npy::write_npy(&path, &Array3::<f32>::zeros((3, 20, 30))).unwrap();
+
+        assert!(load_npy_tensor(&path, TensorLayout::Hwc).is_err());
+        assert!(load_npy_tensor(&path, TensorLayout::Nchw).is_err());
+        assert!(load_npy_tensor(&path, TensorLayout::Chw).is_ok());
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Characters that can not be used in file names on the target platform
+#[cfg(windows)]
+const ILLEGAL_CHARACTERS: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+#[cfg(not(windows))]
+const ILLEGAL_CHARACTERS: &[char] = &['/'];
+
+/// The character that replaces illegal characters in file names
+const REPLACEMENT_CHARACTER: char = '_';
+
+/// Defines what happens if multiple inputs of a batch result in the same output path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    /// Fail the input that would overwrite an earlier output
+    Error,
+    /// Overwrite the earlier output
+    Overwrite,
+    /// Append a number to the file name until it is unique
+    SuffixNumber,
+}
+
+impl FromStr for CollisionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_ref() {
+            "error" => CollisionPolicy::Error,
+            "overwrite" => CollisionPolicy::Overwrite,
+            "suffix-number" => CollisionPolicy::SuffixNumber,
+            _ => anyhow::bail!(
+                "Collision policy {} not known, must be one of (error, overwrite, suffix-number)",
+                s
+            ),
+        })
+    }
+}
+
+/// Keeps track of the output paths of a batch and resolves collisions between them
+pub struct OutputPaths {
+    policy: CollisionPolicy,
+    used: HashSet<PathBuf>,
+}
+
+impl OutputPaths {
+    pub fn new(policy: CollisionPolicy) -> Self {
+        Self {
+            policy,
+            used: HashSet::new(),
+        }
+    }
+
+    /// Reserve an output path, resolving collisions with earlier outputs according to the policy
+    pub fn claim(&mut self, path: PathBuf) -> anyhow::Result<PathBuf> {
+        if !self.used.contains(&path) {
+            self.used.insert(path.clone());
+            return Ok(path);
+        }
+
+        match self.policy {
+            CollisionPolicy::Error => {
+                anyhow::bail!("{} is the output of multiple inputs", path.display())
+            }
+            CollisionPolicy::Overwrite => {
+                log::warn!(
+                    "Overwriting {} with the output of another input",
+                    path.display()
+                );
+                Ok(path)
+            }
+            CollisionPolicy::SuffixNumber => {
+                let stem = path
+                    .file_stem()
+                    .map(|s| s.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                let candidate = (1..)
+                    .map(|i| {
+                        let mut candidate = path.with_file_name(format!("{}_{}", stem, i));
+                        if let Some(extension) = path.extension() {
+                            candidate.set_extension(extension);
+                        }
+                        candidate
+                    })
+                    .find(|candidate| !self.used.contains(candidate))
+                    .unwrap();
+                self.used.insert(candidate.clone());
+                Ok(candidate)
+            }
+        }
+    }
+}
+
+/// Replace characters that can not be used in file names on the target platform
+pub fn sanitize_file_name(name: &str) -> String {
+    sanitize_with(name, ILLEGAL_CHARACTERS)
+}
+
+fn sanitize_with(name: &str, illegal_characters: &[char]) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_control() || illegal_characters.contains(&c) {
+                REPLACEMENT_CHARACTER
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+/// The parameters of a run that can be used in output patterns
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunInfo {
+    /// The name of the model, see `model_name`
+    pub model: String,
+    /// The factor by which the model scales its input
+    pub scale: usize,
+    /// The name of the backend that runs the model
+    pub backend: String,
+}
+
+/// The file name of a model without its extensions, e.g. "denoise" for "denoise.onnx.gz"
+pub fn model_name(model_path: &Path) -> String {
+    let mut name = Path::new(model_path.file_name().unwrap_or_default());
+    while let Some(extension) = name.extension() {
+        if !["onnx", "gz", "zst"].contains(&extension.to_string_lossy().to_lowercase().as_str()) {
+            break;
+        }
+        name = Path::new(name.file_stem().unwrap_or_default());
+    }
+    name.to_string_lossy().to_string()
+}
+
+/// Render the output path for an input file from a pattern
+///
+/// The following tokens are replaced:
+/// - `%NAME%`: the file name of the input without its extension
+/// - `%EXT%`: the extension of the input
+/// - `%DIR%`: the name of the directory that contains the input
+/// - `%INDEX%`: the position of the input in the batch, `%INDEX:04%` pads it with zeros to a
+///   width of 4 digits
+/// - `%MODEL%`, `%SCALE%` and `%BACKEND%`: the parameters of the run, see `RunInfo`
+///
+/// Characters in the replaced values that can not be used in file names are replaced.
+/// If the rendered path has no extension, the extension of the input is used.
+pub fn render_output_pattern(pattern: &str, input: &Path, index: usize, run: &RunInfo) -> PathBuf {
+    let name = input
+        .file_stem()
+        .map(|n| sanitize_file_name(&n.to_string_lossy()))
+        .unwrap_or_default();
+    let extension = input
+        .extension()
+        .map(|e| sanitize_file_name(&e.to_string_lossy()))
+        .unwrap_or_default();
+    let dir = input
+        .parent()
+        .and_then(|p| p.file_name())
+        .map(|d| sanitize_file_name(&d.to_string_lossy()))
+        .unwrap_or_default();
+
+    let rendered = render_index_tokens(pattern, index)
+        .replace("%NAME%", &name)
+        .replace("%EXT%", &extension)
+        .replace("%DIR%", &dir)
+        .replace("%MODEL%", &sanitize_file_name(&run.model))
+        .replace("%SCALE%", &run.scale.to_string())
+        .replace("%BACKEND%", &sanitize_file_name(&run.backend));
+
+    let mut output = PathBuf::from(rendered);
+    if output.extension().is_none() && !extension.is_empty() {
+        output.set_extension(&extension);
+    }
+    output
+}
+
+fn render_index_tokens(pattern: &str, index: usize) -> String {
+    const TOKEN: &str = "%INDEX";
+
+    let mut result = String::with_capacity(pattern.len());
+    let mut remaining = pattern;
+    while let Some(start) = remaining.find(TOKEN) {
+        result.push_str(&remaining[..start]);
+        let after_token = &remaining[start + TOKEN.len()..];
+
+        if let Some(rest) = after_token.strip_prefix('%') {
+            result.push_str(&index.to_string());
+            remaining = rest;
+        } else if let Some((width, rest)) = after_token
+            .strip_prefix(':')
+            .and_then(|s| s.split_once('%'))
+            .and_then(|(width, rest)| width.parse::<usize>().ok().map(|w| (w, rest)))
+        {
+            result.push_str(&format!("{:0width$}", index, width = width));
+            remaining = rest;
+        } else {
+            // Not a valid index token, keep it as it is
+            result.push_str(TOKEN);
+            remaining = after_token;
+        }
+    }
+    result.push_str(remaining);
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    fn run_info() -> RunInfo {
+        RunInfo {
+            model: "denoise".to_owned(),
+            scale: 1,
+            backend: "tract".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_name_and_extension() {
+        let output = render_output_pattern(
+            "%NAME%_denoised.%EXT%",
+            Path::new("in/a.png"),
+            0,
+            &run_info(),
+        );
+        assert_eq!(output, PathBuf::from("a_denoised.png"));
+
+        let output = render_output_pattern("%DIR%/%NAME%", Path::new("in/a.png"), 0, &run_info());
+        assert_eq!(output, PathBuf::from("in/a.png"));
+    }
+
+    #[test]
+    fn test_run_info_tokens() {
+        let run = RunInfo {
+            model: "upscale".to_owned(),
+            scale: 4,
+            backend: "wonnx".to_owned(),
+        };
+        let input = Path::new("in/photo.jpg");
+        assert_eq!(
+            render_output_pattern("%NAME%_%MODEL%", input, 0, &run),
+            PathBuf::from("photo_upscale.jpg")
+        );
+        assert_eq!(
+            render_output_pattern("%NAME%_%SCALE%x", input, 0, &run),
+            PathBuf::from("photo_4x.jpg")
+        );
+        assert_eq!(
+            render_output_pattern("%BACKEND%/%NAME%.png", input, 0, &run),
+            PathBuf::from("wonnx/photo.png")
+        );
+        assert_eq!(
+            render_output_pattern("%NAME%_%MODEL%_%SCALE%x.png", input, 0, &run_info()),
+            PathBuf::from("photo_denoise_1x.png")
+        );
+    }
+
+    #[test]
+    fn test_model_name() {
+        assert_eq!(model_name(Path::new("models/denoise.onnx")), "denoise");
+        assert_eq!(model_name(Path::new("denoise.onnx.gz")), "denoise");
+        assert_eq!(model_name(Path::new("sr.v2.onnx.zst")), "sr.v2");
+    }
+
+    #[test]
+    fn test_index_makes_names_unique() {
+        let outputs: HashSet<_> = (0..100)
+            .map(|i| {
+                let input = PathBuf::from(format!("shoot_{}/photo.jpg", i));
+                render_output_pattern("%INDEX:04%_%NAME%", &input, i, &run_info())
+            })
+            .collect();
+
+        assert_eq!(outputs.len(), 100);
+        assert!(outputs.contains(&PathBuf::from("0000_photo.jpg")));
+        assert!(outputs.contains(&PathBuf::from("0099_photo.jpg")));
+    }
+
+    #[test]
+    fn test_index_padding_width() {
+        let input = Path::new("photo.jpg");
+        assert_eq!(
+            render_output_pattern("%INDEX%", input, 7, &run_info()),
+            PathBuf::from("7.jpg")
+        );
+        assert_eq!(
+            render_output_pattern("%INDEX:6%", input, 7, &run_info()),
+            PathBuf::from("000007.jpg")
+        );
+        assert_eq!(
+            render_output_pattern("%INDEX:2%", input, 1234, &run_info()),
+            PathBuf::from("1234.jpg")
+        );
+    }
+
+    #[test]
+    fn test_sanitize_file_name() {
+        let windows_characters = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+        assert_eq!(
+            sanitize_with("2023-01-01 12:00:00 <final>?", windows_characters),
+            "2023-01-01 12_00_00 _final__"
+        );
+        assert_eq!(sanitize_with("tab\there", &[]), "tab_here");
+        assert_eq!(sanitize_file_name("plain name"), "plain name");
+    }
+
+    #[test]
+    fn test_collision_policies() {
+        let path = PathBuf::from("out/photo.png");
+
+        let mut paths = OutputPaths::new(CollisionPolicy::Error);
+        assert_eq!(paths.claim(path.clone()).unwrap(), path);
+        assert!(paths.claim(path.clone()).is_err());
+
+        let mut paths = OutputPaths::new(CollisionPolicy::Overwrite);
+        assert_eq!(paths.claim(path.clone()).unwrap(), path);
+        assert_eq!(paths.claim(path.clone()).unwrap(), path);
+
+        let mut paths = OutputPaths::new(CollisionPolicy::SuffixNumber);
+        assert_eq!(paths.claim(path.clone()).unwrap(), path);
+        assert_eq!(
+            paths.claim(path.clone()).unwrap(),
+            PathBuf::from("out/photo_1.png")
+        );
+        assert_eq!(
+            paths.claim(path.clone()).unwrap(),
+            PathBuf::from("out/photo_2.png")
+        );
+    }
+
+    #[test]
+    fn test_parse_collision_policy() {
+        assert_eq!(
+            CollisionPolicy::from_str("suffix-number").unwrap(),
+            CollisionPolicy::SuffixNumber
+        );
+        assert!(CollisionPolicy::from_str("rename").is_err());
+    }
+}
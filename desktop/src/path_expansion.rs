@@ -0,0 +1,48 @@
+use anyhow::Context;
+
+/// Expand `~` and environment variables like `$VAR` or `${VAR}` in a path argument
+///
+/// Shells do not expand quoted paths or paths from files like manifests, this expands them the
+/// same way. Undefined variables are an error instead of being replaced with nothing.
+pub fn expand_path(path: &str) -> anyhow::Result<String> {
+    shellexpand::full(path)
+        .map(|expanded| expanded.into_owned())
+        .with_context(|| format!("Could not expand the path {}", path))
+}
+
+/// Expand an optional path argument in place, see `expand_path`
+pub fn expand_optional_path(path: &mut Option<String>) -> anyhow::Result<()> {
+    if let Some(path) = path {
+        *path = expand_path(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_expand_path() {
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(
+            expand_path("~/models/denoise.onnx").unwrap(),
+            format!("{}/models/denoise.onnx", home)
+        );
+
+        std::env::set_var("NEURATABLE_TEST_SHOOT", "/data/shoot");
+        assert_eq!(
+            expand_path("$NEURATABLE_TEST_SHOOT/raw.tif").unwrap(),
+            "/data/shoot/raw.tif"
+        );
+        let mut output = Some("${NEURATABLE_TEST_SHOOT}_out/%NAME%.%EXT%".to_owned());
+        expand_optional_path(&mut output).unwrap();
+        assert_eq!(output.as_deref(), Some("/data/shoot_out/%NAME%.%EXT%"));
+
+        assert_eq!(
+            expand_path("relative/path.png").unwrap(),
+            "relative/path.png"
+        );
+        assert!(expand_path("$NEURATABLE_TEST_UNDEFINED/model.onnx").is_err());
+    }
+}
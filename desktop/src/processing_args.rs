@@ -0,0 +1,69 @@
+use std::str::FromStr;
+
+use backend::image_chunk_iterator::PadMode;
+use backend::image_processor::ImageColorModel;
+
+/// The color channel order of a model, parsed from "RGB" or "BGR"
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArgColorModel(pub ImageColorModel);
+
+impl FromStr for ArgColorModel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let uppercase = s.to_uppercase();
+        Ok(match uppercase.as_ref() {
+            "BGR" => ArgColorModel(ImageColorModel::BGR),
+            "RGB" => ArgColorModel(ImageColorModel::RGB),
+            _ => anyhow::bail!("Color model {} not known, must be one of (RGB, BGR)", s),
+        })
+    }
+}
+
+/// A pad mode, parsed from "reflect", "wrap", "edge", "mean", "constant" or "constant:<value>"
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArgPadMode(pub PadMode);
+
+impl FromStr for ArgPadMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_ref() {
+            "reflect" => ArgPadMode(PadMode::Reflect),
+            "wrap" => ArgPadMode(PadMode::Wrap),
+            "edge" => ArgPadMode(PadMode::Edge),
+            "mean" => ArgPadMode(PadMode::Mean),
+            "constant" => ArgPadMode(PadMode::Constant(0.0)),
+            mode => match mode.strip_prefix("constant:").map(str::parse) {
+                Some(Ok(value)) => ArgPadMode(PadMode::Constant(value)),
+                _ => anyhow::bail!(
+                    "Pad mode {} not known, must be one of (reflect, wrap, edge, mean, constant:<value>)",
+                    s
+                ),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_processing_args() {
+        assert_eq!(
+            "bgr".parse::<ArgColorModel>().unwrap(),
+            ArgColorModel(ImageColorModel::BGR)
+        );
+        assert!("rgba".parse::<ArgColorModel>().is_err());
+        assert_eq!(
+            "Mean".parse::<ArgPadMode>().unwrap(),
+            ArgPadMode(PadMode::Mean)
+        );
+        assert_eq!(
+            "constant:0.5".parse::<ArgPadMode>().unwrap(),
+            ArgPadMode(PadMode::Constant(0.5))
+        );
+        assert!("constant:x".parse::<ArgPadMode>().is_err());
+    }
+}
@@ -0,0 +1,90 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use backend::image_processor::{ImageColorModel, ImageProcessor};
+use backend::model_runner::ModelRunner;
+use backend::model_value_range::ModelValueRange;
+use backend::test_image::{identity_model_bytes, test_image, TestPattern};
+
+use crate::model_error::describe_model_error;
+
+/// The size of the self test image, large enough to be split into several chunks
+const SELFTEST_SIZE: (u32, u32) = (96, 64);
+
+/// The result of a successful `run_selftest`
+#[derive(Debug, Clone)]
+pub struct SelftestReport {
+    pub backend: &'static str,
+    pub duration: Duration,
+    /// The largest difference of the output to the input, relative to the full value range
+    pub max_deviation: f32,
+}
+
+impl fmt::Display for SelftestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Backend:       {}", self.backend)?;
+        writeln!(f, "Duration:      {:.3} s", self.duration.as_secs_f64())?;
+        write!(f, "Max deviation: {:.6}", self.max_deviation)
+    }
+}
+
+/// Process a generated test image with the built-in identity model, to check a setup end to end
+///
+/// Fails if the model can not be run, if the output is all zero or if it differs from the input
+/// by more than the 8 bit quantization step.
+pub async fn run_selftest(pattern: TestPattern, force_cpu: bool) -> anyhow::Result<SelftestReport> {
+    let start = Instant::now();
+    let runner = ModelRunner::from_bytes(&identity_model_bytes(), force_cpu)
+        .await
+        .map_err(|err| anyhow::anyhow!(describe_model_error(&err)))?;
+    let mut processor = ImageProcessor::new(
+        runner,
+        ImageColorModel::RGB,
+        ModelValueRange::asymmetric(1.0),
+        ModelValueRange::asymmetric(1.0),
+    )
+    .await?;
+    let input = test_image(SELFTEST_SIZE.0, SELFTEST_SIZE.1, pattern);
+    let output = processor.process_image(input.clone()).await?;
+    let duration = start.elapsed();
+
+    if output.dimensions() != input.dimensions() {
+        anyhow::bail!(
+            "The output has the size {:?} instead of {:?}",
+            output.dimensions(),
+            input.dimensions()
+        );
+    }
+    if output.iter().all(|&v| v == 0) {
+        anyhow::bail!("The output is all zero");
+    }
+    let max_deviation = output
+        .iter()
+        .zip(input.iter())
+        .map(|(&a, &b)| (a as f32 - b as f32).abs() / u16::MAX as f32)
+        .fold(0.0, f32::max);
+    if max_deviation > 1.0 / 255.0 {
+        anyhow::bail!(
+            "The output differs from the input by up to {:.4}",
+            max_deviation
+        );
+    }
+    Ok(SelftestReport {
+        backend: processor.backend_name(),
+        duration,
+        max_deviation,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_selftest() {
+        for pattern in [TestPattern::Gradient, TestPattern::Noise] {
+            let report = pollster::block_on(run_selftest(pattern, true)).unwrap();
+            assert!(report.max_deviation < 1e-4);
+        }
+    }
+}
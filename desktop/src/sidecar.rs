@@ -0,0 +1,159 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use backend::image_processor::ProcessorSettings;
+use backend::model_runner::BackendInfo;
+use serde::{Deserialize, Serialize};
+
+/// The parameters used to produce an output image
+///
+/// This is written next to the output image to make the result reproducible.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sidecar {
+    pub neuratable_version: String,
+    /// The SHA256 hash of the ONNX model
+    pub model_sha256: String,
+    pub input: PathBuf,
+    pub settings: ProcessorSettings,
+    /// The backend that processed this image, the fallback chunks only count this image
+    pub backend: BackendInfo,
+    pub passes: usize,
+    pub processing_seconds: f64,
+}
+
+impl Sidecar {
+    pub fn new(
+        model_sha256: String,
+        input: &Path,
+        settings: ProcessorSettings,
+        backend: BackendInfo,
+        passes: usize,
+        processing_time: Duration,
+    ) -> Self {
+        Sidecar {
+            neuratable_version: backend::version().to_owned(),
+            model_sha256,
+            input: input.to_owned(),
+            settings,
+            backend,
+            passes,
+            processing_seconds: processing_time.as_secs_f64(),
+        }
+    }
+
+    /// Write the sidecar next to the output image, see `sidecar_path`
+    pub fn write(&self, output_path: &Path) -> anyhow::Result<()> {
+        let path = sidecar_path(output_path);
+        let file = File::create(&path)
+            .with_context(|| format!("Could not create sidecar {}", path.display()))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)?;
+        Ok(())
+    }
+}
+
+/// The path of the sidecar for an output image, e.g. `image.png.json` for `image.png`
+pub fn sidecar_path(output_path: &Path) -> PathBuf {
+    let mut path = output_path.as_os_str().to_owned();
+    path.push(".json");
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::image_utils::model_hash;
+    use crate::test_utils::identity_model_bytes;
+    use backend::image_chunk_iterator::PadMode;
+    use backend::image_processor::{
+        AccumulatorPrecision, ImageColorModel, ImageProcessor, ProcessMode,
+    };
+    use backend::model_runner::{DownscaleFilter, ModelRunner};
+    use backend::model_value_range::ModelValueRange;
+    use backend::ChunkSize;
+
+    #[test]
+    fn test_sidecar_path() {
+        assert_eq!(
+            sidecar_path(Path::new("out/image.png")),
+            PathBuf::from("out/image.png.json")
+        );
+    }
+
+    #[test]
+    fn test_sidecar_matches_configuration() {
+        let model_bytes = identity_model_bytes();
+        let runner = pollster::block_on(ModelRunner::from_bytes(&model_bytes, true)).unwrap();
+        let processor = pollster::block_on(ImageProcessor::new(
+            runner,
+            ImageColorModel::BGR,
+            ModelValueRange::symmetric(1.0),
+            ModelValueRange::asymmetric(255.0),
+        ))
+        .unwrap()
+        .with_process_mode(ProcessMode::Tiled {
+            padding: 4,
+            overlap: 1,
+        })
+        .with_pad_mode(PadMode::Edge)
+        .with_skip_uniform(Some(0.01));
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output.png");
+
+        Sidecar::new(
+            model_hash(&model_bytes),
+            Path::new("input.png"),
+            processor.settings(),
+            processor.backend_info(),
+            2,
+            Duration::from_millis(1500),
+        )
+        .write(&output)
+        .unwrap();
+
+        let sidecar: Sidecar =
+            serde_json::from_reader(File::open(dir.path().join("output.png.json")).unwrap())
+                .unwrap();
+        assert_eq!(sidecar.neuratable_version, backend::version());
+        assert_eq!(sidecar.model_sha256, model_hash(&model_bytes));
+        assert_eq!(sidecar.input, PathBuf::from("input.png"));
+        assert_eq!(
+            sidecar.backend,
+            BackendInfo {
+                name: "tract".to_owned(),
+                device: "CPU".to_owned(),
+                fallback_chunks: 0,
+            }
+        );
+        assert_eq!(sidecar.passes, 2);
+        assert_eq!(sidecar.processing_seconds, 1.5);
+        let settings = sidecar.settings;
+        assert_eq!(settings.color_model, ImageColorModel::BGR);
+        assert_eq!(settings.input_range, ModelValueRange::symmetric(1.0));
+        assert_eq!(settings.output_range, ModelValueRange::asymmetric(255.0));
+        assert_eq!(
+            settings.chunksize,
+            ChunkSize {
+                width: 32,
+                height: 32
+            }
+        );
+        assert_eq!(
+            settings.process_mode,
+            ProcessMode::Tiled {
+                padding: 4,
+                overlap: 1
+            }
+        );
+        assert_eq!(settings.backend, "tract");
+        assert_eq!(settings.pad_mode, PadMode::Edge);
+        assert_eq!(settings.skip_uniform, Some(0.01));
+        assert_eq!(settings.accumulator_precision, AccumulatorPrecision::F32);
+        assert_eq!(settings.downscale_filter, DownscaleFilter::Lanczos3);
+        assert!(!settings.mask);
+        assert!(!settings.upscale);
+        assert_eq!(settings.infer_scale, 1.0);
+    }
+}
@@ -0,0 +1,651 @@
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::Arc;
+
+use backend::image_chunk_iterator::ChunkGeometryReport;
+use backend::image_processor::ImageProcessor;
+use backend::image_tensor::{image_to_tensor, tensor_to_image};
+
+use crate::image_utils::Rgb16Image;
+
+/// A source of image data that can be read region by region
+///
+/// This allows reading only the parts of an image that are needed for processing instead of
+/// decoding the whole image at once.
+pub trait StreamingImageSource {
+    /// The width and height of the image
+    fn dimensions(&self) -> (u32, u32);
+
+    /// Read a rectangular region of the image
+    fn read_region(&self, x: u32, y: u32, width: u32, height: u32) -> anyhow::Result<Rgb16Image>;
+}
+
+impl StreamingImageSource for Rgb16Image {
+    fn dimensions(&self) -> (u32, u32) {
+        self.dimensions()
+    }
+
+    fn read_region(&self, x: u32, y: u32, width: u32, height: u32) -> anyhow::Result<Rgb16Image> {
+        check_region(self.dimensions(), x, y, width, height)?;
+        Ok(image::imageops::crop_imm(self, x, y, width, height).to_image())
+    }
+}
+
+/// The source regions of the chunks of a grid, read from a `StreamingImageSource`
+///
+/// See `read_chunk_regions`. The regions are yielded in the iteration order of the chunks.
+pub struct ChunkRegions {
+    reader: ChunkRegionReader,
+}
+
+enum ChunkRegionReader {
+    Synchronous {
+        source: Arc<dyn StreamingImageSource + Send + Sync>,
+        regions: std::vec::IntoIter<(u32, u32, u32, u32)>,
+    },
+    /// A background thread reads the regions ahead and sends them through a bounded channel
+    Prefetching(Receiver<anyhow::Result<Rgb16Image>>),
+}
+
+impl Iterator for ChunkRegions {
+    type Item = anyhow::Result<Rgb16Image>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.reader {
+            ChunkRegionReader::Synchronous { source, regions } => regions
+                .next()
+                .map(|(x, y, width, height)| source.read_region(x, y, width, height)),
+            // The channel is closed once the thread read all regions
+            ChunkRegionReader::Prefetching(receiver) => receiver.recv().ok(),
+        }
+    }
+}
+
+/// Read the source region of every chunk of a grid, see `ChunkGeometryReport::source_region`
+///
+/// With a `prefetch_depth` above 0, a background thread reads up to that many regions ahead
+/// while the caller processes the earlier ones, which hides the latency of slow sources. With
+/// 0, each region is read when it is requested. Dropping the iterator stops the thread after
+/// its current read.
+pub fn read_chunk_regions<S>(
+    source: Arc<S>,
+    geometry: &ChunkGeometryReport,
+    prefetch_depth: usize,
+) -> ChunkRegions
+where
+    S: StreamingImageSource + Send + Sync + 'static,
+{
+    let regions: Vec<_> = (0..geometry.chunk_count())
+        .map(|index| {
+            let (x, y) = geometry.source_region(index);
+            (
+                x.start as u32,
+                y.start as u32,
+                x.len() as u32,
+                y.len() as u32,
+            )
+        })
+        .collect();
+    if prefetch_depth == 0 {
+        return ChunkRegions {
+            reader: ChunkRegionReader::Synchronous {
+                source,
+                regions: regions.into_iter(),
+            },
+        };
+    }
+
+    let (sender, receiver) = sync_channel(prefetch_depth);
+    std::thread::spawn(move || {
+        for (x, y, width, height) in regions {
+            // Sending fails once the receiving iterator was dropped
+            if sender
+                .send(source.read_region(x, y, width, height))
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    ChunkRegions {
+        reader: ChunkRegionReader::Prefetching(receiver),
+    }
+}
+
+/// Process an image from a streaming source chunk by chunk
+///
+/// Only the regions of the chunks in flight and the output are held in memory, see
+/// `ImageProcessor::process_chunk_regions` for the supported settings and `read_chunk_regions`
+/// for `prefetch_depth`.
+pub async fn process_streaming<S>(
+    processor: &mut ImageProcessor,
+    source: Arc<S>,
+    prefetch_depth: usize,
+) -> anyhow::Result<Rgb16Image>
+where
+    S: StreamingImageSource + Send + Sync + 'static,
+{
+    if processor.channels() != 3 {
+        anyhow::bail!(
+            "Only models with 3 channels can stream images, the model has {}",
+            processor.channels()
+        );
+    }
+    let (width, height) = source.dimensions();
+    let geometry = processor.streaming_geometry(width as usize, height as usize)?;
+    let regions = read_chunk_regions(source, &geometry, prefetch_depth)
+        .map(|region| Ok::<_, anyhow::Error>(image_to_tensor(region?)?));
+    let output = processor.process_chunk_regions(&geometry, regions).await?;
+    Ok(tensor_to_image(output)?)
+}
+
+fn check_region(
+    dimensions: (u32, u32),
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+) -> anyhow::Result<()> {
+    if x as u64 + width as u64 > dimensions.0 as u64
+        || y as u64 + height as u64 > dimensions.1 as u64
+    {
+        anyhow::bail!(
+            "Region {}x{} at ({}, {}) exceeds the image size {:?}",
+            width,
+            height,
+            x,
+            y,
+            dimensions
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::identity_processor;
+    use backend::image_processor::{ProcessMode, ProgressEvent};
+    use backend::ChunkSize;
+    use image::Rgb;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    /// A source that records the size of each region that is read from it
+    struct RecordingSource {
+        image: Rgb16Image,
+        reads: Mutex<Vec<(u32, u32)>>,
+    }
+
+    impl StreamingImageSource for RecordingSource {
+        fn dimensions(&self) -> (u32, u32) {
+            self.image.dimensions()
+        }
+
+        fn read_region(
+            &self,
+            x: u32,
+            y: u32,
+            width: u32,
+            height: u32,
+        ) -> anyhow::Result<Rgb16Image> {
+            self.reads.lock().unwrap().push((width, height));
+            self.image.read_region(x, y, width, height)
+        }
+    }
+
+    fn test_image() -> Rgb16Image {
+        Rgb16Image::from_fn(150, 110, |x, y| {
+            Rgb([(x * 311) as u16, (y * 277) as u16, (x * y) as u16])
+        })
+    }
+
+    fn tiled_processor() -> ImageProcessor {
+        identity_processor().with_process_mode(ProcessMode::Tiled {
+            padding: 4,
+            overlap: 2,
+        })
+    }
+
+    #[test]
+    fn test_prefetch_matches_synchronous_reads() {
+        let image = Arc::new(test_image());
+        let chunksize = ChunkSize {
+            width: 64,
+            height: 48,
+        };
+        let geometry = ChunkGeometryReport::new((150, 110), chunksize, 8, 2).unwrap();
+
+        let read = |prefetch_depth| {
+            read_chunk_regions(image.clone(), &geometry, prefetch_depth)
+                .collect::<anyhow::Result<Vec<_>>>()
+                .unwrap()
+        };
+        let synchronous = read(0);
+        assert_eq!(synchronous.len(), geometry.chunk_count());
+        let (x, y) = geometry.source_region(1);
+        assert_eq!(
+            synchronous[1],
+            image
+                .read_region(
+                    x.start as u32,
+                    y.start as u32,
+                    x.len() as u32,
+                    y.len() as u32
+                )
+                .unwrap()
+        );
+        assert_eq!(read(1), synchronous);
+        assert_eq!(read(3), synchronous);
+    }
+
+    #[test]
+    fn test_process_streaming() {
+        let image = test_image();
+        let expected = pollster::block_on(tiled_processor().process_image(image.clone())).unwrap();
+
+        for prefetch_depth in [0, 2] {
+            let source = Arc::new(RecordingSource {
+                image: image.clone(),
+                reads: Mutex::new(Vec::new()),
+            });
+            let mut processor = tiled_processor();
+            let output = pollster::block_on(process_streaming(
+                &mut processor,
+                source.clone(),
+                prefetch_depth,
+            ))
+            .unwrap();
+            assert_eq!(output, expected);
+
+            // Every chunk is read once and no read is larger than a chunk
+            let geometry = processor.chunk_geometry(150, 110).unwrap();
+            let reads = source.reads.lock().unwrap();
+            assert_eq!(reads.len(), geometry.chunk_count());
+            assert!(reads.len() > 1);
+            for &(width, height) in reads.iter() {
+                assert!(width as usize <= geometry.chunksize.width);
+                assert!(height as usize <= geometry.chunksize.height);
+            }
+        }
+    }
+
+    #[test]
+    fn test_process_streaming_reads_ahead() {
+        // The number of regions that were read when each chunk was finished
+        let reads_per_chunk = |prefetch_depth: usize| {
+            let source = Arc::new(RecordingSource {
+                image: test_image(),
+                reads: Mutex::new(Vec::new()),
+            });
+            let observed = Arc::new(Mutex::new(Vec::new()));
+            let mut processor = tiled_processor();
+            processor.set_progress_callback({
+                let (source, observed) = (source.clone(), observed.clone());
+                move |event: ProgressEvent| {
+                    // Give the background thread time to read ahead, the processing loop does
+                    // not request the next region until the callback returns
+                    let ahead = (event.current_chunk + prefetch_depth).min(event.total_chunks);
+                    let deadline = Instant::now() + Duration::from_secs(5);
+                    while source.reads.lock().unwrap().len() < ahead && Instant::now() < deadline {
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                    let reads = source.reads.lock().unwrap().len();
+                    observed
+                        .lock()
+                        .unwrap()
+                        .push((event.current_chunk, event.total_chunks, reads));
+                }
+            });
+            pollster::block_on(process_streaming(&mut processor, source, prefetch_depth)).unwrap();
+            let observed = observed.lock().unwrap().clone();
+            observed
+        };
+
+        for (chunk, _, reads) in reads_per_chunk(0) {
+            assert_eq!(reads, chunk);
+        }
+        let prefetched = reads_per_chunk(2);
+        assert!(prefetched.len() > 2);
+        for (chunk, total, reads) in prefetched {
+            assert!(
+                reads >= (chunk + 2).min(total),
+                "{} reads after chunk {}",
+                reads,
+                chunk
+            );
+        }
+    }
+}
+
+#[cfg(feature = "mmap")]
+pub use mmap_tiff::MmapTiffSource;
+
+#[cfg(feature = "mmap")]
+mod mmap_tiff {
+    use std::fs::File;
+    use std::io::Cursor;
+    use std::path::Path;
+
+    use anyhow::Context;
+    use memmap2::Mmap;
+    use tiff::decoder::Decoder;
+    use tiff::tags::Tag;
+
+    use super::{check_region, StreamingImageSource};
+    use crate::image_utils::Rgb16Image;
+
+    /// The size of a 16 bit RGB pixel in bytes
+    const PIXEL_SIZE: usize = 6;
+
+    /// How the pixel data of a TIFF file is split into blocks
+    enum BlockLayout {
+        Strips {
+            rows_per_strip: usize,
+        },
+        Tiles {
+            tile_width: usize,
+            tile_height: usize,
+        },
+    }
+
+    /// A memory mapped, uncompressed 16 bit RGB TIFF file
+    ///
+    /// Only the pages that contain requested regions are read from disk by the operating system.
+    ///
+    /// # Safety considerations
+    ///
+    /// The file must not be modified while it is mapped. Changes by other processes become
+    /// visible in the mapped memory and truncating the file makes reads fail with `SIGBUS`.
+    /// Only map files that are not written concurrently, e.g. not the output of a running batch.
+    pub struct MmapTiffSource {
+        mmap: Mmap,
+        width: u32,
+        height: u32,
+        big_endian: bool,
+        layout: BlockLayout,
+        block_offsets: Vec<u64>,
+    }
+
+    impl MmapTiffSource {
+        pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+            let path = path.as_ref();
+            let file =
+                File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+            // SAFETY: See the safety considerations in the type documentation
+            let mmap = unsafe { Mmap::map(&file)? };
+
+            let big_endian = match mmap.get(0..2) {
+                Some(b"II") => false,
+                Some(b"MM") => true,
+                _ => anyhow::bail!("{} is not a TIFF file", path.display()),
+            };
+
+            let mut decoder = Decoder::new(Cursor::new(&mmap[..]))?;
+            let (width, height) = decoder.dimensions()?;
+            if decoder.colortype()? != tiff::ColorType::RGB(16) {
+                anyhow::bail!(
+                    "{} can not be memory mapped, only 16 bit RGB TIFFs are supported",
+                    path.display()
+                );
+            }
+            let compression = decoder
+                .find_tag(Tag::Compression)?
+                .map(|value| value.into_u16())
+                .transpose()?
+                .unwrap_or(1);
+            let planar_configuration = decoder
+                .find_tag(Tag::PlanarConfiguration)?
+                .map(|value| value.into_u16())
+                .transpose()?
+                .unwrap_or(1);
+            if compression != 1 || planar_configuration != 1 {
+                anyhow::bail!(
+                    "{} can not be memory mapped, only uncompressed TIFFs with interleaved \
+                     samples are supported",
+                    path.display()
+                );
+            }
+
+            let (layout, block_offsets) = if decoder.find_tag(Tag::TileWidth)?.is_some() {
+                (
+                    BlockLayout::Tiles {
+                        tile_width: decoder.get_tag_u32(Tag::TileWidth)? as usize,
+                        tile_height: decoder.get_tag_u32(Tag::TileLength)? as usize,
+                    },
+                    decoder.get_tag_u64_vec(Tag::TileOffsets)?,
+                )
+            } else {
+                let rows_per_strip = decoder
+                    .find_tag(Tag::RowsPerStrip)?
+                    .map(|value| value.into_u32())
+                    .transpose()?
+                    .unwrap_or(height);
+                (
+                    BlockLayout::Strips {
+                        rows_per_strip: rows_per_strip as usize,
+                    },
+                    decoder.get_tag_u64_vec(Tag::StripOffsets)?,
+                )
+            };
+
+            Ok(MmapTiffSource {
+                mmap,
+                width,
+                height,
+                big_endian,
+                layout,
+                block_offsets,
+            })
+        }
+
+        /// The byte offset of a pixel in the file
+        fn pixel_offset(&self, x: usize, y: usize) -> anyhow::Result<usize> {
+            let (block, offset_in_block) = match self.layout {
+                BlockLayout::Strips { rows_per_strip } => (
+                    y / rows_per_strip,
+                    ((y % rows_per_strip) * self.width as usize + x) * PIXEL_SIZE,
+                ),
+                BlockLayout::Tiles {
+                    tile_width,
+                    tile_height,
+                } => {
+                    let tiles_across = (self.width as usize + tile_width - 1) / tile_width;
+                    (
+                        (y / tile_height) * tiles_across + x / tile_width,
+                        ((y % tile_height) * tile_width + x % tile_width) * PIXEL_SIZE,
+                    )
+                }
+            };
+            let block_offset = self
+                .block_offsets
+                .get(block)
+                .context("The TIFF file has too few data blocks")?;
+            Ok(*block_offset as usize + offset_in_block)
+        }
+
+        fn read_sample(&self, bytes: &[u8]) -> u16 {
+            let bytes = [bytes[0], bytes[1]];
+            if self.big_endian {
+                u16::from_be_bytes(bytes)
+            } else {
+                u16::from_le_bytes(bytes)
+            }
+        }
+    }
+
+    impl StreamingImageSource for MmapTiffSource {
+        fn dimensions(&self) -> (u32, u32) {
+            (self.width, self.height)
+        }
+
+        fn read_region(
+            &self,
+            x: u32,
+            y: u32,
+            width: u32,
+            height: u32,
+        ) -> anyhow::Result<Rgb16Image> {
+            check_region((self.width, self.height), x, y, width, height)?;
+
+            let mut region = Rgb16Image::new(width, height);
+            for (region_x, region_y, pixel) in region.enumerate_pixels_mut() {
+                let offset = self.pixel_offset((x + region_x) as usize, (y + region_y) as usize)?;
+                let bytes = self
+                    .mmap
+                    .get(offset..offset + PIXEL_SIZE)
+                    .context("The TIFF file is truncated")?;
+                for c in 0..3 {
+                    pixel[c] = self.read_sample(&bytes[2 * c..]);
+                }
+            }
+            Ok(region)
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use crate::image_utils::load_image;
+        use crate::image_utils::ColorManagement;
+        use crate::streaming_source::process_streaming;
+        use crate::test_utils::identity_processor;
+        use image::Rgb;
+        use std::io::Write;
+        use std::sync::Arc;
+
+        fn test_image(width: u32, height: u32) -> Rgb16Image {
+            Rgb16Image::from_fn(width, height, |x, y| {
+                Rgb([(x * 211) as u16, (y * 307) as u16, (x * y) as u16])
+            })
+        }
+
+        /// Write an uncompressed, little endian, tiled TIFF file
+        fn write_tiled_tiff(path: &Path, image: &Rgb16Image, tile_size: u32) {
+            let (width, height) = image.dimensions();
+            let tiles_across = (width + tile_size - 1) / tile_size;
+            let tiles_down = (height + tile_size - 1) / tile_size;
+            let tile_bytes = (tile_size * tile_size) as usize * PIXEL_SIZE;
+            let tile_count = (tiles_across * tiles_down) as usize;
+
+            let mut data = Vec::new();
+            for tile_y in 0..tiles_down {
+                for tile_x in 0..tiles_across {
+                    for y in 0..tile_size {
+                        for x in 0..tile_size {
+                            let (px, py) = (tile_x * tile_size + x, tile_y * tile_size + y);
+                            let pixel = if px < width && py < height {
+                                *image.get_pixel(px, py)
+                            } else {
+                                Rgb([0, 0, 0])
+                            };
+                            for c in 0..3 {
+                                data.extend_from_slice(&pixel[c].to_le_bytes());
+                            }
+                        }
+                    }
+                }
+            }
+
+            let data_offset = 8u32;
+            let bits_offset = data_offset + data.len() as u32;
+            let tile_offsets_offset = bits_offset + 6;
+            let byte_counts_offset = tile_offsets_offset + 4 * tile_count as u32;
+            let ifd_offset = byte_counts_offset + 4 * tile_count as u32;
+
+            let mut file = Vec::new();
+            file.extend_from_slice(b"II*\0");
+            file.extend_from_slice(&ifd_offset.to_le_bytes());
+            file.extend_from_slice(&data);
+            for _ in 0..3 {
+                file.extend_from_slice(&16u16.to_le_bytes());
+            }
+            for tile in 0..tile_count {
+                file.extend_from_slice(&(data_offset + (tile * tile_bytes) as u32).to_le_bytes());
+            }
+            for _ in 0..tile_count {
+                file.extend_from_slice(&(tile_bytes as u32).to_le_bytes());
+            }
+
+            // Tag, type (3 = SHORT, 4 = LONG), count and value or offset
+            let entries: [(u16, u16, u32, u32); 11] = [
+                (256, 4, 1, width),
+                (257, 4, 1, height),
+                (258, 3, 3, bits_offset),
+                (259, 3, 1, 1),
+                (262, 3, 1, 2),
+                (277, 3, 1, 3),
+                (284, 3, 1, 1),
+                (322, 4, 1, tile_size),
+                (323, 4, 1, tile_size),
+                (324, 4, tile_count as u32, tile_offsets_offset),
+                (325, 4, tile_count as u32, byte_counts_offset),
+            ];
+            file.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+            for (tag, kind, count, value) in entries {
+                file.extend_from_slice(&tag.to_le_bytes());
+                file.extend_from_slice(&kind.to_le_bytes());
+                file.extend_from_slice(&count.to_le_bytes());
+                file.extend_from_slice(&value.to_le_bytes());
+            }
+            file.extend_from_slice(&0u32.to_le_bytes());
+
+            File::create(path).unwrap().write_all(&file).unwrap();
+        }
+
+        #[test]
+        fn test_read_region_from_strips() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("strips.tif");
+            let image = test_image(300, 200);
+            image.save(&path).unwrap();
+
+            let source = MmapTiffSource::open(&path).unwrap();
+
+            assert_eq!(source.dimensions(), (300, 200));
+            assert_eq!(
+                source.read_region(17, 33, 100, 150).unwrap(),
+                image.read_region(17, 33, 100, 150).unwrap()
+            );
+        }
+
+        #[test]
+        fn test_process_streaming_tiff() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("tiles.tif");
+            let image = test_image(150, 110);
+            write_tiled_tiff(&path, &image, 32);
+
+            let source = Arc::new(MmapTiffSource::open(&path).unwrap());
+            let output =
+                pollster::block_on(process_streaming(&mut identity_processor(), source, 1))
+                    .unwrap();
+            assert_eq!(
+                output,
+                pollster::block_on(identity_processor().process_image(image)).unwrap()
+            );
+        }
+
+        #[test]
+        fn test_read_region_from_tiles() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("tiles.tif");
+            let image = test_image(300, 200);
+            write_tiled_tiff(&path, &image, 64);
+            // Make sure that the synthetic file is a valid TIFF
+            assert_eq!(
+                load_image(&path, ColorManagement::AssumeSrgb).unwrap(),
+                image
+            );
+
+            let source = MmapTiffSource::open(&path).unwrap();
+
+            assert_eq!(source.dimensions(), (300, 200));
+            assert_eq!(source.read_region(0, 0, 300, 200).unwrap(), image);
+            assert_eq!(
+                source.read_region(250, 120, 50, 80).unwrap(),
+                image.read_region(250, 120, 50, 80).unwrap()
+            );
+            assert!(source.read_region(250, 120, 51, 80).is_err());
+        }
+    }
+}
@@ -0,0 +1,18 @@
+use backend::image_processor::{ImageColorModel, ImageProcessor};
+use backend::model_runner::ModelRunner;
+use backend::model_value_range::ModelValueRange;
+
+pub use backend::test_image::identity_model_bytes;
+
+/// A CPU processor for the identity model with the default settings
+pub fn identity_processor() -> ImageProcessor {
+    let runner =
+        pollster::block_on(ModelRunner::from_bytes(&identity_model_bytes(), true)).unwrap();
+    pollster::block_on(ImageProcessor::new(
+        runner,
+        ImageColorModel::RGB,
+        ModelValueRange::asymmetric(1.0),
+        ModelValueRange::asymmetric(1.0),
+    ))
+    .unwrap()
+}
@@ -0,0 +1,198 @@
+use std::ops::Range;
+use std::time::Duration;
+
+use backend::image_chunk_iterator::ChunkGeometryReport;
+use image::{Rgb, RgbImage};
+
+use crate::image_utils::Rgb16Image;
+
+/// The outline color of the area a chunk contributes to the output
+pub const TILE_COLOR: Rgb<u8> = Rgb([255, 32, 32]);
+/// The outline color of the area passed to the model for a chunk, including its padding
+pub const PADDING_COLOR: Rgb<u8> = Rgb([32, 128, 255]);
+/// The tint of areas in which the output of several chunks is blended
+pub const OVERLAP_COLOR: Rgb<u8> = Rgb([255, 255, 0]);
+
+/// The heatmap color of the fastest chunk
+pub const FAST_COLOR: Rgb<u8> = Rgb([0, 64, 255]);
+/// The heatmap color of the slowest chunk
+pub const SLOW_COLOR: Rgb<u8> = Rgb([255, 64, 0]);
+
+/// The brightness of the image below the overlay, so the overlay colors stand out
+const IMAGE_BRIGHTNESS: f32 = 0.6;
+
+/// Draw the chunk grid of `geometry` on a darkened 8 bit copy of the image
+///
+/// The usable area of each chunk is outlined in `TILE_COLOR` and the padded model input in
+/// `PADDING_COLOR`. Areas that are covered by more than one chunk are tinted in
+/// `OVERLAP_COLOR`, this is where the chunks are blended.
+pub fn draw_tile_overlay(image: &Rgb16Image, geometry: &ChunkGeometryReport) -> RgbImage {
+    let mut overlay = darken(image);
+
+    let (width, height) = geometry.image_size;
+    let mut coverage = vec![0u32; width * height];
+    for index in 0..geometry.chunk_count() {
+        let (x_range, y_range) = geometry.usable_region(index);
+        for y in y_range {
+            for x in x_range.clone() {
+                coverage[y * width + x] += 1;
+            }
+        }
+    }
+    for (x, y, pixel) in overlay.enumerate_pixels_mut() {
+        if coverage[y as usize * width + x as usize] > 1 {
+            for c in 0..3 {
+                pixel[c] = ((pixel[c] as u16 + OVERLAP_COLOR[c] as u16) / 2) as u8;
+            }
+        }
+    }
+
+    // The tile outlines are drawn last, so they are not hidden by the padding of neighbours
+    for index in 0..geometry.chunk_count() {
+        let (x_range, y_range) = geometry.input_region(index);
+        draw_outline(&mut overlay, x_range, y_range, PADDING_COLOR);
+    }
+    for index in 0..geometry.chunk_count() {
+        let (x_range, y_range) = geometry.usable_region(index);
+        draw_outline(&mut overlay, x_range, y_range, TILE_COLOR);
+    }
+    debug_assert_eq!(overlay.dimensions(), (width as u32, height as u32));
+    overlay
+}
+
+/// Tint the usable area of each chunk by its inference time on a darkened copy of the image
+///
+/// `timings` holds the time of each chunk in iteration order, see
+/// `ImageProcessor::chunk_timings`. The colors go from `FAST_COLOR` for the fastest chunk to
+/// `SLOW_COLOR` for the slowest one, chunks without a timing are not tinted. The image can be
+/// the input or a larger output of a scaling model, the chunk areas are scaled to its size.
+pub fn draw_timing_heatmap(
+    image: &Rgb16Image,
+    geometry: &ChunkGeometryReport,
+    timings: &[Option<Duration>],
+) -> RgbImage {
+    let mut heatmap = darken(image);
+    let scale = (image.width() as usize / geometry.image_size.0.max(1)).max(1);
+    let timed = timings.iter().flatten();
+    let (fastest, slowest) = (
+        timed.clone().min().copied().unwrap_or_default(),
+        timed.max().copied().unwrap_or_default(),
+    );
+
+    for (index, timing) in timings.iter().enumerate().take(geometry.chunk_count()) {
+        let timing = match timing {
+            Some(timing) => *timing,
+            None => continue,
+        };
+        let position = if slowest > fastest {
+            (timing - fastest).as_secs_f32() / (slowest - fastest).as_secs_f32()
+        } else {
+            0.0
+        };
+        let color = Rgb([0, 1, 2].map(|c| {
+            (FAST_COLOR[c] as f32 + position * (SLOW_COLOR[c] as f32 - FAST_COLOR[c] as f32))
+                .round() as u8
+        }));
+        let (x_range, y_range) = geometry.usable_region(index);
+        for y in y_range.start * scale..(y_range.end * scale).min(heatmap.height() as usize) {
+            for x in x_range.start * scale..(x_range.end * scale).min(heatmap.width() as usize) {
+                let pixel = heatmap.get_pixel_mut(x as u32, y as u32);
+                for c in 0..3 {
+                    pixel[c] = ((pixel[c] as u16 + color[c] as u16) / 2) as u8;
+                }
+            }
+        }
+    }
+    heatmap
+}
+
+/// An 8 bit copy of the image with the brightness reduced to `IMAGE_BRIGHTNESS`
+fn darken(image: &Rgb16Image) -> RgbImage {
+    RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        let pixel = image.get_pixel(x, y);
+        Rgb(pixel.0.map(|v| {
+            (v as f32 / u16::MAX as f32 * IMAGE_BRIGHTNESS * u8::MAX as f32).round() as u8
+        }))
+    })
+}
+
+/// Draw a one pixel wide rectangle along the inner border of a region
+fn draw_outline(image: &mut RgbImage, x: Range<usize>, y: Range<usize>, color: Rgb<u8>) {
+    if x.is_empty() || y.is_empty() {
+        return;
+    }
+    for px in x.clone() {
+        image.put_pixel(px as u32, y.start as u32, color);
+        image.put_pixel(px as u32, y.end as u32 - 1, color);
+    }
+    for py in y {
+        image.put_pixel(x.start as u32, py as u32, color);
+        image.put_pixel(x.end as u32 - 1, py as u32, color);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use backend::ChunkSize;
+
+    #[test]
+    fn test_draw_tile_overlay() {
+        let image = Rgb16Image::from_pixel(100, 70, Rgb([u16::MAX; 3]));
+        let chunksize = ChunkSize {
+            width: 32,
+            height: 32,
+        };
+        let geometry = ChunkGeometryReport::new((100, 70), chunksize, 4, 4).unwrap();
+
+        let overlay = draw_tile_overlay(&image, &geometry);
+
+        assert_eq!(overlay.dimensions(), (100, 70));
+        // The top left corner of every tile is outlined
+        let tile_corners = geometry
+            .y_origins
+            .iter()
+            .flat_map(|&y| geometry.x_origins.iter().map(move |&x| (x, y)))
+            .filter(|&(x, y)| overlay.get_pixel(x as u32, y as u32) == &TILE_COLOR)
+            .count();
+        assert_eq!(tile_corners, 5 * 4);
+        assert_eq!(tile_corners, geometry.chunk_count());
+
+        // The second column starts inside the usable area of the first one, which is blended
+        let overlap_x = geometry.x_origins[1] as u32 + 1;
+        assert_eq!(overlay.get_pixel(overlap_x, 10), &Rgb([204, 204, 76]));
+        // The image is darkened where there is no overlay
+        assert_eq!(overlay.get_pixel(10, 10), &Rgb([153, 153, 153]));
+    }
+
+    #[test]
+    fn test_draw_timing_heatmap() {
+        let image = Rgb16Image::from_pixel(200, 140, Rgb([0; 3]));
+        let chunksize = ChunkSize {
+            width: 50,
+            height: 50,
+        };
+        // Four chunks without overlap, the output is twice the size of the input
+        let geometry = ChunkGeometryReport::new((100, 70), chunksize, 0, 0).unwrap();
+        let timings = [
+            Some(Duration::from_millis(10)),
+            Some(Duration::from_millis(30)),
+            Some(Duration::from_millis(20)),
+            None,
+        ];
+
+        let heatmap = draw_timing_heatmap(&image, &geometry, &timings);
+
+        assert_eq!(heatmap.dimensions(), (200, 140));
+        let half = |color: Rgb<u8>| Rgb(color.0.map(|c| c / 2));
+        // The centers of the chunks in the scaled output
+        assert_eq!(heatmap.get_pixel(50, 50), &half(FAST_COLOR));
+        assert_eq!(heatmap.get_pixel(150, 50), &half(SLOW_COLOR));
+        let middle = heatmap.get_pixel(50, 120);
+        assert!(middle != &half(FAST_COLOR) && middle != &half(SLOW_COLOR));
+        assert_eq!(heatmap.get_pixel(150, 120), &Rgb([0; 3]));
+        // The tiles end at the scaled chunk borders
+        assert_eq!(heatmap.get_pixel(99, 99), &half(FAST_COLOR));
+        assert_eq!(heatmap.get_pixel(100, 99), &half(SLOW_COLOR));
+    }
+}
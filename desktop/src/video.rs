@@ -0,0 +1,348 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+use anyhow::Context;
+use backend::image_processor::ImageProcessor;
+use ndarray::Array3;
+use serde::Deserialize;
+
+/// The file extensions that are processed as videos
+const VIDEO_EXTENSIONS: [&str; 6] = ["mp4", "m4v", "mkv", "mov", "avi", "webm"];
+
+/// Check if a file is a video by its extension, see `process_video`
+pub fn is_video<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().extension().map_or(false, |extension| {
+        VIDEO_EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str())
+    })
+}
+
+/// Check if ffmpeg and ffprobe can be executed
+pub fn has_ffmpeg() -> bool {
+    ["ffmpeg", "ffprobe"]
+        .iter()
+        .all(|tool| Command::new(tool).arg("-version").output().is_ok())
+}
+
+/// The properties of the first video stream of a file
+///
+/// The size and sample aspect ratio are those of the decoded frames, which ffmpeg turns upright
+/// by the rotation of the stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct VideoInfo {
+    width: usize,
+    height: usize,
+    /// The frame rate as reported by ffprobe, e.g. "30000/1001"
+    frame_rate: String,
+    /// The width of a pixel relative to its height as (numerator, denominator)
+    sample_aspect_ratio: (u32, u32),
+}
+
+/// The part of the JSON output of `ffprobe -show_streams` that `VideoInfo` is read from
+#[derive(Deserialize)]
+struct ProbeOutput {
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+}
+
+#[derive(Deserialize)]
+struct ProbeStream {
+    width: usize,
+    height: usize,
+    r_frame_rate: String,
+    sample_aspect_ratio: Option<String>,
+    /// Older ffmpeg versions store the rotation in the "rotate" tag
+    #[serde(default)]
+    tags: HashMap<String, String>,
+    /// Newer ffmpeg versions store the rotation in a display matrix
+    #[serde(default)]
+    side_data_list: Vec<ProbeSideData>,
+}
+
+#[derive(Deserialize)]
+struct ProbeSideData {
+    rotation: Option<i64>,
+}
+
+fn probe_video(path: &Path) -> anyhow::Result<VideoInfo> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-select_streams", "v:0"])
+        .args(["-show_streams", "-of", "json"])
+        .arg(path)
+        .output()
+        .context("Could not run ffprobe")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "ffprobe could not read {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    parse_probe_output(&output.stdout)?
+        .with_context(|| format!("{} has no video stream", path.display()))
+}
+
+/// Read the `VideoInfo` of the first stream of the ffprobe output, if there is one
+fn parse_probe_output(json: &[u8]) -> anyhow::Result<Option<VideoInfo>> {
+    let output: ProbeOutput =
+        serde_json::from_slice(json).context("Could not parse the ffprobe output")?;
+    let stream = match output.streams.into_iter().next() {
+        Some(stream) => stream,
+        None => return Ok(None),
+    };
+    let rotation = stream
+        .side_data_list
+        .iter()
+        .find_map(|side_data| side_data.rotation)
+        .or_else(|| {
+            stream
+                .tags
+                .get("rotate")
+                .and_then(|rotate| rotate.parse().ok())
+        })
+        .unwrap_or(0);
+    // An unknown aspect ratio is reported as "0:1" or "N/A"
+    let sample_aspect_ratio = stream
+        .sample_aspect_ratio
+        .as_deref()
+        .and_then(|ratio| ratio.split_once(':'))
+        .and_then(|(numerator, denominator)| {
+            Some((numerator.parse().ok()?, denominator.parse().ok()?))
+        })
+        .filter(|&(numerator, denominator)| numerator > 0 && denominator > 0)
+        .unwrap_or((1, 1));
+    Ok(Some(if rotation.rem_euclid(180) == 90 {
+        VideoInfo {
+            width: stream.height,
+            height: stream.width,
+            frame_rate: stream.r_frame_rate,
+            sample_aspect_ratio: (sample_aspect_ratio.1, sample_aspect_ratio.0),
+        }
+    } else {
+        VideoInfo {
+            width: stream.width,
+            height: stream.height,
+            frame_rate: stream.r_frame_rate,
+            sample_aspect_ratio,
+        }
+    }))
+}
+
+/// Process all frames of a video with ffmpeg and encode them to `output_path`
+///
+/// ffmpeg decodes the frames to 16 bit RGB data, which is piped through the processor one frame
+/// at a time, and pipes the results to a second ffmpeg process for encoding. The frame rate is
+/// kept and the audio streams are copied unchanged. The codec and pixel format are chosen by
+/// ffmpeg from the output file extension. Each frame is processed on its own, so there is no
+/// temporal consistency between frames. Returns the number of processed frames.
+pub async fn process_video<P: AsRef<Path>, Q: AsRef<Path>>(
+    processor: &mut ImageProcessor,
+    input_path: P,
+    output_path: Q,
+    passes: usize,
+) -> anyhow::Result<usize> {
+    let (input_path, output_path) = (input_path.as_ref(), output_path.as_ref());
+    if !has_ffmpeg() {
+        anyhow::bail!(
+            "ffmpeg and ffprobe could not be executed, they are needed to process videos"
+        );
+    }
+    if processor.channels() != 3 {
+        anyhow::bail!("Videos can only be processed with RGB models");
+    }
+    if processor.output_scale() != 1 {
+        anyhow::bail!("Videos can only be processed at their own resolution");
+    }
+    let info = probe_video(input_path)?;
+
+    let mut decoder = Command::new("ffmpeg")
+        .args(["-v", "error", "-i"])
+        .arg(input_path)
+        .args([
+            "-map", "0:v:0", "-f", "rawvideo", "-pix_fmt", "rgb48le", "-",
+        ])
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Could not start the ffmpeg decoder")?;
+    let mut encoder = Command::new("ffmpeg")
+        .args(["-v", "error", "-y", "-f", "rawvideo", "-pix_fmt", "rgb48le"])
+        .args(["-s", &format!("{}x{}", info.width, info.height)])
+        .args(["-r", &info.frame_rate, "-i", "-", "-i"])
+        .arg(input_path)
+        .args(["-map", "0:v", "-map", "1:a?", "-c:a", "copy"])
+        .args([
+            "-vf",
+            &format!(
+                "setsar={}/{}",
+                info.sample_aspect_ratio.0, info.sample_aspect_ratio.1
+            ),
+        ])
+        .arg(output_path)
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("Could not start the ffmpeg encoder")?;
+
+    let frames = pipe_frames(
+        processor,
+        &info,
+        decoder.stdout.take().context("No ffmpeg decoder output")?,
+        encoder.stdin.take().context("No ffmpeg encoder input")?,
+        passes,
+    )
+    .await;
+    if frames.is_err() {
+        kill(&mut decoder);
+        kill(&mut encoder);
+    }
+    // The encoder input is closed by now, so both processes finish
+    let frame_count = frames?;
+    for (name, mut process) in [("decoder", decoder), ("encoder", encoder)] {
+        let status = process.wait()?;
+        if !status.success() {
+            anyhow::bail!("The ffmpeg {} failed with {}", name, status);
+        }
+    }
+    Ok(frame_count)
+}
+
+async fn pipe_frames(
+    processor: &mut ImageProcessor,
+    info: &VideoInfo,
+    mut decoded: ChildStdout,
+    mut encoded: ChildStdin,
+    passes: usize,
+) -> anyhow::Result<usize> {
+    let (width, height) = (info.width, info.height);
+    let mut buffer = vec![0u8; width * height * 3 * std::mem::size_of::<u16>()];
+    let mut frame_count = 0;
+    while read_frame(&mut decoded, &mut buffer)? {
+        log::info!("Processing frame {}", frame_count);
+        let input = Array3::from_shape_fn((height, width, 3), |(y, x, c)| {
+            let index = ((y * width + x) * 3 + c) * 2;
+            u16::from_le_bytes([buffer[index], buffer[index + 1]]) as f32 / u16::MAX as f32
+        });
+        let output = processor.process_tensor_passes(input, passes).await?;
+        // Values outside of the [0,1] range are clamped like in `tensor_to_image`
+        for (bytes, value) in buffer.chunks_exact_mut(2).zip(output.iter()) {
+            bytes.copy_from_slice(&((value * u16::MAX as f32) as u16).to_le_bytes());
+        }
+        encoded
+            .write_all(&buffer)
+            .context("Could not pass the frame to the ffmpeg encoder")?;
+        frame_count += 1;
+    }
+    Ok(frame_count)
+}
+
+/// Fill the buffer with the next frame, returns false at the end of the stream
+fn read_frame(reader: &mut impl Read, buffer: &mut [u8]) -> anyhow::Result<bool> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 if filled == 0 => return Ok(false),
+            0 => anyhow::bail!("The video stream ended within a frame"),
+            count => filled += count,
+        }
+    }
+    Ok(true)
+}
+
+fn kill(process: &mut Child) {
+    if let Err(err) = process.kill() {
+        log::warn!("Could not stop ffmpeg: {}", err);
+    }
+    let _ = process.wait();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::identity_processor;
+
+    fn count_frames(path: &Path, stream: &str) -> String {
+        let output = Command::new("ffprobe")
+            .args(["-v", "error", "-count_frames", "-select_streams", stream])
+            .args(["-show_entries", "stream=nb_read_frames", "-of", "csv=p=0"])
+            .arg(path)
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stdout).trim().to_owned()
+    }
+
+    #[test]
+    fn test_parse_probe_output() {
+        let plain = br#"{"streams": [{"width": 32, "height": 24, "r_frame_rate": "10/1",
+            "sample_aspect_ratio": "1:1"}]}"#;
+        assert_eq!(
+            parse_probe_output(plain).unwrap(),
+            Some(VideoInfo {
+                width: 32,
+                height: 24,
+                frame_rate: "10/1".to_owned(),
+                sample_aspect_ratio: (1, 1),
+            })
+        );
+        // A portrait video recorded in landscape orientation with anamorphic pixels
+        let rotated = br#"{"streams": [{"width": 32, "height": 24, "r_frame_rate": "30000/1001",
+            "sample_aspect_ratio": "4:3", "side_data_list": [{"rotation": -90}]}]}"#;
+        assert_eq!(
+            parse_probe_output(rotated).unwrap(),
+            Some(VideoInfo {
+                width: 24,
+                height: 32,
+                frame_rate: "30000/1001".to_owned(),
+                sample_aspect_ratio: (3, 4),
+            })
+        );
+        let tagged = br#"{"streams": [{"width": 32, "height": 24, "r_frame_rate": "25/1",
+            "sample_aspect_ratio": "0:1", "tags": {"rotate": "270"}}]}"#;
+        let info = parse_probe_output(tagged).unwrap().unwrap();
+        assert_eq!((info.width, info.height), (24, 32));
+        assert_eq!(info.sample_aspect_ratio, (1, 1));
+        let upside_down = br#"{"streams": [{"width": 32, "height": 24, "r_frame_rate": "25/1",
+            "side_data_list": [{"rotation": 180}]}]}"#;
+        let info = parse_probe_output(upside_down).unwrap().unwrap();
+        assert_eq!((info.width, info.height), (32, 24));
+
+        assert_eq!(parse_probe_output(br#"{"streams": []}"#).unwrap(), None);
+        assert_eq!(parse_probe_output(b"{}").unwrap(), None);
+        assert!(parse_probe_output(b"not json").is_err());
+    }
+
+    #[test]
+    #[ignore = "requires ffmpeg and ffprobe"]
+    fn test_process_video() {
+        assert!(has_ffmpeg(), "ffmpeg and ffprobe must be installed");
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.mkv");
+        let output = dir.path().join("output.mkv");
+        let status = Command::new("ffmpeg")
+            .args([
+                "-v",
+                "error",
+                "-f",
+                "lavfi",
+                "-i",
+                "testsrc=size=32x24:rate=10",
+            ])
+            .args(["-f", "lavfi", "-i", "sine=duration=1"])
+            .args(["-frames:v", "7", "-vf", "setsar=4/3"])
+            .args(["-c:v", "ffv1", "-c:a", "flac"])
+            .arg(&input)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let mut processor = identity_processor();
+
+        assert!(is_video(&input));
+        let frame_count =
+            pollster::block_on(process_video(&mut processor, &input, &output, 1)).unwrap();
+
+        assert_eq!(frame_count, 7);
+        assert_eq!(count_frames(&output, "v:0"), "7");
+        assert_eq!(probe_video(&output).unwrap(), probe_video(&input).unwrap());
+        // The audio is copied through
+        assert!(!count_frames(&output, "a:0").is_empty());
+    }
+}
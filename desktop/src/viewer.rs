@@ -0,0 +1,160 @@
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, RgbaImage};
+
+/// How `view_images` shows the images
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViewMode {
+    /// Render the images in the terminal, `width` is given in terminal columns
+    Inline { width: u32 },
+    /// Only list the image paths
+    List,
+}
+
+impl ViewMode {
+    /// Render images inline if stdout is a terminal and list them otherwise
+    ///
+    /// Terminals without a graphics protocol like kitty or sixel show the images as unicode
+    /// blocks, so only pipes and files fall back to a list.
+    pub fn detect(width: u32) -> Self {
+        if std::io::stdout().is_terminal() {
+            ViewMode::Inline { width }
+        } else {
+            ViewMode::List
+        }
+    }
+}
+
+/// An output image and the input it was processed from, if it was found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ViewEntry {
+    pub output: PathBuf,
+    pub input: Option<PathBuf>,
+}
+
+/// Find the images in `output_dir`, sorted by path
+///
+/// With an `input_dir`, each output is paired with the input of the same file name.
+pub fn collect_entries(
+    output_dir: &Path,
+    input_dir: Option<&Path>,
+) -> anyhow::Result<Vec<ViewEntry>> {
+    let mut outputs: Vec<_> = output_dir
+        .read_dir()
+        .with_context(|| format!("Could not read the directory {}", output_dir.display()))?
+        .filter_map(|maybe_entry| maybe_entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && ImageFormat::from_path(path).is_ok())
+        .collect();
+    outputs.sort();
+
+    Ok(outputs
+        .into_iter()
+        .map(|output| {
+            let input = input_dir
+                .zip(output.file_name())
+                .map(|(input_dir, name)| input_dir.join(name))
+                .filter(|input| input.is_file());
+            ViewEntry { output, input }
+        })
+        .collect())
+}
+
+/// Show the images of the entries, inputs are shown to the left of their outputs
+///
+/// Images that can not be shown inline are listed with the reason instead.
+pub fn view_images<W: Write>(
+    writer: &mut W,
+    entries: &[ViewEntry],
+    mode: ViewMode,
+) -> anyhow::Result<()> {
+    for entry in entries {
+        match &entry.input {
+            Some(input) => writeln!(writer, "{} -> {}", input.display(), entry.output.display())?,
+            None => writeln!(writer, "{}", entry.output.display())?,
+        }
+        if let ViewMode::Inline { width } = mode {
+            writer.flush()?;
+            if let Err(err) = print_entry(entry, width) {
+                writeln!(writer, "  (can not be shown: {:#})", err)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_entry(entry: &ViewEntry, width: u32) -> anyhow::Result<()> {
+    let output = image::open(&entry.output)?;
+    let image = match &entry.input {
+        Some(input) => side_by_side(&image::open(input)?, &output),
+        None => output,
+    };
+    let config = viuer::Config {
+        width: Some(width),
+        absolute_offset: false,
+        ..Default::default()
+    };
+    viuer::print(&image, &config)?;
+    Ok(())
+}
+
+/// Place two images next to each other, the left one is scaled to the height of the right one
+pub fn side_by_side(left: &DynamicImage, right: &DynamicImage) -> DynamicImage {
+    let left = left.resize(u32::MAX, right.height(), FilterType::Triangle);
+    let mut canvas = RgbaImage::new(left.width() + right.width(), right.height());
+    image::imageops::replace(&mut canvas, &left.to_rgba8(), 0, 0);
+    image::imageops::replace(&mut canvas, &right.to_rgba8(), left.width() as i64, 0);
+    DynamicImage::ImageRgba8(canvas)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn test_list_entries() {
+        let outputs = tempfile::tempdir().unwrap();
+        let inputs = tempfile::tempdir().unwrap();
+        let image = RgbImage::from_pixel(8, 4, Rgb([10, 20, 30]));
+        for name in ["b.png", "a.png"] {
+            image.save(outputs.path().join(name)).unwrap();
+        }
+        image.save(inputs.path().join("a.png")).unwrap();
+        std::fs::write(outputs.path().join("notes.txt"), "not an image").unwrap();
+
+        let entries = collect_entries(outputs.path(), Some(inputs.path())).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                ViewEntry {
+                    output: outputs.path().join("a.png"),
+                    input: Some(inputs.path().join("a.png")),
+                },
+                ViewEntry {
+                    output: outputs.path().join("b.png"),
+                    input: None,
+                },
+            ]
+        );
+
+        let mut listing = Vec::new();
+        view_images(&mut listing, &entries, ViewMode::List).unwrap();
+        let listing = String::from_utf8(listing).unwrap();
+        assert_eq!(listing.lines().count(), 2);
+        assert!(listing.contains("a.png -> "));
+        assert!(listing.ends_with("b.png\n"));
+    }
+
+    #[test]
+    fn test_side_by_side() {
+        let left = DynamicImage::ImageRgb8(RgbImage::new(20, 10));
+        let right = DynamicImage::ImageRgb8(RgbImage::new(8, 5));
+
+        let combined = side_by_side(&left, &right);
+        assert_eq!((combined.width(), combined.height()), (18, 5));
+    }
+}